@@ -3,17 +3,22 @@
 #![feature(kernel_allocation_new)]
 #![feature(kernel_frame_zero)]
 #![feature(kernel_physical_allocator_location)]
+#![feature(kernel_physical_allocator_non_contiguous)]
+#![feature(kernel_memory_stats)]
+#![feature(kernel_physical_allocator_reclaim)]
+#![feature(kernel_allocation_zeroing)]
 
 extern crate alloc;
 
 use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::cmp::min;
 use core::mem;
 use core::num::NonZeroUsize;
 use core::ops::Range;
 use kernel_api::memory::{Frame, AllocError};
-use kernel_api::memory::allocator::{AllocationMeta, BackingAllocator, Config, SizedBackingAllocator, SpecificLocation};
+use kernel_api::memory::allocator::{AllocationMeta, BackingAllocator, Config, ScatterList, SizedBackingAllocator, SpecificLocation, Stats};
 use kernel_api::sync::Mutex;
 use log::{debug, warn};
 
@@ -40,7 +45,9 @@ struct OutOfRangeError;
 
 struct BitmapAllocator {
     first_frame: Frame,
-    bitmap: Box<[usize]>
+    bitmap: Box<[usize]>,
+    /// The number of frames in the regions given to the allocator, which excludes any holes in its range
+    usable_frames: usize
 }
 
 impl BitmapAllocator {
@@ -68,13 +75,28 @@ impl BitmapAllocator {
         else { Ok(FrameState::Free) }
     }
 
+    /// Marks every frame in `region` as free, failing without changing anything if it isn't entirely in range
+    fn add_region(&mut self, region: Range<Frame>) -> Result<(), OutOfRangeError> {
+        if region.start < self.first_frame || region.end > self.last_frame() { return Err(OutOfRangeError); }
+
+        for frame in region {
+            // Frames that are already free were counted when they were given to the allocator
+            if self.get_frame(frame)? == FrameState::Free { continue; }
+
+            self.set_frame(frame, FrameState::Free)?;
+            self.usable_frames += 1;
+        }
+        Ok(())
+    }
+
     fn new(first_frame: Frame, frame_count: usize) -> Self {
         let bitmap_length = frame_count / mem::size_of::<usize>();
         let bitmap = Vec::into_boxed_slice(vec![0; bitmap_length]);
 
         Self {
             first_frame,
-            bitmap
+            bitmap,
+            usable_frames: 0
         }
     }
 
@@ -189,6 +211,92 @@ impl BitmapAllocator {
             Ok(start)
         }
     }
+
+    fn stats(&self) -> Stats {
+        let mut free_frames = 0;
+        let mut largest_free_run = 0;
+        let mut run = 0;
+
+        for entry in self.bitmap.iter() {
+            for bit_idx in 0..mem::size_of::<usize>() {
+                if ((entry >> bit_idx) & 1) == 1 {
+                    free_frames += 1;
+                    run += 1;
+                    largest_free_run = largest_free_run.max(run);
+                } else {
+                    run = 0;
+                }
+            }
+        }
+
+        Stats {
+            total_frames: self.usable_frames,
+            free_frames,
+            largest_free_run
+        }
+    }
+
+    /// Allocates `frame_count` contiguous frames, starting at a frame number that is a multiple of `alignment`
+    #[cold]
+    fn allocate_aligned(&mut self, frame_count: usize, alignment: usize) -> Result<Frame, AllocError> {
+        self.allocate_aligned_below(frame_count, alignment, self.last_frame())
+    }
+
+    /// Allocates `frame_count` frames starting at a multiple of `alignment` frames, which all come before `limit`
+    fn allocate_aligned_below(&mut self, frame_count: usize, alignment: usize, limit: Frame) -> Result<Frame, AllocError> {
+        let limit = min(limit, self.last_frame());
+        let first_index = (self.first_frame.start().addr / 4096).next_multiple_of(alignment);
+        let mut start = Frame::zero() + first_index;
+
+        while start + frame_count <= limit {
+            let free = (start..(start + frame_count)).all(|f| self.get_frame(f).is_ok_and(|state| state == FrameState::Free));
+            if free {
+                for frame in start..(start + frame_count) {
+                    self.set_frame(frame, FrameState::Allocated)
+                            .expect("Cannot have allocated an out of range frame");
+                }
+                return Ok(start);
+            }
+
+            start = start + alignment;
+        }
+
+        alloc_err!("No free memory aligned to {} frames below {:x?}", alignment, limit);
+    }
+
+    /// Allocates the first `frame_count` free frames, wherever they are
+    #[cold]
+    fn allocate_scattered(&mut self, frame_count: usize) -> Result<ScatterList, AllocError> {
+        let mut frames = ScatterList::new();
+        let mut remaining = frame_count;
+        let mut fragmented = false;
+
+        'outer: for (word_idx, entry) in self.bitmap.iter_mut().enumerate() {
+            if *entry == 0 { continue; }
+
+            for bit_idx in 0..mem::size_of::<usize>() {
+                if ((*entry >> bit_idx) & 1) == 0 { continue; }
+
+                let frame = self.first_frame + (word_idx * mem::size_of::<usize>()) + bit_idx;
+                if frames.push(frame..(frame + 1)).is_err() {
+                    fragmented = true;
+                    break 'outer;
+                }
+                *entry &= !(1 << bit_idx);
+
+                remaining -= 1;
+                if remaining == 0 { return Ok(frames); }
+            }
+        }
+
+        for frame in frames.frames() {
+            self.set_frame(frame, FrameState::Free)
+                    .expect("Cannot have allocated an out of range frame");
+        }
+
+        if fragmented { alloc_err!("Free memory too fragmented for {} frames", frame_count); }
+        else { alloc_err!("No free memory"); }
+    }
 }
 
 pub struct Wrapped(Mutex<BitmapAllocator>);
@@ -206,6 +314,29 @@ unsafe impl BackingAllocator for Wrapped {
         }
     }
 
+    fn allocate(&self, frame_count: usize) -> Result<ScatterList, AllocError> {
+        if frame_count == 0 { return Ok(ScatterList::new()); }
+
+        let mut guard = self.0.lock();
+
+        let contiguous = if frame_count == 1 { guard.allocate_one() }
+        else {
+            guard.allocate_multiple_fast(frame_count)
+                    .or_else(|_| guard.allocate_multiple_slow(frame_count))
+        };
+
+        match contiguous {
+            Ok(base) => Ok(ScatterList::from(base..(base + frame_count))),
+            Err(_) => guard.allocate_scattered(frame_count)
+        }
+    }
+
+    fn allocate_zeroed_nowait(&self) -> Result<Frame, AllocError> {
+        let frame = self.0.try_lock().ok_or(AllocError)?.allocate_one()?;
+        unsafe { core::ptr::write_bytes(frame.to_page().as_ptr(), 0, 4096); }
+        Ok(frame)
+    }
+
     unsafe fn deallocate_contiguous(&self, base: Frame, frame_count: NonZeroUsize) {
         let mut guard = self.0.lock();
 
@@ -230,7 +361,7 @@ unsafe impl BackingAllocator for Wrapped {
         let mut guard = self.0.lock();
 
         match location {
-            SpecificLocation::Aligned(_) => todo!(),
+            SpecificLocation::Aligned(alignment) => guard.allocate_aligned(frame_count, alignment.get().try_into().unwrap()),
             SpecificLocation::At(addr) => {
                 let end = addr + frame_count;
                 let free = (addr..end).all(|f| match guard.get_frame(f) {
@@ -241,9 +372,28 @@ unsafe impl BackingAllocator for Wrapped {
                 (addr..end).for_each(|f| guard.set_frame(f, FrameState::Allocated).expect("Must be in range"));
                 Ok(addr)
             }
-            SpecificLocation::Below { .. } => todo!(),
+            SpecificLocation::Below { location, with_alignment } => {
+                guard.allocate_aligned_below(frame_count, with_alignment.get().try_into().unwrap(), location)
+            }
         }
     }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.0.lock().stats())
+    }
+
+    fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, Stats)) {
+        let guard = self.0.lock();
+        let range = guard.first_frame..guard.last_frame();
+        let stats = guard.stats();
+        drop(guard);
+
+        f(range, stats);
+    }
+
+    unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+        self.0.lock().add_region(region).map_err(|_| ())
+    }
 }
 
 unsafe impl SizedBackingAllocator for Wrapped {
@@ -255,6 +405,7 @@ unsafe impl SizedBackingAllocator for Wrapped {
             for frame in free_region {
                 allocator.set_frame(frame, FrameState::Free)
                          .unwrap();
+                allocator.usable_frames += 1;
             }
         }
 
@@ -263,4 +414,54 @@ unsafe impl SizedBackingAllocator for Wrapped {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_count_free_frames() {
+        let mut allocator = BitmapAllocator::new(Frame::zero(), 32);
+        for i in 0..16 {
+            allocator.set_frame(Frame::zero() + i, FrameState::Free).unwrap();
+            allocator.usable_frames += 1;
+        }
+        allocator.set_frame(Frame::zero() + 4, FrameState::Allocated).unwrap();
+
+        let stats = allocator.stats();
+        assert_eq!(stats.total_frames, 16);
+        assert_eq!(stats.free_frames, 15);
+        assert_eq!(stats.used_frames(), 1);
+        assert_eq!(stats.largest_free_run, 11);
+    }
+
+    #[test]
+    fn add_region_counts_new_frames() {
+        let mut allocator = BitmapAllocator::new(Frame::zero(), 32);
+        allocator.add_region(Frame::zero()..(Frame::zero() + 8)).unwrap();
+        allocator.add_region((Frame::zero() + 4)..(Frame::zero() + 12)).unwrap();
+
+        let stats = allocator.stats();
+        assert_eq!(stats.total_frames, 12);
+        assert_eq!(stats.free_frames, 12);
+    }
+
+    #[test]
+    fn add_region_rejects_out_of_range() {
+        let mut allocator = BitmapAllocator::new(Frame::zero() + 8, 32);
+        assert!(allocator.add_region(Frame::zero()..(Frame::zero() + 16)).is_err());
+        assert!(allocator.add_region((Frame::zero() + 16)..(Frame::zero() + 48)).is_err());
+        assert_eq!(allocator.stats().total_frames, 0);
+    }
+
+    #[test]
+    fn allocate_below() {
+        let mut allocator = BitmapAllocator::new(Frame::zero(), 32);
+        for i in (0..4).chain(16..32) {
+            allocator.set_frame(Frame::zero() + i, FrameState::Free).unwrap();
+            allocator.usable_frames += 1;
+        }
+
+        assert_eq!(allocator.allocate_aligned_below(4, 1, Frame::zero() + 8), Ok(Frame::zero()));
+        assert!(allocator.allocate_aligned_below(1, 1, Frame::zero() + 8).is_err());
+        assert_eq!(allocator.allocate_aligned_below(2, 8, Frame::zero() + 32), Ok(Frame::zero() + 16));
+    }
+}
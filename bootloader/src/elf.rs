@@ -11,7 +11,7 @@ use elf::header::program::{ProgramHeaderEntry64, SegmentFlags, SegmentType};
 use elf::symbol_table::SymbolMap;
 use kernel_api::memory::{PhysicalAddress, VirtualAddress};
 
-use crate::paging::{Frame, Page, PageTable};
+use crate::paging::{Frame, Page, PageTable, TableEntryFlags};
 
 pub fn load_module(from: impl AsRef<Path>) -> Result<(),()> {
 	todo!()
@@ -55,6 +55,15 @@ fn load_segment<E: Debug, F: FnMut(usize, AllocateType) -> Result<u64, E>>(kerne
 	})
 }
 
+/// The page table flags a segment's pages are mapped with, so that only writable segments can be written and only
+/// executable segments can be executed
+fn entry_flags(segment_flags: SegmentFlags) -> TableEntryFlags {
+	let mut flags = TableEntryFlags::empty();
+	if segment_flags.contains(SegmentFlags::Writeable) { flags |= TableEntryFlags::WRITABLE; }
+	if !segment_flags.contains(SegmentFlags::Executable) { flags |= TableEntryFlags::NO_EXECUTE; }
+	flags
+}
+
 pub struct KernelLoadInfo<'a> {
 	pub kernel: File<'a>,
 	pub page_table: PageTable,
@@ -84,11 +93,12 @@ pub fn load_kernel<E: Debug, F: FnMut(usize, AllocateType) -> Result<u64, E>>(fr
 			      tls_end = Some(segment.virtual_addr + usize::try_from(segment_meta.memory_size).unwrap());
 		      }
 
-		      page_table.try_map_range(
+		      page_table.try_map_range_with(
 			      Page(segment.virtual_addr.addr.try_into().unwrap()),
 			      Frame(segment.physical_addr.addr.try_into().unwrap()),
 			      segment.page_count.try_into().unwrap(),
-			      || allocator(1, AllocateType::AnyPages)
+			      || allocator(1, AllocateType::AnyPages),
+			      entry_flags(segment_meta.segment_flags)
 		      ).unwrap();
 
 		      Ok(())
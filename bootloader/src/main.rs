@@ -653,7 +653,7 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
             Frame(framebuffer_addr.try_into().unwrap()),
             page_count.try_into().unwrap(),
             || services.allocate_pages(AllocateType::AnyPages, memory_types::PAGE_TABLE, 1).map_err(|_| ()),
-            TableEntryFlags::WRITABLE | TableEntryFlags::NO_EXECUTE | TableEntryFlags::MMIO
+            TableEntryFlags::WRITABLE | TableEntryFlags::NO_EXECUTE | TableEntryFlags::FRAMEBUFFER
         ).ok()?;
 
         let color_format = match mode_info.pixel_format() {
@@ -683,8 +683,13 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
             panic!("Failed to allocate enough memory to load popcorn2");
         };
 
-        page_table.try_map_range::<(), _>(Page((address_range.start.addr+4096).try_into().unwrap()), Frame(allocation), STACK_PAGE_COUNT.try_into().unwrap(), || services.allocate_pages(AllocateType::AnyPages, memory_types::PAGE_TABLE, 1).map_err(|_| ()))
-                         .unwrap();
+        page_table.try_map_range_with::<(), _>(
+            Page((address_range.start.addr+4096).try_into().unwrap()),
+            Frame(allocation),
+            STACK_PAGE_COUNT.try_into().unwrap(),
+            || services.allocate_pages(AllocateType::AnyPages, memory_types::PAGE_TABLE, 1).map_err(|_| ()),
+            TableEntryFlags::WRITABLE | TableEntryFlags::NO_EXECUTE
+        ).unwrap();
 
         handoff::Stack {
             bottom_virt: KPage::new(address_range.start.aligned()),
@@ -733,12 +738,15 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     ) {
         debug!("[page map] {:x?} ({:#x} -> {:#x}) -> {:#x}", mem.ty, mem.phys_start, mem.phys_start + mem.page_count * 4096, mem.phys_start + PAGE_MAP_OFFSET);
 
-        // UEFI memory sections are always aligned by firmware
-        (0..mem.page_count).map(|page_num| mem.phys_start + page_num * 4096).try_for_each(|addr| {
-            let virt_addr = addr + PAGE_MAP_OFFSET;
-            assert!(addr < PAGE_MAP_OFFSET_LEN, "Too much physical memory");
-            page_table.try_map_page::<(), _>(Page(virt_addr), Frame(addr), || services.allocate_pages(AllocateType::AnyPages, memory_types::PAGE_TABLE, 1).map_err(|_| ()))
-        }).unwrap();
+        // UEFI memory sections are always aligned by firmware, and larger pages are used wherever a section allows them
+        assert!(mem.phys_start + mem.page_count * 4096 <= PAGE_MAP_OFFSET_LEN, "Too much physical memory");
+        page_table.try_map_range_huge_with::<(), _>(
+            Page(mem.phys_start + PAGE_MAP_OFFSET),
+            Frame(mem.phys_start),
+            mem.page_count,
+            || services.allocate_pages(AllocateType::AnyPages, memory_types::PAGE_TABLE, 1).map_err(|_| ()),
+            TableEntryFlags::WRITABLE | TableEntryFlags::NO_EXECUTE
+        ).unwrap();
     }
 
     for mem in memory_map.entries().filter(|mem|
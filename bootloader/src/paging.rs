@@ -58,14 +58,25 @@ impl PageTable {
 	}
 
 	pub fn translate_page(&self, virt: Page) -> Option<Frame> {
-		let tab = self.0.get_child_table(virt.l4_index().try_into().unwrap())?
-				.get_child_table(virt.l3_index().try_into().unwrap())?
-				.get_child_table(virt.l2_index().try_into().unwrap())?;
+		let l3 = self.0.get_child_table(virt.l4_index().try_into().unwrap())?;
+		let l3_entry = &l3[virt.l3_index().try_into().unwrap()];
+		if l3_entry.is_huge() {
+			return Some(Frame(l3_entry.pointed_frame()?.0 + (virt.0 & (amd64::L2_MASK | amd64::L1_MASK))));
+		}
+
+		let l2 = l3.get_child_table(virt.l3_index().try_into().unwrap())?;
+		let l2_entry = &l2[virt.l2_index().try_into().unwrap()];
+		if l2_entry.is_huge() {
+			return Some(Frame(l2_entry.pointed_frame()?.0 + (virt.0 & amd64::L1_MASK)));
+		}
+
+		let tab = l2.get_child_table(virt.l2_index().try_into().unwrap())?;
 		tab[virt.l1_index().try_into().unwrap()].pointed_frame()
 	}
 
+	/// Maps a writable and executable page
 	pub fn try_map_page<E, F: Fn() -> Result<u64, E>>(&mut self, page: Page, frame: Frame, allocate: F) -> Result<(),MapError<E>> {
-		self.try_map_page_with(page, frame, allocate, TableEntryFlags::empty())
+		self.try_map_page_with(page, frame, allocate, TableEntryFlags::WRITABLE)
 	}
 
 	pub fn try_map_page_with<E, F: FnMut() -> Result<u64, E>>(&mut self, page: Page, frame: Frame, mut allocate: F, flags: TableEntryFlags) -> Result<(),MapError<E>> {
@@ -76,10 +87,6 @@ impl PageTable {
 		entry.set_pointed_frame(frame, flags).map_err(|_| MapError::AlreadyMapped)
 	}
 
-	pub fn try_map_range<E, F: FnMut() -> Result<u64, E>>(&mut self, page_start: Page, frame_start: Frame, page_count: u64, allocate: F) -> Result<(), MapError<E>> {
-		self.try_map_range_with(page_start, frame_start, page_count, allocate, TableEntryFlags::empty())
-	}
-
 	pub fn try_map_range_with<E, F: FnMut() -> Result<u64, E>>(&mut self, page_start: Page, frame_start: Frame, page_count: u64, mut allocate: F, flags: TableEntryFlags) -> Result<(), MapError<E>> {
 		for i in 0..page_count {
 			let page = Page(page_start.0 + i*4096);
@@ -90,7 +97,45 @@ impl PageTable {
 		Ok(())
 	}
 
+	/// Maps a range like [`try_map_range_with`](Self::try_map_range_with), but uses 2MiB and 1GiB pages wherever the pages
+	/// and frames are both aligned, so that large ranges need far fewer page tables
+	pub fn try_map_range_huge_with<E, F: FnMut() -> Result<u64, E>>(&mut self, page_start: Page, frame_start: Frame, page_count: u64, mut allocate: F, flags: TableEntryFlags) -> Result<(), MapError<E>> {
+		const SIZE_2M: u64 = 1 << amd64::L2_SHIFT;
+		const SIZE_1G: u64 = 1 << amd64::L3_SHIFT;
+
+		let gigabyte_pages = amd64::gigabyte_pages_supported();
+		let len = page_count * 4096;
+		let mut offset = 0;
+
+		while offset < len {
+			let page = Page(page_start.0 + offset);
+			let frame = Frame(frame_start.0 + offset);
+			let fits = |size: u64| page.0 % size == 0 && frame.0 % size == 0 && len - offset >= size;
+
+			if gigabyte_pages && fits(SIZE_1G) {
+				let entry = &mut self.0.try_get_or_create_child_table(page.l4_index().try_into().unwrap(), &mut allocate)?
+					[page.l3_index().try_into().unwrap()];
+				entry.set_pointed_frame(frame, flags | TableEntryFlags::HUGE_PAGE).map_err(|_| MapError::AlreadyMapped)?;
+				offset += SIZE_1G;
+			} else if fits(SIZE_2M) {
+				let entry = &mut self.0.try_get_or_create_child_table(page.l4_index().try_into().unwrap(), &mut allocate)?
+					.try_get_or_create_child_table(page.l3_index().try_into().unwrap(), &mut allocate)?
+					[page.l2_index().try_into().unwrap()];
+				entry.set_pointed_frame(frame, flags | TableEntryFlags::HUGE_PAGE).map_err(|_| MapError::AlreadyMapped)?;
+				offset += SIZE_2M;
+			} else {
+				self.try_map_page_with(page, frame, &mut allocate, flags)?;
+				offset += 4096;
+			}
+		}
+		Ok(())
+	}
+
+	/// Switches to this page table, first enabling no-execute pages so that entries with [`TableEntryFlags::NO_EXECUTE`]
+	/// are valid
 	pub fn switch(&self) {
+		unsafe { amd64::enable_no_execute(); }
+
 		let addr = self.0 as *const _ as usize;
 		unsafe{ asm!("mov cr3, {}", in(reg) addr, options(nostack, preserves_flags)); }
 	}
@@ -144,11 +189,13 @@ impl<Level: TableLevel> Table<Level> {
 
 impl<Level: ParentTableLevel> Table<Level> {
 	pub fn get_child_table(&self, index: usize) -> Option<&'static Table<Level::Child>> {
+		if self.0[index].is_huge() { return None; }
 		self.0[index].pointed_frame()
 				.map(|frame| unsafe { &*(frame.0 as *const Table<_>) })
 	}
 
 	pub fn get_child_table_mut(&mut self, index: usize) -> Option<&'static mut Table<Level::Child>> {
+		if self.0[index].is_huge() { return None; }
 		self.0[index].pointed_frame()
 		             .map(|frame| unsafe { &mut *(frame.0 as *mut Table<_>) })
 	}
@@ -239,16 +286,23 @@ bitflags! {
 
 		const PERMISSIVE =      Self::WRITABLE.bits() | Self::USER_ACCESSIBLE.bits();
 		const MMIO =            Self::WRITE_THROUGH.bits() | Self::NO_CACHE.bits();
+		/// Uncached-minus with the firmware's PAT, and write combining once the kernel has programmed its own
+		const FRAMEBUFFER =     Self::NO_CACHE.bits();
 	}
 }
 
 impl TableEntry {
-	const fn new() -> Self { Self(TableEntryFlags::WRITABLE.bits()) }
+	const fn new() -> Self { Self(0) }
 
 	fn flags(&self) -> TableEntryFlags {
 		TableEntryFlags::from_bits_truncate(self.0)
 	}
 
+	/// Returns `true` if the entry maps a large page, rather than pointing to a child table
+	fn is_huge(&self) -> bool {
+		self.flags().contains(TableEntryFlags::PRESENT | TableEntryFlags::HUGE_PAGE)
+	}
+
 	fn pointed_frame(&self) -> Option<Frame> {
 		if self.flags().contains(TableEntryFlags::PRESENT) {
 			Some(Frame(self.0 & 0x000f_ffff_ffff_f000))
@@ -280,4 +334,24 @@ mod amd64 {
 	pub const L3_MASK:  u64 =     0o777_000_000_0000;
 	pub const L2_MASK:  u64 =         0o777_000_0000;
 	pub const L1_MASK:  u64 =             0o777_0000;
+
+	/// Returns `true` if the CPU can map 1GiB pages
+	pub fn gigabyte_pages_supported() -> bool {
+		// CPUID.80000001h:EDX.Page1GB
+		let features = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) };
+		features.edx & (1 << 26) != 0
+	}
+
+	/// Sets EFER.NXE
+	pub unsafe fn enable_no_execute() {
+		const EFER: u32 = 0xC000_0080;
+		const EFER_NXE: u64 = 1 << 11;
+
+		unsafe {
+			let (low, high): (u32, u32);
+			core::arch::asm!("rdmsr", in("ecx") EFER, out("eax") low, out("edx") high, options(nostack, preserves_flags));
+			let efer = ((high as u64) << 32) | (low as u64) | EFER_NXE;
+			core::arch::asm!("wrmsr", in("ecx") EFER, in("eax") efer as u32, in("edx") (efer >> 32) as u32, options(nostack, preserves_flags));
+		}
+	}
 }
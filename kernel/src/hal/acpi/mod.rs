@@ -3,6 +3,7 @@ use core::mem::ManuallyDrop;
 use core::num::NonZeroUsize;
 use core::ops::{Deref, DerefMut, Div};
 use core::ptr::{from_raw_parts, from_raw_parts_mut, NonNull, Pointee};
+use core::sync::atomic::{AtomicBool, Ordering};
 use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
 use log::debug;
 use kernel_api::memory::mapping::{Config, Location, Mapping};
@@ -14,12 +15,23 @@ use kernel_api::sync::OnceLock;
 use crate::hal;
 
 static TABLES: OnceLock<AcpiTables<Handler<'static>>> = OnceLock::new();
+static RECLAIMED: AtomicBool = AtomicBool::new(false);
 
 #[track_caller]
 pub fn tables() -> &'static AcpiTables<Handler<'static>> {
+	assert!(!RECLAIMED.load(Ordering::Acquire), "ACPI tables have been reclaimed");
 	TABLES.get().expect("ACPI tables not yet parsed")
 }
 
+/// Stops the ACPI tables being used, so that the memory holding them can be reclaimed
+///
+/// # Safety
+///
+/// Nothing can still be borrowing anything from the tables
+pub unsafe fn reclaim() {
+	RECLAIMED.store(true, Ordering::Release);
+}
+
 pub unsafe fn init_tables(rsdp_addr: usize) {
 	TABLES.get_or_init(|| {
 		// SAFETY:
@@ -1,13 +1,16 @@
+use alloc::boxed::Box;
 use core::arch::{asm, global_asm};
+use core::arch::x86_64::{__cpuid, _rdtsc};
 use core::mem;
 use core::mem::offset_of;
 use core::num::NonZeroU8;
 use log::warn;
-use crate::hal::{Hal, SaveState, ThreadControlBlock};
+use crate::hal::{Hal, SaveState, ThreadControlBlock, ThreadMain};
 use crate::hal::arch::amd64::idt::entry::Type;
-use crate::hal::arch::amd64::idt::handler::InterruptStackFrame;
+use crate::hal::arch::amd64::idt::handler::{InterruptStackFrame, PageFaultError};
 use crate::hal::arch::amd64::idt::Idt;
 use crate::hal::exception::{DebugTy, Exception, PageFault, Ty};
+use crate::memory::address_space::AddressSpace;
 use crate::sprintln;
 
 mod gdt;
@@ -19,6 +22,7 @@ mod qemu;
 mod paging2;
 pub(crate) mod paging;
 mod pic;
+mod pat;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -36,6 +40,9 @@ extern "C" fn amd64_handler2(data: &mut IrqData) {
 	const MIN_IRQ: u8 = Amd64Hal::MIN_IRQ_NUM as u8;
 	const MAX_IRQ: u8 = Amd64Hal::MAX_IRQ_NUM as u8;
 
+	// Page faults always start at the top of their IST stack, so a nested fault has to be moved further down it
+	let _nested_fault_guard = (data.num == 14).then(|| unsafe { tss::NestedFaultGuard::new() });
+
 	let exception_payload = match data.num as u8 {
 		0 | 16 | 19 => Exception {
 			ty: Ty::FloatingPoint,
@@ -52,8 +59,13 @@ extern "C" fn amd64_handler2(data: &mut IrqData) {
 		14 => {
 			let cr2: usize;
 			unsafe { asm!("mov {}, cr2", out(reg) cr2); }
+			let error = PageFaultError::from_bits_retain(data.error as u32);
 			Exception {
-				ty: Ty::PageFault(PageFault { access_addr: cr2 }),
+				ty: Ty::PageFault(PageFault {
+					access_addr: cr2,
+					is_write: error.contains(PageFaultError::ATTEMPTED_WRITE),
+					is_user: error.contains(PageFaultError::USER_FAIL)
+				}),
 				at_instruction: data.rip as usize,
 			}
 		},
@@ -468,7 +480,7 @@ impl Amd64Hal {
 			idt_entry!(table, 11);
 			idt_entry!(table, 12);
 			idt_entry!(table, 13);
-			idt_entry!(table, 14);
+			table[14] = idt::entry::Entry::new_ptr(amd64_irq_handler_14, Some(tss::PAGE_FAULT_IST), 0, Type::InterruptGate);
 			idt_entry!(table, 15);
 			idt_entry!(table, 16);
 			idt_entry!(table, 17);
@@ -735,7 +747,25 @@ unsafe impl Hal for Amd64Hal {
 		Ok(())
 	}
 
+	fn boot_entropy() -> u64 {
+		let has_rdrand = unsafe { __cpuid(1) }.ecx & (1 << 30) != 0;
+		if has_rdrand {
+			let (value, success): (u64, u8);
+			unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) success); }
+			if success != 0 { return value; }
+		}
+
+		// Fall back to the timestamp counter, which at least varies with how long firmware took to boot
+		unsafe { _rdtsc() }
+	}
+
+	fn timestamp() -> u64 {
+		unsafe { _rdtsc() }
+	}
+
 	fn early_init() {
+		pat::init();
+
 		let tss = tss::TSS.get_or_init(|| {
 			tss::Tss::new()
 		});
@@ -789,6 +819,11 @@ unsafe impl Hal for Amd64Hal {
 		}
 	}
 
+	fn halt() {
+		// `sti` only takes effect after the next instruction, so no interrupt can arrive before halting
+		unsafe { asm!("sti", "hlt", options(nomem, nostack)); }
+	}
+
 	unsafe fn load_tls(ptr: *mut u8) {
 		let tls_self_ptr_low = ptr as usize as u32;
 		let tls_self_ptr_high = ((ptr as usize) >> 32) as u32;
@@ -841,7 +876,7 @@ unsafe impl Hal for Amd64Hal {
 			const offset_of!(Amd64SaveState, r13) + offset_of!(ThreadControlBlock, save_state),
 			const offset_of!(Amd64SaveState, r14) + offset_of!(ThreadControlBlock, save_state),
 			const offset_of!(Amd64SaveState, r15) + offset_of!(ThreadControlBlock, save_state),
-			const offset_of!(paging2::Amd64TTable, pml4) + offset_of!(ThreadControlBlock, ttable),
+			const offset_of!(paging2::Amd64TTable, pml4) + offset_of!(AddressSpace, ttable) + offset_of!(ThreadControlBlock, address_space),
 			options(noreturn)
 		);
 	}
@@ -862,24 +897,44 @@ struct Amd64SaveState {
 }
 
 impl SaveState for Amd64SaveState {
-	fn new(tcb: &mut ThreadControlBlock, init: fn(), main: fn() -> !) -> Self {
+	fn new(tcb: &mut ThreadControlBlock, init: fn(), main: ThreadMain) -> Self {
 		let stack = &mut tcb.kernel_stack;
+		// `init` returns into the trampoline, which finds `main` in `rbx` since `init` has to preserve it
 		let stack_start = unsafe {
 			let stack_top = stack.virtual_end().start().as_ptr().cast::<usize>();
 			stack_top.sub(1).write(0xdeadbeef);
 			stack_top.sub(2).write(0);
-			stack_top.sub(3).write(main as usize);
+			stack_top.sub(3).write(thread_entry_trampoline as usize);
 			stack_top.sub(4).write(init as usize);
 			stack_top.sub(4)
 		};
 
 		Self {
 			rsp: stack_start as usize,
+			rbx: Box::into_raw(main) as usize,
 			.. Self::default()
 		}
 	}
 }
 
+#[naked]
+unsafe extern "C" fn thread_entry_trampoline() -> ! {
+	asm!(
+		"mov rdi, rbx",
+		"call {}",
+		"ud2",
+		sym thread_entry,
+		options(noreturn)
+	);
+}
+
+extern "C" fn thread_entry(main: *mut Box<dyn FnOnce() + Send>) -> ! {
+	// SAFETY: `main` was leaked by `Amd64SaveState::new`, and is only ever taken back here
+	let main = unsafe { Box::from_raw(main) };
+	main();
+	crate::threading::thread_exit()
+}
+
 extern "x86-interrupt" fn breakpoint(frame: InterruptStackFrame) {
 	warn!("BREAKPOINT: {frame:#x?}");
 }
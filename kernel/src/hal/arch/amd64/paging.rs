@@ -1,6 +1,7 @@
 use core::fmt::{Debug, Formatter};
 use bitflags::{bitflags, Flags};
 use kernel_api::memory::{Frame, PhysicalAddress};
+use kernel_api::memory::mapping::{CacheMode, Protection};
 use crate::hal::paging::{Entry, Level};
 use crate::hal::paging::levels::{Global, Upper, Middle, Lower};
 
@@ -40,13 +41,75 @@ bitflags! {
 		impl Amd64Entry: u64 {
 			const PRESENT = 1<<0;
 			const WRITABLE = 1<<1;
+			const USER = 1<<2;
+			const WRITE_THROUGH = 1<<3;
+			const NO_CACHE = 1<<4;
+			/// The entry maps a large page rather than pointing to a child table
+			const HUGE = 1<<7;
+			const NO_EXECUTE = 1<<63;
 			const ADDRESS = 0x0fff_ffff_ffff_f000;
+
+			const PROTECTION = Self::WRITABLE.bits() | Self::USER.bits() | Self::NO_EXECUTE.bits();
 		}
 	}
 
 impl Amd64Entry {
 	// in future will take into account on-demand paging etc.
 	pub(crate) fn is_used(self) -> bool { self.is_present() }
+
+	/// Returns `true` if the entry maps a 2MiB or 1GiB page, rather than pointing to a child table
+	pub(crate) fn is_huge(self) -> bool { self.is_present() && self.contains(Self::HUGE) }
+
+	/// Returns the flags required to map a page with the given protection
+	pub(crate) fn protection_flags(protection: Protection) -> Self {
+		let mut flags = <Self as Flags>::empty();
+		if protection.contains(Protection::WRITE) { flags |= Self::WRITABLE; }
+		if protection.contains(Protection::USER) { flags |= Self::USER; }
+		if !protection.contains(Protection::EXECUTE) { flags |= Self::NO_EXECUTE; }
+		flags
+	}
+
+	/// Returns the flags required to map a page with the given caching behaviour
+	///
+	/// These select an entry of the PAT, which is programmed by [`pat::init`](super::pat::init)
+	pub(crate) fn cache_flags(cache: CacheMode) -> Self {
+		match cache {
+			CacheMode::WriteBack => <Self as Flags>::empty(),
+			CacheMode::WriteThrough => Self::WRITE_THROUGH,
+			CacheMode::WriteCombining => Self::NO_CACHE,
+			CacheMode::Uncached => Self::WRITE_THROUGH | Self::NO_CACHE,
+		}
+	}
+
+	/// Returns the caching behaviour selected by the entry's flags, the inverse of [`cache_flags`](Self::cache_flags)
+	pub(crate) fn cache_mode(self) -> CacheMode {
+		match (self.contains(Self::WRITE_THROUGH), self.contains(Self::NO_CACHE)) {
+			(false, false) => CacheMode::WriteBack,
+			(true, false) => CacheMode::WriteThrough,
+			(false, true) => CacheMode::WriteCombining,
+			(true, true) => CacheMode::Uncached,
+		}
+	}
+
+	/// Points a non-present entry at `frame`, with `flags` in addition to [`PRESENT`](Self::PRESENT)
+	pub(crate) fn point_to_frame_with(&mut self, frame: Frame, flags: Self) -> Result<(), ()> {
+		if self.is_present() { return Err(()); }
+
+		let masked_addr = u64::try_from(frame.start().addr).unwrap() & Self::ADDRESS.0;
+		self.0 = masked_addr | (flags.0 & !Self::ADDRESS.0) | Self::PRESENT.0;
+
+		Ok(())
+	}
+
+	/// Replaces the protection flags of a present entry
+	pub(crate) fn set_protection(&mut self, protection: Protection) -> Result<(), ()> {
+		if !self.is_present() { return Err(()); }
+
+		self.remove(Self::PROTECTION);
+		self.insert(Self::protection_flags(protection));
+
+		Ok(())
+	}
 }
 
 impl Entry for Amd64Entry {
@@ -64,13 +127,8 @@ impl Entry for Amd64Entry {
 	}
 
 	fn point_to_frame(&mut self, frame: Frame) -> Result<(), ()> {
-		if self.is_present() { return Err(()); }
-
-		let empty_entry = self.0 & !Self::ADDRESS.0;
-		let masked_addr = u64::try_from(frame.start().addr).unwrap() & Self::ADDRESS.0;
-		self.0 = empty_entry | masked_addr | Self::PRESENT.0 | Self::WRITABLE.0;
-
-		Ok(())
+		let empty_entry = Self(self.0 & !Self::ADDRESS.0);
+		self.point_to_frame_with(frame, empty_entry | Self::WRITABLE)
 	}
 }
 
@@ -1,10 +1,10 @@
 use core::arch::asm;
+use core::arch::x86_64::__cpuid;
 use core::fmt::{Debug, Formatter};
 use kernel_api::bridge::paging::MapPageError;
-use kernel_api::memory::allocator::{BackingAllocator};
 use kernel_api::memory::{Frame, Page, PhysicalAddress, AllocError};
-use kernel_api::memory::physical::highmem;
-use table::{Table, PDPT, PML4, PageIndices};
+use kernel_api::memory::mapping::{CacheMode, PageSize, Protection};
+use table::{Blocking, Level, ParentLevel, Table, PD, PDPT, PML4, PageIndices};
 use crate::hal::arch::amd64::paging::Amd64Entry;
 use crate::hal::paging2::{KTable, TTable};
 use crate::hal::paging::Entry;
@@ -20,25 +20,43 @@ pub(crate) unsafe fn construct_tables() -> (Amd64KTable, Amd64TTable) {
 	};
 	let ttable_base = ttable_base & 0xffff_ffff_ffff_f000;
 
+	unsafe { enable_protection(); }
+
 	let ttable = unsafe { Amd64TTable::new_unchecked(Frame::new(PhysicalAddress::new(ttable_base))) };
 
 	let ktable_base = ttable.pml4.pml4().entries[256].pointed_frame()
 			.expect("Invalid TTable");
 	let ktable = Amd64KTable {
-		tables: KTablePtr(ktable_base),
-		allocator: highmem()
+		tables: KTablePtr(ktable_base)
 	};
 
 	(ktable, ttable)
 }
 
+/// Enables no-execute pages, and write protection of read-only pages in kernel mode
+unsafe fn enable_protection() {
+	const EFER: u32 = 0xC000_0080;
+	const EFER_NXE: u64 = 1 << 11;
+	const CR0_WP: u64 = 1 << 16;
+
+	unsafe {
+		let (low, high): (u32, u32);
+		asm!("rdmsr", in("ecx") EFER, out("eax") low, out("edx") high);
+		let efer = ((high as u64) << 32) | (low as u64) | EFER_NXE;
+		asm!("wrmsr", in("ecx") EFER, in("eax") efer as u32, in("edx") (efer >> 32) as u32);
+
+		let cr0: u64;
+		asm!("mov {}, cr0", out(reg) cr0);
+		asm!("mov cr0, {}", in(reg) cr0 | CR0_WP);
+	}
+}
+
 #[derive(Debug)]
 struct KTablePtr(Frame); // points to a [Table<PDPT>; 256]
 
 #[repr(align(8))]
 pub struct Amd64KTable {
 	tables: KTablePtr, // points to a [Table<PDPT>; 256]
-	allocator: &'static dyn BackingAllocator,
 }
 
 impl KTablePtr {
@@ -80,14 +98,12 @@ impl TTablePtr {
 
 pub struct Amd64TTable {
 	pub(super) pml4: TTablePtr,
-	allocator: &'static dyn BackingAllocator,
 }
 
 impl Amd64TTable {
 	pub unsafe fn new_unchecked(pml4: Frame) -> Self {
 		Self {
-			pml4: TTablePtr(pml4),
-			allocator: highmem()
+			pml4: TTablePtr(pml4)
 		}
 	}
 }
@@ -103,81 +119,237 @@ impl KTable for Amd64TTable {
 		assert!(page.start().addr < 0xffff_8000_0000_0000, "TTable only handles lower half addresses");
 
 		let pdpt = self.pml4.pml4().child_table(page.pml4_index())?;
-		let pd = pdpt.child_table(page.pdpt_index())?;
-		let pt = pd.child_table(page.pd_index())?;
-		pt.entries[page.pt_index()].pointed_frame()
+		translate(pdpt, page)
+	}
+
+	fn cache_mode(&self, page: Page) -> Option<CacheMode> {
+		assert!(page.start().addr < 0xffff_8000_0000_0000, "TTable only handles lower half addresses");
+
+		let pdpt = self.pml4.pml4().child_table(page.pml4_index())?;
+		leaf(pdpt, page).map(|(entry, _)| entry.cache_mode())
+	}
+
+	fn map_huge_page(&mut self, page: Page, frame: Frame, size: PageSize, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
+		assert!(page.start().addr < 0xffff_8000_0000_0000, "TTable only handles lower half addresses");
+
+		// Permissions are the intersection of every level, so user pages need every parent table to be user accessible
+		let table_flags = if protection.contains(Protection::USER) { Amd64Entry::USER } else { Amd64Entry::empty() };
+
+		let pdpt = self.pml4.pml4_mut().child_table_or_new(page.pml4_index(), table_flags, Blocking::Wait)?;
+		map(pdpt, page, frame, size, leaf_flags(protection, cache), table_flags, Blocking::Wait)
 	}
 
-	fn map_page(&mut self, page: Page, frame: Frame) -> Result<(), MapPageError> {
+	fn try_map_page(&mut self, page: Page, frame: Frame, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
 		assert!(page.start().addr < 0xffff_8000_0000_0000, "TTable only handles lower half addresses");
 
-		let pdpt = self.pml4.pml4_mut().child_table_or_new(page.pml4_index(), &self.allocator)?;
-		let pd = pdpt.child_table_or_new(page.pdpt_index(), &self.allocator)?;
-		let pt = pd.child_table_or_new(page.pd_index(), &self.allocator)?;
-		pt.entries[page.pt_index()].point_to_frame(frame).map_err(|_| MapPageError::AlreadyMapped)
+		let table_flags = if protection.contains(Protection::USER) { Amd64Entry::USER } else { Amd64Entry::empty() };
+
+		let pdpt = self.pml4.pml4_mut().child_table_or_new(page.pml4_index(), table_flags, Blocking::NoWait)?;
+		map(pdpt, page, frame, PageSize::Size4KiB, leaf_flags(protection, cache), table_flags, Blocking::NoWait)
 	}
 
-	fn unmap_page(&mut self, page: Page) -> Result<(), ()> {
+	fn unmap_huge_page(&mut self, page: Page, size: PageSize) -> Result<(), ()> {
 		assert!(page.start().addr < 0xffff_8000_0000_0000, "TTable only handles lower half addresses");
 
 		let pdpt = self.pml4.pml4_mut().child_table_mut(page.pml4_index()).ok_or(())?;
-		let pd = pdpt.child_table_mut(page.pdpt_index()).ok_or(())?;
-		let pt = pd.child_table_mut(page.pd_index()).ok_or(())?;
-		let entry = &mut pt.entries[page.pt_index()];
-		match (entry.is_used(), entry.is_present()) {
-			(true, false) => {
-				// todo: remove from swap or something
-				Ok(())
-			},
-			(true, true) => {
-				*entry = Amd64Entry::empty();
-				unsafe { asm!("invlpg [{}]", in(reg) page.as_ptr()); }
-				Ok(())
-			},
-			(false, _) => Err(())
-		}
+		for_each_leaf(pdpt, page, size, Amd64Entry::empty(), &mut unmap_leaf)
+	}
+
+	fn protect_huge_page(&mut self, page: Page, size: PageSize, protection: Protection) -> Result<(), ()> {
+		assert!(page.start().addr < 0xffff_8000_0000_0000, "TTable only handles lower half addresses");
+
+		let table_flags = if protection.contains(Protection::USER) { Amd64Entry::USER } else { Amd64Entry::empty() };
+
+		let pml4 = self.pml4.pml4_mut();
+		pml4.entries[page.pml4_index()].insert(table_flags);
+		let pdpt = pml4.child_table_mut(page.pml4_index()).ok_or(())?;
+		for_each_leaf(pdpt, page, size, table_flags, &mut |entry, page| protect_leaf(entry, page, protection))
 	}
 }
 
 impl KTable for Amd64KTable {
 	fn translate_page(&self, page: Page) -> Option<Frame> {
-		assert!(page.start().addr < 0xffff_8000_0000_0000, "TTable only handles lower half addresses");
+		assert!(page.start().addr >= 0xffff_8000_0000_0000, "KTable only handles upper half addresses");
 
 		let pdpt = &self.tables.tables()[page.pml4_index() - 256];
-		let pd = pdpt.child_table(page.pdpt_index())?;
-		let pt = pd.child_table(page.pd_index())?;
-		pt.entries[page.pt_index()].pointed_frame()
+		translate(pdpt, page)
 	}
 
-	fn map_page(&mut self, page: Page, frame: Frame) -> Result<(), MapPageError> {
+	fn cache_mode(&self, page: Page) -> Option<CacheMode> {
 		assert!(page.start().addr >= 0xffff_8000_0000_0000, "KTable only handles upper half addresses");
 
+		let pdpt = &self.tables.tables()[page.pml4_index() - 256];
+		leaf(pdpt, page).map(|(entry, _)| entry.cache_mode())
+	}
+
+	fn map_huge_page(&mut self, page: Page, frame: Frame, size: PageSize, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
+		assert!(page.start().addr >= 0xffff_8000_0000_0000, "KTable only handles upper half addresses");
+
+		let table_flags = if protection.contains(Protection::USER) { Amd64Entry::USER } else { Amd64Entry::empty() };
+
 		let pdpt = &mut self.tables.tables_mut()[page.pml4_index() - 256];
-		let pd = pdpt.child_table_or_new(page.pdpt_index(), &self.allocator)?;
-		let pt = pd.child_table_or_new(page.pd_index(), &self.allocator)?;
-		pt.entries[page.pt_index()].point_to_frame(frame).map_err(|_| MapPageError::AlreadyMapped)
+		map(pdpt, page, frame, size, leaf_flags(protection, cache), table_flags, Blocking::Wait)
 	}
 
-	fn unmap_page(&mut self, page: Page) -> Result<(), ()> {
+	fn try_map_page(&mut self, page: Page, frame: Frame, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
 		assert!(page.start().addr >= 0xffff_8000_0000_0000, "KTable only handles upper half addresses");
 
+		let table_flags = if protection.contains(Protection::USER) { Amd64Entry::USER } else { Amd64Entry::empty() };
+
 		let pdpt = &mut self.tables.tables_mut()[page.pml4_index() - 256];
-		let pd = pdpt.child_table_mut(page.pdpt_index()).ok_or(())?;
-		let pt = pd.child_table_mut(page.pd_index()).ok_or(())?;
-		let entry = &mut pt.entries[page.pt_index()];
-		match (entry.is_used(), entry.is_present()) {
-			(true, false) => {
-				// todo: remove from swap or something
-				Ok(())
-			},
-			(true, true) => {
-				*entry = Amd64Entry::empty();
-				unsafe { asm!("invlpg [{}]", in(reg) page.as_ptr()); }
-				Ok(())
-			},
-			(false, _) => Err(())
+		map(pdpt, page, frame, PageSize::Size4KiB, leaf_flags(protection, cache), table_flags, Blocking::NoWait)
+	}
+
+	fn unmap_huge_page(&mut self, page: Page, size: PageSize) -> Result<(), ()> {
+		assert!(page.start().addr >= 0xffff_8000_0000_0000, "KTable only handles upper half addresses");
+
+		let pdpt = &mut self.tables.tables_mut()[page.pml4_index() - 256];
+		for_each_leaf(pdpt, page, size, Amd64Entry::empty(), &mut unmap_leaf)
+	}
+
+	fn protect_huge_page(&mut self, page: Page, size: PageSize, protection: Protection) -> Result<(), ()> {
+		assert!(page.start().addr >= 0xffff_8000_0000_0000, "KTable only handles upper half addresses");
+
+		let table_flags = if protection.contains(Protection::USER) { Amd64Entry::USER } else { Amd64Entry::empty() };
+
+		let pdpt = &mut self.tables.tables_mut()[page.pml4_index() - 256];
+		for_each_leaf(pdpt, page, size, table_flags, &mut |entry, page| protect_leaf(entry, page, protection))
+	}
+}
+
+/// Returns `true` if the CPU can map 1GiB pages
+fn gigabyte_pages_supported() -> bool {
+	// CPUID.80000001h:EDX.Page1GB
+	let features = unsafe { __cpuid(0x8000_0001) };
+	features.edx & (1 << 26) != 0
+}
+
+fn translate(pdpt: &Table<PDPT>, page: Page) -> Option<Frame> {
+	leaf(pdpt, page).map(|(_, frame)| frame)
+}
+
+/// Returns the entry that maps `page`, whatever size of page it maps, and the frame `page` is mapped to
+fn leaf(pdpt: &Table<PDPT>, page: Page) -> Option<(Amd64Entry, Frame)> {
+	let entry = pdpt.entries[page.pdpt_index()];
+	if entry.is_huge() {
+		return Some((entry, entry.pointed_frame()? + page.pd_index() * PD::ENTRY_FRAMES + page.pt_index()));
+	}
+
+	let pd = pdpt.child_table(page.pdpt_index())?;
+	let entry = pd.entries[page.pd_index()];
+	if entry.is_huge() {
+		return Some((entry, entry.pointed_frame()? + page.pt_index()));
+	}
+
+	let pt = pd.child_table(page.pd_index())?;
+	let entry = pt.entries[page.pt_index()];
+	Some((entry, entry.pointed_frame()?))
+}
+
+fn map(pdpt: &mut Table<PDPT>, page: Page, frame: Frame, size: PageSize, leaf_flags: Amd64Entry, table_flags: Amd64Entry, blocking: Blocking) -> Result<(), MapPageError> {
+	let frame_index = frame.start().addr / 4096;
+	let page_index = page.start().addr / 4096;
+	assert!(frame_index % size.frame_count() == 0 && page_index % size.frame_count() == 0, "Pages must be aligned to their size");
+
+	match size {
+		PageSize::Size1GiB => {
+			if !gigabyte_pages_supported() { return Err(MapPageError::UnsupportedSize); }
+			map_huge(pdpt, page.pdpt_index(), frame, leaf_flags, page)
+		}
+		PageSize::Size2MiB => {
+			let pd = pdpt.child_table_or_new(page.pdpt_index(), table_flags, blocking)?;
+			map_huge(pd, page.pd_index(), frame, leaf_flags, page)
+		}
+		PageSize::Size4KiB => {
+			let pd = pdpt.child_table_or_new(page.pdpt_index(), table_flags, blocking)?;
+			let pt = pd.child_table_or_new(page.pd_index(), table_flags, blocking)?;
+			pt.entries[page.pt_index()].point_to_frame_with(frame, leaf_flags).map_err(|_| MapPageError::AlreadyMapped)
+		}
+	}
+}
+
+/// Maps a large page using the entry at `idx`, replacing an empty child table if there is one
+fn map_huge<L: ParentLevel>(table: &mut Table<L>, idx: usize, frame: Frame, leaf_flags: Amd64Entry, page: Page) -> Result<(), MapPageError> {
+	if let Some(child) = table.child_table(idx) {
+		if !child.is_empty() { return Err(MapPageError::AlreadyMapped); }
+
+		let child_frame = table.entries[idx].pointed_frame().expect("Entry points to a table");
+		table.entries[idx] = Amd64Entry::empty();
+		// The CPU may have cached the old table while walking it
+		unsafe {
+			asm!("invlpg [{}]", in(reg) page.as_ptr());
+			Table::<L::Child>::free(child_frame);
 		}
 	}
+
+	table.entries[idx].point_to_frame_with(frame, leaf_flags | Amd64Entry::HUGE).map_err(|_| MapPageError::AlreadyMapped)
+}
+
+/// Calls `f` on every entry that maps part of the `size` region at `page`
+///
+/// Larger pages that only partly overlap the region are split first, so that `f` never affects memory outside it. Fails
+/// if any part of the region isn't mapped.
+fn for_each_leaf(pdpt: &mut Table<PDPT>, page: Page, size: PageSize, table_flags: Amd64Entry, f: &mut impl FnMut(&mut Amd64Entry, Page) -> Result<(), ()>) -> Result<(), ()> {
+	let idx = page.pdpt_index();
+	if pdpt.entries[idx].is_huge() {
+		if size == PageSize::Size1GiB { return f(&mut pdpt.entries[idx], page); }
+		pdpt.split(idx).map_err(|_| ())?;
+	}
+
+	if pdpt.entries[idx].is_present() { pdpt.entries[idx].insert(table_flags); }
+	let pd = pdpt.child_table_mut(idx).ok_or(())?;
+	if size == PageSize::Size1GiB {
+		for i in 0..512 {
+			for_each_leaf_in_pd(pd, page + i * PD::ENTRY_FRAMES, PageSize::Size2MiB, table_flags, f)?;
+		}
+		Ok(())
+	} else {
+		for_each_leaf_in_pd(pd, page, size, table_flags, f)
+	}
+}
+
+fn for_each_leaf_in_pd(pd: &mut Table<PD>, page: Page, size: PageSize, table_flags: Amd64Entry, f: &mut impl FnMut(&mut Amd64Entry, Page) -> Result<(), ()>) -> Result<(), ()> {
+	let idx = page.pd_index();
+	if pd.entries[idx].is_huge() {
+		if size == PageSize::Size2MiB { return f(&mut pd.entries[idx], page); }
+		pd.split(idx).map_err(|_| ())?;
+	}
+
+	if pd.entries[idx].is_present() { pd.entries[idx].insert(table_flags); }
+	let pt = pd.child_table_mut(idx).ok_or(())?;
+	if size == PageSize::Size2MiB {
+		for (i, entry) in pt.entries.iter_mut().enumerate() {
+			f(entry, page + i)?;
+		}
+		Ok(())
+	} else {
+		f(&mut pt.entries[page.pt_index()], page)
+	}
+}
+
+fn unmap_leaf(entry: &mut Amd64Entry, page: Page) -> Result<(), ()> {
+	match (entry.is_used(), entry.is_present()) {
+		(true, false) => {
+			// todo: remove from swap or something
+			Ok(())
+		},
+		(true, true) => {
+			*entry = Amd64Entry::empty();
+			unsafe { asm!("invlpg [{}]", in(reg) page.as_ptr()); }
+			Ok(())
+		},
+		(false, _) => Err(())
+	}
+}
+
+fn protect_leaf(entry: &mut Amd64Entry, page: Page, protection: Protection) -> Result<(), ()> {
+	entry.set_protection(protection)?;
+	unsafe { asm!("invlpg [{}]", in(reg) page.as_ptr()); }
+	Ok(())
+}
+
+fn leaf_flags(protection: Protection, cache: CacheMode) -> Amd64Entry {
+	Amd64Entry::protection_flags(protection) | Amd64Entry::cache_flags(cache)
 }
 
 impl TTable for Amd64TTable {
@@ -188,8 +360,8 @@ impl TTable for Amd64TTable {
 		unsafe { asm!("mov cr3, {}", in(reg) addr); }
 	}
 
-	fn new(ktable: &Amd64KTable, allocator: &'static dyn BackingAllocator) -> Result<Self, AllocError> {
-		let pml4_frame = Table::<PML4>::empty_with(allocator)?;
+	fn new(ktable: &Amd64KTable) -> Result<Self, AllocError> {
+		let pml4_frame = Table::<PML4>::new_empty(Blocking::Wait)?;
 		let pml4 = pml4_frame.to_page().as_ptr().cast::<Table<PML4>>();
 		assert!(!pml4.is_null() && pml4.is_aligned());
 		let pml4 = unsafe { &mut *pml4 };
@@ -201,8 +373,64 @@ impl TTable for Amd64TTable {
 		}
 
 		Ok(Self {
-			pml4: TTablePtr(pml4_frame),
-			allocator
+			pml4: TTablePtr(pml4_frame)
 		})
 	}
+
+	unsafe fn clear(&mut self, free_table: &mut dyn FnMut(Frame)) {
+		let pml4 = self.pml4.pml4_mut();
+		let lower_half: [Amd64Entry; 256] = pml4.entries[..256].try_into().unwrap();
+		pml4.entries[..256].fill(Amd64Entry::empty());
+
+		// Lower half mappings are never global, so reloading the table flushes all of them
+		let active: usize;
+		unsafe { asm!("mov {}, cr3", out(reg) active); }
+		if active & 0xffff_ffff_ffff_f000 == self.pml4.0.start().addr {
+			unsafe { self.load(); }
+		}
+
+		for entry in lower_half {
+			if let Some(pdpt) = entry.pointed_frame() {
+				unsafe { free_tables(pdpt, free_table); }
+			}
+		}
+	}
+
+	unsafe fn free(mut self, free_table: &mut dyn FnMut(Frame)) {
+		let active: usize;
+		unsafe { asm!("mov {}, cr3", out(reg) active); }
+		assert_ne!(active & 0xffff_ffff_ffff_f000, self.pml4.0.start().addr, "Cannot free the active page table");
+
+		unsafe { self.clear(free_table); }
+
+		// The kernel half belongs to the ktable, so it is only unlinked
+		self.pml4.pml4_mut().entries[256..].fill(Amd64Entry::empty());
+		free_table(self.pml4.0);
+	}
+}
+
+/// Frees the PDPT in `pdpt_frame` and every table below it, emptying each one first
+///
+/// # Safety
+///
+/// The PDPT must no longer be referenced by any other table
+unsafe fn free_tables(pdpt_frame: Frame, free_table: &mut dyn FnMut(Frame)) {
+	fn empty_and_free<L: Level>(table: &mut Table<L>, frame: Frame, free_table: &mut dyn FnMut(Frame)) {
+		table.entries.fill(Amd64Entry::empty());
+		free_table(frame);
+	}
+
+	let pdpt = unsafe { &mut *pdpt_frame.to_page().as_ptr().cast::<Table<PDPT>>() };
+	for i in 0..512 {
+		let Some(pd_frame) = pdpt.entries[i].pointed_frame() else { continue };
+		let Some(pd) = pdpt.child_table_mut(i) else { continue };
+
+		for j in 0..512 {
+			let Some(pt_frame) = pd.entries[j].pointed_frame() else { continue };
+			let Some(pt) = pd.child_table_mut(j) else { continue };
+			empty_and_free(pt, pt_frame, free_table);
+		}
+		empty_and_free(pd, pd_frame, free_table);
+	}
+	empty_and_free(pdpt, pdpt_frame, free_table);
 }
@@ -1,12 +1,17 @@
 use core::marker::PhantomData;
-use kernel_api::memory::allocator::BackingAllocator;
-use kernel_api::memory::{AllocError, Frame, Page};
+use core::ptr::NonNull;
+use kernel_api::bridge::paging::MapPageError;
+use kernel_api::memory::{AllocError, Frame, Page, VirtualAddress};
 use crate::hal::arch::amd64::paging::Amd64Entry;
 use crate::hal::paging::Entry;
+use crate::memory::cache::PAGE_TABLES;
 
 pub(super) trait Level {
 	const MASK: usize;
 	const SHIFT: usize;
+
+	/// The number of frames covered by each entry in a table of this level
+	const ENTRY_FRAMES: usize = 1 << (Self::SHIFT - 12);
 }
 
 pub(super) trait ParentLevel: Level {
@@ -60,6 +65,14 @@ impl ParentLevel for PD {
 	type Child = PT;
 }
 
+/// Whether allocating a new table can wait for the locks it needs
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum Blocking {
+	Wait,
+	/// Fail instead of waiting, since a page fault may have interrupted code on this CPU that holds the lock
+	NoWait
+}
+
 #[derive(Debug)]
 #[repr(C, align(4096))]
 pub(super) struct Table<L> {
@@ -68,31 +81,39 @@ pub(super) struct Table<L> {
 }
 
 impl<L: Level> Table<L> {
-	pub(super) fn empty() -> Self {
-		Self {
-			entries: [Amd64Entry(0); 512],
-			_phantom: PhantomData
-		}
-	}
-
-	pub(super) fn empty_with(allocator: impl BackingAllocator) -> Result<Frame, AllocError> {
-		let table_frame = allocator.allocate_one()?;
-		let table_ptr: *mut Self = table_frame.to_page().as_ptr().cast();
-		assert!(!table_ptr.is_null() && table_ptr.is_aligned());
-
-		unsafe { table_ptr.write(Table::empty()); }
-
-		Ok(table_frame)
+	/// Allocates a table from the [page table cache](PAGE_TABLES), which keeps its frames zeroed so that the table is
+	/// already empty
+	pub(super) fn new_empty(blocking: Blocking) -> Result<Frame, AllocError> {
+		let table = match blocking {
+			Blocking::Wait => PAGE_TABLES.allocate()?,
+			Blocking::NoWait => PAGE_TABLES.try_allocate()?
+		};
+		let table_ptr: *mut Self = table.as_ptr().cast();
+		assert!(table_ptr.is_aligned());
+		debug_assert!(unsafe { &*table_ptr }.is_empty());
+
+		Ok(Page::new(VirtualAddress::new(table.as_ptr() as usize)).to_frame())
 	}
 
 	pub(super) fn is_empty(&self) -> bool {
 		self.entries.iter().all(|entry| !entry.is_used())
 	}
+
+	/// Gives a table back to the [page table cache](PAGE_TABLES)
+	///
+	/// # Safety
+	///
+	/// The table must be empty, and no longer referenced by any other table
+	pub(super) unsafe fn free(frame: Frame) {
+		let table = NonNull::new(frame.to_page().as_ptr()).expect("Physical memory map is not at null");
+		unsafe { PAGE_TABLES.deallocate(table); }
+	}
 }
 
 impl<L: ParentLevel> Table<L> {
 	pub(super) fn child_table(&self, idx: usize) -> Option<&Table<L::Child>> {
 		let entry = self.entries[idx];
+		if entry.is_huge() { return None; }
 		let table_frame = entry.pointed_frame()?;
 		let table_page = table_frame.to_page();
 		Some(unsafe { &*table_page.as_ptr().cast() })
@@ -100,19 +121,52 @@ impl<L: ParentLevel> Table<L> {
 
 	pub(super) fn child_table_mut(&mut self, idx: usize) -> Option<&mut Table<L::Child>> {
 		let entry = self.entries[idx];
+		if entry.is_huge() { return None; }
 		let table_frame = entry.pointed_frame()?;
 		let table_page = table_frame.to_page();
 		Some(unsafe { &mut *table_page.as_ptr().cast() })
 	}
 
-	pub(super) fn child_table_or_new(&mut self, idx: usize, allocator: impl BackingAllocator) -> Result<&mut Table<L::Child>, AllocError> {
+	/// Returns the child table at `idx`, creating it if it doesn't exist
+	///
+	/// `flags` are added to the entry pointing to the child table, whether or not it already existed. Fails if the entry
+	/// maps a large page.
+	pub(super) fn child_table_or_new(&mut self, idx: usize, flags: Amd64Entry, blocking: Blocking) -> Result<&mut Table<L::Child>, MapPageError> {
+		if self.entries[idx].is_huge() { return Err(MapPageError::AlreadyMapped); }
+
 		if self.child_table_mut(idx).is_none() {
-			let table_frame = Table::<L::Child>::empty_with(allocator)?;
+			let table_frame = Table::<L::Child>::new_empty(blocking)?;
 			self.entries[idx].point_to_frame(table_frame).expect("Entry was not present");
 		}
+		self.entries[idx].insert(flags);
 
 		Ok(self.child_table_mut(idx).expect("Just mapped this entry"))
 	}
+
+	/// Replaces the large page at `idx` with a child table, which maps the same memory using the next smaller page size
+	pub(super) fn split(&mut self, idx: usize) -> Result<&mut Table<L::Child>, AllocError> {
+		let entry = self.entries[idx];
+		assert!(entry.is_huge(), "Only large pages can be split");
+		let base = entry.pointed_frame().expect("Large page is present");
+
+		// The smaller pages keep the protection and caching of the large page
+		let mut flags = entry.difference(Amd64Entry::ADDRESS | Amd64Entry::PRESENT);
+		if L::Child::SHIFT == PT::SHIFT { flags.remove(Amd64Entry::HUGE); }
+
+		let table_frame = Table::<L::Child>::new_empty(Blocking::Wait)?;
+		let table = unsafe { &mut *table_frame.to_page().as_ptr().cast::<Table<L::Child>>() };
+		for (i, child) in table.entries.iter_mut().enumerate() {
+			child.point_to_frame_with(base + i * L::Child::ENTRY_FRAMES, flags).expect("New table is empty");
+		}
+
+		// Permissions are the intersection of every level, so the table itself doesn't restrict the smaller pages
+		let mut table_entry = Amd64Entry::empty();
+		table_entry.point_to_frame_with(table_frame, Amd64Entry::WRITABLE | (entry & Amd64Entry::USER))
+				.expect("Entry was just cleared");
+		self.entries[idx] = table_entry;
+
+		Ok(table)
+	}
 }
 
 pub trait PageIndices {
@@ -0,0 +1,84 @@
+//! Programs the page attribute table, which decides the memory type selected by a page's caching flags
+//!
+//! The table is laid out so that every [`CacheMode`](kernel_api::memory::mapping::CacheMode) can be selected with only
+//! the `WRITE_THROUGH` and `NO_CACHE` flags, since the PAT flag of a 4KiB entry is in the same place as the `HUGE` flag
+//! of larger ones. The upper half of the table mirrors the lower half in case a PAT flag is ever set.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use log::warn;
+
+const IA32_PAT: u32 = 0x277;
+
+#[repr(u8)]
+#[derive(Copy, Clone)]
+enum MemoryType {
+	Uncached = 0,
+	WriteCombining = 1,
+	WriteThrough = 4,
+	WriteBack = 6,
+}
+
+/// The memory types for entries 0 to 3, which are selected by `NO_CACHE << 1 | WRITE_THROUGH`
+const TABLE: [MemoryType; 4] = [
+	MemoryType::WriteBack,
+	MemoryType::WriteThrough,
+	MemoryType::WriteCombining,
+	MemoryType::Uncached,
+];
+
+const fn pat_value() -> u64 {
+	let mut value = 0;
+	let mut i = 0;
+	while i < 8 {
+		value |= (TABLE[i % 4] as u64) << (i * 8);
+		i += 1;
+	}
+	value
+}
+
+fn is_supported() -> bool {
+	unsafe { __cpuid(1) }.edx & (1 << 16) != 0
+}
+
+/// Programs the PAT on the current CPU
+///
+/// Until this runs, write combining mappings are only uncached, since firmware leaves entry 2 as UC-.
+pub fn init() {
+	if !is_supported() {
+		warn!("CPU does not support PAT, write combining will be uncached");
+		return;
+	}
+
+	let value = pat_value();
+	unsafe {
+		asm!("wrmsr", in("ecx") IA32_PAT, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack));
+
+		// Anything cached or in the TLB under the old memory types has to go
+		asm!("wbinvd", options(nostack));
+		asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _, options(nostack));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn read_pat() -> u64 {
+		let (low, high): (u32, u32);
+		unsafe { asm!("rdmsr", in("ecx") IA32_PAT, out("eax") low, out("edx") high, options(nostack)); }
+		u64::from(high) << 32 | u64::from(low)
+	}
+
+	#[test]
+	fn init_programs_table() {
+		if !is_supported() { return; }
+		init();
+
+		let expected = [MemoryType::WriteBack, MemoryType::WriteThrough, MemoryType::WriteCombining, MemoryType::Uncached]
+				.map(|ty| ty as u8);
+		let pat = read_pat().to_le_bytes();
+		assert_eq!(pat[..4], expected);
+		assert_eq!(pat[4..], expected);
+	}
+}
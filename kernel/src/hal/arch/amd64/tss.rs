@@ -1,21 +1,34 @@
 use core::arch::asm;
 use core::cell::SyncUnsafeCell;
 use core::mem;
+use core::num::NonZeroU8;
+use core::ptr;
 use kernel_api::memory::VirtualAddress;
 use kernel_api::sync::OnceLock;
+use crate::sprintln;
 
 pub static TSS: OnceLock<Tss> = OnceLock::new();
 
-struct StaticStack([u8; 3*4096]);
+/// The IST entry used for page faults, so that a fault caused by a kernel stack overflow still has a stack to run on
+pub const PAGE_FAULT_IST: NonZeroU8 = match NonZeroU8::new(2) { Some(ist) => ist, None => unreachable!() };
 
-static DOUBLE_FAULT_STACK: SyncUnsafeCell<StaticStack> = SyncUnsafeCell::new(StaticStack([0; 4096*3]));
+/// Page faults can nest, such as when a fault handler touches lazily mapped memory, so the page fault stack is split into
+/// slots and each nested fault is given the next slot down
+const PAGE_FAULT_SLOTS: usize = 4;
+const PAGE_FAULT_SLOT_SIZE: usize = 4*4096;
+
+#[repr(align(16))]
+struct StaticStack<const SIZE: usize>([u8; SIZE]);
+
+static DOUBLE_FAULT_STACK: SyncUnsafeCell<StaticStack<{ 3*4096 }>> = SyncUnsafeCell::new(StaticStack([0; 4096*3]));
+static PAGE_FAULT_STACK: SyncUnsafeCell<StaticStack<{ PAGE_FAULT_SLOTS*PAGE_FAULT_SLOT_SIZE }>> = SyncUnsafeCell::new(StaticStack([0; PAGE_FAULT_SLOTS*PAGE_FAULT_SLOT_SIZE]));
 
 #[repr(C, packed(4))]
 pub struct Tss {
     _res0: u32,
     privilege_stack_table: [VirtualAddress; 3],
     _res1: u64,
-    interrupt_stack_table: [VirtualAddress; 7],
+    interrupt_stack_table: SyncUnsafeCell<[VirtualAddress; 7]>,
     _res2: u64,
     _res3: u16,
     io_map_base: u16,
@@ -27,15 +40,15 @@ impl Tss {
             _res0: 0,
             privilege_stack_table: [VirtualAddress::new(0); 3],
             _res1: 0,
-            interrupt_stack_table: [
+            interrupt_stack_table: SyncUnsafeCell::new([
                 VirtualAddress::from(unsafe { DOUBLE_FAULT_STACK.get().add(1) }),
+                VirtualAddress::from(unsafe { PAGE_FAULT_STACK.get().add(1) }),
                 VirtualAddress::new(0),
                 VirtualAddress::new(0),
                 VirtualAddress::new(0),
                 VirtualAddress::new(0),
                 VirtualAddress::new(0),
-                VirtualAddress::new(0),
-            ],
+            ]),
             _res2: 0,
             _res3: 0,
             io_map_base: mem::size_of::<Tss>() as u16,
@@ -45,4 +58,44 @@ impl Tss {
     pub unsafe fn load(gdt_index: u16) {
         asm!("ltr {0:x}", in(reg) gdt_index);
     }
+
+    fn ist(&self, ist: NonZeroU8) -> *mut VirtualAddress {
+        // The table is only 4 byte aligned, so entries have to be accessed unaligned
+        let table = SyncUnsafeCell::raw_get(ptr::addr_of!(self.interrupt_stack_table));
+        unsafe { table.cast::<VirtualAddress>().add(usize::from(ist.get()) - 1) }
+    }
+}
+
+/// Moves the page fault IST entry down by one slot until dropped, so that a nested page fault doesn't overwrite the stack
+/// of the fault currently being handled
+pub struct NestedFaultGuard {
+    old_top: VirtualAddress
+}
+
+impl NestedFaultGuard {
+    /// # Safety
+    ///
+    /// Must only be created while handling a page fault with interrupts disabled, and guards must be dropped in the
+    /// reverse order to their creation
+    pub unsafe fn new() -> Self {
+        let entry = TSS.get().expect("TSS is loaded before page faults are handled").ist(PAGE_FAULT_IST);
+        let old_top = unsafe { entry.read_unaligned() };
+
+        let bottom = VirtualAddress::from(PAGE_FAULT_STACK.get());
+        if old_top.addr - bottom.addr <= PAGE_FAULT_SLOT_SIZE {
+            // Another nested fault would run off the end of the stack, so there's nowhere left to handle it
+            sprintln!("\u{001b}[31m\u{001b}[1mFATAL: page faults nested more than {PAGE_FAULT_SLOTS} deep\u{001b}[0m");
+            loop {}
+        }
+
+        unsafe { entry.write_unaligned(VirtualAddress::new(old_top.addr - PAGE_FAULT_SLOT_SIZE)); }
+        Self { old_top }
+    }
+}
+
+impl Drop for NestedFaultGuard {
+    fn drop(&mut self) {
+        let entry = TSS.get().expect("TSS is loaded before page faults are handled").ist(PAGE_FAULT_IST);
+        unsafe { entry.write_unaligned(self.old_top); }
+    }
 }
@@ -6,16 +6,17 @@ use core::mem;
 use core::ptr::addr_of_mut;
 use core::time::Duration;
 use acpi::madt::MadtEntry;
-use acpi::{AcpiHandler, PhysicalMapping};
 use log::{debug, info};
 use crate::hal::timing::{Eoi, Timer};
 use bit_field::BitField;
 use macros::Fields;
 use crate::hal;
 use timer::TimerMode;
-use crate::mmio::MmioCell;
+use crate::mmio::{MmioCell, MmioMapping};
 use crate::threading::scheduler::IrqCell;
 use crate::projection::Project;
+use kernel_api::memory::PhysicalAddress;
+use kernel_api::sync::OnceLock;
 
 mod timer;
 
@@ -113,12 +114,16 @@ impl MmioCell<Apic> {
 	}
 }
 
-struct Lapic(OnceCell<IrqCell<PhysicalMapping<hal::acpi::Handler<'static>, Apic>>>, UnsafeCell<()>);
+struct Lapic(OnceCell<IrqCell<MmioMapping<Apic>>>, UnsafeCell<()>);
 
 #[thread_local]
 static LAPIC: Lapic = Lapic(OnceCell::new(), UnsafeCell::new(()));
 
-pub type LapicTimer = &'static IrqCell<PhysicalMapping<hal::acpi::Handler<'static>, Apic>>;
+/// The physical address of the HPET, which is looked up when the APIC is initialised since the ACPI tables may have been
+/// reclaimed by the time the timer is calibrated
+static HPET_ADDRESS: OnceLock<Option<usize>> = OnceLock::new();
+
+pub type LapicTimer = &'static IrqCell<MmioMapping<Apic>>;
 
 #[derive(Debug)]
 pub enum SupportError {
@@ -135,7 +140,7 @@ impl Timer for LapicTimer {
 
 	fn set_irq_number(&mut self, irq: usize) -> Result<(), ()> {
 		let borrow = self.lock();
-		let apic = unsafe { MmioCell::new(borrow.virtual_start().as_ptr()) };
+		let apic = borrow.cell();
 
 		let mut lvt = apic.project::<Apic::timer_lvt>();
 		let val = lvt.read()
@@ -146,18 +151,19 @@ impl Timer for LapicTimer {
 	}
 
 	fn get_time_period_picos(&self) -> Result<u64, SupportError> {
-		let Ok(hpet) = acpi::hpet::HpetInfo::new(hal::acpi::tables()) else {
+		let Some(hpet_address) = HPET_ADDRESS.get().copied().flatten() else {
 			return Err(SupportError::NoFreq);
 		};
 
 		let borrow = self.lock();
-		let apic = unsafe { MmioCell::new(borrow.virtual_start().as_ptr()) };
+		let apic = borrow.cell();
 
 		let (start, end, hpet_period) = {
 			use super::hpet::Header as Hpet;
 
-			let hpet = unsafe { hal::acpi::Handler::new(&hal::acpi::Allocator).map_physical_region::<Hpet>(hpet.base_address, mem::size_of::<super::hpet::Header>()) };
-			let hpet = unsafe { MmioCell::new(hpet.virtual_start().as_ptr()) };
+			let hpet_mapping = unsafe { MmioMapping::<Hpet>::uncached(PhysicalAddress::new(hpet_address)) }
+					.map_err(|_| SupportError::NoFreq)?;
+			let hpet = hpet_mapping.cell();
 
 			let mut timer_lvt = apic.project::<Apic::timer_lvt>();
 			let mut timer_divide_register = apic.project::<Apic::timer_divide_config>();
@@ -218,14 +224,14 @@ impl Timer for LapicTimer {
 		};
 
 		let borrow = self.lock();
-		let apic = unsafe { MmioCell::new(borrow.virtual_start().as_ptr()) };
+		let apic = borrow.cell();
 		apic.project::<Apic::timer_divide_config>().write(val);
 		Ok(())
 	}
 
 	fn set_oneshot_time(&mut self, ticks: u128) -> Result<(), <u32 as TryFrom<u128>>::Error> {
 		let borrow = self.lock();
-		let apic = unsafe { MmioCell::new(borrow.virtual_start().as_ptr()) };
+		let apic = borrow.cell();
 
 		let val = apic.project::<Apic::timer_lvt>().read()
 		                                            .with_mode(TimerMode::OneShot)
@@ -238,7 +244,7 @@ impl Timer for LapicTimer {
 
 	fn start_periodic(&mut self, ticks: u128) -> Result<(), <u32 as TryFrom<u128>>::Error> {
 		let borrow = self.lock();
-		let apic = unsafe { MmioCell::new(borrow.virtual_start().as_ptr()) };
+		let apic = borrow.cell();
 
 		let val = apic.project::<Apic::timer_lvt>().read()
 		                     .with_mode(TimerMode::Periodic)
@@ -251,7 +257,7 @@ impl Timer for LapicTimer {
 
 	fn stop_periodic(&mut self) {
 		let borrow = self.lock();
-		let apic = unsafe { MmioCell::new(borrow.virtual_start().as_ptr()) };
+		let apic = borrow.cell();
 		apic.project::<Apic::timer_initial_count>().write(0);
 	}
 
@@ -265,7 +271,7 @@ pub struct EoiHandle(LapicTimer);
 
 impl Eoi for EoiHandle {
 	fn send(self) {
-		unsafe { MmioCell::new(self.0.lock().virtual_start().as_ptr()).eoi(); }
+		unsafe { self.0.lock().cell().eoi(); }
 	}
 }
 
@@ -274,6 +280,10 @@ pub(in crate::hal) fn init(spurious_vector: u8) {
 		panic!("No MADT found");
 	};
 
+	HPET_ADDRESS.get_or_init(|| {
+		acpi::hpet::HpetInfo::new(hal::acpi::tables()).ok().map(|hpet| hpet.base_address)
+	});
+
 	let mut apic_addr = madt.local_apic_address as u64;
 
 	for entry in madt.entries() {
@@ -285,8 +295,9 @@ pub(in crate::hal) fn init(spurious_vector: u8) {
 		}
 	}
 
-	let apic = unsafe { hal::acpi::Handler::new(&hal::acpi::Allocator).map_physical_region::<Apic>(apic_addr as usize, mem::size_of::<Apic>()) };
-	let apic_boxed = unsafe { MmioCell::new(apic.virtual_start().as_ptr()) };
+	let apic = unsafe { MmioMapping::<Apic>::uncached(PhysicalAddress::new(apic_addr as usize)) }
+			.expect("Unable to map LAPIC");
+	let apic_boxed = apic.cell();
 
 	info!("LAPIC located at {apic_addr:#x}");
 
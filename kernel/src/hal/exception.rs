@@ -36,11 +36,15 @@ pub enum DebugTy {
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct PageFault {
-	pub access_addr: usize
+	pub access_addr: usize,
+	pub is_write: bool,
+	/// The fault happened while running in user mode
+	pub is_user: bool
 }
 
 impl Display for PageFault {
 	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-		write!(f, "Attempted to access address {:#x}", self.access_addr)
+		let access = if self.is_write { "write to" } else { "access" };
+		write!(f, "Attempted to {access} address {:#x}", self.access_addr)
 	}
 }
@@ -6,20 +6,26 @@ pub mod acpi;
 pub mod timing;
 
 use alloc::borrow::Cow;
+use alloc::boxed::Box;
 use core::arch::asm;
 use core::fmt::Debug;
-use kernel_api::memory::mapping;
+use kernel_api::memory::{AllocError, mapping};
 use kernel_api::memory::mapping::Stack;
 use kernel_api::memory::physical::highmem;
 use kernel_api::memory::r#virtual::Global;
+use kernel_api::threading::Priority;
 pub(crate) use macros::Hal;
-use paging2::{KTable, TTable, TTableTy};
+use paging2::{KTable, TTable};
+use crate::memory::address_space::AddressSpace;
 use core::num::NonZeroUsize;
 
 pub enum Result { Success, Failure }
 
+/// The closure a new thread runs, boxed again so that it can be passed to the thread as a thin pointer
+pub type ThreadMain = Box<Box<dyn FnOnce() + Send>>;
+
 pub trait SaveState: Debug + Default {
-	fn new(tcb: &mut ThreadControlBlock, init: fn(), main: fn() -> !) -> Self;
+	fn new(tcb: &mut ThreadControlBlock, init: fn(), main: ThreadMain) -> Self;
 }
 
 pub unsafe trait Hal {
@@ -32,11 +38,17 @@ pub unsafe trait Hal {
 	fn breakpoint();
 	fn exit(result: Result) -> !;
 	fn debug_output(data: &[u8]) -> core::result::Result<(), ()>;
+	/// Returns a value that differs between boots, for seeding randomisation that doesn't need to be secure
+	fn boot_entropy() -> u64;
+	/// Returns a counter that increases at a constant rate, for measuring how long things take
+	fn timestamp() -> u64;
 	fn early_init();
 	fn post_acpi_init();
 	fn enable_interrupts();
 	fn get_and_disable_interrupts() -> usize;
 	fn set_interrupts(old_state: usize);
+	/// Enables interrupts and waits for the next one to arrive
+	fn halt();
 	unsafe fn load_tls(ptr: *mut u8);
 	unsafe fn construct_tables() -> (Self::KTableTy, Self::TTableTy);
 	unsafe extern "C" fn switch_thread(from: &mut ThreadControlBlock, to: &ThreadControlBlock);
@@ -73,37 +85,52 @@ macro_rules! sprint {
 
 #[derive(Debug)]
 pub struct ThreadControlBlock {
-	pub ttable: TTableTy,
+	pub address_space: AddressSpace,
 	pub save_state: <HalTy as Hal>::SaveState,
 	pub name: Cow<'static, str>,
 	pub kernel_stack: Stack<'static, Global>,
 	pub state: ThreadState,
+	pub priority: Priority,
 }
 
 impl ThreadControlBlock {
-	pub fn new(name: Cow<'static, str>, ttable: TTableTy, startup: fn(), main: fn() -> !) -> Self {
+	pub fn new(
+		name: Cow<'static, str>,
+		address_space: AddressSpace,
+		stack_pages: NonZeroUsize,
+		priority: Priority,
+		startup: fn(),
+		main: ThreadMain
+	) -> core::result::Result<Self, AllocError> {
+		// The stack is touched while the page table and allocator locks are held, where a lazy fault can't be resolved
 		let new_stack = Stack::new(
-			mapping::Config::<Global>::new(NonZeroUsize::new(8).unwrap())
-		).unwrap();
+			mapping::Config::<Global>::new(stack_pages)
+					.laziness(mapping::Laziness::Prefault)
+		)?;
 
 		let mut new_thread = ThreadControlBlock {
-			ttable,
+			address_space,
 			save_state: Default::default(),
 			name,
 			kernel_stack: new_stack,
 			state: ThreadState::Ready,
+			priority,
 		};
 		let save_state = SaveState::new(&mut new_thread, startup, main);
 		new_thread.save_state = save_state;
 
-		new_thread
+		Ok(new_thread)
 	}
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ThreadState {
 	Ready,
-	Running
+	Running,
+	/// The thread is waiting for something other than the CPU, so isn't put back in the run queue
+	Blocked,
+	/// The thread has finished, and is waiting for another thread to free its resources
+	Exited
 }
 
 #[export_name = "__popcorn_enable_irq"]
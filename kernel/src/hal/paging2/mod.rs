@@ -2,7 +2,7 @@ use core::fmt::Debug;
 use kernel_api::bridge::paging::MapPageError;
 use kernel_api::memory::{Frame, Page, PhysicalAddress, VirtualAddress, AllocError};
 use crate::{Hal, HalTy};
-use kernel_api::memory::allocator::{BackingAllocator};
+use kernel_api::memory::mapping::{CacheMode, PageSize, Protection};
 
 pub type KTableTy = <HalTy as crate::Hal>::KTableTy;
 pub type TTableTy = <HalTy as crate::Hal>::TTableTy;
@@ -14,6 +14,9 @@ pub unsafe fn construct_tables() -> (KTableTy, TTableTy) {
 pub trait KTable: Debug + Sized {
 	fn translate_page(&self, page: Page) -> Option<Frame>;
 
+	/// Returns the caching behaviour `page` is mapped with, or `None` if it isn't mapped
+	fn cache_mode(&self, page: Page) -> Option<CacheMode>;
+
 	fn translate_address(&self, addr: VirtualAddress) -> Option<PhysicalAddress> {
 		let aligned = addr.align_down();
 		let diff = addr - aligned;
@@ -21,8 +24,37 @@ pub trait KTable: Debug + Sized {
 		Some(physical.start() + diff)
 	}
 
-	fn map_page(&mut self, page: Page, frame: Frame) -> Result<(), MapPageError>;
-	fn unmap_page(&mut self, page: Page) -> Result<(), ()>;
+	fn map_page(&mut self, page: Page, frame: Frame, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
+		self.map_huge_page(page, frame, PageSize::Size4KiB, protection, cache)
+	}
+
+	/// Maps a page like [`map_page`](KTable::map_page), but fails instead of waiting if a new page table is needed and
+	/// can't be allocated without waiting for a lock
+	///
+	/// This is for page fault handlers, which may have interrupted code on the same CPU that holds the lock.
+	fn try_map_page(&mut self, page: Page, frame: Frame, protection: Protection, cache: CacheMode) -> Result<(), MapPageError>;
+
+	fn unmap_page(&mut self, page: Page) -> Result<(), ()> {
+		self.unmap_huge_page(page, PageSize::Size4KiB)
+	}
+
+	/// Changes the protection of an already mapped page, keeping its caching behaviour
+	fn protect_page(&mut self, page: Page, protection: Protection) -> Result<(), ()> {
+		self.protect_huge_page(page, PageSize::Size4KiB, protection)
+	}
+
+	/// Maps `size` worth of memory starting at `page` with a single entry
+	///
+	/// Both `page` and `frame` must be aligned to `size`. Returns [`MapPageError::UnsupportedSize`] if the CPU can't map
+	/// pages of that size.
+	fn map_huge_page(&mut self, page: Page, frame: Frame, size: PageSize, protection: Protection, cache: CacheMode) -> Result<(), MapPageError>;
+
+	/// Unmaps `size` worth of memory starting at `page`, splitting any larger page that only partly overlaps it
+	fn unmap_huge_page(&mut self, page: Page, size: PageSize) -> Result<(), ()>;
+
+	/// Changes the protection of `size` worth of memory starting at `page`, splitting any larger page that only partly
+	/// overlaps it
+	fn protect_huge_page(&mut self, page: Page, size: PageSize, protection: Protection) -> Result<(), ()>;
 }
 
 pub trait TTable: KTable + Sized {
@@ -37,7 +69,26 @@ pub trait TTable: KTable + Sized {
 	/// Figure out a better signature involving `Arc` or something
 	unsafe fn load(&self);
 
-	fn new(ktable: &Self::KTableTy, allocator: &'static dyn BackingAllocator) -> Result<Self, AllocError>;
+	/// Creates a table with only the kernel half mapped, taking its page tables from the page table cache
+	fn new(ktable: &Self::KTableTy) -> Result<Self, AllocError>;
+
+	/// Unmaps everything in the lower half, then empties every page table that was only used by the lower half and
+	/// passes its frame to `free_table`
+	///
+	/// Tables are only freed once they can no longer be reached by the CPU, so `free_table` can reuse them immediately.
+	/// Tables made by [`TTable::new`] belong to the page table cache, but the bootloader's were never part of it.
+	///
+	/// # Safety
+	///
+	/// Nothing can still be accessing memory through the lower half of this table
+	unsafe fn clear(&mut self, free_table: &mut dyn FnMut(Frame));
+
+	/// [Clears](TTable::clear) the table, then passes the frame of its top level table to `free_table` as well
+	///
+	/// # Safety
+	///
+	/// The table can't be loaded on any CPU, and nothing can still be accessing memory through its lower half
+	unsafe fn free(self, free_table: &mut dyn FnMut(Frame));
 }
 
 #[export_name = "__popcorn_paging_ktable_translate_page"]
@@ -51,11 +102,31 @@ fn translate_address(this: &<HalTy as Hal>::KTableTy, addr: VirtualAddress) -> O
 }
 
 #[export_name = "__popcorn_paging_ktable_map_page"]
-fn map_page(this: &mut <HalTy as Hal>::KTableTy, page: Page, frame: Frame) -> Result<(), MapPageError> {
-	<<HalTy as Hal>::KTableTy as KTable>::map_page(this, page, frame)
+fn map_page(this: &mut <HalTy as Hal>::KTableTy, page: Page, frame: Frame, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
+	<<HalTy as Hal>::KTableTy as KTable>::map_page(this, page, frame, protection, cache)
 }
 
 #[export_name = "__popcorn_paging_ktable_unmap_page"]
 fn unmap_page(this: &mut <HalTy as Hal>::KTableTy, page: Page) -> Result<(), ()> {
 	<<HalTy as Hal>::KTableTy as KTable>::unmap_page(this, page)
 }
+
+#[export_name = "__popcorn_paging_ktable_protect_page"]
+fn protect_page(this: &mut <HalTy as Hal>::KTableTy, page: Page, protection: Protection) -> Result<(), ()> {
+	<<HalTy as Hal>::KTableTy as KTable>::protect_page(this, page, protection)
+}
+
+#[export_name = "__popcorn_paging_ktable_map_huge_page"]
+fn map_huge_page(this: &mut <HalTy as Hal>::KTableTy, page: Page, frame: Frame, size: PageSize, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
+	<<HalTy as Hal>::KTableTy as KTable>::map_huge_page(this, page, frame, size, protection, cache)
+}
+
+#[export_name = "__popcorn_paging_ktable_unmap_huge_page"]
+fn unmap_huge_page(this: &mut <HalTy as Hal>::KTableTy, page: Page, size: PageSize) -> Result<(), ()> {
+	<<HalTy as Hal>::KTableTy as KTable>::unmap_huge_page(this, page, size)
+}
+
+#[export_name = "__popcorn_paging_ktable_protect_huge_page"]
+fn protect_huge_page(this: &mut <HalTy as Hal>::KTableTy, page: Page, size: PageSize, protection: Protection) -> Result<(), ()> {
+	<<HalTy as Hal>::KTableTy as KTable>::protect_huge_page(this, page, size, protection)
+}
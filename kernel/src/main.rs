@@ -38,6 +38,7 @@
 
 #![feature(kernel_heap)]
 #![feature(kernel_allocation_new)]
+#![feature(kernel_allocation_zeroing)]
 #![feature(kernel_sync_once)]
 #![feature(kernel_physical_page_offset)]
 #![feature(kernel_memory_addr_access)]
@@ -48,6 +49,11 @@
 #![feature(kernel_physical_allocator_non_contiguous)]
 #![feature(kernel_physical_allocator_location)]
 #![feature(kernel_ptr)]
+#![feature(kernel_object_cache)]
+#![feature(kernel_threads)]
+#![feature(kernel_memory_stats)]
+#![feature(kernel_physical_allocator_reclaim)]
+#![feature(kernel_dma)]
 
 #![no_std]
 #![no_main]
@@ -60,14 +66,14 @@ extern crate unwinding;
 
 extern crate self as kernel;
 
-use alloc::borrow::Cow;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::alloc::{Allocator, GlobalAlloc, Layout};
 use core::arch::asm;
 use core::cell::{RefCell, UnsafeCell};
 use core::fmt::Write;
-use core::ops::Deref;
+use core::ops::{Deref, Range};
 use core::panic::PanicInfo;
 use core::ptr::{addr_of_mut, slice_from_raw_parts_mut};
 use log::{debug, error, info, trace, warn};
@@ -80,8 +86,6 @@ use core::time::Duration;
 use ::acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
 use ::acpi::madt::MadtEntry;
 use kernel_api::memory::{allocator::BackingAllocator};
-#[warn(deprecated)]
-use kernel_api::memory::mapping::OldMapping;
 use hal::{HalTy, Hal, ThreadControlBlock, ThreadState, SaveState};
 use handoff_protection::HandoffWrapper;
 
@@ -132,6 +136,9 @@ fn irq_handler(num: usize) {
 	} else {
 		warn!("Unhandled IRQ num {num}");
 	}
+
+	// The handler lock has been released, so the thread can be switched out if its time slice is up
+	unsafe { threading::preempt::irq_exit(); }
 }
 
 #[inline]
@@ -154,10 +161,21 @@ fn exception_handler(exception: hal::exception::Exception) {
 				todo!()
 			}
 		},
-		ty @ Ty::PageFault(_) => {
-			// todo: check for CoW etc.
+		Ty::PageFault(ref fault) => {
+			if memory::lazy::handle_fault(fault.access_addr, fault.is_user) { return; }
+			if fault.is_write && memory::cow::handle_write_fault(fault.access_addr) { return; }
+			if fault.is_write && fault.access_addr < 0x0000_8000_0000_0000 && threading::handle_write_fault(fault.access_addr) { return; }
+
 			if is_kernel_mode {
-				error!("Kernel page fault occurred at {:#x} - {}:\n{ty}", exception.at_instruction, panicking::get_symbol_name(exception.at_instruction));
+				// Page faults run on their own stack, so there is room to report an overflow of the thread's stack
+				if let Some(thread) = threading::overflowed_stack(fault.access_addr) {
+					error!("kernel stack overflow in thread {thread}");
+					error!("Overflowed at {:#x} - {}", exception.at_instruction, panicking::get_symbol_name(exception.at_instruction));
+					panicking::stack_trace();
+					loop {}
+				}
+
+				error!("Kernel page fault occurred at {:#x} - {}:\n{}", exception.at_instruction, panicking::get_symbol_name(exception.at_instruction), exception.ty);
 				loop {}
 			} else {
 				todo!()
@@ -178,15 +196,27 @@ mod handoff_protection {
 	use core::fmt::{Debug, Formatter};
 	use core::ops::Deref;
 	use derive_more::Constructor;
+	use kernel_api::memory::allocator::BackingAllocator;
+	use kernel_api::memory::physical::highmem;
 	use crate::hal::{Hal, HalTy};
+	use crate::hal::paging2::TTable;
 
 	#[derive(Constructor)]
 	pub struct HandoffWrapper(&'static utils::handoff::Data, <HalTy as Hal>::TTableTy);
 
 	impl HandoffWrapper {
+		/// Removes the bootloader's identity mappings from the page table, which also ends access to the handoff data
 		pub fn to_empty_ttable(self) -> <HalTy as Hal>::TTableTy {
-			// todo!("empty the ttable");
-			self.1
+			let mut ttable = self.1;
+
+			// The bootloader's page tables came from the firmware rather than the page table cache
+			unsafe {
+				ttable.clear(&mut |table| {
+					highmem().add_region(table..(table + 1)).expect("Highmem should accept new memory");
+				});
+			}
+
+			ttable
 		}
 	}
 
@@ -229,8 +259,15 @@ extern "sysv64" fn kstart(handoff_data: &'static utils::handoff::Data) -> ! {
             Frame::new(entry.start().align_up())..Frame::new(entry.end().align_down())
         });
 
+		let highest_frame = spaces.clone().map(|space| space.end).max().expect("Free memory should exist");
+
 		let mut watermark_allocator = memory::watermark_allocator::WatermarkAllocator::new(&mut spaces);
-		memory::physical::with_highmem_as(&mut watermark_allocator, || test_main());
+		memory::physical::with_highmem_as(&mut watermark_allocator, || {
+			memory::physical::init_refcounts(Frame::new(PhysicalAddress::new(0))..highest_frame);
+			// Magazines and preemption are tested through their thread locals
+			unsafe { init_tls(handoff_data.tls); }
+			test_main()
+		});
 
 		unreachable!("test harness returned")
 	}
@@ -239,18 +276,39 @@ extern "sysv64" fn kstart(handoff_data: &'static utils::handoff::Data) -> ! {
 use kernel_api::memory::{Frame};
 use kernel_api::memory::allocator::{Config, SizedBackingAllocator, SpecificLocation};
 use kernel_api::memory::mapping::Stack;
-use kernel_api::memory::physical::highmem;
 use kernel_api::memory::r#virtual::Global;
 use kernel_api::ptr::Unique;
 use kernel_api::sync::Mutex;
-use crate::hal::paging2::{construct_tables, TTable, TTableTy};
+use kernel_api::threading::Priority;
+use crate::hal::paging2::construct_tables;
 use utils::handoff::MemoryType;
 use crate::hal::acpi::XPhysicalMapping;
 use crate::hal::exception::{PageFault, Ty};
-use crate::memory::paging::ktable;
 use crate::memory::watermark_allocator::WatermarkAllocator;
 use crate::task::executor::Executor;
 
+/// Sets up thread locals for the current CPU from the kernel's TLS `template`
+///
+/// # Safety
+///
+/// Must only be called once per CPU, since the previous thread locals are lost
+unsafe fn init_tls(template: utils::handoff::Range<VirtualAddress>) {
+	let tls_size = template.end() - template.start() + mem::size_of::<*mut u8>();
+	// Is this always correctly aligned?
+	// The TLS area is used for the rest of the kernel's lifetime so is never unmapped
+	let tls = mapping::Mapping::new(
+		mapping::Config::<Global>::new(NonZeroUsize::new(tls_size.div_ceil(4096)).unwrap())
+				.laziness(mapping::Laziness::Prefault)
+	).expect("Unable to allocate TLS area");
+	let tls = mem::ManuallyDrop::new(tls).virtual_start();
+	unsafe {
+		core::ptr::copy_nonoverlapping(template.start().as_ptr(), tls.as_ptr(), tls_size - core::mem::size_of::<*mut u8>());
+		let tls_self_ptr = tls.as_ptr().byte_add(tls_size - core::mem::size_of::<*mut u8>());
+		tls_self_ptr.cast::<*mut u8>().write(tls_self_ptr);
+		HalTy::load_tls(tls_self_ptr);
+	}
+}
+
 fn kmain(handoff_data: HandoffWrapper) -> ! {
 	let _ = logging::init();
 
@@ -265,6 +323,11 @@ fn kmain(handoff_data: HandoffWrapper) -> ! {
 		entry.ty == MemoryType::Free || entry.ty == MemoryType::BootloaderCode
 	);
 
+	// Memory that can only be used once the handoff data and ACPI tables are no longer needed
+	let reclaimable_memory = handoff_data.memory.map.iter().filter(|entry|
+		entry.ty == MemoryType::BootloaderData || entry.ty == MemoryType::AcpiReclaim
+	);
+
 	// Split allocator system is used when a significant portion of memory is above the 4GiB boundary
 	// This allows better optimization for non-DMA allocations as well as reducing pressure on memory usable by DMA
 	// The current algorithm uses split allocators when the total amount of non-DMA memory is >= 1GiB
@@ -284,37 +347,75 @@ fn kmain(handoff_data: HandoffWrapper) -> ! {
 	{
 		use kernel_api::memory::PhysicalAddress;
 
-		if split_allocators {
-			todo!("split allocators not supported yet :(");
-		}
-
+		// Reclaimable memory is added to the allocators later, so has to be within their range
 		let max_usable_memory = usable_memory.clone()
+		                                     .chain(reclaimable_memory.clone())
 		                                     .max_by(|a, b| a.end().cmp(&b.end()))
 		                                     .expect("Free memory should exist");
 		let max_usable_memory = max_usable_memory.end();
 
-		let mut spaces = usable_memory.clone()
-		                              .map(|entry| {
-			                              Frame::new(entry.start().align_up())..Frame::new(entry.end().align_down())
-		                              });
+		let spaces = usable_memory.clone()
+		                          .map(|entry| {
+			                          Frame::new(entry.start().align_up())..Frame::new(entry.end().align_down())
+		                          });
 
 		let mut spaces2 = spaces.clone();
 		let watermark_allocator = WatermarkAllocator::new(&mut spaces2);
 
-		debug!("Initialising highmem");
+		let new_allocator = |allocation_range: Range<Frame>| {
+			let mut regions = spaces.clone()
+			                        .map(|region| {
+				                        max(region.start, allocation_range.start)..min(region.end, allocation_range.end)
+			                        })
+			                        .filter(|region| !region.is_empty());
 
-		let allocator = memory::physical::with_highmem_as(&watermark_allocator, || {
-			<bitmap_allocator::Wrapped as SizedBackingAllocator>::new(
+			<memory::physical::PhysicalAllocator as SizedBackingAllocator>::new(
 				Config {
-					allocation_range: Frame::new(PhysicalAddress::new(0))..Frame::new(max_usable_memory.align_down()),
-					regions: &mut spaces
+					allocation_range,
+					regions: &mut regions
 				}
 			)
-		});
+		};
+
+		let lowest_frame = Frame::new(PhysicalAddress::new(0));
+		let highest_frame = Frame::new(max_usable_memory.align_down());
+
+		let highmem: &'static dyn BackingAllocator = if split_allocators {
+			const FOUR_GB: Frame = Frame::new(PhysicalAddress::new(1<<32));
+
+			debug!("Initialising highmem and dmamem");
+
+			let (highmem, dmamem) = memory::physical::with_highmem_as(&watermark_allocator, || {
+				(new_allocator(FOUR_GB..highest_frame), new_allocator(lowest_frame..FOUR_GB))
+			});
+
+			watermark_allocator.drain_into(&mut [&mut *highmem, &mut *dmamem]);
+			let (highmem, dmamem) = (&*highmem, &*dmamem);
+			memory::physical::init_dmamem(dmamem);
+
+			// The fallback allocator has to live on the heap, which needs highmem to already be usable
+			memory::physical::init_highmem(highmem);
+			let highmem = memory::fallback_allocator::FallbackAllocator::new(highmem, FOUR_GB..highest_frame, dmamem);
+			Box::leak(Box::new(highmem))
+		} else {
+			debug!("Initialising highmem");
 
-		watermark_allocator.drain_into(allocator);
-		memory::physical::init_highmem(allocator);
-		memory::physical::init_dmamem(allocator);
+			let allocator = memory::physical::with_highmem_as(&watermark_allocator, || {
+				new_allocator(lowest_frame..highest_frame)
+			});
+
+			watermark_allocator.drain_into(&mut [&mut *allocator]);
+			memory::physical::init_dmamem(allocator);
+			allocator
+		};
+
+		memory::physical::init_highmem(highmem);
+		memory::physical::init_refcounts(lowest_frame..highest_frame);
+
+		// Page tables and other zeroed allocations are served from frames zeroed by the frame zeroer thread
+		let zeroer: &'static _ = Box::leak(Box::new(memory::background_zeroer::BackgroundZeroer::new(highmem)));
+		memory::physical::init_highmem(zeroer);
+		memory::physical::init_highmem_zeroer(zeroer);
 
 		let btree_alloc = {
 			use core::iter::Iterator;
@@ -322,11 +423,15 @@ fn kmain(handoff_data: HandoffWrapper) -> ! {
 			const PAGE_MAP_OFFSET: usize = 0xffff_8000_0000_0000;
 			const PAGE_MAP_OFFSET_LEN: usize = 2usize.pow(46);
 
-			let mut btree_alloc = ranged_btree_allocator::RangedBtreeAllocator::new(
-				// unfortunately this means a page is missing :(
-				Page::new(VirtualAddress::new(PAGE_MAP_OFFSET+PAGE_MAP_OFFSET_LEN))..Page::new(VirtualAddress::new(0xffff_ffff_ffff_f000))
+			let btree_alloc = ranged_btree_allocator::RangedBtreeAllocator::new_inclusive(
+				Page::new(VirtualAddress::new(PAGE_MAP_OFFSET+PAGE_MAP_OFFSET_LEN))..=Page::new(VirtualAddress::new(0xffff_ffff_ffff_f000))
 			);
 
+			// Randomising placement costs page tables, as mappings no longer share them with their neighbours
+			#[cfg(feature = "kaslr")]
+			let btree_alloc = btree_alloc.with_placement(ranged_btree_allocator::Placement::Randomised { seed: HalTy::boot_entropy() });
+			let mut btree_alloc = btree_alloc;
+
 			let virtual_reserved = [
 				// entire bootstrap region in case adding allocations uses more heap
 				// realisation: this will now cause an OOM on all subsequent heap allocations
@@ -345,6 +450,13 @@ fn kmain(handoff_data: HandoffWrapper) -> ! {
 		*memory::r#virtual::GLOBAL_VIRTUAL_ALLOCATOR.write() = Box::leak(Box::new(btree_alloc));
 	}
 
+	// The symbol map was loaded into bootloader memory, so is moved to the heap before that memory is reclaimed
+	*panicking::SYMBOL_MAP.write() = map.map(|map| &*Box::leak(Box::<[u8]>::from(map)));
+
+	let reclaimable_memory: Vec<_> = reclaimable_memory.map(|entry| {
+		Frame::new(entry.start().align_up())..Frame::new(entry.end().align_down())
+	}).collect();
+
 	unsafe {
 		hal::acpi::init_tables(handoff_data.rsdp.addr);
 	}
@@ -428,18 +540,10 @@ fn kmain(handoff_data: HandoffWrapper) -> ! {
 		(Some(update_line), Some(picos_per_tick))
 	} else { (None, None) };
 
-	let tls_size = handoff_data.tls.end() - handoff_data.tls.start() + mem::size_of::<*mut u8>();
-	// Is this always correctly aligned?
-	#[warn(deprecated)]
-			let tls = OldMapping::new(tls_size.div_ceil(4096))
-			.expect("Unable to allocate TLS area");
-	let (tls, _) = tls.into_raw_parts();
-	unsafe {
-		core::ptr::copy_nonoverlapping(handoff_data.tls.start().as_ptr(), tls.as_ptr(), tls_size - core::mem::size_of::<*mut u8>());
-		let tls_self_ptr = tls.as_ptr().byte_add(tls_size - core::mem::size_of::<*mut u8>());
-		tls_self_ptr.cast::<*mut u8>().write(tls_self_ptr);
-		HalTy::load_tls(tls_self_ptr);
-	}
+	unsafe { init_tls(handoff_data.tls); }
+
+	// The magazines live in thread locals, so can only be used now that they are set up
+	memory::physical::init_magazines();
 
 	{
 		if let Ok(hpet) = ::acpi::hpet::HpetInfo::new(hal::acpi::tables()) {
@@ -482,23 +586,30 @@ fn kmain(handoff_data: HandoffWrapper) -> ! {
 
 		<HalTy as Hal>::post_acpi_init();
 
-		if let Some(mut update_line) = update_line {
+		{
 			use crate::hal::timing::{Timer, Eoi};
 
+			/// The tick period used for scheduling when there is no progress bar to animate
+			const SCHEDULER_TICK_PICOS: u128 = 1_000_000_000;
+
 			let mut timer = <HalTy as Hal>::LocalTimer::get();
 			let eoi = timer.eoi_handle();
 
+			let mut update_line = update_line;
 			let tick_func = move || {
-				update_line();
+				if let Some(ref mut update_line) = update_line { update_line(); }
+				threading::preempt::tick();
 				eoi.send();
 			};
 			IRQ_HANDLES.lock().insert(48, Box::new(tick_func));
 
+			let picos_per_tick = picos_per_tick.unwrap_or(SCHEDULER_TICK_PICOS);
 			let time_period = timer.get_time_period_picos().unwrap() * 4;
 			timer.set_irq_number(48).unwrap();
 			timer.set_divisor(4).unwrap();
-			debug!("{time_period:?} {} {}", picos_per_tick.unwrap(), picos_per_tick.unwrap() / u128::from(time_period));
-			timer.start_periodic(picos_per_tick.unwrap() / u128::from(time_period)).unwrap();
+			debug!("{time_period:?} {} {}", picos_per_tick, picos_per_tick / u128::from(time_period));
+			threading::preempt::set_tick_period(picos_per_tick);
+			timer.start_periodic(picos_per_tick / u128::from(time_period)).unwrap();
 		}
 	}
 
@@ -508,35 +619,46 @@ fn kmain(handoff_data: HandoffWrapper) -> ! {
 	let init_thread = unsafe { threading::init(handoff_data) };
 	debug!("{init_thread:x?}");
 
-	fn foo() -> ! {
+	// Threading took the last of the handoff data, and the ACPI tables have been read by everything that needs them
+	unsafe {
+		hal::acpi::reclaim();
+		memory::physical::reclaim(&reclaimable_memory);
+	}
+
+	fn foo() {
 		sprintln!("hello from foo!");
 
 		threading::thread_yield();
-
-		unreachable!()
 	}
 
-	fn bar() {
-		unsafe {
-			threading::scheduler::SCHEDULER.unlock();
+	fn zero_frames() {
+		use memory::background_zeroer::RefillStop;
+
+		let zeroer = memory::physical::highmem_zeroer().expect("Highmem zeroer should be initialised before threading");
+
+		// Zero a small batch at a time so other threads aren't starved
+		loop {
+			match zeroer.refill(16) {
+				(_, RefillStop::Done) => threading::thread_yield(),
+				// There's nothing to do until allocations have used some of the pool, or memory has been freed
+				(_, RefillStop::Full | RefillStop::Exhausted) => zeroer.wait_for_demand()
+			}
 		}
 	}
 
-	{
-		let ttable = TTableTy::new(&*ktable(), highmem()).unwrap();
-		let task = ThreadControlBlock::new(
-			Cow::Borrowed("hellooo"),
-			ttable,
-			bar,
-			foo,
-		);
-		let mut guard = threading::scheduler::SCHEDULER.lock();
-		guard.add_task(task);
-	}
+	// The zeroer runs for as long as the kernel does
+	let _ = threading::Builder::new()
+			.name("frame zeroer")
+			.priority(Priority::Low)
+			.spawn(zero_frames)
+			.expect("Failed to spawn frame zeroer");
+	let hello = threading::spawn("hellooo", foo);
 
-	threading::thread_yield();
+	hello.join();
+	debug!("foo has been joined");
 
-	loop {}
+	// Nothing wakes the init thread, so the CPU is left to the other threads and the idle thread
+	loop { threading::block_current(); }
 
 	let mut executor = Executor::new();
 	static mut WAKER: Option<Waker> = None;
@@ -634,6 +756,14 @@ mod allocator {
 				None => {}
 			}
 		}
+
+		unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+			let Some(ptr) = NonNull::new(ptr) else { return ptr::null_mut(); };
+			match kernel_default_heap::__popcorn_kernel_heap_reallocate(ptr, layout, new_size) {
+				Ok(ptr) => ptr.as_ptr(),
+				Err(_) => ptr::null_mut()
+			}
+		}
 	}
 
 	#[cfg_attr(not(test), global_allocator)]
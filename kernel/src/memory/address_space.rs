@@ -0,0 +1,353 @@
+//! Lower half address spaces, which hold the user memory of a thread
+//!
+//! An [`AddressSpace`] owns a [`TTable`] along with a record of every [virtual memory area](Vma) mapped in it. Frames of
+//! [anonymous](Backing::Anonymous) areas belong to the address space, and are shared copy-on-write when it is
+//! [forked](AddressSpace::fork).
+
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use core::mem::ManuallyDrop;
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use core::ptr::NonNull;
+use log::warn;
+use kernel_api::memory::{AllocError, Frame, Page, VirtualAddress};
+use kernel_api::memory::allocator::BackingAllocator;
+use kernel_api::memory::mapping::{CacheMode, Protection};
+use ranged_btree::RangedBTreeMap;
+use crate::hal::paging2::{KTable, TTable, TTableTy};
+use crate::memory::cache::PAGE_TABLES;
+use crate::memory::paging::ktable;
+use crate::memory::{cow, physical};
+
+const LOWER_HALF_END: usize = 0x0000_8000_0000_0000;
+
+/// Where the memory of a [`Vma`] comes from
+#[derive(Copy, Clone)]
+pub enum Backing {
+	/// Zeroed frames are allocated from `allocator` when the area is mapped, and given back when it is unmapped
+	Anonymous { allocator: &'static dyn BackingAllocator },
+	/// Memory starting at `base` that doesn't belong to the address space, such as device memory
+	Physical { base: Frame }
+}
+
+impl Debug for Backing {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Backing::Anonymous { .. } => f.write_str("Anonymous"),
+			Backing::Physical { base } => f.debug_struct("Physical").field("base", base).finish()
+		}
+	}
+}
+
+/// A virtual memory area, which is a range of pages mapped with the same protection and backing
+#[derive(Debug, Copy, Clone)]
+pub struct Vma {
+	pub protection: Protection,
+	pub cache: CacheMode,
+	pub backing: Backing
+}
+
+impl Vma {
+	/// Returns the area covering the pages `offset` pages into this one
+	fn offset_by(self, offset: usize) -> Self {
+		let backing = match self.backing {
+			Backing::Physical { base } => Backing::Physical { base: base + offset },
+			anonymous => anonymous
+		};
+
+		Self { backing, ..self }
+	}
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MapError {
+	/// Part of the range is already mapped
+	Overlapping,
+	/// The range is empty or reaches outside of the lower half
+	InvalidRange,
+	OutOfMemory
+}
+
+impl From<AllocError> for MapError {
+	fn from(_: AllocError) -> Self {
+		Self::OutOfMemory
+	}
+}
+
+pub struct AddressSpace {
+	/// Only taken when the address space is dropped
+	pub(crate) ttable: ManuallyDrop<TTableTy>,
+	vmas: RangedBTreeMap<Page, Vma>
+}
+
+impl AddressSpace {
+	/// Creates an address space with nothing mapped in the lower half
+	pub fn new() -> Result<Self, AllocError> {
+		let ttable = TTableTy::new(&*ktable())?;
+		Ok(Self { ttable: ManuallyDrop::new(ttable), vmas: RangedBTreeMap::new() })
+	}
+
+	/// Creates an address space from a table with nothing mapped in the lower half
+	///
+	/// # Safety
+	///
+	/// If the address space is ever dropped, `ttable` must have been made by [`TTable::new`], since its tables are given
+	/// back to the page table cache
+	pub unsafe fn from_ttable(ttable: TTableTy) -> Self {
+		Self { ttable: ManuallyDrop::new(ttable), vmas: RangedBTreeMap::new() }
+	}
+
+	/// Returns the area containing `page`
+	pub fn vma(&self, page: Page) -> Option<(&Range<Page>, &Vma)> {
+		self.vmas.get_range_at_point(page)
+	}
+
+	/// Maps `pages` with `vma`, allocating all of its memory if it is [anonymous](Backing::Anonymous)
+	///
+	/// User accessible memory needs [`Protection::USER`].
+	pub fn map(&mut self, pages: Range<Page>, vma: Vma) -> Result<(), MapError> {
+		if pages.start >= pages.end || pages.end.start().addr > LOWER_HALF_END { return Err(MapError::InvalidRange); }
+		if self.vmas.used_regions().any(|range| range.start < pages.end && pages.start < range.end) {
+			return Err(MapError::Overlapping);
+		}
+
+		for (i, page) in (0..(pages.end - pages.start)).map(|i| (i, pages.start + i)) {
+			let mapped = match vma.backing {
+				Backing::Anonymous { allocator } => allocator.allocate_zeroed(1)
+						.map_err(MapError::from)
+						.and_then(|frame| {
+							self.ttable.map_page(page, frame, vma.protection, vma.cache).map_err(|_| {
+								unsafe { allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+								MapError::OutOfMemory
+							})
+						}),
+				Backing::Physical { base } => self.ttable.map_page(page, base + i, vma.protection, vma.cache)
+						.map_err(|_| MapError::OutOfMemory)
+			};
+
+			if let Err(e) = mapped {
+				self.unmap_pages(pages.start..page, &vma);
+				return Err(e);
+			}
+		}
+
+		self.vmas.insert(pages, vma).expect("Range was checked for overlaps");
+		Ok(())
+	}
+
+	/// Unmaps every page in `pages`, splitting any area that is only partly covered
+	///
+	/// Pages that aren't mapped are skipped.
+	pub fn unmap(&mut self, pages: Range<Page>) {
+		for (range, vma) in self.take_vmas(pages) {
+			self.unmap_pages(range, &vma);
+		}
+	}
+
+	/// Changes the protection of every page in `pages`, splitting any area that is only partly covered
+	///
+	/// Pages that are still shared copy-on-write with another address space are kept read only.
+	pub fn protect(&mut self, pages: Range<Page>, protection: Protection) {
+		for (range, vma) in self.take_vmas(pages) {
+			for page in (0..(range.end - range.start)).map(|i| range.start + i) {
+				let Some(frame) = self.ttable.translate_page(page) else { continue; };
+				let shared = matches!(vma.backing, Backing::Anonymous { .. }) && physical::is_shared(frame);
+				let protection = if shared { protection.difference(Protection::WRITE) } else { protection };
+				self.ttable.protect_page(page, protection).expect("Page was just translated");
+			}
+
+			self.vmas.insert(range, Vma { protection, ..vma }).expect("Area was just removed");
+		}
+	}
+
+	/// Creates a copy of this address space
+	///
+	/// Anonymous memory is shared copy-on-write between the two, so both lose write access to it until one of them is
+	/// written to. Physical memory is mapped in both.
+	pub fn fork(&mut self) -> Result<Self, AllocError> {
+		let mut child = Self::new()?;
+
+		let vmas: Vec<_> = self.vmas.iter().map(|(range, vma)| (range.clone(), *vma)).collect();
+		for (range, vma) in vmas {
+			// Inserted first so that the child frees anything already shared if mapping fails
+			child.vmas.insert(range.clone(), vma).expect("Areas can't overlap in the parent");
+
+			for (i, page) in (0..(range.end - range.start)).map(|i| (i, range.start + i)) {
+				match vma.backing {
+					Backing::Anonymous { .. } => {
+						cow::share_page(&mut *self.ttable, page, &mut *child.ttable, page, vma.protection, vma.cache)
+								.map_err(|_| AllocError)?;
+					}
+					Backing::Physical { base } => {
+						child.ttable.map_page(page, base + i, vma.protection, vma.cache)
+								.map_err(|_| AllocError)?;
+					}
+				}
+			}
+		}
+
+		Ok(child)
+	}
+
+	/// Attempts to resolve a write fault at `addr` by giving this address space its own copy of a shared frame
+	///
+	/// Returns `true` if the faulting instruction can be restarted
+	pub fn handle_write_fault(&mut self, addr: usize) -> bool {
+		let page = Page::new(VirtualAddress::new(addr).align_down());
+		let Some(&vma) = self.vmas.get_entry_at_point(page) else { return false; };
+
+		let Backing::Anonymous { allocator } = vma.backing else { return false; };
+		if !vma.protection.contains(Protection::WRITE) { return false; }
+
+		cow::copy_on_write(&mut *self.ttable, page, vma.protection, vma.cache, allocator)
+	}
+
+	/// Removes the areas overlapping `pages` and returns the parts of them inside it
+	///
+	/// The parts outside of `pages` are put back as their own areas.
+	fn take_vmas(&mut self, pages: Range<Page>) -> Vec<(Range<Page>, Vma)> {
+		let overlapping: Vec<_> = self.vmas.used_regions()
+				.filter(|range| range.start < pages.end && pages.start < range.end)
+				.cloned()
+				.collect();
+
+		overlapping.into_iter().map(|range| {
+			let vma = self.vmas.remove(range.start).expect("Area was just found");
+
+			if range.start < pages.start {
+				self.vmas.insert(range.start..pages.start, vma).expect("Area was just removed");
+			}
+			if pages.end < range.end {
+				let tail = vma.offset_by(pages.end - range.start);
+				self.vmas.insert(pages.end..range.end, tail).expect("Area was just removed");
+			}
+
+			let start = range.start.max(pages.start);
+			(start..range.end.min(pages.end), vma.offset_by(start - range.start))
+		}).collect()
+	}
+
+	/// Unmaps `pages`, which are covered by `vma`, freeing any anonymous frames that are no longer shared
+	fn unmap_pages(&mut self, pages: Range<Page>, vma: &Vma) {
+		for page in (0..(pages.end - pages.start)).map(|i| pages.start + i) {
+			let Some(frame) = self.ttable.translate_page(page) else { continue; };
+			if self.ttable.unmap_page(page).is_err() {
+				warn!("Failed to unmap {page:x?}");
+				continue;
+			}
+
+			if let Backing::Anonymous { allocator } = vma.backing && physical::release(frame) {
+				unsafe { allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+			}
+		}
+	}
+}
+
+impl Debug for AddressSpace {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		f.debug_map()
+				.entries(self.vmas.iter())
+				.finish()
+	}
+}
+
+impl Drop for AddressSpace {
+	fn drop(&mut self) {
+		// Entries don't need to be removed one at a time, since the whole lower half is cleared afterwards
+		for (range, vma) in self.vmas.iter() {
+			let Backing::Anonymous { allocator } = vma.backing else { continue; };
+
+			for page in (0..(range.end - range.start)).map(|i| range.start + i) {
+				let Some(frame) = self.ttable.translate_page(page) else { continue; };
+				if physical::release(frame) {
+					unsafe { allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+				}
+			}
+		}
+
+		let ttable = unsafe { ManuallyDrop::take(&mut self.ttable) };
+		unsafe {
+			ttable.free(&mut |table| {
+				let table = NonNull::new(table.to_page().as_ptr()).expect("Physical memory map is not at null");
+				PAGE_TABLES.deallocate(table);
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use kernel_api::memory::physical::highmem;
+	use super::*;
+
+	fn anonymous(protection: Protection) -> Vma {
+		Vma { protection, cache: CacheMode::WriteBack, backing: Backing::Anonymous { allocator: highmem() } }
+	}
+
+	fn pages(start: usize, len: usize) -> Range<Page> {
+		let start = Page::new(VirtualAddress::new(start));
+		start..(start + len)
+	}
+
+	#[test]
+	fn anonymous_memory_is_mapped() {
+		let mut space = AddressSpace::new().unwrap();
+		let range = pages(0x4000_0000, 4);
+		space.map(range.clone(), anonymous(Protection::RW)).unwrap();
+
+		for i in 0..4 {
+			assert!(space.ttable.translate_page(range.start + i).is_some());
+		}
+		assert_eq!(space.map(pages(0x4000_2000, 4), anonymous(Protection::RW)), Err(MapError::Overlapping));
+	}
+
+	#[test]
+	fn unmap_splits_area() {
+		let mut space = AddressSpace::new().unwrap();
+		let range = pages(0x4000_0000, 4);
+		space.map(range.clone(), anonymous(Protection::RW)).unwrap();
+
+		space.unmap((range.start + 1)..(range.start + 3));
+		assert!(space.ttable.translate_page(range.start).is_some());
+		assert_eq!(space.ttable.translate_page(range.start + 1), None);
+		assert_eq!(space.ttable.translate_page(range.start + 2), None);
+		assert!(space.ttable.translate_page(range.start + 3).is_some());
+
+		assert_eq!(space.vma(range.start).unwrap().0, &(range.start..(range.start + 1)));
+		assert_eq!(space.vma(range.start + 3).unwrap().0, &((range.start + 3)..range.end));
+		assert!(space.vma(range.start + 1).is_none());
+	}
+
+	#[test]
+	fn protect_splits_area() {
+		let mut space = AddressSpace::new().unwrap();
+		let range = pages(0x4000_0000, 3);
+		space.map(range.clone(), anonymous(Protection::RW)).unwrap();
+
+		space.protect((range.start + 1)..range.end, Protection::R);
+		assert_eq!(space.vma(range.start).unwrap().1.protection, Protection::RW);
+		assert_eq!(space.vma(range.start + 2).unwrap().1.protection, Protection::R);
+	}
+
+	#[test]
+	fn fork_shares_copy_on_write() {
+		let mut parent = AddressSpace::new().unwrap();
+		let range = pages(0x4000_0000, 2);
+		parent.map(range.clone(), anonymous(Protection::RW)).unwrap();
+		let frame = parent.ttable.translate_page(range.start).unwrap();
+		unsafe { frame.to_page().as_ptr().write(0xab); }
+
+		let mut child = parent.fork().unwrap();
+		assert_eq!(child.ttable.translate_page(range.start), Some(frame));
+		assert!(physical::is_shared(frame));
+
+		assert!(child.handle_write_fault(range.start.start().addr));
+		let copy = child.ttable.translate_page(range.start).unwrap();
+		assert_ne!(copy, frame);
+		assert_eq!(unsafe { copy.to_page().as_ptr().read() }, 0xab);
+
+		drop(child);
+		assert!(!physical::is_shared(parent.ttable.translate_page(range.start + 1).unwrap()));
+	}
+}
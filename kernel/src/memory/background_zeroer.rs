@@ -0,0 +1,306 @@
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use kernel_api::memory::{AllocError, Frame, PhysicalAddress};
+use kernel_api::memory::allocator::{self, BackingAllocator, ScatterList, SpecificLocation, ZeroAllocError};
+use kernel_api::memory::allocator::new_allocator::fast_zero_memory_sse_nocache;
+use kernel_api::sync::Mutex;
+use crate::threading;
+use crate::threading::scheduler::Tid;
+
+/// The number of prezeroed frames kept by a [`BackgroundZeroer`]
+pub const POOL_CAPACITY: usize = 256;
+
+/// The number of pooled frames below which a thread [waiting](BackgroundZeroer::wait_for_demand) to refill the pool is woken
+pub const LOW_WATERMARK: usize = POOL_CAPACITY / 4;
+
+struct Pool {
+	frames: [Frame; POOL_CAPACITY],
+	len: usize
+}
+
+impl Pool {
+	fn pop(&mut self) -> Option<Frame> {
+		self.len = self.len.checked_sub(1)?;
+		Some(self.frames[self.len])
+	}
+
+	fn push(&mut self, frame: Frame) -> Result<(), Frame> {
+		if self.len == POOL_CAPACITY { return Err(frame); }
+		self.frames[self.len] = frame;
+		self.len += 1;
+		Ok(())
+	}
+}
+
+/// Why a [refill](BackgroundZeroer::refill) stopped
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RefillStop {
+	/// As many frames as were asked for were added
+	Done,
+	/// The pool is full
+	Full,
+	/// The backing allocator has run out of memory
+	Exhausted
+}
+
+/// Statistics about the frames served by a [`BackgroundZeroer`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Stats {
+	/// The number of prezeroed frames currently in the pool
+	pub pooled: usize,
+	/// The maximum number of frames the pool can hold
+	pub capacity: usize,
+	/// The number of zeroed allocations served from the pool
+	pub hits: usize,
+	/// The number of zeroed allocations that had to be zeroed by the caller
+	pub misses: usize,
+	/// The total number of frames zeroed in the background
+	pub zeroed: usize
+}
+
+/// A physical allocator that keeps a pool of prezeroed frames to serve [`try_allocate_zeroed`](BackingAllocator::try_allocate_zeroed)
+///
+/// The pool is only refilled by calling [`refill`](Self::refill), which is intended to be done from a low priority
+/// thread so that zeroing happens while the CPU would otherwise be idle. That thread can
+/// [wait](Self::wait_for_demand) while the pool is full or the backing allocator has run out, and is woken once the pool
+/// drops below [`LOW_WATERMARK`] or memory is freed.
+/// Only single frame requests are served from the pool, since pooled frames are rarely contiguous.
+pub struct BackgroundZeroer<A: BackingAllocator> {
+	backing: A,
+	pool: Mutex<Pool>,
+	/// The thread waiting for the pool to run low
+	refiller: Mutex<Option<Tid>>,
+	/// Set when refilling runs out of memory, until a frame is freed or allocated again
+	exhausted: AtomicBool,
+	hits: AtomicUsize,
+	misses: AtomicUsize,
+	zeroed: AtomicUsize
+}
+
+impl<A: BackingAllocator> BackgroundZeroer<A> {
+	pub fn new(backing: A) -> Self {
+		Self {
+			backing,
+			pool: Mutex::new(Pool {
+				frames: [Frame::new(PhysicalAddress::new(0)); POOL_CAPACITY],
+				len: 0
+			}),
+			refiller: Mutex::new(None),
+			exhausted: AtomicBool::new(false),
+			hits: AtomicUsize::new(0),
+			misses: AtomicUsize::new(0),
+			zeroed: AtomicUsize::new(0)
+		}
+	}
+
+	/// Zeroes up to `max_frames` frames and adds them to the pool, stopping early if the pool is full or there is no
+	/// memory left
+	///
+	/// Returns the number of frames added to the pool, and why it stopped
+	pub fn refill(&self, max_frames: usize) -> (usize, RefillStop) {
+		let mut added = 0;
+
+		let stop = loop {
+			if added == max_frames { break RefillStop::Done; }
+			if self.pool.lock().len == POOL_CAPACITY { break RefillStop::Full; }
+
+			let Ok(frame) = self.backing.allocate_one() else {
+				self.exhausted.store(true, Ordering::Relaxed);
+				break RefillStop::Exhausted;
+			};
+			self.exhausted.store(false, Ordering::Relaxed);
+
+			// The lock isn't held while zeroing, so that allocations aren't blocked
+			unsafe {
+				let memory = slice::from_raw_parts_mut(frame.to_page().as_ptr(), 4096);
+				fast_zero_memory_sse_nocache(memory);
+			}
+
+			if let Err(frame) = self.pool.lock().push(frame) {
+				unsafe { self.backing.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+				break RefillStop::Full;
+			}
+			added += 1;
+		};
+
+		self.zeroed.fetch_add(added, Ordering::Relaxed);
+		(added, stop)
+	}
+
+	/// Blocks the current thread until the pool has fewer than [`LOW_WATERMARK`] frames, and the backing allocator
+	/// isn't known to be out of memory
+	pub fn wait_for_demand(&self) {
+		threading::block_current_unless(|tid| {
+			if self.pool.lock().len < LOW_WATERMARK && !self.exhausted.load(Ordering::Relaxed) { return true; }
+			*self.refiller.lock() = Some(tid);
+			false
+		});
+	}
+
+	/// Takes a frame from the pool, and wakes the waiting thread if that leaves the pool running low
+	fn pop(&self, pool: &mut Pool) -> Option<Frame> {
+		let frame = pool.pop()?;
+
+		// There's no point refilling while the backing allocator is out of memory, that waits for a frame to be freed
+		if pool.len < LOW_WATERMARK && !self.exhausted.load(Ordering::Relaxed) {
+			self.wake_refiller();
+		}
+		Some(frame)
+	}
+
+	fn wake_refiller(&self) {
+		let mut refiller = self.refiller.lock();
+		// If the scheduler is locked the thread stays waiting, and is woken by a later allocation or free
+		if let Some(tid) = *refiller && threading::try_wake(tid) {
+			*refiller = None;
+		}
+	}
+
+	pub fn stats(&self) -> Stats {
+		Stats {
+			pooled: self.pool.lock().len,
+			capacity: POOL_CAPACITY,
+			hits: self.hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed),
+			zeroed: self.zeroed.load(Ordering::Relaxed)
+		}
+	}
+}
+
+unsafe impl<A: BackingAllocator> BackingAllocator for BackgroundZeroer<A> {
+	fn allocate_contiguous(&self, frame_count: usize) -> Result<Frame, AllocError> {
+		match self.backing.allocate_contiguous(frame_count) {
+			Err(AllocError) if frame_count == 1 => self.allocate_one(),
+			result => result
+		}
+	}
+
+	fn allocate_one(&self) -> Result<Frame, AllocError> {
+		// Pooled frames are still free memory, so are given out once the backing allocator has run out
+		self.backing.allocate_one()
+				.or_else(|_| self.pop(&mut self.pool.lock()).ok_or(AllocError))
+	}
+
+	fn allocate(&self, frame_count: usize) -> Result<ScatterList, AllocError> {
+		self.backing.allocate(frame_count)
+	}
+
+	fn try_allocate_zeroed(&self, frame_count: usize) -> Result<Frame, ZeroAllocError> {
+		if frame_count == 1 && let Some(frame) = self.pop(&mut self.pool.lock()) {
+			self.hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(frame);
+		}
+
+		self.misses.fetch_add(1, Ordering::Relaxed);
+		self.backing.try_allocate_zeroed(frame_count)
+	}
+
+	fn allocate_zeroed_nowait(&self) -> Result<Frame, AllocError> {
+		if let Some(mut pool) = self.pool.try_lock() && let Some(frame) = self.pop(&mut pool) {
+			self.hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(frame);
+		}
+
+		self.misses.fetch_add(1, Ordering::Relaxed);
+		self.backing.allocate_zeroed_nowait()
+	}
+
+	unsafe fn deallocate_contiguous(&self, base: Frame, frame_count: NonZeroUsize) {
+		unsafe { self.backing.deallocate_contiguous(base, frame_count) }
+
+		if self.exhausted.swap(false, Ordering::Relaxed) {
+			self.wake_refiller();
+		}
+	}
+
+	fn allocate_at(&self, frame_count: usize, location: SpecificLocation) -> Result<Frame, AllocError> {
+		self.backing.allocate_at(frame_count, location)
+	}
+
+	fn stats(&self) -> Option<allocator::Stats> {
+		// Pooled frames are allocated from the backing allocator, but are still free to be handed out
+		let stats = self.backing.stats()?;
+		let pooled = self.pool.lock().len;
+		Some(allocator::Stats { free_frames: stats.free_frames + pooled, ..stats })
+	}
+
+	fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, allocator::Stats)) {
+		self.backing.region_stats(f)
+	}
+
+	unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+		unsafe { self.backing.add_region(region) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::memory::physical::highmem;
+	use super::*;
+
+	/// An allocator that has run out of memory
+	struct Exhausted;
+
+	unsafe impl BackingAllocator for Exhausted {
+		fn allocate_contiguous(&self, _: usize) -> Result<Frame, AllocError> { Err(AllocError) }
+		unsafe fn deallocate_contiguous(&self, _: Frame, _: NonZeroUsize) {}
+		fn allocate_at(&self, _: usize, _: SpecificLocation) -> Result<Frame, AllocError> { Err(AllocError) }
+	}
+
+	#[test]
+	fn empty_pool_is_uninit() {
+		let zeroer = BackgroundZeroer::new(highmem());
+		assert!(matches!(zeroer.try_allocate_zeroed(1), Err(ZeroAllocError::Uninit(_))));
+		assert_eq!(zeroer.stats().misses, 1);
+	}
+
+	#[test]
+	fn refilled_frames_are_zeroed() {
+		let zeroer = BackgroundZeroer::new(highmem());
+		assert_eq!(zeroer.refill(2), (2, RefillStop::Done));
+
+		let frame = zeroer.try_allocate_zeroed(1).expect("Pool was refilled");
+		let memory = unsafe { slice::from_raw_parts(frame.to_page().as_ptr(), 4096) };
+		assert!(memory.iter().all(|&byte| byte == 0));
+
+		let stats = zeroer.stats();
+		assert_eq!((stats.pooled, stats.hits, stats.zeroed), (1, 1, 2));
+	}
+
+	#[test]
+	fn refill_stops_when_full() {
+		let zeroer = BackgroundZeroer::new(highmem());
+		assert_eq!(zeroer.refill(POOL_CAPACITY + 5), (POOL_CAPACITY, RefillStop::Full));
+		assert_eq!(zeroer.refill(1), (0, RefillStop::Full));
+	}
+
+	#[test]
+	fn refill_reports_exhaustion() {
+		let zeroer = BackgroundZeroer::new(Exhausted);
+		assert_eq!(zeroer.refill(1), (0, RefillStop::Exhausted));
+		assert!(zeroer.exhausted.load(Ordering::Relaxed));
+
+		unsafe { zeroer.deallocate_contiguous(Frame::new(PhysicalAddress::new(0x1000)), NonZeroUsize::new(1).unwrap()); }
+		assert!(!zeroer.exhausted.load(Ordering::Relaxed));
+	}
+
+	#[test]
+	fn allocate_one_falls_back_to_pool() {
+		let zeroer = BackgroundZeroer::new(Exhausted);
+		assert!(zeroer.allocate_one().is_err());
+
+		zeroer.pool.lock().push(Frame::new(PhysicalAddress::new(0x1000))).unwrap();
+		assert_eq!(zeroer.allocate_contiguous(1), Ok(Frame::new(PhysicalAddress::new(0x1000))));
+		assert_eq!(zeroer.stats().pooled, 0);
+	}
+
+	#[test]
+	fn contiguous_requests_bypass_pool() {
+		let zeroer = BackgroundZeroer::new(highmem());
+		zeroer.refill(2);
+		assert!(matches!(zeroer.try_allocate_zeroed(2), Err(ZeroAllocError::Uninit(_))));
+		assert_eq!(zeroer.stats().pooled, 2);
+	}
+}
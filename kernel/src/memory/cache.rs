@@ -0,0 +1,310 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::mem;
+use core::num::NonZeroUsize;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::debug;
+use kernel_api::memory::{AllocError, Frame, Page, VirtualAddress};
+use kernel_api::memory::allocator::BackingAllocator;
+use kernel_api::memory::cache::{Hooks, Placement, Stats};
+use kernel_api::memory::physical::highmem;
+use kernel_api::sync::Mutex;
+use slab_allocator::SlabAllocator;
+
+/// A cache of equally sized objects
+pub struct Cache {
+	name: &'static str,
+	slabs: Mutex<SlabAllocator<Placement>>
+}
+
+impl Cache {
+	pub fn new(name: &'static str, layout: Layout, placement: Placement, hooks: Hooks) -> Self {
+		debug!("Creating object cache {name} for {layout:?} ({placement:?})");
+		Self {
+			name,
+			slabs: Mutex::new(SlabAllocator::new(placement, layout, hooks))
+		}
+	}
+
+	pub fn allocate(&self) -> Result<NonNull<u8>, AllocError> {
+		self.slabs.lock().allocate()
+	}
+
+	/// # Safety
+	///
+	/// `ptr` must have been allocated from this cache, and be in its constructed state if the cache has a constructor
+	pub unsafe fn deallocate(&self, ptr: NonNull<u8>) {
+		unsafe { self.slabs.lock().deallocate(ptr); }
+	}
+
+	pub fn shrink(&self) -> usize {
+		self.slabs.lock().shrink()
+	}
+
+	pub fn stats(&self) -> Stats {
+		self.slabs.lock().stats()
+	}
+}
+
+/// The most free tables [`PAGE_TABLES`] keeps, beyond which freed tables are given back to highmem
+const MAX_FREE_TABLES: usize = 64;
+
+/// A cache of zeroed frames for page tables
+///
+/// Every table is a single frame, so tables are taken straight from highmem rather than carved out of slabs. Tables are
+/// allocated while the page tables are locked, so free tables are reached through the physical page map and linked
+/// through their first word, which is zeroed again when they are reused.
+pub struct TableCache {
+	free: Mutex<FreeTables>,
+	in_use: AtomicUsize
+}
+
+struct FreeTables {
+	head: Option<Frame>,
+	len: usize
+}
+
+impl FreeTables {
+	fn pop(&mut self) -> Option<Frame> {
+		let frame = self.head?;
+		let link = frame.to_page().as_ptr().cast::<Option<Frame>>();
+		unsafe {
+			self.head = link.read();
+			link.cast::<u8>().write_bytes(0, mem::size_of::<Option<Frame>>());
+		}
+		self.len -= 1;
+		Some(frame)
+	}
+
+	/// # Safety
+	///
+	/// `frame` must be a zeroed table that isn't used anywhere else
+	unsafe fn push(&mut self, frame: Frame) {
+		unsafe { frame.to_page().as_ptr().cast::<Option<Frame>>().write(self.head); }
+		self.head = Some(frame);
+		self.len += 1;
+	}
+}
+
+impl TableCache {
+	const fn new() -> Self {
+		Self {
+			free: Mutex::new(FreeTables { head: None, len: 0 }),
+			in_use: AtomicUsize::new(0)
+		}
+	}
+
+	pub fn allocate(&self) -> Result<NonNull<u8>, AllocError> {
+		let cached = self.free.lock().pop();
+		let frame = match cached {
+			Some(frame) => frame,
+			None => highmem().allocate_zeroed(1)?
+		};
+		Ok(self.hand_out(frame))
+	}
+
+	/// Allocates a table like [`allocate`](Self::allocate), but fails instead of waiting for a lock
+	///
+	/// This is for page fault handlers, which may have interrupted code on the same CPU that holds the lock.
+	pub fn try_allocate(&self) -> Result<NonNull<u8>, AllocError> {
+		let cached = self.free.try_lock().ok_or(AllocError)?.pop();
+		let frame = match cached {
+			Some(frame) => frame,
+			None => highmem().allocate_zeroed_nowait()?
+		};
+		Ok(self.hand_out(frame))
+	}
+
+	fn hand_out(&self, frame: Frame) -> NonNull<u8> {
+		self.in_use.fetch_add(1, Ordering::Relaxed);
+		NonNull::new(frame.to_page().as_ptr()).expect("Physical memory map is not at null")
+	}
+
+	/// # Safety
+	///
+	/// `table` must have been allocated from this cache, and be zeroed
+	pub unsafe fn deallocate(&self, table: NonNull<u8>) {
+		let frame = Page::new(VirtualAddress::new(table.as_ptr() as usize)).to_frame();
+		self.in_use.fetch_sub(1, Ordering::Relaxed);
+
+		let mut free = self.free.lock();
+		if free.len < MAX_FREE_TABLES {
+			unsafe { free.push(frame); }
+		} else {
+			drop(free);
+			unsafe { highmem().deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+		}
+	}
+
+	pub fn stats(&self) -> Stats {
+		let in_use = self.in_use.load(Ordering::Relaxed);
+		let free = self.free.lock().len;
+		Stats {
+			object_size: 4096,
+			in_use,
+			free,
+			slabs: in_use + free
+		}
+	}
+}
+
+/// Frames for page tables, which are kept zeroed while cached so that every new table is already empty
+pub static PAGE_TABLES: TableCache = TableCache::new();
+
+struct CachePtr(NonNull<Cache>);
+
+// SAFETY: caches are only shared through their lock
+unsafe impl Send for CachePtr {}
+
+/// Every cache created through `kernel_api`, so that their statistics can be listed
+static CACHES: Mutex<Vec<CachePtr>> = Mutex::new(Vec::new());
+
+#[export_name = "__popcorn_memory_cache_create"]
+fn create(name: &'static str, layout: Layout, placement: Placement, hooks: Hooks) -> Result<NonNull<Cache>, AllocError> {
+	let cache = Box::try_new(Cache::new(name, layout, placement, hooks)).map_err(|_| AllocError)?;
+	let cache = NonNull::from(Box::leak(cache));
+
+	CACHES.lock().push(CachePtr(cache));
+	Ok(cache)
+}
+
+/// # Safety
+///
+/// `cache` must be from [`create`], and not used again
+#[export_name = "__popcorn_memory_cache_destroy"]
+unsafe fn destroy(cache: NonNull<Cache>) {
+	CACHES.lock().retain(|other| other.0 != cache);
+
+	let cache = unsafe { Box::from_raw(cache.as_ptr()) };
+	debug!("Destroying object cache {}", cache.name);
+}
+
+#[export_name = "__popcorn_memory_cache_allocate"]
+fn allocate(cache: &Cache) -> Result<NonNull<u8>, AllocError> {
+	cache.allocate()
+}
+
+/// # Safety
+///
+/// `ptr` must have been allocated from `cache`, and be in its constructed state if the cache has a constructor
+#[export_name = "__popcorn_memory_cache_deallocate"]
+unsafe fn deallocate(cache: &Cache, ptr: NonNull<u8>) {
+	unsafe { cache.deallocate(ptr); }
+}
+
+#[export_name = "__popcorn_memory_cache_shrink"]
+fn shrink(cache: &Cache) -> usize {
+	cache.shrink()
+}
+
+#[export_name = "__popcorn_memory_cache_stats"]
+fn cache_stats(cache: &Cache) -> Stats {
+	cache.stats()
+}
+
+/// Returns the name and statistics of every object cache
+pub fn stats() -> Vec<(&'static str, Stats)> {
+	let mut stats = alloc::vec![("page_tables", PAGE_TABLES.stats())];
+	stats.extend(CACHES.lock().iter().map(|cache| {
+		let cache = unsafe { cache.0.as_ref() };
+		(cache.name, cache.stats())
+	}));
+	stats
+}
+
+#[cfg(test)]
+mod tests {
+	use kernel_api::memory::cache::{Cacheable, ObjectCache};
+	use super::*;
+
+	#[test]
+	fn stats_track_allocations() {
+		let cache = ObjectCache::<[u64; 4]>::new("test_stats", Placement::default()).unwrap();
+		let first = cache.allocate([1; 4]).unwrap();
+		let second = cache.allocate([2; 4]).unwrap();
+		assert_eq!((*first, *second), ([1; 4], [2; 4]));
+
+		let stats = cache.stats();
+		assert_eq!((stats.object_size, stats.in_use, stats.slabs), (32, 2, 1));
+
+		drop(first);
+		assert_eq!(cache.stats().in_use, 1);
+		assert!(super::stats().iter().any(|&(name, stats)| name == "test_stats" && stats.in_use == 1));
+	}
+
+	#[test]
+	fn destroyed_caches_are_unlisted() {
+		let cache = ObjectCache::<u8>::new("test_destroyed", Placement::default()).unwrap();
+		drop(cache);
+		assert!(super::stats().iter().all(|&(name, _)| name != "test_destroyed"));
+	}
+
+	#[test]
+	fn constructed_objects_are_reused() {
+		static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+		struct Counted(usize);
+
+		impl Cacheable for Counted {
+			fn construct() -> Self {
+				CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+				Counted(0)
+			}
+
+			fn recycle(&mut self) {
+				self.0 = 0;
+			}
+		}
+
+		let cache = ObjectCache::<Counted>::new_constructed("test_constructed", Placement::default()).unwrap();
+		let mut object = cache.allocate_constructed().unwrap();
+		let constructed = CONSTRUCTED.load(Ordering::Relaxed);
+		object.0 = 5;
+		drop(object);
+
+		let object = cache.allocate_constructed().unwrap();
+		assert_eq!(object.0, 0);
+		assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), constructed);
+	}
+
+	#[test]
+	fn page_tables_are_zeroed() {
+		let table = PAGE_TABLES.allocate().unwrap();
+		let memory = unsafe { core::slice::from_raw_parts(table.as_ptr(), 4096) };
+		assert!(memory.iter().all(|&byte| byte == 0));
+		assert!(super::stats().iter().any(|&(name, stats)| name == "page_tables" && stats.in_use > 0));
+		unsafe { PAGE_TABLES.deallocate(table); }
+	}
+
+	#[test]
+	fn freed_page_tables_are_reused_zeroed() {
+		let table = PAGE_TABLES.allocate().unwrap();
+		unsafe { PAGE_TABLES.deallocate(table); }
+
+		let reused = PAGE_TABLES.try_allocate().unwrap();
+		assert_eq!(reused, table);
+		let memory = unsafe { core::slice::from_raw_parts(reused.as_ptr(), 4096) };
+		assert!(memory.iter().all(|&byte| byte == 0));
+		unsafe { PAGE_TABLES.deallocate(reused); }
+	}
+
+	#[test]
+	fn try_allocate_fails_while_locked() {
+		let _free = PAGE_TABLES.free.lock();
+		assert!(PAGE_TABLES.try_allocate().is_err());
+	}
+
+	#[test]
+	fn physical_map_objects_have_frames() {
+		#[repr(C, align(4096))]
+		struct Frame([u8; 4096]);
+
+		let cache = ObjectCache::<Frame>::new("test_physical_map", Placement::PhysicalMap(highmem())).unwrap();
+		let object = cache.allocate(Frame([0xab; 4096])).unwrap();
+		let page = Page::new(VirtualAddress::new(&*object as *const Frame as usize));
+		assert_eq!(page.to_frame().to_page(), page);
+		assert_eq!(unsafe { page.as_ptr().read() }, 0xab);
+	}
+}
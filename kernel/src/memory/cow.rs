@@ -0,0 +1,229 @@
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use core::ptr;
+use log::{debug, warn};
+use kernel_api::bridge::paging::MapPageError;
+use kernel_api::memory::{Page, VirtualAddress};
+use kernel_api::memory::allocator::BackingAllocator;
+use kernel_api::memory::mapping::{CacheMode, Protection};
+use kernel_api::sync::RwLock;
+use ranged_btree::RangedBTreeMap;
+use crate::hal::paging2::KTable;
+use crate::memory::paging::{ktable, try_ktable};
+use crate::memory::physical;
+
+/// A region of kernel virtual memory whose frames may be shared with other regions until they are written to
+struct CowRegion {
+	allocator: &'static dyn BackingAllocator,
+	/// The protection of the region once a page is no longer shared
+	protection: Protection,
+	cache: CacheMode
+}
+
+static COW_REGIONS: RwLock<RangedBTreeMap<Page, CowRegion>> = RwLock::new(RangedBTreeMap::new());
+
+/// # Safety
+///
+/// `allocator` must remain valid until the region is unregistered
+#[export_name = "__popcorn_memory_cow_register"]
+unsafe fn register(pages: Range<Page>, allocator: &'static dyn BackingAllocator, protection: Protection, cache: CacheMode) -> Result<(), ()> {
+	debug!("Registering copy-on-write region {pages:x?}");
+	COW_REGIONS.write().insert(pages, CowRegion { allocator, protection, cache })
+}
+
+#[export_name = "__popcorn_memory_cow_unregister"]
+fn unregister(start: Page) {
+	let region = COW_REGIONS.write().remove(start);
+	if region.is_none() { warn!("Attempted to unregister unknown copy-on-write region at {start:x?}"); }
+}
+
+/// Changes the protection of the region starting at `start`
+///
+/// Pages that are still shared are kept read only
+#[export_name = "__popcorn_memory_cow_protect"]
+fn protect(start: Page, protection: Protection) {
+	let mut regions = COW_REGIONS.write();
+	let Some(range) = regions.used_regions().find(|range| range.start == start).cloned() else {
+		warn!("Attempted to protect unknown copy-on-write region at {start:x?}");
+		return;
+	};
+	let mut region = regions.remove(start).expect("Region was just found");
+	region.protection = protection;
+
+	let mut ktable = ktable();
+	for page in (0..(range.end - range.start)).map(|i| range.start + i) {
+		let frame = ktable.translate_page(page).expect("Copy-on-write regions are always mapped");
+		let protection = if physical::is_shared(frame) { protection.difference(Protection::WRITE) } else { protection };
+		ktable.protect_page(page, protection).expect("Page was just translated");
+	}
+
+	regions.insert(range, region).expect("Region was just removed");
+}
+
+/// Maps the frame behind `page` in `from` at `to_page` in `to`, with both pages read only until one is written to
+///
+/// Both pages are given `protection` without write access, and the new page uses `cache`. The caller is responsible for
+/// resolving write faults using [`copy_on_write`], and for releasing the frame once either page is unmapped.
+pub fn share_page(from: &mut impl KTable, page: Page, to: &mut impl KTable, to_page: Page, protection: Protection, cache: CacheMode) -> Result<(), MapPageError> {
+	let frame = from.translate_page(page).expect("Page to share must be mapped");
+	physical::share(frame).expect("Frame must be reference counted to be shared");
+
+	let read_only = protection.difference(Protection::WRITE);
+	if let Err(e) = to.map_page(to_page, frame, read_only, cache) {
+		physical::release(frame);
+		return Err(e);
+	}
+
+	from.protect_page(page, read_only).expect("Page was just translated");
+	Ok(())
+}
+
+/// Resolves a write to `page`, copying its frame first if it is shared with another page
+///
+/// Returns `false` if `page` isn't mapped or memory for the copy couldn't be allocated. This is called from the page
+/// fault handler, so it fails rather than waiting for the allocator's lock.
+pub fn copy_on_write(table: &mut impl KTable, page: Page, protection: Protection, cache: CacheMode, allocator: &dyn BackingAllocator) -> bool {
+	let Some(frame) = table.translate_page(page) else { return false; };
+
+	// If every other owner has already copied or dropped the frame, it can just be made writable again
+	if !physical::is_shared(frame) {
+		table.protect_page(page, protection).expect("Page was just translated");
+		return true;
+	}
+
+	let Ok(copy) = allocator.allocate_zeroed_nowait() else {
+		warn!("Unable to allocate memory while copying shared page {page:x?}");
+		return false;
+	};
+
+	unsafe {
+		ptr::copy_nonoverlapping(frame.to_page().as_ptr(), copy.to_page().as_ptr(), 4096);
+	}
+
+	table.unmap_page(page).expect("Page was just translated");
+	// The page's tables are kept when it is unmapped, so this never has to allocate a new one
+	table.try_map_page(page, copy, protection, cache).expect("Page was just unmapped");
+
+	// The other owners may have gone away since checking
+	if physical::release(frame) {
+		unsafe { allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+	}
+
+	true
+}
+
+/// Attempts to resolve a write fault at `addr` by giving the faulting page its own copy of a shared frame
+///
+/// Returns `true` if the faulting instruction can be restarted. The fault may have interrupted code on this CPU that
+/// holds the locks needed to copy the page, so this fails rather than waiting for them.
+pub fn handle_write_fault(addr: usize) -> bool {
+	let page = Page::new(VirtualAddress::new(addr).align_down());
+
+	let Some(regions) = COW_REGIONS.try_read() else { return false; };
+	let Some(region) = regions.get_entry_at_point(page) else { return false; };
+
+	// A write to a region that is never writable is a protection violation
+	if !region.protection.contains(Protection::WRITE) { return false; }
+
+	let Some(mut ktable) = try_ktable() else {
+		warn!("Page table locked while copying shared page {page:x?}");
+		return false;
+	};
+
+	copy_on_write(&mut *ktable, page, region.protection, region.cache, region.allocator)
+}
+
+#[cfg(test)]
+mod tests {
+	use core::num::NonZeroUsize;
+	use kernel_api::memory::mapping::{Config, Laziness, Mapping};
+	use kernel_api::memory::r#virtual::Global;
+	use crate::hal::paging2::{TTable, TTableTy};
+	use crate::memory::physical::highmem;
+	use super::*;
+
+	fn prefaulted(len: usize) -> Mapping<'static> {
+		Mapping::new(Config::<Global>::new(NonZeroUsize::new(len).unwrap()).laziness(Laziness::Prefault)).unwrap()
+	}
+
+	#[test]
+	fn shared_mappings_share_frames() {
+		let mut mapping = prefaulted(2);
+		let shared = mapping.share().unwrap();
+
+		for i in 0..2 {
+			let frame = ktable().translate_page(mapping.virtual_start() + i).unwrap();
+			assert_eq!(ktable().translate_page(shared.virtual_start() + i), Some(frame));
+			assert!(physical::is_shared(frame));
+		}
+	}
+
+	#[test]
+	fn write_fault_copies_only_faulting_page() {
+		let mut mapping = prefaulted(2);
+		unsafe { mapping.virtual_start().as_ptr().write(0xab); }
+		let shared = mapping.share().unwrap();
+
+		assert!(handle_write_fault(shared.virtual_start().start().addr));
+
+		let original = ktable().translate_page(mapping.virtual_start()).unwrap();
+		let copy = ktable().translate_page(shared.virtual_start()).unwrap();
+		assert_ne!(original, copy);
+		assert!(!physical::is_shared(original));
+		assert_eq!(unsafe { shared.virtual_start().as_ptr().read() }, 0xab);
+
+		assert_eq!(
+			ktable().translate_page(mapping.virtual_start() + 1),
+			ktable().translate_page(shared.virtual_start() + 1)
+		);
+	}
+
+	#[test]
+	fn write_after_other_copy_keeps_frame() {
+		let mut mapping = prefaulted(1);
+		let shared = mapping.share().unwrap();
+
+		assert!(handle_write_fault(shared.virtual_start().start().addr));
+		let frame = ktable().translate_page(mapping.virtual_start()).unwrap();
+		assert!(handle_write_fault(mapping.virtual_start().start().addr));
+		assert_eq!(ktable().translate_page(mapping.virtual_start()), Some(frame));
+	}
+
+	#[test]
+	fn write_to_read_only_region_is_unhandled() {
+		let mut mapping = prefaulted(1);
+		mapping.change_protection(Protection::R);
+		let shared = mapping.share().unwrap();
+
+		assert!(!handle_write_fault(shared.virtual_start().start().addr));
+	}
+
+	#[test]
+	fn write_fault_while_regions_locked_is_unhandled() {
+		let mut mapping = prefaulted(1);
+		let shared = mapping.share().unwrap();
+
+		let _regions = COW_REGIONS.write();
+		assert!(!handle_write_fault(shared.virtual_start().start().addr));
+	}
+
+	#[test]
+	fn share_page_between_ttables() {
+		let mut first = TTableTy::new(&*ktable()).unwrap();
+		let mut second = TTableTy::new(&*ktable()).unwrap();
+		let page = Page::new(VirtualAddress::new(0xcafebabe000));
+		let frame = highmem().allocate_one().unwrap();
+		unsafe { frame.to_page().as_ptr().write(0xcd); }
+
+		first.map_page(page, frame, Protection::RW, CacheMode::WriteBack).unwrap();
+		share_page(&mut first, page, &mut second, page, Protection::RW, CacheMode::WriteBack).unwrap();
+		assert_eq!(second.translate_page(page), Some(frame));
+
+		assert!(copy_on_write(&mut second, page, Protection::RW, CacheMode::WriteBack, highmem()));
+		let copy = second.translate_page(page).unwrap();
+		assert_ne!(copy, frame);
+		assert_eq!(unsafe { copy.to_page().as_ptr().read() }, 0xcd);
+		assert_eq!(first.translate_page(page), Some(frame));
+		assert!(!physical::is_shared(frame));
+	}
+}
@@ -0,0 +1,215 @@
+use core::cmp::{max, min};
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use kernel_api::memory::allocator::{BackingAllocator, ScatterList, SpecificLocation, Stats, ZeroAllocError};
+use kernel_api::memory::{AllocError, Frame};
+
+/// A physical allocator that uses a second allocator once the first is exhausted
+///
+/// This is used when highmem and DMA memory are managed separately, so that general allocations prefer memory above
+/// 4GiB but can still be satisfied from DMA memory when there is none left.
+pub struct FallbackAllocator<'a> {
+	primary: &'a dyn BackingAllocator,
+	/// The frames managed by `primary`, used to determine which allocator a deallocation belongs to
+	primary_range: Range<Frame>,
+	fallback: &'a dyn BackingAllocator
+}
+
+impl<'a> FallbackAllocator<'a> {
+	pub fn new(primary: &'a dyn BackingAllocator, primary_range: Range<Frame>, fallback: &'a dyn BackingAllocator) -> Self {
+		Self { primary, primary_range, fallback }
+	}
+
+	fn owner_of(&self, frame: Frame) -> &'a dyn BackingAllocator {
+		if self.primary_range.contains(&frame) { self.primary }
+		else { self.fallback }
+	}
+}
+
+/// Returns `true` if `region` is entirely within one of the ranges `allocator` reports through
+/// [`region_stats`](BackingAllocator::region_stats)
+fn covers(allocator: &dyn BackingAllocator, region: &Range<Frame>) -> bool {
+	let mut covered = false;
+	allocator.region_stats(&mut |range, _| {
+		covered |= range.start <= region.start && region.end <= range.end;
+	});
+	covered
+}
+
+unsafe impl BackingAllocator for FallbackAllocator<'_> {
+	fn allocate_contiguous(&self, frame_count: usize) -> Result<Frame, AllocError> {
+		self.primary.allocate_contiguous(frame_count)
+				.or_else(|_| self.fallback.allocate_contiguous(frame_count))
+	}
+
+	fn allocate_one(&self) -> Result<Frame, AllocError> {
+		self.primary.allocate_one()
+				.or_else(|_| self.fallback.allocate_one())
+	}
+
+	fn allocate(&self, frame_count: usize) -> Result<ScatterList, AllocError> {
+		self.primary.allocate(frame_count)
+				.or_else(|_| self.fallback.allocate(frame_count))
+	}
+
+	fn try_allocate_zeroed(&self, frame_count: usize) -> Result<Frame, ZeroAllocError> {
+		match self.primary.try_allocate_zeroed(frame_count) {
+			Err(ZeroAllocError::AllocError) => self.fallback.try_allocate_zeroed(frame_count),
+			res => res
+		}
+	}
+
+	fn allocate_zeroed_nowait(&self) -> Result<Frame, AllocError> {
+		self.primary.allocate_zeroed_nowait()
+				.or_else(|_| self.fallback.allocate_zeroed_nowait())
+	}
+
+	unsafe fn deallocate_contiguous(&self, base: Frame, frame_count: NonZeroUsize) {
+		self.owner_of(base).deallocate_contiguous(base, frame_count)
+	}
+
+	fn allocate_at(&self, frame_count: usize, location: SpecificLocation) -> Result<Frame, AllocError> {
+		match location {
+			SpecificLocation::At(frame) => self.owner_of(frame).allocate_at(frame_count, location),
+			SpecificLocation::Below { location: limit, .. } if limit <= self.primary_range.start => {
+				self.fallback.allocate_at(frame_count, location)
+			}
+			SpecificLocation::Aligned(alignment) => {
+				self.primary.allocate_at(frame_count, SpecificLocation::Aligned(alignment))
+						.or_else(|_| self.fallback.allocate_at(frame_count, SpecificLocation::Aligned(alignment)))
+			}
+			SpecificLocation::Below { location, with_alignment } => {
+				self.primary.allocate_at(frame_count, SpecificLocation::Below { location, with_alignment })
+						.or_else(|_| self.fallback.allocate_at(frame_count, SpecificLocation::Below { location, with_alignment }))
+			}
+		}
+	}
+
+	fn stats(&self) -> Option<Stats> {
+		Some(self.primary.stats()?.combine(self.fallback.stats()?))
+	}
+
+	fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, Stats)) {
+		self.primary.region_stats(f);
+		self.fallback.region_stats(f);
+	}
+
+	unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+		// Each part of the region goes to the allocator that owns it, and both parts are checked first so that nothing is
+		// added if either would fail
+		let split = min(max(region.start, self.primary_range.start), region.end);
+		let (low, high) = (region.start..split, split..region.end);
+
+		if !low.is_empty() && !covers(self.fallback, &low) { return Err(()); }
+		if !high.is_empty() && !covers(self.primary, &high) { return Err(()); }
+
+		unsafe {
+			if !high.is_empty() { self.primary.add_region(high)?; }
+			if !low.is_empty() { self.fallback.add_region(low)?; }
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use alloc::vec::Vec;
+	use core::num::NonZeroUsize;
+	use core::ops::Range;
+	use kernel_api::memory::allocator::{BackingAllocator, SpecificLocation, Stats};
+	use kernel_api::memory::{AllocError, Frame, PhysicalAddress};
+	use kernel_api::sync::Mutex;
+	use super::FallbackAllocator;
+	use super::super::tests::MockAllocator;
+
+	fn frame(index: usize) -> Frame {
+		Frame::new(PhysicalAddress::new(index * 4096))
+	}
+
+	/// Manages `range` and records the regions added to it
+	struct Reclaiming {
+		range: Range<Frame>,
+		added: Mutex<Vec<Range<Frame>>>
+	}
+
+	impl Reclaiming {
+		fn new(range: Range<Frame>) -> Self {
+			Self { range, added: Mutex::new(Vec::new()) }
+		}
+	}
+
+	unsafe impl BackingAllocator for Reclaiming {
+		fn allocate_contiguous(&self, _: usize) -> Result<Frame, AllocError> { Err(AllocError) }
+		unsafe fn deallocate_contiguous(&self, _: Frame, _: NonZeroUsize) {}
+		fn allocate_at(&self, _: usize, _: SpecificLocation) -> Result<Frame, AllocError> { Err(AllocError) }
+
+		fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, Stats)) {
+			f(self.range.clone(), Stats::default());
+		}
+
+		unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+			if region.start < self.range.start || region.end > self.range.end { return Err(()); }
+			self.added.lock().push(region);
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn prefers_primary() {
+		let primary = MockAllocator::new_with_frames(2);
+		let fallback = MockAllocator::new_fail();
+		let range = Frame::new(PhysicalAddress::new(0))..Frame::new(PhysicalAddress::new(0x2000));
+		let allocator = FallbackAllocator::new(&primary, range, &fallback);
+
+		assert!(allocator.allocate_contiguous(2).is_ok());
+		primary.verify();
+	}
+
+	#[test]
+	fn falls_back_when_primary_exhausted() {
+		let primary = MockAllocator::new_fail();
+		let fallback = MockAllocator::new_with_frames(3);
+		let range = Frame::new(PhysicalAddress::new(0))..Frame::new(PhysicalAddress::new(0x2000));
+		let allocator = FallbackAllocator::new(&primary, range, &fallback);
+
+		assert!(allocator.allocate_contiguous(2).is_ok());
+		assert!(allocator.allocate_one().is_ok());
+		fallback.verify();
+	}
+
+	#[test]
+	fn fails_when_both_exhausted() {
+		let primary = MockAllocator::new_fail();
+		let fallback = MockAllocator::new_fail();
+		let range = Frame::new(PhysicalAddress::new(0))..Frame::new(PhysicalAddress::new(0x2000));
+		let allocator = FallbackAllocator::new(&primary, range, &fallback);
+
+		assert!(allocator.allocate_contiguous(1).is_err());
+	}
+
+	#[test]
+	fn added_region_is_split_between_allocators() {
+		let primary = Reclaiming::new(frame(16)..frame(32));
+		let fallback = Reclaiming::new(frame(0)..frame(16));
+		let allocator = FallbackAllocator::new(&primary, frame(16)..frame(32), &fallback);
+
+		unsafe {
+			allocator.add_region(frame(8)..frame(24)).unwrap();
+			allocator.add_region(frame(2)..frame(4)).unwrap();
+			allocator.add_region(frame(20)..frame(22)).unwrap();
+		}
+		assert_eq!(*primary.added.lock(), [frame(16)..frame(24), frame(20)..frame(22)]);
+		assert_eq!(*fallback.added.lock(), [frame(8)..frame(16), frame(2)..frame(4)]);
+	}
+
+	#[test]
+	fn out_of_range_region_adds_nothing() {
+		let primary = Reclaiming::new(frame(16)..frame(32));
+		let fallback = Reclaiming::new(frame(0)..frame(16));
+		let allocator = FallbackAllocator::new(&primary, frame(16)..frame(32), &fallback);
+
+		assert!(unsafe { allocator.add_region(frame(8)..frame(40)) }.is_err());
+		assert!(primary.added.lock().is_empty());
+		assert!(fallback.added.lock().is_empty());
+	}
+}
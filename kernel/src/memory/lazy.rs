@@ -0,0 +1,175 @@
+use core::mem;
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use log::{debug, warn};
+use kernel_api::memory::{Page, VirtualAddress};
+use kernel_api::memory::allocator::BackingAllocator;
+use kernel_api::memory::mapping::{CacheMode, Protection};
+use kernel_api::sync::{RwLock, RwUpgradableReadGuard};
+use ranged_btree::RangedBTreeMap;
+use crate::hal::paging2::KTable;
+use crate::memory::paging::try_ktable;
+
+/// A region of kernel virtual memory whose physical memory is allocated on first access
+///
+/// The regions are only ever locked for writing without allocating, since the heap may itself be lazily mapped.
+#[derive(Clone)]
+struct LazyRegion {
+	allocator: &'static dyn BackingAllocator,
+	protection: Protection,
+	cache: CacheMode
+}
+
+static LAZY_REGIONS: RwLock<RangedBTreeMap<Page, LazyRegion>> = RwLock::new(RangedBTreeMap::new());
+
+/// # Safety
+///
+/// `allocator` must remain valid until the region is unregistered
+#[export_name = "__popcorn_memory_lazy_register"]
+unsafe fn register(pages: Range<Page>, allocator: &'static dyn BackingAllocator, protection: Protection, cache: CacheMode) -> Result<(), ()> {
+	debug!("Registering lazy region {pages:x?}");
+
+	// The new map is built while other CPUs can still fault in pages, then swapped in without allocating
+	let regions = LAZY_REGIONS.upgradable_read();
+	let mut new_regions = regions.clone();
+	new_regions.insert(pages, LazyRegion { allocator, protection, cache })?;
+
+	let old_regions = mem::replace(&mut *RwUpgradableReadGuard::upgrade(regions), new_regions);
+	drop(old_regions);
+	Ok(())
+}
+
+#[export_name = "__popcorn_memory_lazy_unregister"]
+fn unregister(start: Page) {
+	let region = LAZY_REGIONS.write().remove(start);
+	if region.is_none() { warn!("Attempted to unregister unknown lazy region at {start:x?}"); }
+}
+
+/// Changes the protection used for pages of the region starting at `start` that are faulted in from now on
+///
+/// Pages that are already mapped are not affected, the caller is responsible for updating their entries
+#[export_name = "__popcorn_memory_lazy_protect"]
+fn protect(start: Page, protection: Protection) {
+	let mut regions = LAZY_REGIONS.write();
+	if !regions.used_regions().any(|range| range.start == start) {
+		warn!("Attempted to protect unknown lazy region at {start:x?}");
+		return;
+	}
+	regions.get_entry_at_point_mut(start).expect("Region was just found").protection = protection;
+}
+
+/// Attempts to resolve a page fault at `addr` by backing it with zeroed physical memory
+///
+/// Returns `true` if the faulting instruction can be restarted. The fault may have interrupted code on this CPU that
+/// holds the locks needed to map the page, so this fails rather than waiting for them.
+pub fn handle_fault(addr: usize, is_user: bool) -> bool {
+	// Lazy regions are kernel memory, so user mode is never allowed to fault them in
+	if is_user { return false; }
+
+	let page = Page::new(VirtualAddress::new(addr).align_down());
+
+	let Some(regions) = LAZY_REGIONS.try_read() else { return false; };
+	let Some(region) = regions.get_entry_at_point(page) else { return false; };
+
+	let Some(mut ktable) = try_ktable() else {
+		warn!("Page table locked while faulting in lazy page {page:x?}");
+		return false;
+	};
+
+	// A fault on an already mapped page is a protection violation, not a missing page
+	if ktable.translate_page(page).is_some() { return false; }
+
+	let Ok(frame) = region.allocator.allocate_zeroed_nowait() else {
+		warn!("Unable to allocate memory while faulting in lazy page {page:x?}");
+		return false;
+	};
+
+	match ktable.try_map_page(page, frame, region.protection, region.cache) {
+		Ok(_) => true,
+		Err(e) => {
+			warn!("Unable to map lazy page {page:x?}: {e:?}");
+			unsafe { region.allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use core::num::NonZeroUsize;
+	use kernel_api::memory::mapping::{Config, Mapping};
+	use kernel_api::memory::r#virtual::Global;
+	use crate::memory::paging::ktable;
+	use super::*;
+
+	#[test]
+	fn lazy_mapping_starts_unmapped() {
+		let mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(2).unwrap())).unwrap();
+		assert_eq!(ktable().translate_page(mapping.virtual_start()), None);
+		assert_eq!(ktable().translate_page(mapping.virtual_start() + 1), None);
+	}
+
+	#[test]
+	fn fault_maps_single_page() {
+		let mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(2).unwrap())).unwrap();
+		let page = mapping.virtual_start() + 1;
+
+		assert!(handle_fault(page.start().addr + 0x123, false));
+		assert!(ktable().translate_page(page).is_some());
+		assert_eq!(ktable().translate_page(mapping.virtual_start()), None);
+	}
+
+	#[test]
+	fn faulted_page_is_zeroed() {
+		let mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(1).unwrap())).unwrap();
+		assert!(handle_fault(mapping.virtual_start().start().addr, false));
+
+		let bytes = unsafe { core::slice::from_raw_parts(mapping.as_ptr(), 4096) };
+		assert!(bytes.iter().all(|&b| b == 0));
+	}
+
+	#[test]
+	fn user_fault_is_unhandled() {
+		let mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(1).unwrap())).unwrap();
+		assert!(!handle_fault(mapping.virtual_start().start().addr, true));
+		assert_eq!(ktable().translate_page(mapping.virtual_start()), None);
+	}
+
+	#[test]
+	fn fault_on_mapped_page_is_unhandled() {
+		let mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(1).unwrap())).unwrap();
+		let addr = mapping.virtual_start().start().addr;
+
+		assert!(handle_fault(addr, false));
+		assert!(!handle_fault(addr, false));
+	}
+
+	#[test]
+	fn shrunk_region_stops_faulting() {
+		let mut mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(2).unwrap())).unwrap();
+		let tail = mapping.virtual_start() + 1;
+		assert!(handle_fault(tail.start().addr, false));
+
+		mapping.resize_in_place(NonZeroUsize::new(1).unwrap()).unwrap();
+		assert_eq!(ktable().translate_page(tail), None);
+		assert!(!handle_fault(tail.start().addr, false));
+	}
+
+	#[test]
+	fn moved_region_keeps_faulted_pages() {
+		let mut mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(1).unwrap())).unwrap();
+		let start = mapping.virtual_start();
+		assert!(handle_fault(start.start().addr, false));
+		let frame = ktable().translate_page(start).unwrap();
+
+		mapping.resize(NonZeroUsize::new(2).unwrap()).unwrap();
+		assert_eq!(ktable().translate_page(mapping.virtual_start()), Some(frame));
+		assert_eq!(ktable().translate_page(mapping.virtual_start() + 1), None);
+		assert!(handle_fault((mapping.virtual_start() + 1).start().addr, false));
+	}
+
+	#[test]
+	fn fault_outside_region_is_unhandled() {
+		assert!(!handle_fault(0xcafebabe000, false));
+	}
+}
@@ -0,0 +1,321 @@
+//! Per-CPU caches of free frames in front of a physical allocator
+//!
+//! Single frame allocations and frees are served from a small cache on the current CPU, which is refilled from and
+//! flushed to the backing allocator in batches so that its lock is taken much less often.
+
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel_api::memory::{AllocError, Frame, PhysicalAddress};
+use kernel_api::memory::allocator::{self, BackingAllocator, ScatterList, SpecificLocation, ZeroAllocError};
+use log::warn;
+use crate::threading::scheduler::IrqCell;
+
+/// The number of frames each CPU can cache for one allocator
+pub const MAGAZINE_CAPACITY: usize = 64;
+/// The number of frames moved to or from the backing allocator at once
+const BATCH: usize = MAGAZINE_CAPACITY / 2;
+/// The number of [`MagazineAllocator`]s that can exist at once, since each needs its own per-CPU magazine
+const MAX_ALLOCATORS: usize = 8;
+
+struct Magazine {
+	frames: [Frame; MAGAZINE_CAPACITY],
+	len: usize
+}
+
+impl Magazine {
+	const fn new() -> Self {
+		Self {
+			frames: [Frame::new(PhysicalAddress::new(0)); MAGAZINE_CAPACITY],
+			len: 0
+		}
+	}
+
+	fn pop(&mut self) -> Option<Frame> {
+		self.len = self.len.checked_sub(1)?;
+		Some(self.frames[self.len])
+	}
+
+	fn push(&mut self, frame: Frame) -> Result<(), Frame> {
+		if self.len == MAGAZINE_CAPACITY { return Err(frame); }
+		self.frames[self.len] = frame;
+		self.len += 1;
+		Ok(())
+	}
+}
+
+#[thread_local]
+static MAGAZINES: [IrqCell<Magazine>; MAX_ALLOCATORS] = [const { IrqCell::new(Magazine::new()) }; MAX_ALLOCATORS];
+/// A bit for each slot in [`MAGAZINES`], set while it is used by an allocator
+static USED_SLOTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Gives `frames` back to `backing`, a run at a time
+fn flush(backing: &impl BackingAllocator, frames: &mut [Frame]) {
+	frames.sort_unstable();
+
+	let mut frames = frames.iter().copied().peekable();
+	while let Some(start) = frames.next() {
+		let mut len = 1;
+		while frames.next_if(|&frame| frame == start + len).is_some() { len += 1; }
+		unsafe { backing.deallocate_contiguous(start, NonZeroUsize::new(len).unwrap()); }
+	}
+}
+
+/// A physical allocator that caches single frames on each CPU
+///
+/// The magazines are only locked with interrupts disabled, and an allocation that interrupts one that is already using
+/// the magazine goes straight to the backing allocator instead.
+pub struct MagazineAllocator<A: BackingAllocator> {
+	backing: A,
+	/// The index of this allocator's magazine in [`MAGAZINES`]
+	slot: usize,
+	/// The number of frames cached across every CPU
+	cached: AtomicUsize
+}
+
+impl<A: BackingAllocator> MagazineAllocator<A> {
+	/// # Panics
+	///
+	/// Panics if too many magazine allocators already exist
+	pub fn new(backing: A) -> Self {
+		let used = USED_SLOTS.fetch_update(Ordering::Acquire, Ordering::Relaxed, |used| {
+			let slot = used.trailing_ones() as usize;
+			(slot < MAX_ALLOCATORS).then(|| used | (1 << slot))
+		}).unwrap_or_else(|_| panic!("Only {MAX_ALLOCATORS} magazine allocators can exist at once"));
+		let slot = used.trailing_ones() as usize;
+
+		Self {
+			backing,
+			slot,
+			cached: AtomicUsize::new(0)
+		}
+	}
+
+	fn magazine(&self) -> &IrqCell<Magazine> {
+		&MAGAZINES[self.slot]
+	}
+
+	/// Calls `f`, and if the backing allocator has run out of memory, gives it the frames cached on this CPU and calls
+	/// `f` once more
+	fn retry_drained<T>(&self, f: impl Fn() -> Result<T, AllocError>) -> Result<T, AllocError> {
+		f().or_else(|AllocError| {
+			self.drain_local();
+			f()
+		})
+	}
+
+	/// Takes a batch of frames from the backing allocator, returning one and caching the rest
+	fn refill(&self) -> Result<Frame, AllocError> {
+		// No lock is held while allocating, as the backing allocator may itself allocate frames
+		let Ok(list) = self.backing.allocate(BATCH) else {
+			return self.retry_drained(|| self.backing.allocate_one());
+		};
+
+		let mut frames = list.frames();
+		let frame = frames.next().expect("Batch is not empty");
+
+		let mut leftover = [Frame::new(PhysicalAddress::new(0)); BATCH];
+		let mut leftover_len = 0;
+		match self.magazine().try_lock() {
+			Some(mut magazine) => for frame in frames {
+				match magazine.push(frame) {
+					Ok(()) => { self.cached.fetch_add(1, Ordering::Relaxed); },
+					Err(frame) => { leftover[leftover_len] = frame; leftover_len += 1; }
+				}
+			},
+			None => for frame in frames {
+				leftover[leftover_len] = frame;
+				leftover_len += 1;
+			}
+		}
+
+		flush(&self.backing, &mut leftover[..leftover_len]);
+		Ok(frame)
+	}
+
+	/// Gives every frame cached on the current CPU back to the backing allocator
+	pub fn drain_local(&self) {
+		let mut frames = [Frame::new(PhysicalAddress::new(0)); MAGAZINE_CAPACITY];
+		let len = {
+			let Some(mut magazine) = self.magazine().try_lock() else { return; };
+			let len = magazine.len;
+			frames[..len].copy_from_slice(&magazine.frames[..len]);
+			magazine.len = 0;
+			len
+		};
+
+		self.cached.fetch_sub(len, Ordering::Relaxed);
+		flush(&self.backing, &mut frames[..len]);
+	}
+}
+
+impl<A: BackingAllocator> Drop for MagazineAllocator<A> {
+	fn drop(&mut self) {
+		self.drain_local();
+
+		// Frames still cached on other CPUs would be handed out by the next allocator to use the slot
+		if self.cached.load(Ordering::Relaxed) != 0 {
+			warn!("Leaking magazine slot {} with frames cached on other CPUs", self.slot);
+			return;
+		}
+		USED_SLOTS.fetch_and(!(1 << self.slot), Ordering::Release);
+	}
+}
+
+unsafe impl<A: BackingAllocator> BackingAllocator for MagazineAllocator<A> {
+	fn allocate_contiguous(&self, frame_count: usize) -> Result<Frame, AllocError> {
+		if frame_count == 1 { return self.allocate_one(); }
+		self.retry_drained(|| self.backing.allocate_contiguous(frame_count))
+	}
+
+	fn allocate_one(&self) -> Result<Frame, AllocError> {
+		if let Some(mut magazine) = self.magazine().try_lock() && let Some(frame) = magazine.pop() {
+			self.cached.fetch_sub(1, Ordering::Relaxed);
+			return Ok(frame);
+		}
+
+		self.refill()
+	}
+
+	fn allocate(&self, frame_count: usize) -> Result<ScatterList, AllocError> {
+		if frame_count == 1 {
+			let frame = self.allocate_one()?;
+			return Ok(ScatterList::from(frame..(frame + 1)));
+		}
+		self.retry_drained(|| self.backing.allocate(frame_count))
+	}
+
+	fn try_allocate_zeroed(&self, frame_count: usize) -> Result<Frame, ZeroAllocError> {
+		// Cached frames have been used, so zeroed allocations come from the backing allocator's prezeroed frames
+		self.backing.try_allocate_zeroed(frame_count)
+	}
+
+	fn allocate_zeroed_nowait(&self) -> Result<Frame, AllocError> {
+		self.backing.allocate_zeroed_nowait()
+	}
+
+	unsafe fn deallocate_contiguous(&self, base: Frame, frame_count: NonZeroUsize) {
+		if frame_count.get() != 1 {
+			unsafe { self.backing.deallocate_contiguous(base, frame_count); }
+			return;
+		}
+
+		let mut flushed = [Frame::new(PhysicalAddress::new(0)); BATCH];
+		let mut flushed_len = 0;
+		{
+			let Some(mut magazine) = self.magazine().try_lock() else {
+				unsafe { self.backing.deallocate_contiguous(base, frame_count); }
+				return;
+			};
+
+			if magazine.len == MAGAZINE_CAPACITY {
+				// Flush the older half, so that the most recently freed frames stay cached while they're still hot
+				flushed.copy_from_slice(&magazine.frames[..BATCH]);
+				flushed_len = BATCH;
+				magazine.frames.copy_within(BATCH.., 0);
+				magazine.len -= BATCH;
+				self.cached.fetch_sub(BATCH, Ordering::Relaxed);
+			}
+
+			magazine.push(base).expect("Magazine has room after flushing");
+			self.cached.fetch_add(1, Ordering::Relaxed);
+		}
+
+		flush(&self.backing, &mut flushed[..flushed_len]);
+	}
+
+	fn allocate_at(&self, frame_count: usize, location: SpecificLocation) -> Result<Frame, AllocError> {
+		self.retry_drained(|| self.backing.allocate_at(frame_count, location))
+	}
+
+	fn stats(&self) -> Option<allocator::Stats> {
+		// Cached frames are allocated from the backing allocator, but are still free to be handed out
+		let stats = self.backing.stats()?;
+		let cached = self.cached.load(Ordering::Relaxed);
+		Some(allocator::Stats { free_frames: stats.free_frames + cached, ..stats })
+	}
+
+	fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, allocator::Stats)) {
+		self.backing.region_stats(f)
+	}
+
+	unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+		unsafe { self.backing.add_region(region) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use kernel_api::sync::Mutex;
+	use crate::memory::physical::highmem;
+	use super::*;
+
+	const FRAME: Frame = Frame::new(PhysicalAddress::new(0x1000));
+
+	/// An allocator with a single frame, which is never accessed
+	struct Single(Mutex<bool>);
+
+	unsafe impl BackingAllocator for Single {
+		fn allocate_contiguous(&self, frame_count: usize) -> Result<Frame, AllocError> {
+			self.allocate_at(frame_count, SpecificLocation::At(FRAME))
+		}
+
+		unsafe fn deallocate_contiguous(&self, base: Frame, frame_count: NonZeroUsize) {
+			assert_eq!((base, frame_count.get()), (FRAME, 1));
+			*self.0.lock() = true;
+		}
+
+		fn allocate_at(&self, frame_count: usize, location: SpecificLocation) -> Result<Frame, AllocError> {
+			let mut free = self.0.lock();
+			let SpecificLocation::At(frame) = location else { return Err(AllocError); };
+			if frame_count != 1 || frame != FRAME || !*free { return Err(AllocError); }
+			*free = false;
+			Ok(FRAME)
+		}
+	}
+
+	#[test]
+	fn freed_frames_are_reused() {
+		let magazines = MagazineAllocator::new(highmem());
+
+		let frame = magazines.allocate_one().unwrap();
+		unsafe { magazines.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+		assert_eq!(magazines.allocate_one(), Ok(frame));
+
+		unsafe { magazines.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+		magazines.drain_local();
+	}
+
+	#[test]
+	fn refill_caches_a_batch() {
+		let magazines = MagazineAllocator::new(highmem());
+		let before = highmem().stats().unwrap().free_frames;
+
+		let frame = magazines.allocate_one().unwrap();
+		assert_eq!(magazines.cached.load(Ordering::Relaxed), BATCH - 1);
+		assert_eq!(magazines.stats().unwrap().free_frames, before - 1);
+
+		unsafe { magazines.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+		magazines.drain_local();
+		assert_eq!(magazines.cached.load(Ordering::Relaxed), 0);
+	}
+
+	#[test]
+	fn cached_frames_are_drained_when_backing_is_exhausted() {
+		let magazines = MagazineAllocator::new(Single(Mutex::new(true)));
+
+		let frame = magazines.allocate_one().unwrap();
+		unsafe { magazines.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+		assert_eq!(magazines.cached.load(Ordering::Relaxed), 1);
+
+		assert_eq!(magazines.allocate_at(1, SpecificLocation::At(FRAME)), Ok(FRAME));
+		assert_eq!(magazines.cached.load(Ordering::Relaxed), 0);
+	}
+
+	#[test]
+	fn dropped_allocators_free_their_slot() {
+		for _ in 0..(MAX_ALLOCATORS * 2) {
+			drop(MagazineAllocator::new(highmem()));
+		}
+	}
+}
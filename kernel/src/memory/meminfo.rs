@@ -0,0 +1,144 @@
+//! A kernel wide snapshot of memory usage, for debugging out of memory errors
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use kernel_api::memory::{allocator, cache, heap, r#virtual};
+use kernel_api::memory::allocator::BackingAllocator;
+use kernel_api::memory::physical::{dmamem, highmem};
+use kernel_api::memory::r#virtual::{Global, VirtualAllocator};
+use log::info;
+use crate::sprintln;
+
+/// The memory usage of every kernel allocator at one point in time
+///
+/// Each allocator is locked in turn rather than all at once, so the numbers may not be exactly consistent with each
+/// other if memory is being allocated concurrently.
+#[derive(Debug, Clone)]
+pub struct MemInfo {
+	pub highmem: Option<allocator::Stats>,
+	pub dmamem: Option<allocator::Stats>,
+	pub kernel_virtual: Option<r#virtual::Stats>,
+	pub heap: Option<heap::Stats>,
+	/// The cache that page tables are allocated from, which is also included in `caches`
+	pub page_tables: cache::Stats,
+	pub caches: Vec<(&'static str, cache::Stats)>
+}
+
+/// Takes a snapshot of the kernel's memory usage
+///
+/// This allocates on the heap to list the object caches, so may fail if the kernel is completely out of memory.
+pub fn meminfo() -> MemInfo {
+	MemInfo {
+		highmem: highmem().stats(),
+		dmamem: dmamem().stats(),
+		kernel_virtual: Global.stats(),
+		heap: kernel_default_heap::__popcorn_kernel_heap_stats(),
+		page_tables: super::cache::PAGE_TABLES.stats(),
+		caches: super::cache::stats()
+	}
+}
+
+/// Logs a snapshot of the kernel's memory usage
+pub fn log() {
+	info!("{}", meminfo());
+}
+
+/// Prints every live heap allocation and where it was made from, if the heap was built with allocation tracking
+///
+/// This is printed straight to the serial port, as the heap is locked while its allocations are listed.
+pub fn report_leaks() {
+	let mut count = 0;
+	let tracked = kernel_default_heap::__popcorn_kernel_heap_allocations(&mut |allocation| {
+		count += 1;
+		sprintln!("Live allocation {:p} of {:?}, allocated at:", allocation.ptr, allocation.layout);
+		for &ip in allocation.call_sites {
+			sprintln!("    {ip:#x} - {}", crate::panicking::get_symbol_name(ip));
+		}
+	});
+
+	if tracked {
+		info!("{count} heap allocations still live");
+	} else {
+		info!("Heap doesn't track allocations, build with the `debug_heap` feature to report leaks");
+	}
+}
+
+impl Display for MemInfo {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		fn physical(f: &mut Formatter<'_>, name: &str, stats: Option<allocator::Stats>) -> core::fmt::Result {
+			match stats {
+				Some(stats) => writeln!(f, "{name}: {} / {} frames used, {} free, largest free run {}",
+				                        stats.used_frames(), stats.total_frames, stats.free_frames, stats.largest_free_run),
+				None => writeln!(f, "{name}: unknown")
+			}
+		}
+
+		writeln!(f, "Memory info:")?;
+		physical(f, "highmem", self.highmem)?;
+		physical(f, "dmamem", self.dmamem)?;
+
+		match self.kernel_virtual {
+			Some(stats) => writeln!(f, "kernel virtual: {} / {} pages used, largest free run {}",
+			                        stats.used_pages(), stats.total_pages, stats.largest_free_run)?,
+			None => writeln!(f, "kernel virtual: unknown")?
+		}
+
+		match self.heap {
+			Some(stats) => writeln!(f, "heap: {} bytes allocated in {} pages", stats.allocated_bytes, stats.mapped_pages)?,
+			None => writeln!(f, "heap: unknown")?
+		}
+
+		writeln!(f, "page tables: {} in use, {} free", self.page_tables.in_use, self.page_tables.free)?;
+		for (name, stats) in &self.caches {
+			writeln!(f, "cache {name}: {} x {} bytes in use, {} free, {} slabs", stats.in_use, stats.object_size, stats.free, stats.slabs)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use core::num::NonZeroUsize;
+	use kernel_api::memory::allocator::BackingAllocator;
+	use kernel_api::memory::physical::highmem;
+	use super::meminfo;
+
+	#[test]
+	fn allocations_show_in_highmem() {
+		let before = meminfo().highmem.expect("Highmem keeps track of its memory");
+		let frame = highmem().allocate_contiguous(4).unwrap();
+		let after = meminfo().highmem.unwrap();
+
+		assert_eq!(after.total_frames, before.total_frames);
+		assert!(after.free_frames < before.free_frames);
+		assert!(after.free_frames <= after.total_frames);
+		unsafe { highmem().deallocate_contiguous(frame, NonZeroUsize::new(4).unwrap()); }
+	}
+
+	#[test]
+	#[cfg(feature = "debug_heap")]
+	fn allocations_are_tracked() {
+		use alloc::boxed::Box;
+		use core::alloc::Layout;
+
+		let allocation = Box::new([0u64; 5]);
+		let ptr = core::ptr::addr_of!(*allocation).cast::<u8>();
+
+		let mut found = None;
+		assert!(kernel_default_heap::__popcorn_kernel_heap_allocations(&mut |tracked| {
+			if tracked.ptr.as_ptr().cast_const() == ptr { found = Some((tracked.layout, tracked.call_sites.len())); }
+		}));
+
+		let (layout, call_sites) = found.expect("Live allocation should be tracked");
+		assert_eq!(layout, Layout::new::<[u64; 5]>());
+		assert!(call_sites > 0);
+	}
+
+	#[test]
+	fn page_tables_are_counted() {
+		let info = meminfo();
+		assert!(info.page_tables.in_use > 0);
+		assert!(info.heap.is_some_and(|heap| heap.mapped_pages > 0));
+	}
+}
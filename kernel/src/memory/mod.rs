@@ -2,15 +2,27 @@ pub mod r#virtual;
 pub mod physical;
 pub mod paging;
 pub mod watermark_allocator;
+pub mod fallback_allocator;
+pub mod background_zeroer;
+pub mod magazine;
+pub mod lazy;
+pub mod cow;
+pub mod cache;
+pub mod meminfo;
+pub mod address_space;
 
 #[cfg(test)]
 mod tests {
-	use core::num::NonZeroUsize;
+	use core::num::{NonZeroU32, NonZeroUsize};
 	use core::sync::atomic::{AtomicUsize, Ordering};
 	use kernel_api::memory::allocator::{BackingAllocator, SpecificLocation};
-	use kernel_api::memory::{AllocError, Frame, PhysicalAddress};
+	use kernel_api::memory::{AllocError, Frame, Page, PhysicalAddress};
+	use kernel_api::memory::dma::{Constraints, Direction, DmaBuffer, DmaSlice, StreamingMapping};
+	use kernel_api::memory::mapping::{Config, Laziness, Mapping, PageSize, Stack};
+	use kernel_api::memory::r#virtual::{Global, VirtualAllocator};
+	use crate::memory::paging::ktable;
 
-	struct MockAllocator {
+	pub(super) struct MockAllocator {
 		expected_frames: usize,
 		actual_frames: AtomicUsize,
 		base: Frame,
@@ -18,21 +30,21 @@ mod tests {
 	}
 
 	impl MockAllocator {
-		fn new_fail() -> Self {
+		pub(super) fn new_fail() -> Self {
 			Self {
 				always_fail: true,
 				.. Default::default()
 			}
 		}
 
-		fn new_with_frames(expected: usize) -> Self {
+		pub(super) fn new_with_frames(expected: usize) -> Self {
 			Self {
 				expected_frames: expected,
 				.. Default::default()
 			}
 		}
 
-		fn verify(&self) {
+		pub(super) fn verify(&self) {
 			let actual = self.actual_frames.load(Ordering::Acquire);
 			if actual != self.expected_frames {
 				panic!("{actual} frames allocated when {} were expected", self.expected_frames);
@@ -115,4 +127,172 @@ mod tests {
 		assert!(mock.allocate_one().is_ok());
 		mock.verify();
 	}
+
+	fn prefaulted(len: usize) -> Mapping<'static> {
+		Mapping::new(Config::<Global>::new(NonZeroUsize::new(len).unwrap()).laziness(Laziness::Prefault)).unwrap()
+	}
+
+	#[test]
+	fn shrink_unmaps_tail() {
+		let mut mapping = prefaulted(3);
+		let start = mapping.virtual_start();
+		mapping.resize_in_place(NonZeroUsize::new(1).unwrap()).unwrap();
+
+		assert_eq!(mapping.virtual_end(), start + 1);
+		assert!(ktable().translate_page(start).is_some());
+		assert_eq!(ktable().translate_page(start + 1), None);
+		assert_eq!(ktable().translate_page(start + 2), None);
+	}
+
+	#[test]
+	fn grow_keeps_contents() {
+		let mut mapping = prefaulted(1);
+		unsafe { mapping.virtual_start().as_ptr().write_bytes(0xab, 4096); }
+
+		mapping.resize(NonZeroUsize::new(4).unwrap()).unwrap();
+		assert_eq!(mapping.physical_len().get(), 4);
+
+		let memory = unsafe { core::slice::from_raw_parts(mapping.virtual_start().as_ptr(), 4 * 4096) };
+		assert!(memory[..4096].iter().all(|&byte| byte == 0xab));
+		for i in 0..4 {
+			assert_eq!(
+				ktable().translate_page(mapping.virtual_start() + i),
+				Some(mapping.physical_start() + i)
+			);
+		}
+	}
+
+	#[test]
+	fn scattered_mapping_is_mapped() {
+		let mut mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(3).unwrap()).laziness(Laziness::Scattered)).unwrap();
+		for i in 0..3 {
+			assert!(ktable().translate_page(mapping.virtual_start() + i).is_some());
+		}
+
+		mapping.resize(NonZeroUsize::new(5).unwrap()).unwrap();
+		for i in 0..5 {
+			assert!(ktable().translate_page(mapping.virtual_start() + i).is_some());
+		}
+
+		mapping.resize_in_place(NonZeroUsize::new(2).unwrap()).unwrap();
+		assert_eq!(ktable().translate_page(mapping.virtual_start() + 2), None);
+	}
+
+	#[test]
+	fn default_allocate_is_all_or_nothing() {
+		let mock = MockAllocator::new_with_frames(3);
+		let frames = mock.allocate(3).unwrap();
+		assert_eq!(frames.frame_count(), 3);
+		mock.verify();
+
+		assert!(MockAllocator::new_fail().allocate(3).is_err());
+	}
+
+	#[test]
+	fn huge_page_mapping_is_mapped() {
+		let mapping = Mapping::new(Config::<Global>::new(NonZeroUsize::new(513).unwrap()).page_size(PageSize::Size2MiB)).unwrap();
+		let start = mapping.virtual_start();
+		for i in 0..513 {
+			assert_eq!(ktable().translate_page(start + i), Some(mapping.physical_start() + i));
+		}
+
+		drop(mapping);
+		assert_eq!(ktable().translate_page(start), None);
+		assert_eq!(ktable().translate_page(start + 512), None);
+	}
+
+	#[test]
+	fn stack_guard_page_is_unmapped() {
+		let stack = Stack::new(Config::<Global>::new(NonZeroUsize::new(4).unwrap()).laziness(Laziness::Prefault)).unwrap();
+		let guard_page = stack.guard_page();
+
+		assert_eq!(guard_page, stack.virtual_start());
+		assert_eq!(ktable().translate_page(guard_page), None);
+		assert_eq!(ktable().translate_page(guard_page + 1), Some(stack.physical_start()));
+	}
+
+	#[test]
+	fn resize_in_place_fails_when_blocked() {
+		let mut mapping = prefaulted(1);
+		let blocker = Global.allocate_contiguous_at(mapping.virtual_end(), 1);
+
+		if blocker.is_ok() {
+			assert!(mapping.resize_in_place(NonZeroUsize::new(2).unwrap()).is_err());
+			assert_eq!(mapping.physical_len().get(), 1);
+
+			let start = mapping.virtual_start();
+			mapping.resize(NonZeroUsize::new(2).unwrap()).unwrap();
+			assert_ne!(mapping.virtual_start(), start);
+			assert_eq!(ktable().translate_page(start), None);
+		}
+	}
+
+	#[test]
+	fn dma_limit_is_exclusive() {
+		let constraints = Constraints::below(PhysicalAddress::new(0x10000));
+		assert!(constraints.can_reach(PhysicalAddress::new(0xf000), 0x1000));
+		assert!(!constraints.can_reach(PhysicalAddress::new(0xf000), 0x1001));
+		assert!(!constraints.can_reach(PhysicalAddress::new(0x10000), 1));
+	}
+
+	#[test]
+	fn dma_alignment_of_one_is_unconstrained() {
+		assert!(Constraints::ANY.can_reach(PhysicalAddress::new(0x1234), 16));
+
+		let constraints = Constraints::ANY.alignment(NonZeroU32::new(2).unwrap());
+		assert!(constraints.can_reach(PhysicalAddress::new(0x2000), 16));
+		assert!(!constraints.can_reach(PhysicalAddress::new(0x1000), 16));
+		assert!(!constraints.can_reach(PhysicalAddress::new(0x2004), 16));
+	}
+
+	#[test]
+	fn dma_segment_boundary_cannot_be_crossed() {
+		let constraints = Constraints::ANY.segment_boundary(NonZeroUsize::new(0x10000).unwrap());
+		assert!(constraints.can_reach(PhysicalAddress::new(0xf000), 0x1000));
+		assert!(!constraints.can_reach(PhysicalAddress::new(0xf000), 0x1001));
+		assert!(constraints.can_reach(PhysicalAddress::new(0x10000), 0));
+	}
+
+	#[test]
+	fn dma_end_overflow_is_unreachable() {
+		assert!(!Constraints::ANY.can_reach(PhysicalAddress::new(usize::MAX - 1), 4));
+	}
+
+	#[test]
+	fn dma_buffer_reports_its_addresses() {
+		let buffer = DmaBuffer::new(0xabcd_u64, Constraints::ANY).unwrap();
+		assert_eq!(buffer.virtual_address().addr, &*buffer as *const u64 as usize);
+
+		let page = Page::new(buffer.virtual_address().align_down());
+		assert_eq!(ktable().translate_page(page), Some(Frame::new(buffer.physical_address().align_down())));
+		assert_eq!(*buffer, 0xabcd);
+
+		let slice = DmaSlice::new_slice(7_u32, 3, Constraints::ANY).unwrap();
+		assert_eq!(&*slice, &[7, 7, 7]);
+		assert_eq!(slice.virtual_address().addr, slice.as_ptr() as usize);
+	}
+
+	#[test]
+	fn reachable_buffer_is_not_bounced() {
+		let mut buffer = DmaSlice::new_slice(0_u8, 64, Constraints::ANY).unwrap();
+		let physical_start = buffer.physical_address();
+
+		let streaming = StreamingMapping::new(&mut buffer[1..], Direction::ToDevice, Constraints::ANY).unwrap();
+		assert!(!streaming.is_bounced());
+		assert_eq!(streaming.physical_address().addr, physical_start.addr + 1);
+	}
+
+	#[test]
+	fn unreachable_buffer_is_bounced() {
+		let mut buffer = DmaSlice::new_slice(0_u8, 2 * 4096, Constraints::ANY).unwrap();
+		buffer[4000..4200].fill(0x5a);
+		let constraints = Constraints::ANY.segment_boundary(NonZeroUsize::new(4096).unwrap());
+
+		let streaming = StreamingMapping::new(&mut buffer[4000..4200], Direction::Bidirectional, constraints).unwrap();
+		assert!(streaming.is_bounced());
+		assert!(constraints.can_reach(streaming.physical_address(), streaming.len()));
+		drop(streaming);
+
+		assert!(buffer[4000..4200].iter().all(|&byte| byte == 0x5a));
+	}
 }
@@ -22,15 +22,20 @@ pub fn ktable() -> impl DerefMut<Target = KTableTy> {
 	KERNEL_PAGE_TABLE.write()
 }
 
+/// Locks the kernel page table, unless it is already locked
+pub fn try_ktable() -> Option<impl DerefMut<Target = KTableTy>> {
+	KERNEL_PAGE_TABLE.try_write()
+}
+
 #[cfg(test)]
 mod tests {
+	use kernel_api::memory::mapping::{CacheMode, PageSize, Protection};
 	use crate::hal::paging2::{TTable, TTableTy};
-	use crate::memory::physical::highmem;
 	use super::*;
 
 	#[test]
 	fn unmapped_page_doesnt_translate() {
-		let table = TTableTy::new(&*KERNEL_PAGE_TABLE.read(), highmem()).unwrap();
+		let table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
 		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0xcafebabe000))), None);
 		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0xdeadbeef000))), None);
 		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0x347e40000))), None);
@@ -38,7 +43,7 @@ mod tests {
 
 	#[test]
 	fn unmapped_address_doesnt_translate() {
-		let table = TTableTy::new(&*KERNEL_PAGE_TABLE.read(), highmem()).unwrap();
+		let table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
 		assert_eq!(table.translate_address(VirtualAddress::new(0xcafebabe)), None);
 		assert_eq!(table.translate_address(VirtualAddress::new(0xdeadbeef)), None);
 		assert_eq!(table.translate_address(VirtualAddress::new(0x347e40)), None);
@@ -46,10 +51,12 @@ mod tests {
 
 	#[test]
 	fn translations_after_mapping() {
-		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read(), highmem()).unwrap();
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
 		table.map_page(
 			Page::new(VirtualAddress::new(0xcafebabe000)),
 			Frame::new(PhysicalAddress::new(0x347e40000)),
+			Protection::RW,
+			CacheMode::WriteBack,
 		).expect("Page not yet mapped");
 		assert_eq!(
 			table.translate_page(Page::new(VirtualAddress::new(0xcafebabe000))),
@@ -59,27 +66,153 @@ mod tests {
 
 	#[test]
 	fn cannot_overmap() {
-		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read(), highmem()).unwrap();
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
 		table.map_page(
 			Page::new(VirtualAddress::new(0xcafebabe000)),
 			Frame::new(PhysicalAddress::new(0x347e40000)),
+			Protection::RW,
+			CacheMode::WriteBack,
 		).expect("Page not yet mapped");
 		table.map_page(
 			Page::new(VirtualAddress::new(0xcafebabe000)),
 			Frame::new(PhysicalAddress::new(0xcafebabe000)),
+			Protection::RW,
+			CacheMode::WriteBack,
 		).expect_err("Page already mapped");
 	}
 
 	#[test]
 	fn address_offset() {
-		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read(), highmem()).unwrap();
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
 		table.map_page(
 			Page::new(VirtualAddress::new(0xcafebabe000)),
 			Frame::new(PhysicalAddress::new(0x347e40000)),
+			Protection::RW,
+			CacheMode::WriteBack,
 		).expect("Page not yet mapped");
 		assert_eq!(
 			table.translate_address(VirtualAddress::new(0xcafebabe123)),
 			Some(PhysicalAddress::new(0x347e40123))
 		)
 	}
+	#[test]
+	fn protect_keeps_translation() {
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
+		let page = Page::new(VirtualAddress::new(0xcafebabe000));
+		table.map_page(page, Frame::new(PhysicalAddress::new(0x347e40000)), Protection::RW, CacheMode::WriteBack)
+				.expect("Page not yet mapped");
+		table.protect_page(page, Protection::R).expect("Page is mapped");
+		assert_eq!(table.translate_page(page), Some(Frame::new(PhysicalAddress::new(0x347e40000))));
+	}
+
+	#[test]
+	fn cannot_protect_unmapped_page() {
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
+		table.protect_page(Page::new(VirtualAddress::new(0xcafebabe000)), Protection::R).expect_err("Page not mapped");
+	}
+
+	#[test]
+	fn huge_page_translates_inner_pages() {
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
+		table.map_huge_page(
+			Page::new(VirtualAddress::new(0xcafe_0000_0000)),
+			Frame::new(PhysicalAddress::new(0x4000_0000)),
+			PageSize::Size2MiB,
+			Protection::RW,
+			CacheMode::WriteBack,
+		).expect("Page not yet mapped");
+
+		assert_eq!(
+			table.translate_page(Page::new(VirtualAddress::new(0xcafe_0012_3000))),
+			Some(Frame::new(PhysicalAddress::new(0x4012_3000)))
+		);
+		assert_eq!(
+			table.translate_address(VirtualAddress::new(0xcafe_001f_f123)),
+			Some(PhysicalAddress::new(0x401f_f123))
+		);
+	}
+
+	#[test]
+	fn cannot_map_inside_huge_page() {
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
+		table.map_huge_page(
+			Page::new(VirtualAddress::new(0xcafe_0000_0000)),
+			Frame::new(PhysicalAddress::new(0x4000_0000)),
+			PageSize::Size2MiB,
+			Protection::RW,
+			CacheMode::WriteBack,
+		).expect("Page not yet mapped");
+		table.map_page(
+			Page::new(VirtualAddress::new(0xcafe_0000_1000)),
+			Frame::new(PhysicalAddress::new(0x347e40000)),
+			Protection::RW,
+			CacheMode::WriteBack,
+		).expect_err("Page already mapped by the huge page");
+	}
+
+	#[test]
+	fn partial_unmap_splits_huge_page() {
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
+		table.map_huge_page(
+			Page::new(VirtualAddress::new(0xcafe_0000_0000)),
+			Frame::new(PhysicalAddress::new(0x4000_0000)),
+			PageSize::Size2MiB,
+			Protection::RW,
+			CacheMode::WriteBack,
+		).expect("Page not yet mapped");
+
+		table.unmap_page(Page::new(VirtualAddress::new(0xcafe_0000_5000))).expect("Page is mapped");
+		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0xcafe_0000_5000))), None);
+		assert_eq!(
+			table.translate_page(Page::new(VirtualAddress::new(0xcafe_0000_4000))),
+			Some(Frame::new(PhysicalAddress::new(0x4000_4000)))
+		);
+		assert_eq!(
+			table.translate_page(Page::new(VirtualAddress::new(0xcafe_001f_f000))),
+			Some(Frame::new(PhysicalAddress::new(0x401f_f000)))
+		);
+	}
+
+	#[test]
+	fn huge_unmap_covers_small_pages() {
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
+		for i in 0..512 {
+			table.map_page(
+				Page::new(VirtualAddress::new(0xcafe_0000_0000 + i * 4096)),
+				Frame::new(PhysicalAddress::new(0x4000_0000 + i * 4096)),
+				Protection::RW,
+				CacheMode::WriteBack,
+			).expect("Page not yet mapped");
+		}
+
+		table.unmap_huge_page(Page::new(VirtualAddress::new(0xcafe_0000_0000)), PageSize::Size2MiB).expect("Every page is mapped");
+		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0xcafe_0000_0000))), None);
+		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0xcafe_001f_f000))), None);
+	}
+
+	#[test]
+	fn clear_frees_lower_half_tables() {
+		let mut table = TTableTy::new(&*KERNEL_PAGE_TABLE.read()).unwrap();
+		for addr in [0xcafebabe000, 0xdeadbeef000] {
+			table.map_page(
+				Page::new(VirtualAddress::new(addr)),
+				Frame::new(PhysicalAddress::new(0x347e40000)),
+				Protection::RW,
+				CacheMode::WriteBack,
+			).expect("Page not yet mapped");
+		}
+
+		let mut freed = alloc::vec::Vec::new();
+		unsafe { table.clear(&mut |frame| freed.push(frame)); }
+
+		// A PDPT, PD and PT for each page, as they are in different PML4 entries
+		assert_eq!(freed.len(), 6);
+		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0xcafebabe000))), None);
+		assert_eq!(table.translate_page(Page::new(VirtualAddress::new(0xdeadbeef000))), None);
+
+		for frame in freed {
+			let table = NonNull::new(frame.to_page().as_ptr()).unwrap();
+			unsafe { crate::memory::cache::PAGE_TABLES.deallocate(table); }
+		}
+	}
 }
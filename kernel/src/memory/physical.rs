@@ -1,9 +1,15 @@
-use core::mem;
+use alloc::boxed::Box;
+use core::{mem, slice};
 use core::mem::ManuallyDrop;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+use log::{debug, info, warn};
+use kernel_api::memory::Frame;
 use kernel_api::memory::allocator::BackingAllocator;
-use kernel_api::sync::{RwLock, RwUpgradableReadGuard, RwWriteGuard};
+use kernel_api::sync::{OnceLock, RwLock, RwUpgradableReadGuard, RwWriteGuard};
 use kernel_api::memory::physical::GlobalAllocator;
+use crate::memory::background_zeroer::BackgroundZeroer;
+use crate::memory::magazine::MagazineAllocator;
 
 #[export_name = "__popcorn_memory_physical_highmem"]
 static GLOBAL_HIGHMEM: GlobalAllocator = GlobalAllocator { rwlock: RwLock::new(None) };
@@ -12,6 +18,14 @@ static GLOBAL_DMA: GlobalAllocator = GlobalAllocator { rwlock: RwLock::new(None)
 
 pub use kernel_api::memory::physical::{highmem, dmamem};
 
+/// The allocator used to manage physical memory once the bootstrap allocator is no longer needed
+///
+/// This is selected at build time using the `buddy_allocator` feature
+#[cfg(not(feature = "buddy_allocator"))]
+pub type PhysicalAllocator = bitmap_allocator::Wrapped;
+#[cfg(feature = "buddy_allocator")]
+pub type PhysicalAllocator = buddy_allocator::Wrapped;
+
 pub fn init_highmem<'a>(allocator: &'static dyn BackingAllocator) {
 	GLOBAL_HIGHMEM.rwlock.write().replace(allocator);
 }
@@ -20,6 +34,48 @@ pub fn init_dmamem<'a>(allocator: &'static dyn BackingAllocator) {
 	GLOBAL_DMA.rwlock.write().replace(allocator);
 }
 
+/// Gives memory that was only needed during early boot to the physical allocators
+///
+/// # Safety
+///
+/// Nothing can still be using any of `regions`
+pub unsafe fn reclaim(regions: &[Range<Frame>]) {
+	let mut reclaimed = 0;
+	for region in regions {
+		match unsafe { highmem().add_region(region.clone()) } {
+			Ok(()) => reclaimed += region.end - region.start,
+			Err(()) => warn!("Unable to reclaim {region:x?}")
+		}
+	}
+
+	info!("Reclaimed {} KiB of boot memory", reclaimed * 4);
+}
+
+/// Puts per-CPU [magazines](MagazineAllocator) in front of `highmem` and `dmamem`
+///
+/// Must be called once thread locals have been set up, since the magazines are stored in them.
+pub fn init_magazines() {
+	for global in [&GLOBAL_HIGHMEM, &GLOBAL_DMA] {
+		let backing = (*global.rwlock.read()).expect("Physical allocators are initialised before magazines");
+
+		// The heap may need frames for the magazines, so they're created before the allocator is locked for writing
+		let magazines: &'static _ = Box::leak(Box::new(MagazineAllocator::new(backing)));
+		global.rwlock.write().replace(magazines);
+	}
+}
+
+static HIGHMEM_ZEROER: OnceLock<&'static BackgroundZeroer<&'static dyn BackingAllocator>> = OnceLock::new();
+
+/// Sets the zeroer wrapping `highmem`, so that it can be refilled by the frame zeroer thread
+pub fn init_highmem_zeroer(zeroer: &'static BackgroundZeroer<&'static dyn BackingAllocator>) {
+	assert!(HIGHMEM_ZEROER.get().is_none(), "Highmem zeroer already initialised");
+	HIGHMEM_ZEROER.get_or_init(|| zeroer);
+}
+
+pub fn highmem_zeroer() -> Option<&'static BackgroundZeroer<&'static dyn BackingAllocator>> {
+	HIGHMEM_ZEROER.get().copied()
+}
+
 pub fn with_highmem_as<'a, R>(allocator: &'a dyn BackingAllocator, f: impl FnOnce() -> R) -> R {
 	// FIXME: huge issue in that all allocations get lost therefore only safe to use for bootstrap
 	// FIXME(soundness): is this sound?
@@ -53,19 +109,128 @@ pub fn with_highmem_as<'a, R>(allocator: &'a dyn BackingAllocator, f: impl FnOnc
 	f()
 }
 
-static REFCOUNTS: [RefCountEntry; 0] = [];
+/// Reference counts for every frame that can be shared between several owners
+///
+/// A count of zero means the frame has a single owner, so that freshly allocated frames don't need to be registered
+struct RefCounts {
+	base: Frame,
+	entries: &'static [RefCountEntry]
+}
 
+impl RefCounts {
+	fn entry(&self, frame: Frame) -> Option<&RefCountEntry> {
+		if frame < self.base { return None; }
+		self.entries.get(frame - self.base)
+	}
+}
+
+static REFCOUNTS: OnceLock<RefCounts> = OnceLock::new();
+
+#[repr(transparent)]
 struct RefCountEntry {
-	strong_count: AtomicUsize,
-	next_segment: Option<AtomicUsize>
+	strong_count: AtomicU32
 }
 
 impl RefCountEntry {
 	fn increment(&self) {
-		self.strong_count.fetch_add(1, Ordering::Relaxed);
+		let _ = self.strong_count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| match count {
+			0 => Some(2),
+			count => Some(count + 1)
+		});
 	}
 
 	fn decrement(&self) -> bool {
-		self.strong_count.fetch_sub(1, Ordering::Relaxed) == 1
+		let old = self.strong_count.fetch_update(Ordering::Release, Ordering::Relaxed, |count| match count {
+			0 => None,
+			2 => Some(0),
+			count => Some(count - 1)
+		});
+		old.is_err()
+	}
+
+	fn is_shared(&self) -> bool {
+		self.strong_count.load(Ordering::Acquire) > 1
+	}
+}
+
+/// Allocates the reference count table used to share the frames in `frames`
+///
+/// Must be called once `highmem` can allocate the table
+pub fn init_refcounts(frames: Range<Frame>) {
+	let len = frames.end - frames.start;
+	let table_frames = (len * mem::size_of::<RefCountEntry>()).div_ceil(4096);
+
+	let table_base = highmem().allocate_contiguous(table_frames).expect("Unable to allocate frame reference counts");
+	let table_ptr = table_base.to_page().as_ptr();
+	// SAFETY: the frames are freshly allocated and mapped in the physical memory map, and zero is a valid `RefCountEntry`
+	let entries = unsafe {
+		table_ptr.write_bytes(0, table_frames * 4096);
+		slice::from_raw_parts(table_ptr.cast::<RefCountEntry>(), len)
+	};
+
+	debug!("Reference counting {len} frames starting at {:?}", frames.start);
+	assert!(REFCOUNTS.get().is_none(), "Frame reference counts already initialised");
+	REFCOUNTS.get_or_init(|| RefCounts { base: frames.start, entries });
+}
+
+/// Adds a reference to `frame`
+///
+/// Fails if the frame is outside of the reference counted range
+#[export_name = "__popcorn_memory_physical_share"]
+pub fn share(frame: Frame) -> Result<(), ()> {
+	let entry = REFCOUNTS.get().and_then(|refcounts| refcounts.entry(frame)).ok_or(())?;
+	entry.increment();
+	Ok(())
+}
+
+/// Removes a reference to `frame`, returning `true` if it was the last one and the frame should be deallocated
+#[export_name = "__popcorn_memory_physical_release"]
+pub fn release(frame: Frame) -> bool {
+	match REFCOUNTS.get().and_then(|refcounts| refcounts.entry(frame)) {
+		Some(entry) => entry.decrement(),
+		None => true
+	}
+}
+
+/// Returns `true` if there is more than one reference to `frame`
+pub fn is_shared(frame: Frame) -> bool {
+	REFCOUNTS.get()
+			.and_then(|refcounts| refcounts.entry(frame))
+			.is_some_and(RefCountEntry::is_shared)
+}
+
+#[cfg(test)]
+mod tests {
+	use kernel_api::memory::PhysicalAddress;
+	use super::*;
+
+	fn test_frame() -> Frame {
+		highmem().allocate_one().unwrap()
+	}
+
+	#[test]
+	fn unshared_frame_is_released() {
+		let frame = test_frame();
+		assert!(!is_shared(frame));
+		assert!(release(frame));
+	}
+
+	#[test]
+	fn shared_frame_needs_every_release() {
+		let frame = test_frame();
+		share(frame).unwrap();
+		share(frame).unwrap();
+		assert!(is_shared(frame));
+
+		assert!(!release(frame));
+		assert!(is_shared(frame));
+		assert!(!release(frame));
+		assert!(!is_shared(frame));
+		assert!(release(frame));
+	}
+
+	#[test]
+	fn frames_outside_table_cannot_be_shared() {
+		assert!(share(Frame::new(PhysicalAddress::new(0xffff_ffff_f000))).is_err());
 	}
 }
@@ -1,7 +1,7 @@
 use core::num::NonZeroUsize;
 use core::ops::Range;
 use log::trace;
-use kernel_api::memory::allocator::{AllocateNonContiguousRet, AllocationMeta, BackingAllocator, Config, Location, SizedBackingAllocator, SpecificLocation};
+use kernel_api::memory::allocator::{AllocationMeta, BackingAllocator, Config, Location, SizedBackingAllocator, SpecificLocation, Stats};
 use kernel_api::memory::{Frame, PhysicalAddress, AllocError, physical, allocator};
 use kernel_api::sync::Mutex;
 
@@ -12,9 +12,14 @@ impl<'mem_map> WatermarkAllocator<'mem_map> {
 		Self(Mutex::new(Inner::new(free_regions)))
 	}
 
-	pub fn drain_into(mut self, into: &mut dyn BackingAllocator) where Self: Sized {
+	/// Marks every frame handed out by this allocator as used in each of `into`
+	///
+	/// Allocators are expected to ignore any part of the allocation outside of the range they manage.
+	pub fn drain_into(mut self, into: &mut [&mut dyn BackingAllocator]) where Self: Sized {
 		let inner = self.0.into_inner();
-		into.push(AllocationMeta::new(inner.prev_frame..inner.top));
+		for allocator in into {
+			allocator.push(AllocationMeta::new(inner.prev_frame..inner.top));
+		}
 	}
 }
 
@@ -36,13 +41,27 @@ unsafe impl BackingAllocator for WatermarkAllocator<'_> {
 	fn allocate_at(&self, frame_count: usize, location: SpecificLocation) -> Result<Frame, AllocError> {
 		unimplemented!()
 	}
+
+	fn stats(&self) -> Option<Stats> {
+		Some(self.0.lock().stats())
+	}
+
+	fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, Stats)) {
+		let guard = self.0.lock();
+		let range = guard.last_in_current_region..guard.top;
+		let stats = guard.stats();
+		drop(guard);
+
+		f(range, stats);
+	}
 }
 
 pub struct Inner<'mem_map> {
 	free_regions: &'mem_map mut (dyn DoubleEndedIterator<Item = Range<Frame>> + Send),
 	last_in_current_region: Frame,
 	prev_frame: Frame,
-	top: Frame
+	top: Frame,
+	allocated: usize
 }
 
 impl<'mem_map> Inner<'mem_map> {
@@ -55,7 +74,19 @@ impl<'mem_map> Inner<'mem_map> {
 			free_regions,
 			last_in_current_region: last_free_section.start,
 			prev_frame: last_free_section.end,
-			top: last_free_section.end
+			top: last_free_section.end,
+			allocated: 0
+		}
+	}
+
+	/// Only the free frames in the current region are counted, as later regions haven't been taken from the memory map
+	/// yet
+	pub fn stats(&self) -> Stats {
+		let free_frames = self.prev_frame - self.last_in_current_region;
+		Stats {
+			total_frames: self.allocated + free_frames,
+			free_frames,
+			largest_free_run: free_frames
 		}
 	}
 
@@ -79,6 +110,7 @@ impl<'mem_map> Inner<'mem_map> {
 		}
 
 		self.prev_frame = test_frame;
+		self.allocated += page_count.get();
 		Ok(test_frame)
 	}
 }
@@ -122,6 +154,16 @@ mod tests {
 		assert_eq!(alloc.allocate_contiguous(NonZeroUsize::new(1).unwrap(), 0), Err(AllocError));
 	}
 
+	#[test]
+	fn stats_count_current_region() {
+		let mut iter = MEMORY_LAYOUT[2..4].iter().cloned();
+		let mut alloc = Inner::new(&mut iter);
+		alloc.allocate_contiguous(NonZeroUsize::new(2).unwrap(), 0).unwrap();
+
+		let stats = alloc.stats();
+		assert_eq!((stats.total_frames, stats.free_frames, stats.used_frames()), (6, 4, 2));
+	}
+
 	#[test]
 	fn allocates_multiple_pages() {
 		let mut iter = MEMORY_LAYOUT[3..4].iter().cloned();
@@ -1,4 +1,11 @@
-use core::fmt::{Formatter, Pointer};
+use core::fmt::{Debug, Formatter, Pointer};
+use core::marker::PhantomData;
+use core::mem;
+use core::num::NonZeroUsize;
+use kernel_api::memory::{AllocError, Frame, PhysicalAddress};
+use kernel_api::memory::mapping::{CacheMode, Config, Location, Mapping};
+use kernel_api::memory::r#virtual::Global;
+use crate::hal;
 use crate::projection::{Field, Project, ProjectSuper};
 
 // Based on `mmio::VolBox`
@@ -49,3 +56,90 @@ impl<T> Project<T> for MmioCell<T> {
 		}
 	}
 }
+
+/// A mapping of a device's registers at a fixed physical address
+///
+/// Unlike [`hal::acpi::Handler::map_region`], the caching behaviour is chosen by the caller. The frames are given back to
+/// [`hal::acpi::Allocator`] when the mapping is dropped, which does nothing since the device memory was never allocated.
+pub struct MmioMapping<T> {
+	mapping: Mapping<'static>,
+	physical_address: PhysicalAddress,
+	offset: usize,
+	_phantom: PhantomData<*mut T>
+}
+
+// SAFETY: the registers are only accessed through volatile reads and writes
+unsafe impl<T> Send for MmioMapping<T> {}
+
+impl<T> MmioMapping<T> {
+	/// Maps a `T` at `physical_address` with caching behaviour `cache`
+	///
+	/// # Safety
+	///
+	/// `physical_address` must point to device memory holding a `T`, which isn't mapped anywhere else with a
+	/// different caching behaviour
+	pub unsafe fn new(physical_address: PhysicalAddress, cache: CacheMode) -> Result<Self, AllocError> {
+		let start = physical_address.align_down::<4096>();
+		let offset = physical_address.addr - start.addr;
+		let page_count = NonZeroUsize::new((offset + mem::size_of::<T>()).div_ceil(4096).max(1)).unwrap();
+
+		let mapping = Mapping::new(
+			Config::<Global>::new(page_count)
+					.physical_allocator(&hal::acpi::Allocator)
+					.physical_location(Location::At(Frame::new(start)))
+					.cache(cache)
+		)?;
+
+		Ok(Self {
+			mapping,
+			physical_address,
+			offset,
+			_phantom: PhantomData
+		})
+	}
+
+	/// Maps a `T` at `physical_address` with no caching, as device registers usually need
+	///
+	/// # Safety
+	///
+	/// See [`new`](Self::new)
+	pub unsafe fn uncached(physical_address: PhysicalAddress) -> Result<Self, AllocError> {
+		unsafe { Self::new(physical_address, CacheMode::Uncached) }
+	}
+
+	pub fn cell(&self) -> MmioCell<T> {
+		unsafe { MmioCell::new(self.mapping.virtual_start().as_ptr().byte_add(self.offset).cast()) }
+	}
+
+	pub fn physical_address(&self) -> PhysicalAddress {
+		self.physical_address
+	}
+}
+
+impl<T> Debug for MmioMapping<T> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("MmioMapping")
+				.field("physical_address", &self.physical_address)
+				.field("mapping", &self.mapping)
+				.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use kernel_api::memory::{Page, VirtualAddress};
+	use crate::hal::paging2::KTable;
+	use crate::memory::paging::ktable;
+	use super::*;
+
+	#[test]
+	fn mapping_uses_requested_frame_and_cache_mode() {
+		// The local APIC's registers, which are never mapped as normal memory
+		let physical_address = PhysicalAddress::new(0xfee0_0030);
+		let mapping = unsafe { MmioMapping::<u32>::new(physical_address, CacheMode::WriteCombining) }.unwrap();
+
+		let addr = VirtualAddress::new(mapping.cell().ptr as usize);
+		assert_eq!(ktable().translate_address(addr), Some(physical_address));
+		assert_eq!(ktable().cache_mode(Page::new(addr.align_down())), Some(CacheMode::WriteCombining));
+	}
+}
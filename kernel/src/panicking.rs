@@ -56,30 +56,53 @@ pub fn get_symbol_name(ip: usize) -> &'static str {
 	sym_name
 }
 
-pub fn stack_trace() {
+/// Calls `f` with the instruction pointer of each frame on the stack, starting from the caller, until it returns `false`
+fn walk_stack(mut f: impl FnMut(usize) -> bool) {
 	use unwinding::abi::{UnwindContext, _Unwind_GetIP, _Unwind_Backtrace};
 	use core::ffi::c_void;
 
-	struct CallbackData {
-		counter: usize,
-	}
 	extern "C" fn callback(
 		unwind_ctx: &UnwindContext<'_>,
 		arg: *mut c_void,
 	) -> UnwindReasonCode {
-		let data = unsafe { &mut *arg.cast::<CallbackData>() };
-		data.counter += 1;
+		let f = unsafe { &mut *arg.cast::<&mut dyn FnMut(usize) -> bool>() };
 		let ip = _Unwind_GetIP(unwind_ctx);
+		if f(ip) { UnwindReasonCode::NO_REASON } else { UnwindReasonCode::END_OF_STACK }
+	}
+	let mut f: &mut dyn FnMut(usize) -> bool = &mut f;
+	_Unwind_Backtrace(callback, ptr::addr_of_mut!(f).cast());
+}
+
+pub fn stack_trace() {
+	let mut counter = 0;
+	walk_stack(|ip| {
+		counter += 1;
 		sprintln!(
 			"{:4}:{:#19x} - {}",
-			data.counter,
+			counter,
 			ip,
 			get_symbol_name(ip)
 		);
-		UnwindReasonCode::NO_REASON
-	}
-	let mut data = CallbackData { counter: 0 };
-	_Unwind_Backtrace(callback, ptr::addr_of_mut!(data).cast());
+		true
+	});
+}
+
+/// Fills `frames` with the instruction pointers of the caller's stack frames, returning how many were written
+///
+/// This doesn't allocate, so can be used from within the heap.
+#[export_name = "__popcorn_backtrace_capture"]
+pub fn capture_stack_trace(frames: &mut [usize]) -> usize {
+	let mut count = 0;
+	let mut skipped = false;
+	walk_stack(|ip| {
+		// The first frame is this function
+		if !skipped { skipped = true; return true; }
+		if count == frames.len() { return false; }
+		frames[count] = ip;
+		count += 1;
+		true
+	});
+	count
 }
 
 pub(crate) fn do_panic() -> ! {
@@ -1,7 +1,5 @@
 use crate::resource::arc_frames::OwnedFrames;
 
-//pub mod background_zeroer;
-
 pub trait FrameAllocator {
 	fn allocate(&self, count: usize) -> Result<OwnedFrames<'_>, AllocError>;
 	fn deallocate(&self, frames: &OwnedFrames);
@@ -140,6 +140,9 @@ pub fn test_runner(tests: &[&TestDescAndFn]) -> ! {
 
 	<FORMATTER as Formatter>::teardown(success, success_count, tests.len() - success_count - ignore_count, ignore_count);
 
+	#[cfg(feature = "debug_heap")]
+	crate::memory::meminfo::report_leaks();
+
 	struct DebugOut;
 
 	impl minicov::CoverageWriter for DebugOut {
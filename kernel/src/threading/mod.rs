@@ -1,14 +1,28 @@
 use alloc::borrow::Cow;
+use alloc::boxed::Box;
 use core::num::NonZeroUsize;
+use kernel_api::memory::AllocError;
+use kernel_api::memory::cache::{ObjectCache, Placement};
 use kernel_api::memory::mapping::Stack;
 use kernel_api::memory::physical::{highmem, OwnedFrames};
 use kernel_api::memory::r#virtual::{Global, OwnedPages};
-use crate::hal::paging2::TTableTy;
-use crate::hal::{ThreadControlBlock, ThreadState};
+use kernel_api::sync::LazyLock;
+use kernel_api::threading::Priority;
+use crate::memory::address_space::AddressSpace;
+use crate::hal::{Hal, HalTy, ThreadControlBlock, ThreadState};
 use utils::handoff;
-use scheduler::Tid;
+use scheduler::{IdleTime, Tid};
 
 pub mod scheduler;
+pub mod preempt;
+
+pub use kernel_api::threading::{Builder, JoinHandle, spawn};
+
+/// Threads are switched using pointers to their control blocks, so they are kept in a cache rather than moved around in
+/// the scheduler's collections
+static THREAD_CONTROL_BLOCKS: LazyLock<ObjectCache<ThreadControlBlock>> = LazyLock::new(|| {
+	ObjectCache::new("thread_control_blocks", Placement::default()).expect("Failed to create thread control block cache")
+});
 
 pub unsafe fn init(handoff_data: crate::HandoffWrapper) -> Tid {
 	let stack = handoff_data.memory.stack;
@@ -31,15 +45,162 @@ pub unsafe fn init(handoff_data: crate::HandoffWrapper) -> Tid {
 	let tcb =  ThreadControlBlock {
 		name: Cow::Borrowed("init"),
 		kernel_stack: Stack::from_raw_parts(stack_frames, stack_pages),
-		ttable,
+		// The init thread never exits, so the bootloader's tables are never given to the page table cache
+		address_space: unsafe { AddressSpace::from_ttable(ttable) },
 		state: ThreadState::Running,
+		priority: Priority::Normal,
 		save_state: Default::default(),
 	};
+	let priority = tcb.priority;
+	let tcb = THREAD_CONTROL_BLOCKS.allocate(tcb).expect("Failed to allocate thread control block");
 	assert!(scheduler.tasks.insert(Tid(0), tcb).is_none());
+	scheduler.run_queue.add(Tid(0), priority);
+
+	// The idle thread is never queued, so its priority is never used
+	let idle = ThreadControlBlock::new(
+		Cow::Borrowed("idle"),
+		AddressSpace::new().expect("Failed to create idle thread address space"),
+		NonZeroUsize::new(8).unwrap(),
+		Priority::Low,
+		unlock_scheduler,
+		Box::new(Box::new(idle))
+	).expect("Failed to create idle thread");
+	scheduler.set_idle_task(idle);
+	preempt::init();
 
 	Tid(0)
 }
 
+/// Releases the scheduler lock held across the switch into a new thread
+fn unlock_scheduler() {
+	preempt::reset_count();
+	unsafe { scheduler::SCHEDULER.unlock(); }
+}
+
+fn idle() {
+	loop {
+		// Anything that makes a thread runnable arrives as an interrupt, which wakes the CPU. Interrupts stay disabled
+		// from checking the run queue until halting, so a thread can't be woken in between without the CPU noticing.
+		let _ = HalTy::get_and_disable_interrupts();
+		let nothing_ready = scheduler::SCHEDULER.lock().run_queue.is_empty();
+		if nothing_ready {
+			HalTy::halt();
+		} else {
+			// The scheduler hands the thread switched to whatever interrupt state it was locked with
+			HalTy::enable_interrupts();
+		}
+		thread_yield();
+	}
+}
+
+/// Returns how long the current CPU has spent in its idle thread
+pub fn idle_time() -> IdleTime {
+	scheduler::SCHEDULER.lock().idle_time()
+}
+
+/// Stops running the current thread until it is [woken](scheduler::Scheduler::wake)
+pub fn block_current() {
+	block_current_unless(|_| false);
+}
+
+/// Stops running the current thread until it is [woken](try_wake), unless `ready` returns `true`
+///
+/// `ready` is given the current thread's ID and is called with the scheduler locked, so the thread can't be woken
+/// between `ready` deciding to block and the thread blocking.
+pub fn block_current_unless(ready: impl FnOnce(Tid) -> bool) {
+	let mut scheduler = scheduler::SCHEDULER.lock();
+	let current_tid = scheduler.current_tid;
+	if ready(current_tid) { return; }
+
+	scheduler.tasks.get_mut(&current_tid).expect("Current thread must exist").state = ThreadState::Blocked;
+	scheduler.schedule();
+}
+
+/// Wakes `tid` if it is blocked
+///
+/// Returns `false` if the scheduler is locked, since this may be called while it is being used.
+pub fn try_wake(tid: Tid) -> bool {
+	let Some(mut scheduler) = scheduler::SCHEDULER.try_lock() else { return false; };
+	scheduler.wake(tid);
+	true
+}
+
+/// Returns the name of the current thread if `addr` is in the guard page below its kernel stack
+///
+/// Returns `None` if the scheduler is locked, since the fault may have happened while it was being used.
+pub fn overflowed_stack(addr: usize) -> Option<Cow<'static, str>> {
+	let scheduler = scheduler::SCHEDULER.try_lock()?;
+	let tcb = scheduler.tasks.get(&scheduler.current_tid)?;
+	let guard_page = tcb.kernel_stack.guard_page();
+
+	(guard_page.start().addr..(guard_page + 1).start().addr).contains(&addr)
+		.then(|| tcb.name.clone())
+}
+
+/// Attempts to resolve a write fault at `addr` in the current thread's address space
+///
+/// Returns `false` if the scheduler is locked, since the fault may have happened while it was being used.
+pub fn handle_write_fault(addr: usize) -> bool {
+	let Some(mut scheduler) = scheduler::SCHEDULER.try_lock() else { return false; };
+	let current_tid = scheduler.current_tid;
+	let Some(tcb) = scheduler.tasks.get_mut(&current_tid) else { return false; };
+
+	tcb.address_space.handle_write_fault(addr)
+}
+
+/// Starts a kernel thread running `main`, which exits when `main` returns
+///
+/// This backs [`Builder::spawn`], which should be used instead.
+#[export_name = "__popcorn_thread_spawn"]
+fn spawn_raw(name: Cow<'static, str>, stack_pages: NonZeroUsize, priority: Priority, main: Box<dyn FnOnce() + Send>) -> Result<usize, AllocError> {
+	let main = Box::try_new(main).map_err(|_| AllocError)?;
+	let address_space = AddressSpace::new()?;
+	let tcb = ThreadControlBlock::new(name, address_space, stack_pages, priority, unlock_scheduler, main)?;
+	let tid = scheduler::SCHEDULER.lock().add_task(tcb);
+
+	Ok(tid.0)
+}
+
+/// Blocks the current thread until `tid` has exited
+///
+/// This backs [`JoinHandle::join`].
+#[export_name = "__popcorn_thread_join"]
+fn join(tid: usize) {
+	let tid = Tid(tid);
+	loop {
+		let mut scheduler = scheduler::SCHEDULER.lock();
+		if scheduler.has_exited(tid) { return; }
+
+		scheduler.join(tid);
+		scheduler.schedule();
+	}
+}
+
+#[export_name = "__popcorn_thread_current"]
+fn current() -> usize {
+	scheduler::SCHEDULER.lock().current_tid.0
+}
+
+/// Changes the priority of `tid`, returning `false` if it doesn't exist
+///
+/// This backs [`kernel_api::threading::set_priority`].
+#[export_name = "__popcorn_thread_set_priority"]
+fn set_priority(tid: usize, priority: Priority) -> bool {
+	scheduler::SCHEDULER.lock().set_priority(Tid(tid), priority)
+}
+
+/// Ends the current thread
+///
+/// Its kernel stack and address space are freed once another thread has been switched to.
+pub fn thread_exit() -> ! {
+	let mut scheduler = scheduler::SCHEDULER.lock();
+	scheduler.exit_current();
+	scheduler.schedule();
+
+	unreachable!("Exited thread was scheduled again")
+}
+
+#[export_name = "__popcorn_thread_yield"]
 pub fn thread_yield() {
 	scheduler::SCHEDULER.lock().schedule();
 }
@@ -0,0 +1,202 @@
+//! Switches threads from the local timer tick once the running thread has used up its time slice, or at the end of any
+//! interrupt once a thread that outranks it has been woken
+//!
+//! Preemption is counted per CPU. Spinlocks and reader-writer locks in `kernel_api` [disable](preempt_disable) it while
+//! they are held, so a thread is never switched out while another thread could end up spinning on its lock. The
+//! [scheduler](super::scheduler::SCHEDULER) itself is an [`IrqCell`](super::scheduler::IrqCell), which keeps interrupts
+//! disabled, so the tick can never arrive while it is locked.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::hal::{Hal, HalTy};
+use super::scheduler::SCHEDULER;
+
+/// How long a thread runs before it is preempted, if something else can run
+const TIME_SLICE_PICOS: u128 = 10_000_000_000;
+
+/// Set once threading is initialised, since thread locals can't be used until then
+static READY: AtomicBool = AtomicBool::new(false);
+
+#[thread_local]
+static PREEMPT_COUNT: Cell<usize> = Cell::new(0);
+
+#[thread_local]
+static TICKS_PER_SLICE: Cell<usize> = Cell::new(1);
+
+#[thread_local]
+static TICKS_LEFT: Cell<usize> = Cell::new(1);
+
+#[thread_local]
+static NEEDS_RESCHEDULE: Cell<bool> = Cell::new(false);
+
+/// Set along with [`NEEDS_RESCHEDULE`] when the reschedule is because the current thread used its whole time slice
+#[thread_local]
+static SLICE_EXPIRED: Cell<bool> = Cell::new(false);
+
+/// Allows the current CPU to be preempted
+///
+/// # Safety
+///
+/// Must be called after thread locals are set up and the CPU has an idle thread
+pub(super) unsafe fn init() {
+	READY.store(true, Ordering::Release);
+}
+
+/// Sets how often [`tick`] is called, so that time slices can be counted in ticks
+pub fn set_tick_period(picos: u128) {
+	let ticks = (TIME_SLICE_PICOS / picos.max(1)).max(1);
+	TICKS_PER_SLICE.set(ticks.try_into().unwrap_or(usize::MAX));
+	TICKS_LEFT.set(TICKS_PER_SLICE.get());
+}
+
+/// Gives the thread that is about to run a full time slice
+pub(super) fn reset_slice() {
+	if !READY.load(Ordering::Acquire) { return; }
+
+	TICKS_LEFT.set(TICKS_PER_SLICE.get());
+	NEEDS_RESCHEDULE.set(false);
+	SLICE_EXPIRED.set(false);
+}
+
+/// Switches threads at the end of the next interrupt, without waiting for the current time slice to expire
+///
+/// Used when a thread that outranks the current one becomes ready.
+pub(super) fn request_reschedule() {
+	if !READY.load(Ordering::Acquire) { return; }
+	NEEDS_RESCHEDULE.set(true);
+}
+
+/// Counts down the current thread's time slice
+///
+/// Must be called from the local timer interrupt. The switch happens in [`irq_exit`].
+pub fn tick() {
+	if !READY.load(Ordering::Acquire) { return; }
+
+	match TICKS_LEFT.get() {
+		0 | 1 => {
+			NEEDS_RESCHEDULE.set(true);
+			SLICE_EXPIRED.set(true);
+		},
+		left => TICKS_LEFT.set(left - 1)
+	}
+}
+
+/// Switches threads if a reschedule is needed and the current thread can be preempted
+///
+/// # Safety
+///
+/// Must only be called at the end of an interrupt handler, once the interrupt has been acknowledged and no locks are
+/// held by the handler
+pub unsafe fn irq_exit() {
+	if !READY.load(Ordering::Acquire) { return; }
+	if !NEEDS_RESCHEDULE.get() || PREEMPT_COUNT.get() != 0 { return; }
+	NEEDS_RESCHEDULE.set(false);
+	let slice_expired = SLICE_EXPIRED.replace(false);
+
+	// The interrupted thread had interrupts enabled, so switching is done as if it had yielded with them enabled. This
+	// way the thread that is switched to gets them enabled again when it unlocks the scheduler.
+	HalTy::enable_interrupts();
+	if let Some(mut scheduler) = SCHEDULER.try_lock() {
+		// Only a thread that used its whole time slice is demoted, not one that was outranked by a woken thread
+		if slice_expired { scheduler.preempt(); } else { scheduler.schedule(); }
+	}
+}
+
+/// Stops the current CPU from being preempted until a matching [`preempt_enable`]
+///
+/// A thread that has its time slice expire while preemption is disabled is switched out at the next tick after it is
+/// enabled again.
+#[export_name = "__popcorn_preempt_disable"]
+pub fn preempt_disable() {
+	if !READY.load(Ordering::Acquire) { return; }
+	PREEMPT_COUNT.set(PREEMPT_COUNT.get() + 1);
+}
+
+#[export_name = "__popcorn_preempt_enable"]
+pub fn preempt_enable() {
+	if !READY.load(Ordering::Acquire) { return; }
+
+	let count = PREEMPT_COUNT.get();
+	debug_assert_ne!(count, 0, "Preemption enabled more times than it was disabled");
+	PREEMPT_COUNT.set(count.saturating_sub(1));
+}
+
+/// Allows preemption on a CPU that has just started a new thread, which can't be holding any locks yet
+///
+/// The count left by the thread that switched away doesn't belong to the new thread.
+pub(super) fn reset_count() {
+	if !READY.load(Ordering::Acquire) { return; }
+	PREEMPT_COUNT.set(0);
+}
+
+/// Disables preemption until dropped
+#[derive(Debug)]
+pub struct PreemptGuard(());
+
+impl PreemptGuard {
+	pub fn new() -> Self {
+		preempt_disable();
+		Self(())
+	}
+}
+
+impl Drop for PreemptGuard {
+	fn drop(&mut self) {
+		preempt_enable();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Runs `f` as if threading had been initialised, then puts this CPU's preemption state back
+	fn with_ready(f: impl FnOnce()) {
+		READY.store(true, Ordering::Release);
+		f();
+		PREEMPT_COUNT.set(0);
+		NEEDS_RESCHEDULE.set(false);
+		SLICE_EXPIRED.set(false);
+		READY.store(false, Ordering::Release);
+	}
+
+	#[test]
+	fn tick_period_divides_slice() {
+		set_tick_period(TIME_SLICE_PICOS / 10);
+		assert_eq!(TICKS_PER_SLICE.get(), 10);
+
+		// Partial ticks are rounded down, so slices are never longer than asked for
+		set_tick_period(TIME_SLICE_PICOS / 10 + 1);
+		assert_eq!(TICKS_PER_SLICE.get(), 9);
+
+		set_tick_period(TIME_SLICE_PICOS * 2);
+		assert_eq!(TICKS_PER_SLICE.get(), 1);
+		set_tick_period(0);
+		assert_eq!(TICKS_PER_SLICE.get(), usize::try_from(TIME_SLICE_PICOS).unwrap());
+	}
+
+	#[test]
+	fn slice_expires_after_its_ticks() {
+		with_ready(|| {
+			set_tick_period(TIME_SLICE_PICOS / 3);
+			tick();
+			tick();
+			assert!(!NEEDS_RESCHEDULE.get());
+			tick();
+			assert!(NEEDS_RESCHEDULE.get() && SLICE_EXPIRED.get());
+		});
+	}
+
+	#[test]
+	fn irq_exit_does_not_switch_while_disabled() {
+		with_ready(|| {
+			NEEDS_RESCHEDULE.set(true);
+			preempt_disable();
+
+			// There are no threads to switch to, so this would panic if it tried
+			unsafe { irq_exit(); }
+			assert!(NEEDS_RESCHEDULE.get());
+			preempt_enable();
+		});
+	}
+}
@@ -1,4 +1,5 @@
 use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
 use core::borrow::Borrow;
 use core::cell::{Cell, UnsafeCell};
 use core::fmt::{Debug, Formatter};
@@ -7,7 +8,10 @@ use core::ops::{Deref, DerefMut};
 use core::ptr::NonNull;
 use crate::hal::{HalTy, Hal, ThreadControlBlock, ThreadState};
 use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel_api::memory::cache::CacheBox;
+use kernel_api::threading::Priority;
 use log::debug;
+use super::THREAD_CONTROL_BLOCKS;
 
 #[thread_local]
 pub static SCHEDULER: IrqCell<Scheduler> = IrqCell::new(Scheduler::new());
@@ -32,6 +36,12 @@ impl<T: ?Sized> IrqCell<T> {
 		IrqGuard { cell: self }
 	}
 
+	/// Locks the cell, unless it is already locked
+	pub fn try_lock(&self) -> Option<IrqGuard<'_, T>> {
+		if self.state.get().is_some() { return None; }
+		Some(self.lock())
+	}
+
 	pub unsafe fn unlock(&self) {
 		let old_state = self.state.take();
 		HalTy::set_interrupts(old_state.unwrap());
@@ -80,11 +90,175 @@ impl Tid {
 	}
 }
 
+/// How many feedback levels each [`Priority`] is split into
+const FEEDBACK_LEVELS: usize = 3;
+
+/// How many time slices can expire before every thread is boosted back to the top feedback level, so that demoted
+/// threads aren't starved by ones that keep blocking
+const BOOST_INTERVAL: usize = 64;
+
+#[derive(Debug, Copy, Clone)]
+struct QueueEntry {
+	priority: Priority,
+	level: usize,
+	queued: bool
+}
+
+/// Picks which ready thread runs next
+///
+/// Threads are first ordered by their static [`Priority`], which is strict: a thread only runs once no thread of a
+/// higher priority is ready. Within a priority, threads are put in a multilevel feedback queue. Every thread starts on
+/// the top level, and is [demoted](Self::demote) a level each time it uses a whole time slice, so threads that hog the
+/// CPU make way for ones that don't. Threads that block are [promoted](Self::promote) back to the top level when they are
+/// woken, and every thread is boosted back to the top level every [`BOOST_INTERVAL`] demotions.
+#[derive(Debug)]
+pub(super) struct RunQueue {
+	/// The ready threads at each priority, lowest first, then at each feedback level, top first
+	queues: [[VecDeque<Tid>; FEEDBACK_LEVELS]; 3],
+	threads: BTreeMap<Tid, QueueEntry>,
+	demotions_until_boost: usize
+}
+
+impl RunQueue {
+	pub const fn new() -> Self {
+		const EMPTY: [VecDeque<Tid>; FEEDBACK_LEVELS] = [VecDeque::new(), VecDeque::new(), VecDeque::new()];
+
+		Self {
+			queues: [EMPTY, EMPTY, EMPTY],
+			threads: BTreeMap::new(),
+			demotions_until_boost: BOOST_INTERVAL
+		}
+	}
+
+	/// Starts tracking `tid` on the top feedback level, without queueing it
+	pub fn add(&mut self, tid: Tid, priority: Priority) {
+		let old = self.threads.insert(tid, QueueEntry { priority, level: 0, queued: false });
+		assert!(old.is_none(), "Thread {tid:?} is already in the run queue");
+	}
+
+	/// Stops tracking `tid`, taking it out of its queue if it is queued
+	pub fn remove(&mut self, tid: Tid) {
+		self.unqueue(tid);
+		self.threads.remove(&tid);
+	}
+
+	/// Puts `tid` at the back of the queue for its priority and feedback level
+	pub fn push(&mut self, tid: Tid) {
+		let entry = self.threads.get_mut(&tid).expect("Cannot queue a thread that isn't in the run queue");
+		if entry.queued { return; }
+
+		entry.queued = true;
+		self.queues[entry.priority as usize][entry.level].push_back(tid);
+	}
+
+	/// Returns `true` if no threads are queued
+	pub fn is_empty(&self) -> bool {
+		self.queues.iter().flatten().all(VecDeque::is_empty)
+	}
+
+	/// Takes the thread that should run next out of its queue
+	pub fn pop(&mut self) -> Option<Tid> {
+		let tid = self.queues.iter_mut().rev()
+				.flat_map(|levels| levels.iter_mut())
+				.find_map(VecDeque::pop_front)?;
+
+		self.threads.get_mut(&tid).expect("Queued thread must be tracked").queued = false;
+		Some(tid)
+	}
+
+	/// Moves `tid` down a feedback level, since it used its whole time slice
+	pub fn demote(&mut self, tid: Tid) {
+		let queued = self.unqueue(tid);
+		if let Some(entry) = self.threads.get_mut(&tid) {
+			entry.level = (entry.level + 1).min(FEEDBACK_LEVELS - 1);
+		}
+		if queued { self.push(tid); }
+
+		self.demotions_until_boost -= 1;
+		if self.demotions_until_boost == 0 {
+			self.boost();
+		}
+	}
+
+	/// Moves `tid` back to the top feedback level, since it blocked before using its time slice
+	pub fn promote(&mut self, tid: Tid) {
+		let queued = self.unqueue(tid);
+		if let Some(entry) = self.threads.get_mut(&tid) {
+			entry.level = 0;
+		}
+		if queued { self.push(tid); }
+	}
+
+	/// Changes the static priority of `tid`, returning `false` if it isn't tracked
+	pub fn set_priority(&mut self, tid: Tid, priority: Priority) -> bool {
+		let queued = self.unqueue(tid);
+		let Some(entry) = self.threads.get_mut(&tid) else { return false; };
+		entry.priority = priority;
+		if queued { self.push(tid); }
+
+		true
+	}
+
+	/// Moves every thread back to the top feedback level, keeping queued threads in the order they would have run
+	fn boost(&mut self) {
+		self.demotions_until_boost = BOOST_INTERVAL;
+
+		for levels in &mut self.queues {
+			let (top, lower) = levels.split_first_mut().expect("There is always a top level");
+			for level in lower {
+				top.append(level);
+			}
+		}
+		for entry in self.threads.values_mut() {
+			entry.level = 0;
+		}
+	}
+
+	/// Takes `tid` out of its queue, returning whether it was queued
+	fn unqueue(&mut self, tid: Tid) -> bool {
+		let Some(entry) = self.threads.get_mut(&tid) else { return false; };
+		if !entry.queued { return false; }
+
+		entry.queued = false;
+		let queue = &mut self.queues[entry.priority as usize][entry.level];
+		let index = queue.iter().position(|&queued| queued == tid).expect("Queued thread must be in its queue");
+		queue.remove(index);
+		true
+	}
+}
+
 #[derive(Debug)]
 pub struct Scheduler {
-	pub(super) tasks: BTreeMap<Tid, ThreadControlBlock>,
-	pub(super) queue: VecDeque<Tid>,
-	pub(super) current_tid: Tid
+	pub(super) tasks: BTreeMap<Tid, CacheBox<'static, ThreadControlBlock>>,
+	pub(super) run_queue: RunQueue,
+	pub(super) current_tid: Tid,
+	/// Threads that have exited but still need their resources freeing
+	exited: Vec<Tid>,
+	/// The threads waiting for each thread to exit
+	joiners: BTreeMap<Tid, Vec<Tid>>,
+	/// The thread run when nothing else can be, which is never put in the run queue
+	idle_tid: Option<Tid>,
+	/// The [timestamp](Hal::timestamp) the idle thread was set
+	started_at: u64,
+	/// The timestamp the idle thread last started running, if it is running now
+	idle_since: Option<u64>,
+	idle_ticks: u64
+}
+
+/// How long a CPU has spent idle, in [timestamp](Hal::timestamp) ticks
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IdleTime {
+	pub idle: u64,
+	/// The time since the scheduler was given an idle thread
+	pub total: u64
+}
+
+impl IdleTime {
+	/// Returns how much of the time was spent idle, as a percentage
+	pub fn idle_percent(&self) -> u64 {
+		if self.total == 0 { return 0; }
+		u64::try_from(u128::from(self.idle) * 100 / u128::from(self.total)).unwrap()
+	}
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -120,47 +294,194 @@ impl Scheduler {
 	pub const fn new() -> Self {
 		Self {
 			tasks: BTreeMap::new(),
-			queue: VecDeque::new(),
-			current_tid: Tid(0)
+			run_queue: RunQueue::new(),
+			current_tid: Tid(0),
+			exited: Vec::new(),
+			joiners: BTreeMap::new(),
+			idle_tid: None,
+			started_at: 0,
+			idle_since: None,
+			idle_ticks: 0
 		}
 	}
 
+	/// Sets the thread to run when there is nothing else to do
+	///
+	/// The thread should halt the CPU until there is more work, and yield once there is.
+	pub fn set_idle_task(&mut self, tcb: ThreadControlBlock) -> Tid {
+		assert!(self.idle_tid.is_none(), "Scheduler already has an idle thread");
+
+		let tcb = THREAD_CONTROL_BLOCKS.allocate(tcb).expect("Failed to allocate thread control block");
+		let tid = Tid::new();
+		self.tasks.insert(tid, tcb);
+		self.idle_tid = Some(tid);
+		self.started_at = HalTy::timestamp();
+		tid
+	}
+
+	pub fn idle_time(&self) -> IdleTime {
+		let now = HalTy::timestamp();
+		let current = self.idle_since.map_or(0, |since| now - since);
+
+		IdleTime {
+			idle: self.idle_ticks + current,
+			total: now - self.started_at
+		}
+	}
+
+	/// Puts a [blocked](ThreadState::Blocked) thread back in the run queue, on the top feedback level
+	///
+	/// If the thread outranks the current one, it is switched to at the end of the next interrupt.
+	pub fn wake(&mut self, tid: Tid) {
+		let Some(tcb) = self.tasks.get_mut(&tid) else { return; };
+		if tcb.state != ThreadState::Blocked { return; }
+
+		tcb.state = ThreadState::Ready;
+		let priority = tcb.priority;
+		self.run_queue.promote(tid);
+		self.run_queue.push(tid);
+
+		let current_priority = self.tasks.get(&self.current_tid).map(|tcb| tcb.priority);
+		if Some(self.current_tid) == self.idle_tid || current_priority.is_some_and(|current| priority > current) {
+			super::preempt::request_reschedule();
+		}
+	}
+
+	/// Starts scheduling a new thread at the [priority](ThreadControlBlock::priority) it was created with
 	pub fn add_task(&mut self, tcb: ThreadControlBlock) -> Tid {
+		let priority = tcb.priority;
+		let tcb = THREAD_CONTROL_BLOCKS.allocate(tcb).expect("Failed to allocate thread control block");
 		let tid = Tid::new();
 		self.tasks.insert(tid, tcb);
-		self.queue.push_back(tid);
+		self.run_queue.add(tid, priority);
+		self.run_queue.push(tid);
 		tid
 	}
 
-	pub fn schedule(&mut self) {
-		if let Some(new_tid) = self.queue.pop_front() {
-			let old_tid = self.current_tid;
-			self.queue.push_back(old_tid);
-			self.current_tid = new_tid;
+	/// Changes the priority of `tid`, returning `false` if it has exited or doesn't exist
+	///
+	/// The new priority takes effect the next time a thread is picked to run, so lowering the priority of the current
+	/// thread doesn't switch away from it immediately.
+	pub fn set_priority(&mut self, tid: Tid, priority: Priority) -> bool {
+		if self.has_exited(tid) { return false; }
 
-			let [old_tcb, new_tcb] = self.tasks.get_many_mut([&old_tid, &new_tid]).expect("Can't switch to same task");
-			let old_tcb = old_tcb.expect("Cannot have been running a task that doesn't exist");
-			let new_tcb = new_tcb.expect("Next task in queue has already exited");
+		self.tasks.get_mut(&tid).expect("Thread hasn't exited").priority = priority;
+		self.run_queue.set_priority(tid, priority)
+	}
 
-			old_tcb.state = ThreadState::Ready;
-			new_tcb.state = ThreadState::Running;
+	/// Switches away from the current thread because its time slice has expired
+	pub fn preempt(&mut self) {
+		if Some(self.current_tid) != self.idle_tid {
+			self.run_queue.demote(self.current_tid);
+		}
+		self.schedule();
+	}
 
-			unsafe {
-				HalTy::switch_thread(old_tcb, new_tcb);
+	/// Marks the current thread as [exited](ThreadState::Exited), and wakes any threads joining it
+	///
+	/// The thread's resources are freed by the next thread to call [`schedule`](Self::schedule) once it has switched
+	/// away, since it is still running on its stack and address space until then.
+	pub fn exit_current(&mut self) {
+		let tid = self.current_tid;
+		assert_ne!(Some(tid), self.idle_tid, "Idle thread cannot exit");
+
+		self.tasks.get_mut(&tid).expect("Current thread must exist").state = ThreadState::Exited;
+		self.exited.push(tid);
+		for joiner in self.joiners.remove(&tid).unwrap_or_default() {
+			self.wake(joiner);
+		}
+	}
+
+	/// Returns `true` if `tid` has exited, or has already been freed
+	pub fn has_exited(&self, tid: Tid) -> bool {
+		self.tasks.get(&tid).map_or(true, |tcb| tcb.state == ThreadState::Exited)
+	}
+
+	/// Blocks the current thread until `tid` exits
+	///
+	/// The caller must still [`schedule`](Self::schedule) to switch away.
+	pub fn join(&mut self, tid: Tid) {
+		let current_tid = self.current_tid;
+		assert_ne!(tid, current_tid, "Thread cannot join itself");
+
+		self.joiners.entry(tid).or_default().push(current_tid);
+		self.tasks.get_mut(&current_tid).expect("Current thread must exist").state = ThreadState::Blocked;
+	}
+
+	/// Frees every exited thread other than the current one
+	fn reap(&mut self) {
+		let mut i = 0;
+		while i < self.exited.len() {
+			if self.exited[i] == self.current_tid {
+				i += 1;
+				continue;
 			}
-		} else {
-			let current_tcb = self.tasks.get(&self.current_tid).expect("Cannot have been running a task that doesn't exist");
 
-			if current_tcb.state == ThreadState::Running { return; }
+			let tid = self.exited.swap_remove(i);
+			debug!("Reaping thread {tid:?}");
+			self.run_queue.remove(tid);
+			drop(self.tasks.remove(&tid));
+		}
+	}
+
+	/// Takes the thread to run next out of the run queue, or returns `None` if the current thread should carry on
+	///
+	/// A running thread is queued again before picking, so that it only carries on if no ready thread outranks it. The
+	/// idle thread is only picked when every queue is empty.
+	fn next_tid(&mut self) -> Option<Tid> {
+		let current_tid = self.current_tid;
+		let state = self.tasks.get(&current_tid).expect("Cannot have been running a task that doesn't exist").state;
+
+		// The idle thread is only ever picked when the queues are empty, so it never goes back in them
+		if state == ThreadState::Running && Some(current_tid) != self.idle_tid {
+			self.run_queue.push(current_tid);
+		}
+
+		let tid = self.run_queue.pop().or(self.idle_tid).expect("Nothing to run and no idle thread");
+		(tid != current_tid).then_some(tid)
+	}
+
+	pub fn schedule(&mut self) {
+		self.reap();
+
+		let old_tid = self.current_tid;
+		let Some(new_tid) = self.next_tid() else {
+			// A thread that was woken before it had switched away also carries on
+			self.tasks.get_mut(&old_tid).expect("Current thread must exist").state = ThreadState::Running;
+			super::preempt::reset_slice();
+			return;
+		};
+		self.current_tid = new_tid;
+
+		let now = HalTy::timestamp();
+		if Some(old_tid) == self.idle_tid {
+			self.idle_ticks += self.idle_since.take().map_or(0, |since| now - since);
+		}
+		if Some(new_tid) == self.idle_tid {
+			self.idle_since = Some(now);
+		}
+
+		let [old_tcb, new_tcb] = self.tasks.get_many_mut([&old_tid, &new_tid]).expect("Can't switch to same task");
+		let old_tcb = old_tcb.expect("Cannot have been running a task that doesn't exist");
+		let new_tcb = new_tcb.expect("Next task in queue has already exited");
 
-			panic!("can't yet idle");
+		if old_tcb.state == ThreadState::Running { old_tcb.state = ThreadState::Ready; }
+		new_tcb.state = ThreadState::Running;
+		super::preempt::reset_slice();
+
+		unsafe {
+			HalTy::switch_thread(old_tcb, new_tcb);
 		}
 	}
 }
 
 #[cfg(test)]
 mod tests {
+	use alloc::borrow::Cow;
+	use alloc::boxed::Box;
 	use alloc::collections::BTreeMap;
+	use core::num::NonZeroUsize;
+	use crate::memory::address_space::AddressSpace;
 	use super::*;
 
 	#[test]
@@ -168,4 +489,215 @@ mod tests {
 		let mut tree = BTreeMap::from([(1, true), (2, false), (3, true)]);
 		assert_eq!(tree.get_many_mut([&1, &1]), Err(DuplicateKey));
 	}
+
+	#[test]
+	fn idle_percent() {
+		assert_eq!(IdleTime { idle: 0, total: 0 }.idle_percent(), 0);
+		assert_eq!(IdleTime { idle: 25, total: 100 }.idle_percent(), 25);
+		assert_eq!(IdleTime { idle: u64::MAX, total: u64::MAX }.idle_percent(), 100);
+	}
+
+	fn run_queue(threads: &[(usize, Priority)]) -> RunQueue {
+		let mut queue = RunQueue::new();
+		for &(tid, priority) in threads {
+			queue.add(Tid(tid), priority);
+			queue.push(Tid(tid));
+		}
+		queue
+	}
+
+	fn drain(queue: &mut RunQueue) -> Vec<usize> {
+		core::iter::from_fn(|| queue.pop()).map(|tid| tid.0).collect()
+	}
+
+	#[test]
+	fn higher_priority_runs_first() {
+		let mut queue = run_queue(&[(1, Priority::Low), (2, Priority::Normal), (3, Priority::High), (4, Priority::Normal)]);
+		assert_eq!(drain(&mut queue), [3, 2, 4, 1]);
+	}
+
+	#[test]
+	fn demoted_thread_runs_after_same_priority() {
+		let mut queue = run_queue(&[(1, Priority::Normal), (2, Priority::Normal), (3, Priority::Low)]);
+
+		// Thread 1 runs for its whole time slice
+		assert_eq!(queue.pop(), Some(Tid(1)));
+		queue.demote(Tid(1));
+		queue.push(Tid(1));
+
+		assert_eq!(drain(&mut queue), [2, 1, 3]);
+	}
+
+	#[test]
+	fn demoted_thread_runs_before_lower_priority() {
+		let mut queue = run_queue(&[(1, Priority::Low), (2, Priority::High)]);
+		for _ in 0..FEEDBACK_LEVELS {
+			queue.demote(Tid(2));
+		}
+
+		assert_eq!(drain(&mut queue), [2, 1]);
+	}
+
+	#[test]
+	fn woken_thread_is_promoted() {
+		let mut queue = run_queue(&[(1, Priority::Normal), (2, Priority::Normal)]);
+		queue.demote(Tid(1));
+		queue.demote(Tid(2));
+
+		// Thread 2 blocks, and is woken
+		assert_eq!(queue.pop(), Some(Tid(1)));
+		assert_eq!(queue.pop(), Some(Tid(2)));
+		queue.push(Tid(1));
+		queue.promote(Tid(2));
+		queue.push(Tid(2));
+
+		assert_eq!(drain(&mut queue), [2, 1]);
+	}
+
+	#[test]
+	fn boost_resets_levels() {
+		let mut queue = run_queue(&[(1, Priority::Normal), (2, Priority::Normal)]);
+		queue.demote(Tid(1));
+		for _ in 1..BOOST_INTERVAL {
+			queue.demote(Tid(2));
+		}
+
+		// Both threads are back on the top level, in the order they were queued
+		assert_eq!(drain(&mut queue), [1, 2]);
+	}
+
+	#[test]
+	fn set_priority_moves_queued_thread() {
+		let mut queue = run_queue(&[(1, Priority::Normal), (2, Priority::Normal)]);
+		assert!(queue.set_priority(Tid(2), Priority::High));
+		assert!(!queue.set_priority(Tid(3), Priority::High));
+
+		assert_eq!(drain(&mut queue), [2, 1]);
+	}
+
+	#[test]
+	fn removed_thread_is_not_picked() {
+		let mut queue = run_queue(&[(1, Priority::Normal), (2, Priority::Normal)]);
+		queue.remove(Tid(1));
+
+		assert_eq!(drain(&mut queue), [2]);
+	}
+
+	fn test_thread(priority: Priority) -> ThreadControlBlock {
+		ThreadControlBlock::new(
+			Cow::Borrowed("test"),
+			AddressSpace::new().unwrap(),
+			NonZeroUsize::new(1).unwrap(),
+			priority,
+			|| {},
+			Box::new(Box::new(|| {}))
+		).unwrap()
+	}
+
+	/// Creates a scheduler with an idle thread, and a normal priority thread that it is pretending to run
+	fn scheduler() -> Scheduler {
+		let mut scheduler = Scheduler::new();
+		scheduler.set_idle_task(test_thread(Priority::Low));
+
+		let current_tid = scheduler.add_task(test_thread(Priority::Normal));
+		assert_eq!(scheduler.run_queue.pop(), Some(current_tid));
+		scheduler.current_tid = current_tid;
+		scheduler.tasks.get_mut(&current_tid).unwrap().state = ThreadState::Running;
+		scheduler
+	}
+
+	#[test]
+	fn idle_is_not_picked_while_threads_are_ready() {
+		let mut scheduler = scheduler();
+		let current_tid = scheduler.current_tid;
+		scheduler.tasks.get_mut(&current_tid).unwrap().state = ThreadState::Blocked;
+
+		for priority in [Priority::Low, Priority::Normal, Priority::High] {
+			let tid = scheduler.add_task(test_thread(priority));
+			for _ in 0..FEEDBACK_LEVELS {
+				scheduler.run_queue.demote(tid);
+			}
+
+			assert!(!scheduler.run_queue.is_empty());
+			assert_eq!(scheduler.next_tid(), Some(tid));
+		}
+
+		assert!(scheduler.run_queue.is_empty());
+		assert_eq!(scheduler.next_tid(), scheduler.idle_tid);
+	}
+
+	#[test]
+	fn running_thread_is_queued_before_picking() {
+		let mut scheduler = scheduler();
+		let current_tid = scheduler.current_tid;
+
+		// Nothing outranks the current thread, so it carries on
+		let low = scheduler.add_task(test_thread(Priority::Low));
+		assert_eq!(scheduler.next_tid(), None);
+
+		// A ready thread of the same priority gets a turn first, with the current thread queued behind it
+		let peer = scheduler.add_task(test_thread(Priority::Normal));
+		assert_eq!(scheduler.next_tid(), Some(peer));
+		assert_eq!(drain(&mut scheduler.run_queue), [current_tid.0, low.0]);
+	}
+
+	#[test]
+	fn higher_priority_thread_is_picked_over_running_thread() {
+		let mut scheduler = scheduler();
+		let current_tid = scheduler.current_tid;
+
+		let high = scheduler.add_task(test_thread(Priority::High));
+		assert_eq!(scheduler.next_tid(), Some(high));
+		assert_eq!(drain(&mut scheduler.run_queue), [current_tid.0]);
+	}
+
+	/// Pretends to switch to `tid`, without running it
+	fn switch_to(scheduler: &mut Scheduler, tid: Tid) {
+		scheduler.run_queue.unqueue(tid);
+		scheduler.current_tid = tid;
+		scheduler.tasks.get_mut(&tid).unwrap().state = ThreadState::Running;
+	}
+
+	#[test]
+	fn joiner_is_woken_once() {
+		let mut scheduler = scheduler();
+		let joiner = scheduler.current_tid;
+		let target = scheduler.add_task(test_thread(Priority::Normal));
+
+		// A joiner that is woken spuriously joins again
+		scheduler.join(target);
+		scheduler.join(target);
+		assert_eq!(scheduler.tasks[&joiner].state, ThreadState::Blocked);
+
+		switch_to(&mut scheduler, target);
+		scheduler.exit_current();
+		assert_eq!(scheduler.tasks[&joiner].state, ThreadState::Ready);
+		assert_eq!(scheduler.run_queue.pop(), Some(joiner));
+		assert_eq!(scheduler.run_queue.pop(), None);
+	}
+
+	#[test]
+	fn reap_keeps_current_thread() {
+		let mut scheduler = scheduler();
+		let exiting = scheduler.current_tid;
+		scheduler.exit_current();
+
+		scheduler.reap();
+		assert!(scheduler.tasks.contains_key(&exiting));
+		assert!(scheduler.has_exited(exiting));
+	}
+
+	#[test]
+	fn reaped_thread_stays_exited() {
+		let mut scheduler = scheduler();
+		let exiting = scheduler.current_tid;
+		let next = scheduler.add_task(test_thread(Priority::Normal));
+		scheduler.exit_current();
+
+		switch_to(&mut scheduler, next);
+		scheduler.reap();
+		assert!(!scheduler.tasks.contains_key(&exiting));
+		assert!(scheduler.has_exited(exiting));
+		assert!(!scheduler.has_exited(next));
+	}
 }
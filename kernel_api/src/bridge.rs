@@ -17,6 +17,7 @@ pub mod panic {
 	extern "Rust" {
 		pub fn __popcorn_panic_handler(info: &PanicInfo) -> !;
 		pub fn __popcorn_backtrace();
+		pub fn __popcorn_backtrace_capture(frames: &mut [usize]) -> usize;
 		pub fn __popcorn_is_panicking() -> bool;
 	}
 }
@@ -29,11 +30,30 @@ pub mod hal {
 	}
 }
 
+pub mod threading {
+	use alloc::borrow::Cow;
+	use alloc::boxed::Box;
+	use core::num::NonZeroUsize;
+	use crate::memory::AllocError;
+	use crate::threading::Priority;
+
+	extern "Rust" {
+		pub fn __popcorn_preempt_disable();
+		pub fn __popcorn_preempt_enable();
+		pub fn __popcorn_thread_spawn(name: Cow<'static, str>, stack_pages: NonZeroUsize, priority: Priority, main: Box<dyn FnOnce() + Send>) -> Result<usize, AllocError>;
+		pub fn __popcorn_thread_join(tid: usize);
+		pub fn __popcorn_thread_yield();
+		pub fn __popcorn_thread_current() -> usize;
+		pub fn __popcorn_thread_set_priority(tid: usize, priority: Priority) -> bool;
+	}
+}
+
 pub mod paging {
 	use core::marker::PhantomData;
 	use core::ops::DerefMut;
 	use crate::memory::{Frame, Page, PhysicalAddress, VirtualAddress, AllocError};
 	use crate::memory::allocator::{BackingAllocator};
+	use crate::memory::mapping::{CacheMode, PageSize, Protection};
 	use crate::sync::RwWriteGuard;
 
 	// FIXME: replace with extern type when alignment can be specified
@@ -45,8 +65,12 @@ pub mod paging {
 
 		pub fn __popcorn_paging_ktable_translate_page(this: &KTable, page: Page) -> Option<Frame>;
 		pub fn __popcorn_paging_ktable_translate_address(this: &KTable, addr: VirtualAddress) -> Option<PhysicalAddress>;
-		pub fn __popcorn_paging_ktable_map_page(this: &mut KTable, page: Page, frame: Frame) -> Result<(), MapPageError>;
+		pub fn __popcorn_paging_ktable_map_page(this: &mut KTable, page: Page, frame: Frame, protection: Protection, cache: CacheMode) -> Result<(), MapPageError>;
 		pub fn __popcorn_paging_ktable_unmap_page(this: &mut KTable, page: Page) -> Result<(), ()>;
+		pub fn __popcorn_paging_ktable_protect_page(this: &mut KTable, page: Page, protection: Protection) -> Result<(), ()>;
+		pub fn __popcorn_paging_ktable_map_huge_page(this: &mut KTable, page: Page, frame: Frame, size: PageSize, protection: Protection, cache: CacheMode) -> Result<(), MapPageError>;
+		pub fn __popcorn_paging_ktable_unmap_huge_page(this: &mut KTable, page: Page, size: PageSize) -> Result<(), ()>;
+		pub fn __popcorn_paging_ktable_protect_huge_page(this: &mut KTable, page: Page, size: PageSize, protection: Protection) -> Result<(), ()>;
 	}
 
 	pub unsafe fn __popcorn_paging_get_ktable() -> impl DerefMut<Target = KTable> {
@@ -60,7 +84,9 @@ pub mod paging {
 	#[derive(Debug, Copy, Clone)]
 	pub enum MapPageError {
 		AllocError,
-		AlreadyMapped
+		AlreadyMapped,
+		/// The CPU can't map pages of the requested size
+		UnsupportedSize
 	}
 
 	#[doc(hidden)]
@@ -72,13 +98,45 @@ pub mod paging {
 }
 
 pub mod memory {
+	use core::alloc::Layout;
+	use core::marker::PhantomData;
+	use core::ops::Range;
+	use core::ptr::NonNull;
+	use crate::memory::allocator::BackingAllocator;
+	use crate::memory::{AllocError, Frame, Page};
+	use crate::memory::cache::{Hooks, Placement, Stats};
+	use crate::memory::mapping::{CacheMode, Protection};
 	use crate::memory::physical::GlobalAllocator;
 
+	// FIXME: replace with extern type when alignment can be specified
+	#[repr(align(8))]
+	pub struct RawCache((), PhantomData<RawCacheInner>);
+
 	extern "Rust" {
+		type RawCacheInner;
+
 		#[link_name = "__popcorn_memory_physical_highmem"]
 		pub static GLOBAL_HIGHMEM: GlobalAllocator;
 
 		#[link_name = "__popcorn_memory_physical_dmamem"]
 		pub static GLOBAL_DMA: GlobalAllocator;
+
+		pub fn __popcorn_memory_lazy_register(pages: Range<Page>, allocator: &'static dyn BackingAllocator, protection: Protection, cache: CacheMode) -> Result<(), ()>;
+		pub fn __popcorn_memory_lazy_unregister(start: Page);
+		pub fn __popcorn_memory_lazy_protect(start: Page, protection: Protection);
+
+		pub fn __popcorn_memory_physical_share(frame: Frame) -> Result<(), ()>;
+		pub fn __popcorn_memory_physical_release(frame: Frame) -> bool;
+
+		pub fn __popcorn_memory_cow_register(pages: Range<Page>, allocator: &'static dyn BackingAllocator, protection: Protection, cache: CacheMode) -> Result<(), ()>;
+		pub fn __popcorn_memory_cow_unregister(start: Page);
+		pub fn __popcorn_memory_cow_protect(start: Page, protection: Protection);
+
+		pub fn __popcorn_memory_cache_create(name: &'static str, layout: Layout, placement: Placement, hooks: Hooks) -> Result<NonNull<RawCache>, AllocError>;
+		pub fn __popcorn_memory_cache_destroy(cache: NonNull<RawCache>);
+		pub fn __popcorn_memory_cache_allocate(cache: &RawCache) -> Result<NonNull<u8>, AllocError>;
+		pub fn __popcorn_memory_cache_deallocate(cache: &RawCache, ptr: NonNull<u8>);
+		pub fn __popcorn_memory_cache_shrink(cache: &RawCache) -> usize;
+		pub fn __popcorn_memory_cache_stats(cache: &RawCache) -> Stats;
 	}
 }
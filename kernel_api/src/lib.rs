@@ -28,4 +28,7 @@ pub mod sync;
 #[cfg(all(not(feature = "use_std"), feature = "full"))]
 pub mod bridge;
 
+#[cfg(all(not(feature = "use_std"), feature = "full"))]
+pub mod threading;
+
 pub mod ptr;
@@ -49,15 +49,163 @@ impl AllocationMeta {
     }
 }
 
+/// The frames of a non-contiguous allocation, as a list of physically contiguous runs
+///
+/// Runs are stored inline rather than on the heap, since the heap may itself be allocating physical memory.
+#[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+#[derive(Debug, Clone)]
+pub struct ScatterList {
+    runs: [(Frame, usize); ScatterList::CAPACITY],
+    len: usize
+}
+
+impl ScatterList {
+    /// The maximum number of runs in a list
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub const CAPACITY: usize = 16;
+
+    /// Creates a list with no frames
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub const fn new() -> Self {
+        Self {
+            runs: [(Frame::zero(), 0); Self::CAPACITY],
+            len: 0
+        }
+    }
+
+    /// Appends `run` to the end of the list, merging it with the last run if they are physically adjacent
+    ///
+    /// Fails if `run` is a new run and the list is already full.
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub fn push(&mut self, run: Range<Frame>) -> Result<(), ()> {
+        let run_len = run.end - run.start;
+        if run_len == 0 { return Ok(()); }
+
+        if let Some((base, len)) = self.runs[..self.len].last_mut() {
+            if *base + *len == run.start {
+                *len += run_len;
+                return Ok(());
+            }
+        }
+
+        if self.len == Self::CAPACITY { return Err(()); }
+        self.runs[self.len] = (run.start, run_len);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns an iterator over the physically contiguous runs in the list, in the order they were added
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub fn runs(&self) -> impl Iterator<Item = Range<Frame>> + '_ {
+        self.runs[..self.len].iter().map(|&(base, len)| base..(base + len))
+    }
+
+    /// Returns an iterator over every frame in the list
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub fn frames(&self) -> impl Iterator<Item = Frame> + '_ {
+        self.runs().flatten()
+    }
+
+    /// Returns the total number of frames in the list
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub fn frame_count(&self) -> usize {
+        self.runs[..self.len].iter().map(|&(_, len)| len).sum()
+    }
+
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Deallocates every run in the list
+    ///
+    /// # Safety
+    ///
+    /// Every frame in the list must have been allocated from `allocator`, and must not be used afterwards
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    pub unsafe fn deallocate(&self, allocator: &(impl BackingAllocator + ?Sized)) {
+        for run in self.runs() {
+            let len = NonZeroUsize::new(run.end - run.start).expect("Runs are never empty");
+            unsafe { allocator.deallocate_contiguous(run.start, len); }
+        }
+    }
+}
+
+#[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+impl From<Range<Frame>> for ScatterList {
+    fn from(run: Range<Frame>) -> Self {
+        let mut list = Self::new();
+        list.push(run).expect("Empty list cannot be full");
+        list
+    }
+}
+
+#[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+impl IntoIterator for ScatterList {
+    type Item = Frame;
+    type IntoIter = ScatterListFrames;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ScatterListFrames { list: self, run: 0, offset: 0 }
+    }
+}
+
+/// An iterator over the frames in a [`ScatterList`]
 #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
-pub type AllocateNonContiguousRet = impl IntoIterator<Item = Frame>;
+#[derive(Debug, Clone)]
+pub struct ScatterListFrames {
+    list: ScatterList,
+    run: usize,
+    offset: usize
+}
+
+#[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+impl Iterator for ScatterListFrames {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let &(base, len) = self.list.runs[..self.list.len].get(self.run)?;
+        let frame = base + self.offset;
+
+        self.offset += 1;
+        if self.offset == len {
+            self.run += 1;
+            self.offset = 0;
+        }
+        Some(frame)
+    }
+}
+
+/// A snapshot of how much of a [`BackingAllocator`]'s memory is in use
+#[unstable(feature = "kernel_memory_stats", issue = "none")]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// The number of frames the allocator can hand out, whether or not they are currently allocated
+    pub total_frames: usize,
+    /// The number of frames that aren't currently allocated
+    pub free_frames: usize,
+    /// The length of the longest run of free frames, which is the largest contiguous allocation that can succeed
+    pub largest_free_run: usize
+}
+
+#[unstable(feature = "kernel_memory_stats", issue = "none")]
+impl Stats {
+    /// The number of frames that are currently allocated
+    #[unstable(feature = "kernel_memory_stats", issue = "none")]
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
 
-const _: () = {
-    fn dummy() -> AllocateNonContiguousRet {
-        fn f() -> Range<Frame> { unimplemented!() }
-        f()
+    /// Combines the stats of two allocators, as if they were a single allocator
+    #[unstable(feature = "kernel_memory_stats", issue = "none")]
+    pub fn combine(self, other: Self) -> Self {
+        Self {
+            total_frames: self.total_frames + other.total_frames,
+            free_frames: self.free_frames + other.free_frames,
+            largest_free_run: self.largest_free_run.max(other.largest_free_run)
+        }
     }
-};
+}
 
 /// An allocator that managed physical memory
 ///
@@ -68,26 +216,51 @@ const _: () = {
 pub unsafe trait BackingAllocator: Send + Sync {
     // (Bitmap, Buddy, Watermark, ...)
 
-    // UNRESOLVED: how should errors work into this - does it error early and somehow figure out ahead of time if there's enough free space for the entire allocate, or does the allocation itself happen lazily and so an alloc error can happen on each iteration. If the latter, what happens if you call next() after getting an alloc error?
-    // Allocates `frame_count` frames of physical memory, not necessarily contiguously.
-    // Returns an iterator over the allocated frames.
-    // It is undecided for now whether allocation should be done upfront or lazily as the iterator is polled.
-    //
-    // Implementations should only implement this when there is a fast path compared to [`allocate_contiguous`](Self::allocate_contiguous).
-    // Otherwise the default implementation will use [`allocate_contiguous`](Self::allocate_contiguous).
-    /*fn allocate(&self, frame_count: usize) -> impl Iterator<Item = Result<Frame, AllocError>> where Self: Sized {
-        gen move {
-            let base = self.allocate_contiguous(frame_count);
-            if let Ok(base) = base {
-                for i in 0..frame_count { yield Ok(base + i); }
-            } else { yield Err(AllocError); }
+    /// Allocates `frame_count` frames of physical memory, not necessarily contiguously
+    ///
+    /// Allocation is all or nothing: either every frame is allocated and described by the returned [`ScatterList`],
+    /// or nothing is allocated and an error is returned. This also fails if the frames can't be described in
+    /// [`ScatterList::CAPACITY`] runs, in which case the caller can retry with fewer frames.
+    ///
+    /// The frames are deallocated with [`deallocate_contiguous`](Self::deallocate_contiguous), either a run at a time
+    /// or in smaller pieces.
+    ///
+    /// The default implementation tries [`allocate_contiguous`](Self::allocate_contiguous) first, then falls back to
+    /// allocating runs of half the size until enough frames are allocated.
+    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
+    fn allocate(&self, frame_count: usize) -> Result<ScatterList, AllocError> {
+        if frame_count == 0 { return Ok(ScatterList::new()); }
+
+        if let Ok(base) = self.allocate_contiguous(frame_count) {
+            return Ok(ScatterList::from(base..(base + frame_count)));
         }
-    }*/
 
-    #[unstable(feature = "kernel_physical_allocator_non_contiguous", issue = "none")]
-    fn allocate(&self, frame_count: usize) -> Result<AllocateNonContiguousRet, AllocError> {
-        let base = self.allocate_contiguous(frame_count)?;
-        Ok(base..(base + frame_count))
+        let mut list = ScatterList::new();
+        let mut remaining = frame_count;
+        let mut run_len = frame_count.div_ceil(2);
+
+        while remaining > 0 {
+            let len = run_len.min(remaining);
+            match self.allocate_contiguous(len) {
+                Ok(base) => {
+                    if list.push(base..(base + len)).is_err() {
+                        unsafe {
+                            self.deallocate_contiguous(base, NonZeroUsize::new(len).unwrap());
+                            list.deallocate(self);
+                        }
+                        return Err(AllocError);
+                    }
+                    remaining -= len;
+                }
+                Err(_) if run_len > 1 => run_len /= 2,
+                Err(e) => {
+                    unsafe { list.deallocate(self); }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(list)
     }
 
     /// Allocates a contiguous range of physical memory
@@ -132,6 +305,15 @@ pub unsafe trait BackingAllocator: Send + Sync {
         }
     }
 
+    /// Allocates a single zeroed [`Frame`], failing instead of waiting if the allocator is locked
+    ///
+    /// This is for page fault handlers, which may have interrupted code on the same CPU that holds the lock. The default
+    /// implementation can't tell whether the allocator is locked, so allocators with a lock should override it.
+    #[unstable(feature = "kernel_allocation_zeroing", issue = "2")]
+    fn allocate_zeroed_nowait(&self) -> Result<Frame, AllocError> {
+        self.allocate_zeroed(1)
+    }
+
     /// # Safety
     /// Must be deallocated with the same allocator that made the allocation
     #[stable(feature = "kernel_core_api", since = "0.1.0")]
@@ -142,6 +324,28 @@ pub unsafe trait BackingAllocator: Send + Sync {
 
     #[unstable(feature = "kernel_physical_allocator_location", issue = "none")]
     fn allocate_at(&self, frame_count: usize, location: SpecificLocation) -> Result<Frame, AllocError>;
+
+    /// Gives the allocator free memory that it didn't manage when it was created, such as memory the bootloader was
+    /// using
+    ///
+    /// Fails without adding anything if any part of `region` is outside the allocator's allocation range, or if the
+    /// allocator can't take on new memory.
+    ///
+    /// # Safety
+    ///
+    /// `region` must not be in use, and no part of it can already be managed by an allocator
+    #[unstable(feature = "kernel_physical_allocator_reclaim", issue = "none")]
+    unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> { Err(()) }
+
+    /// Returns how much of the allocator's memory is in use, or `None` if it doesn't keep track
+    #[unstable(feature = "kernel_memory_stats", issue = "none")]
+    fn stats(&self) -> Option<Stats> { None }
+
+    /// Calls `f` with the range and [`Stats`] of each region of memory the allocator manages separately
+    ///
+    /// Allocators that don't keep track of their memory by region don't call `f` at all.
+    #[unstable(feature = "kernel_memory_stats", issue = "none")]
+    fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, Stats)) {}
 }
 
 #[unstable(feature = "kernel_allocation_new", issue = "5")]
@@ -169,7 +373,7 @@ pub enum Location {
 #[unstable(feature = "kernel_physical_allocator_location", issue = "none")]
 pub enum AlignError {
     OomError,
-    Unaligned(AllocateNonContiguousRet)
+    Unaligned(ScatterList)
 }
 
 pub mod new_allocator {
@@ -188,21 +392,31 @@ pub mod new_allocator {
         }
     }
 
+    /// Zeroes `memory` without pulling it into the cache
+    ///
+    /// This is intended for memory that won't be used soon, such as frames being zeroed in the background.
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support SSE2
     #[cfg(target_arch = "x86_64")]
     #[target_feature(enable = "sse,sse2")]
-    unsafe fn fast_zero_memory_sse_nocache(memory: &mut [u8]) {
+    pub unsafe fn fast_zero_memory_sse_nocache(memory: &mut [u8]) {
         let (prologue, aligned, epilogue) = unsafe { memory.align_to_mut::<u128>() };
         fast_zero_memory_stos(prologue);
         unsafe {
             asm!(
             "xorps {0}, {0}",
-            "jmp 2f",
+            "test {1}, {1}",
+            "jz 2f",
             "3:",
             "movntdq [{2}], {0}",
             "add {2}, 16",
             "dec {1}",
-            "2:",
             "jnz 3b",
+            "2:",
+            // Non-temporal stores are weakly ordered, so make sure they are visible before the memory is handed out
+            "sfence",
             out (xmm_reg) _,
             inout (reg) aligned.len() => _,
             inout (reg) aligned.as_mut_ptr() => _,
@@ -247,3 +461,43 @@ pub mod new_allocator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::PhysicalAddress;
+
+    fn frame(n: usize) -> Frame {
+        Frame::new(PhysicalAddress::new(n * PAGE_SIZE))
+    }
+
+    #[test]
+    fn adjacent_runs_merge() {
+        let mut list = ScatterList::new();
+        list.push(frame(1)..frame(3)).unwrap();
+        list.push(frame(3)..frame(4)).unwrap();
+        list.push(frame(8)..frame(9)).unwrap();
+
+        assert!(list.runs().eq([frame(1)..frame(4), frame(8)..frame(9)]));
+        assert_eq!(list.frame_count(), 4);
+    }
+
+    #[test]
+    fn full_list_rejects_new_runs() {
+        let mut list = ScatterList::new();
+        for i in 0..ScatterList::CAPACITY {
+            list.push(frame(i * 2)..frame(i * 2 + 1)).unwrap();
+        }
+
+        assert!(list.push(frame(100)..frame(101)).is_err());
+        assert!(list.push(frame(ScatterList::CAPACITY * 2 - 1)..frame(ScatterList::CAPACITY * 2)).is_ok());
+    }
+
+    #[test]
+    fn frames_are_in_order() {
+        let mut list = ScatterList::from(frame(5)..frame(7));
+        list.push(frame(2)..frame(3)).unwrap();
+
+        assert!(list.into_iter().eq([frame(5), frame(6), frame(2)]));
+    }
+}
@@ -0,0 +1,265 @@
+//! Provides typed caches for objects that are frequently allocated and freed
+//!
+//! An [`ObjectCache`] carves equally sized objects out of slabs of memory, so allocating from it is cheaper than
+//! going through the [heap](super::heap). Caches created with [`ObjectCache::new_constructed`] also keep their free
+//! objects constructed, so that expensive initialisation only happens once per object rather than once per allocation.
+
+#![unstable(feature = "kernel_object_cache", issue = "none")]
+
+use core::alloc::Layout;
+use core::fmt::{Debug, Formatter};
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::ptr::NonNull;
+use crate::bridge::memory::{
+	RawCache,
+	__popcorn_memory_cache_allocate,
+	__popcorn_memory_cache_create,
+	__popcorn_memory_cache_deallocate,
+	__popcorn_memory_cache_destroy,
+	__popcorn_memory_cache_shrink,
+	__popcorn_memory_cache_stats
+};
+use crate::memory::allocator::BackingAllocator;
+use crate::memory::physical::highmem;
+use crate::memory::r#virtual::{Global, VirtualAllocator};
+use super::AllocError;
+
+/// An object that can be kept in its constructed state while it is unused in an [`ObjectCache`]
+pub trait Cacheable: Sized {
+	/// Creates an object in its constructed state
+	///
+	/// This is called for every object in a slab when the slab is created, rather than on every allocation.
+	fn construct() -> Self;
+
+	/// Returns an object that is being freed to its constructed state
+	fn recycle(&mut self) {}
+}
+
+/// Where the slabs of an [`ObjectCache`] are placed
+#[derive(Copy, Clone)]
+pub enum Placement {
+	/// Slabs are mapped into the kernel address space
+	Mapped {
+		/// The allocator for the physical memory of each slab
+		physical_allocator: &'static dyn BackingAllocator,
+		/// The allocator for the virtual memory each slab is mapped into
+		virtual_allocator: &'static dyn VirtualAllocator
+	},
+	/// Slabs are accessed through the physical page map
+	///
+	/// Objects in these caches have a known physical address, and allocating them never touches the page tables, so
+	/// they can be allocated while the page tables are locked.
+	PhysicalMap(&'static dyn BackingAllocator)
+}
+
+impl Default for Placement {
+	fn default() -> Self {
+		Self::Mapped {
+			physical_allocator: highmem(),
+			virtual_allocator: &Global
+		}
+	}
+}
+
+impl Debug for Placement {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::Mapped { .. } => f.write_str("Mapped"),
+			Self::PhysicalMap(_) => f.write_str("PhysicalMap")
+		}
+	}
+}
+
+/// The functions run on untyped objects as their slabs are created and released
+#[doc(hidden)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Hooks {
+	pub constructor: Option<unsafe fn(NonNull<u8>)>,
+	pub destructor: Option<unsafe fn(NonNull<u8>)>
+}
+
+/// Statistics about the objects in an [`ObjectCache`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Stats {
+	/// The size of each object
+	pub object_size: usize,
+	/// The number of objects currently allocated
+	pub in_use: usize,
+	/// The number of objects that can be allocated without creating a new slab
+	pub free: usize,
+	/// The number of slabs owned by the cache
+	pub slabs: usize
+}
+
+/// A cache of objects of type `T`
+///
+/// Objects are allocated as [`CacheBox`]es, which give the object back to the cache when dropped.
+pub struct ObjectCache<T> {
+	raw: NonNull<RawCache>,
+	recycle: Option<unsafe fn(NonNull<u8>)>,
+	_phantom: PhantomData<T>
+}
+
+// SAFETY: the kernel synchronises access to the underlying cache
+unsafe impl<T: Send> Send for ObjectCache<T> {}
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
+
+unsafe fn construct<T: Cacheable>(ptr: NonNull<u8>) {
+	unsafe { ptr.cast::<T>().write(T::construct()); }
+}
+
+unsafe fn destruct<T>(ptr: NonNull<u8>) {
+	unsafe { ptr::drop_in_place(ptr.cast::<T>().as_ptr()); }
+}
+
+unsafe fn recycle<T: Cacheable>(ptr: NonNull<u8>) {
+	unsafe { ptr.cast::<T>().as_mut().recycle(); }
+}
+
+impl<T> ObjectCache<T> {
+	/// Creates a cache whose objects are only initialised when they are allocated
+	///
+	/// `name` identifies the cache in statistics.
+	pub fn new(name: &'static str, placement: Placement) -> Result<Self, AllocError> {
+		Self::with_hooks(name, placement, Hooks::default(), None)
+	}
+
+	fn with_hooks(name: &'static str, placement: Placement, hooks: Hooks, recycle: Option<unsafe fn(NonNull<u8>)>) -> Result<Self, AllocError> {
+		let raw = unsafe { __popcorn_memory_cache_create(name, Layout::new::<T>(), placement, hooks) }?;
+		Ok(Self {
+			raw,
+			recycle,
+			_phantom: PhantomData
+		})
+	}
+
+	fn raw(&self) -> &RawCache {
+		unsafe { self.raw.as_ref() }
+	}
+
+	/// Moves `value` into an object from the cache
+	///
+	/// If the cache keeps its objects constructed, the constructed object is dropped and replaced by `value`, and
+	/// [`Cacheable::recycle`] must be able to return `value` to its constructed state.
+	pub fn allocate(&self, value: T) -> Result<CacheBox<'_, T>, AllocError> {
+		let ptr = unsafe { __popcorn_memory_cache_allocate(self.raw()) }?.cast::<T>();
+		unsafe {
+			if self.recycle.is_some() {
+				*ptr.as_ptr() = value;
+			} else {
+				ptr.write(value);
+			}
+		}
+
+		Ok(CacheBox { ptr, cache: self })
+	}
+
+	/// Gives any unused slabs back to the system, returning the number of slabs released
+	pub fn shrink(&self) -> usize {
+		unsafe { __popcorn_memory_cache_shrink(self.raw()) }
+	}
+
+	/// Returns statistics about the objects in the cache
+	pub fn stats(&self) -> Stats {
+		unsafe { __popcorn_memory_cache_stats(self.raw()) }
+	}
+}
+
+impl<T: Cacheable> ObjectCache<T> {
+	/// Creates a cache whose free objects are kept in their constructed state
+	///
+	/// Objects are created using [`Cacheable::construct`] when their slab is created, returned to their constructed
+	/// state using [`Cacheable::recycle`] when freed, and dropped when their slab is released.
+	pub fn new_constructed(name: &'static str, placement: Placement) -> Result<Self, AllocError> {
+		let hooks = Hooks {
+			constructor: Some(construct::<T>),
+			destructor: Some(destruct::<T>)
+		};
+		Self::with_hooks(name, placement, hooks, Some(recycle::<T>))
+	}
+
+	/// Allocates an object in its constructed state
+	///
+	/// # Panics
+	///
+	/// Panics if the cache wasn't created with [`new_constructed`](Self::new_constructed)
+	pub fn allocate_constructed(&self) -> Result<CacheBox<'_, T>, AllocError> {
+		assert!(self.recycle.is_some(), "Cache does not keep its objects constructed");
+
+		let ptr = unsafe { __popcorn_memory_cache_allocate(self.raw()) }?.cast::<T>();
+		Ok(CacheBox { ptr, cache: self })
+	}
+}
+
+impl<T> Debug for ObjectCache<T> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("ObjectCache")
+				.field("stats", &self.stats())
+				.finish_non_exhaustive()
+	}
+}
+
+impl<T> Drop for ObjectCache<T> {
+	fn drop(&mut self) {
+		unsafe { __popcorn_memory_cache_destroy(self.raw); }
+	}
+}
+
+/// An object allocated from an [`ObjectCache`]
+pub struct CacheBox<'cache, T> {
+	ptr: NonNull<T>,
+	cache: &'cache ObjectCache<T>
+}
+
+// SAFETY: the object is uniquely owned, like a `Box`
+unsafe impl<T: Send> Send for CacheBox<'_, T> {}
+unsafe impl<T: Sync> Sync for CacheBox<'_, T> {}
+
+impl<'cache, T> CacheBox<'cache, T> {
+	/// Consumes the box without giving the object back to the cache
+	pub fn into_raw(this: Self) -> NonNull<T> {
+		ManuallyDrop::new(this).ptr
+	}
+
+	/// # Safety
+	///
+	/// `ptr` must be from a call to [`into_raw`](Self::into_raw) on a box allocated from `cache`
+	pub unsafe fn from_raw(cache: &'cache ObjectCache<T>, ptr: NonNull<T>) -> Self {
+		Self { ptr, cache }
+	}
+}
+
+impl<T> Deref for CacheBox<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { self.ptr.as_ref() }
+	}
+}
+
+impl<T> DerefMut for CacheBox<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { self.ptr.as_mut() }
+	}
+}
+
+impl<T: Debug> Debug for CacheBox<'_, T> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		Debug::fmt(&**self, f)
+	}
+}
+
+impl<T> Drop for CacheBox<'_, T> {
+	fn drop(&mut self) {
+		unsafe {
+			match self.cache.recycle {
+				Some(recycle) => recycle(self.ptr.cast()),
+				None => ptr::drop_in_place(self.ptr.as_ptr())
+			}
+			__popcorn_memory_cache_deallocate(self.cache.raw(), self.ptr.cast());
+		}
+	}
+}
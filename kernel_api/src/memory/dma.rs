@@ -0,0 +1,307 @@
+//! Memory for devices to access directly
+//!
+//! [`DmaBuffer`]s are long lived buffers that are allocated to meet a device's [`Constraints`], and know the physical
+//! address the device should be given. For one-off transfers to or from memory the caller already owns,
+//! [`StreamingMapping`] gives the device the buffer directly if it can reach it, or bounces the data through a
+//! [`DmaSlice`] if it can't.
+//!
+//! DMA is assumed to be cache coherent, so no cache maintenance is done other than mapping buffers with the requested
+//! [`CacheMode`].
+
+#![unstable(feature = "kernel_dma", issue = "none")]
+
+use core::fmt::{Debug, Formatter};
+use core::mem::{align_of, size_of};
+use core::num::{NonZeroU32, NonZeroUsize};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::ptr::NonNull;
+use crate::memory::{AllocError, Frame, PhysicalAddress, VirtualAddress};
+use crate::memory::allocator::BackingAllocator;
+use crate::memory::mapping::{CacheMode, Config, Laziness, Location, Mapping};
+use crate::memory::physical::{dmamem, highmem};
+
+const PAGE_SIZE: usize = 4096;
+
+/// The end of the memory that `dmamem` allocates from
+const FOUR_GIB: usize = 1 << 32;
+
+/// The memory a device is able to access
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Constraints {
+	/// The device can only access memory below this address
+	pub limit: Option<PhysicalAddress>,
+	/// The number of [`Frame`]s the start of the buffer must be aligned to, where `1` means it can start at any byte
+	pub alignment: NonZeroU32,
+	/// The buffer can't cross a multiple of this many bytes, which must be a power of two
+	pub segment_boundary: Option<NonZeroUsize>,
+	/// The caching behaviour of the CPU's mapping of the buffer
+	pub cache: CacheMode
+}
+
+impl Constraints {
+	/// Constraints for a device that can access any memory
+	pub const ANY: Self = Self {
+		limit: None,
+		alignment: match NonZeroU32::new(1) { Some(a) => a, None => unreachable!() },
+		segment_boundary: None,
+		cache: CacheMode::WriteBack
+	};
+
+	/// Constraints for a device that can only access memory below `limit`
+	pub const fn below(limit: PhysicalAddress) -> Self {
+		Self { limit: Some(limit), ..Self::ANY }
+	}
+
+	pub const fn alignment(self, alignment: NonZeroU32) -> Self {
+		Self { alignment, ..self }
+	}
+
+	pub const fn segment_boundary(self, boundary: NonZeroUsize) -> Self {
+		Self { segment_boundary: Some(boundary), ..self }
+	}
+
+	pub const fn cache(self, cache: CacheMode) -> Self {
+		Self { cache, ..self }
+	}
+
+	/// Devices that can only reach the low 4 GiB are given memory from `dmamem`, so that `highmem` isn't used up
+	fn physical_allocator(&self) -> &'static dyn BackingAllocator {
+		match self.limit {
+			Some(limit) if limit.addr <= FOUR_GIB => dmamem(),
+			_ => highmem()
+		}
+	}
+
+	/// The alignment in frames needed for `len` bytes to meet the alignment and segment constraints
+	fn frame_alignment(&self, len: usize) -> Result<NonZeroU32, AllocError> {
+		let Some(boundary) = self.segment_boundary else { return Ok(self.alignment); };
+		if len > boundary.get() { return Err(AllocError); }
+
+		// A power of two sized block aligned to its size can't cross a larger power of two boundary
+		let frames = len.next_power_of_two().div_ceil(PAGE_SIZE);
+		let frames = NonZeroU32::new(frames.try_into().map_err(|_| AllocError)?).unwrap();
+		Ok(frames.max(self.alignment))
+	}
+
+	/// Returns `true` if a device with these constraints can access `len` bytes at `addr`
+	pub fn can_reach(&self, addr: PhysicalAddress, len: usize) -> bool {
+		let Some(end) = addr.addr.checked_add(len) else { return false; };
+
+		let below_limit = self.limit.map_or(true, |limit| end <= limit.addr);
+		let aligned = self.alignment.get() == 1 || addr.addr % (self.alignment.get() as usize * PAGE_SIZE) == 0;
+		let in_segment = self.segment_boundary.map_or(true, |boundary| {
+			len == 0 || addr.addr / boundary.get() == (end - 1) / boundary.get()
+		});
+
+		below_limit && aligned && in_segment
+	}
+
+	fn location(&self, alignment: NonZeroU32) -> Location<Frame> {
+		match self.limit {
+			Some(limit) => Location::Below { location: Frame::new(limit.align_down()), with_alignment: alignment },
+			None if alignment.get() > 1 => Location::Aligned(alignment),
+			None => Location::Any
+		}
+	}
+}
+
+/// A buffer in physically contiguous memory that a device can access
+///
+/// The memory is unmapped and freed when the buffer is dropped, so the device must have finished with it by then.
+pub struct DmaBuffer<T: ?Sized> {
+	mapping: Mapping<'static>,
+	ptr: NonNull<T>
+}
+
+/// A [`DmaBuffer`] holding a slice
+pub type DmaSlice<T> = DmaBuffer<[T]>;
+
+// SAFETY: the buffer uniquely owns its value, like a `Box`
+unsafe impl<T: ?Sized + Send> Send for DmaBuffer<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for DmaBuffer<T> {}
+
+/// Maps enough prefaulted memory for `len` bytes, meeting `constraints`
+fn map(len: usize, align: usize, constraints: &Constraints) -> Result<Mapping<'static>, AllocError> {
+	// Mappings are always page aligned, which is plenty for any reasonable type
+	if align > PAGE_SIZE { return Err(AllocError); }
+
+	let frames = NonZeroUsize::new(len.div_ceil(PAGE_SIZE).max(1)).unwrap();
+	let alignment = constraints.frame_alignment(len)?;
+
+	let mapping = Mapping::new(
+		Config::<crate::memory::r#virtual::Global>::new(frames)
+				.physical_allocator(constraints.physical_allocator())
+				.physical_location(constraints.location(alignment))
+				.laziness(Laziness::Prefault)
+				.cache(constraints.cache)
+	)?;
+
+	debug_assert!(constraints.can_reach(PhysicalAddress::new(mapping.physical_start().start().addr), len));
+	Ok(mapping)
+}
+
+impl<T> DmaBuffer<T> {
+	/// Moves `value` into memory that a device with `constraints` can access
+	pub fn new(value: T, constraints: Constraints) -> Result<Self, AllocError> {
+		let mapping = map(size_of::<T>(), align_of::<T>(), &constraints)?;
+		let ptr = NonNull::new(mapping.virtual_start().as_ptr().cast::<T>()).expect("Mapping was at null");
+		unsafe { ptr.write(value); }
+
+		Ok(Self { mapping, ptr })
+	}
+}
+
+impl<T: Copy> DmaBuffer<[T]> {
+	/// Creates a slice of `len` copies of `value` in memory that a device with `constraints` can access
+	pub fn new_slice(value: T, len: usize, constraints: Constraints) -> Result<Self, AllocError> {
+		let size = size_of::<T>().checked_mul(len).ok_or(AllocError)?;
+		let mapping = map(size, align_of::<T>(), &constraints)?;
+
+		let base = mapping.virtual_start().as_ptr().cast::<T>();
+		for i in 0..len {
+			unsafe { base.add(i).write(value); }
+		}
+
+		let ptr = NonNull::new(ptr::slice_from_raw_parts_mut(base, len)).expect("Mapping was at null");
+		Ok(Self { mapping, ptr })
+	}
+}
+
+impl<T: ?Sized> DmaBuffer<T> {
+	/// The address the device should use to access the buffer
+	pub fn physical_address(&self) -> PhysicalAddress {
+		PhysicalAddress::new(self.mapping.physical_start().start().addr)
+	}
+
+	/// The address the CPU uses to access the buffer
+	pub fn virtual_address(&self) -> VirtualAddress {
+		VirtualAddress::new(self.ptr.as_ptr().cast::<u8>() as usize)
+	}
+}
+
+impl<T: ?Sized> Deref for DmaBuffer<T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { self.ptr.as_ref() }
+	}
+}
+
+impl<T: ?Sized> DerefMut for DmaBuffer<T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { self.ptr.as_mut() }
+	}
+}
+
+impl<T: ?Sized> Debug for DmaBuffer<T> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("DmaBuffer")
+				.field("physical_address", &self.physical_address())
+				.field("mapping", &self.mapping)
+				.finish()
+	}
+}
+
+impl<T: ?Sized> Drop for DmaBuffer<T> {
+	fn drop(&mut self) {
+		// The mapping is dropped afterwards, which unmaps and frees the memory
+		unsafe { ptr::drop_in_place(self.ptr.as_ptr()); }
+	}
+}
+
+/// Which way data moves during a streaming transfer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+	/// The device reads from the buffer
+	ToDevice,
+	/// The device writes to the buffer
+	FromDevice,
+	/// The device both reads from and writes to the buffer
+	Bidirectional
+}
+
+/// A caller's buffer made available to a device for a single transfer
+///
+/// If the device can't reach the buffer, because it isn't physically contiguous or doesn't meet the device's
+/// [`Constraints`], the transfer goes through a bounce buffer instead. Data the device wrote is copied back to the
+/// caller's buffer when this is dropped, so the transfer must have finished by then.
+pub struct StreamingMapping<'buf> {
+	buffer: &'buf mut [u8],
+	direction: Direction,
+	bounce: Option<DmaSlice<u8>>
+}
+
+impl<'buf> StreamingMapping<'buf> {
+	/// Makes `buffer` available to a device with `constraints`
+	///
+	/// `buffer` must be in kernel memory that is mapped for the whole transfer.
+	pub fn new(buffer: &'buf mut [u8], direction: Direction, constraints: Constraints) -> Result<Self, AllocError> {
+		if physical_address_of(buffer).is_some_and(|addr| constraints.can_reach(addr, buffer.len())) {
+			return Ok(Self { buffer, direction, bounce: None });
+		}
+
+		let mut bounce = DmaSlice::new_slice(0u8, buffer.len(), constraints)?;
+		if direction != Direction::FromDevice {
+			bounce.copy_from_slice(buffer);
+		}
+
+		Ok(Self { buffer, direction, bounce: Some(bounce) })
+	}
+
+	/// The address the device should use for the transfer
+	pub fn physical_address(&self) -> PhysicalAddress {
+		match self.bounce {
+			Some(ref bounce) => bounce.physical_address(),
+			None => physical_address_of(self.buffer).expect("Buffer was reachable when it was mapped")
+		}
+	}
+
+	/// Returns `true` if the transfer goes through a bounce buffer
+	pub fn is_bounced(&self) -> bool {
+		self.bounce.is_some()
+	}
+
+	pub fn len(&self) -> usize {
+		self.buffer.len()
+	}
+}
+
+impl Debug for StreamingMapping<'_> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("StreamingMapping")
+				.field("physical_address", &self.physical_address())
+				.field("len", &self.len())
+				.field("direction", &self.direction)
+				.field("bounced", &self.is_bounced())
+				.finish()
+	}
+}
+
+impl Drop for StreamingMapping<'_> {
+	fn drop(&mut self) {
+		if self.direction == Direction::ToDevice { return; }
+		if let Some(ref bounce) = self.bounce {
+			self.buffer.copy_from_slice(bounce);
+		}
+	}
+}
+
+/// Returns the physical address of `buffer`, if it is physically contiguous
+fn physical_address_of(buffer: &[u8]) -> Option<PhysicalAddress> {
+	let start = buffer.as_ptr() as usize;
+	let page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+	let translate = |addr: usize| unsafe {
+		crate::bridge::paging::__popcorn_paging_ktable_translate_address(&page_table, VirtualAddress::new(addr))
+	};
+
+	let physical_start = translate(start)?;
+	let mut page = start - start % PAGE_SIZE;
+	while page + PAGE_SIZE < start + buffer.len() {
+		page += PAGE_SIZE;
+		let expected = physical_start.addr + (page - start);
+		if translate(page)?.addr != expected { return None; }
+	}
+
+	Some(physical_start)
+}
@@ -8,6 +8,15 @@ use core::ptr;
 use core::ptr::NonNull;
 use super::AllocError;
 
+/// A snapshot of how much memory a [`Heap`] is using
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// The number of pages the heap has mapped
+    pub mapped_pages: usize,
+    /// The number of bytes currently allocated, rounded up to the granularity the heap allocates in
+    pub allocated_bytes: usize
+}
+
 /// A heap manager
 pub trait Heap {
     /// Creates a new instance of the heap manager
@@ -43,7 +52,11 @@ pub trait Heap {
         let new = self.allocate(new_layout)?;
         unsafe {
             ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr(), min(old_layout.size(), new_size));
+            self.deallocate(ptr, old_layout);
         }
         Ok(new)
     }
+
+    /// Returns how much memory the heap is using, or `None` if it doesn't keep track
+    fn stats(&self) -> Option<Stats> { None }
 }
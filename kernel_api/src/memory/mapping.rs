@@ -12,12 +12,16 @@
 
 #![unstable(feature = "kernel_mmap", issue = "24")]
 
+use core::cmp::Ordering;
 use core::fmt::{Debug, Formatter};
 use core::marker::PhantomData;
+use core::mem;
 use core::mem::ManuallyDrop;
 use core::num::{NonZeroU32, NonZeroUsize};
+use core::ops::Range;
 use core::ptr;
 use log::debug;
+use crate::bridge::paging::{KTable, MapPageError};
 use crate::memory::allocator::{AllocationMeta, BackingAllocator, SpecificLocation, ZeroAllocError};
 use crate::memory::{AllocError, Frame, Page};
 use crate::memory::physical::{OwnedFrames, highmem};
@@ -57,9 +61,21 @@ unsafe impl BackingAllocator for Highmem {
 		highmem().allocate_at(frame_count, location)
 	}
 
-	fn allocate(&self, frame_count: usize) -> Result<crate::memory::allocator::AllocateNonContiguousRet, AllocError> {
+	fn allocate(&self, frame_count: usize) -> Result<crate::memory::allocator::ScatterList, AllocError> {
 		highmem().allocate(frame_count)
 	}
+
+	fn stats(&self) -> Option<crate::memory::allocator::Stats> {
+		highmem().stats()
+	}
+
+	fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, crate::memory::allocator::Stats)) {
+		highmem().region_stats(f)
+	}
+
+	unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+		unsafe { highmem().add_region(region) }
+	}
 }
 
 /// An owned region of memory
@@ -91,7 +107,7 @@ impl<A: BackingAllocator> OldMapping<A> {
 		// TODO: huge pages
 		let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
 		for (frame, page) in (0..len).map(|i| (physical_mem + i, virtual_mem + i)) {
-			unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame) }
+			unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame, Protection::RW, CacheMode::WriteBack) }
 					.expect("todo");
 		}
 
@@ -115,15 +131,24 @@ impl OldMapping<Highmem> {
 		let original_physical_allocator = self.allocator;
 
 		if new_len < self.len {
-			// todo: actually free and unmap the extra memory
+			let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+			for page in (new_len..self.len).map(|i| self.base + i) {
+				let frame = unsafe { crate::bridge::paging::__popcorn_paging_ktable_translate_page(&page_table, page) }
+						.expect("Mapping memory is always mapped");
+				unsafe {
+					crate::bridge::paging::__popcorn_paging_ktable_unmap_page(&mut page_table, page).expect("Page was just translated");
+					self.allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap());
+				}
+			}
+			drop(page_table);
 
+			Global.deallocate_contiguous(self.base + new_len, self.len - new_len);
 			self.len = new_len;
 			Ok(())
 		} else {
 			let extra_len = new_len - self.len;
 
 			debug!("allocating extra physical memory");
-			// fixme: physical mem leak
 			let extra_physical_mem = original_physical_allocator.allocate_contiguous(extra_len).map_err(|_| None)?;
 			debug!("allocating extra virtual memory");
 			let extra_virtual_mem = Global.allocate_contiguous_at(self.base + self.len, extra_len);
@@ -136,7 +161,7 @@ impl OldMapping<Highmem> {
 					let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
 
 					for (frame, page) in (0..extra_len).map(|i| (extra_physical_mem + i, start_of_extra + i)) {
-						unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame) }
+						unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame, Protection::RW, CacheMode::WriteBack) }
 								.expect("todo");
 					}
 
@@ -150,7 +175,12 @@ impl OldMapping<Highmem> {
 
 	pub fn resize_in_place(&mut self, new_len: usize) -> Result<(), AllocError> {
 		self.resize_inner(new_len)
-				.map_err(|_| AllocError)
+				.map_err(|extra_physical_mem| {
+					if let Some(extra_physical_mem) = extra_physical_mem {
+						unsafe { self.allocator.deallocate_contiguous(extra_physical_mem, NonZeroUsize::new(new_len - self.len).unwrap()); }
+					}
+					AllocError
+				})
 	}
 
 	pub fn resize(&mut self, new_len: usize) -> Result<(), AllocError> {
@@ -164,18 +194,31 @@ impl OldMapping<Highmem> {
 				let original_physical_allocator = self.allocator;
 
 				let extra_len = new_len - self.len;
-				let new_virtual_mem = Global.allocate_contiguous(new_len)?;
+				let new_virtual_mem = match Global.allocate_contiguous(new_len) {
+					Ok(new_virtual_mem) => new_virtual_mem,
+					Err(e) => {
+						unsafe { original_physical_allocator.deallocate_contiguous(extra_physical_mem, NonZeroUsize::new(extra_len).unwrap()); }
+						return Err(e);
+					}
+				};
 
 				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
 
-				let physical_base: Frame = todo!();
-				for (frame, page) in (0..self.len).map(|i| (physical_base + i, new_virtual_mem + i)) {
-					unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame) }.expect("todo");
+				// The original memory may not be physically contiguous after earlier resizes, so it is moved page by page
+				for (old_page, page) in (0..self.len).map(|i| (self.base + i, new_virtual_mem + i)) {
+					let frame = unsafe { crate::bridge::paging::__popcorn_paging_ktable_translate_page(&page_table, old_page) }
+							.expect("Mapping memory is always mapped");
+					unsafe {
+						crate::bridge::paging::__popcorn_paging_ktable_unmap_page(&mut page_table, old_page).expect("Page was just translated");
+						crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame, Protection::RW, CacheMode::WriteBack).expect("todo");
+					}
 				}
 				for (frame, page) in (0..extra_len).map(|i| (extra_physical_mem + i, new_virtual_mem + self.len + i)) {
-					unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame) }.expect("todo");
+					unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame, Protection::RW, CacheMode::WriteBack) }.expect("todo");
 				}
+				drop(page_table);
 
+				Global.deallocate_contiguous(self.base, self.len);
 				self.base = new_virtual_mem;
 				self.len = new_len;
 
@@ -237,8 +280,8 @@ impl OldMapping<Highmem> {
 	}*/
 
 	pub fn into_raw_parts(self) -> (Page, usize) {
-		let Self { base, len, .. } = self;
-		(base, len)
+		let this = ManuallyDrop::new(self);
+		(this.base, this.len)
 	}
 
 	pub unsafe fn from_raw_parts(base: Page, len: usize) -> Self {
@@ -260,7 +303,21 @@ impl OldMapping<Highmem> {
 
 impl<A: BackingAllocator> Drop for OldMapping<A> {
 	fn drop(&mut self) {
-		// todo
+		if self.len == 0 { return; }
+
+		// Resizing can leave the memory physically discontiguous, so each frame is freed separately
+		let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+		for page in (0..self.len).map(|i| self.base + i) {
+			let frame = unsafe { crate::bridge::paging::__popcorn_paging_ktable_translate_page(&page_table, page) }
+					.expect("Mapping memory is always mapped");
+			unsafe {
+				crate::bridge::paging::__popcorn_paging_ktable_unmap_page(&mut page_table, page).expect("Page was just translated");
+				self.allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap());
+			}
+		}
+		drop(page_table);
+
+		Global.deallocate_contiguous(self.base, self.len);
 	}
 }
 
@@ -276,9 +333,94 @@ pub trait Mappable {
 }
 
 /// The memory protection to use for the memory mapping
-pub enum Protection {
+///
+/// Protections can be combined using `|`, for example `Protection::RW | Protection::USER`.
+/// Architectures may not be able to express every combination. In particular, any mapped page is readable on amd64.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Protection(u8);
+
+impl Protection {
+	/// The mapping can be read from
+	pub const READ: Self = Self(1 << 0);
+	/// The mapping can be written to
+	pub const WRITE: Self = Self(1 << 1);
+	/// The mapping can be executed from
+	pub const EXECUTE: Self = Self(1 << 2);
+	/// The mapping can be accessed from userspace
+	pub const USER: Self = Self(1 << 3);
+
+	/// The mapping is read only
+	pub const R: Self = Self::READ;
+	/// The mapping is read-write
+	pub const RW: Self = Self(Self::READ.0 | Self::WRITE.0);
+	/// The mapping is read only and can be executed from
+	pub const RX: Self = Self(Self::READ.0 | Self::EXECUTE.0);
 	/// The mapping is read-write and can be executed from
-	RWX
+	pub const RWX: Self = Self(Self::READ.0 | Self::WRITE.0 | Self::EXECUTE.0);
+
+	/// Returns `true` if every permission in `other` is also in `self`
+	pub const fn contains(self, other: Self) -> bool {
+		(self.0 & other.0) == other.0
+	}
+
+	/// Returns the permissions in `self` that are not in `other`
+	pub const fn difference(self, other: Self) -> Self {
+		Self(self.0 & !other.0)
+	}
+}
+
+impl core::ops::BitOr for Protection {
+	type Output = Self;
+
+	fn bitor(self, rhs: Self) -> Self::Output {
+		Self(self.0 | rhs.0)
+	}
+}
+
+/// The caching behaviour to use for the memory mapping
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CacheMode {
+	/// Reads and writes are cached, and writes are only written back to memory when evicted
+	WriteBack,
+	/// Reads are cached, and writes go straight to memory
+	WriteThrough,
+	/// No accesses are cached, but writes may be buffered and combined before going to memory
+	///
+	/// This suits memory like framebuffers, which are written in large blocks and rarely read back
+	WriteCombining,
+	/// No accesses are cached
+	Uncached
+}
+
+/// The size of the pages used to map memory
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum PageSize {
+	/// 4KiB pages, which can be used for any mapping
+	Size4KiB,
+	/// 2MiB pages
+	Size2MiB,
+	/// 1GiB pages, which may not be supported by every CPU
+	Size1GiB
+}
+
+impl PageSize {
+	/// The number of [`Frame`]s in one page of this size
+	pub const fn frame_count(self) -> usize {
+		match self {
+			Self::Size4KiB => 1,
+			Self::Size2MiB => 512,
+			Self::Size1GiB => 512 * 512
+		}
+	}
+
+	/// The next smaller page size, which splits a page of this size into 512 pages
+	pub const fn smaller(self) -> Option<Self> {
+		match self {
+			Self::Size4KiB => None,
+			Self::Size2MiB => Some(Self::Size4KiB),
+			Self::Size1GiB => Some(Self::Size2MiB)
+		}
+	}
 }
 
 mod private {
@@ -320,21 +462,38 @@ impl From<Location<Frame>> for super::allocator::Location {
 }
 
 /// When to allocate physical memory for the [mapping](self)
-pub enum Laziness { Lazy, Prefault }
+pub enum Laziness {
+	/// Physical memory is allocated a page at a time when each page is first accessed
+	///
+	/// This is only possible when the physical memory can be placed anywhere. Mappings requesting a specific
+	/// [`physical_location`](Config::physical_location) are always prefaulted.
+	Lazy,
+	/// All physical memory is allocated and mapped when the mapping is created
+	Prefault,
+	/// All physical memory is allocated and mapped when the mapping is created, but it may be physically discontiguous
+	///
+	/// This can succeed when physical memory is too fragmented for a prefaulted mapping. Like [`Lazy`](Self::Lazy), it
+	/// is only possible when the physical memory can be placed anywhere, and other locations are prefaulted instead.
+	Scattered
+}
 
 /// Configuration for creating a [mapping](self)
 ///
 /// By default, it will allocate memory anywhere that is valid, using the kernel [`VirtualAllocator`], and the
 /// `highmem` [`physical allocator`](BackingAllocator). It will lazily allocate physical memory, and map it
-/// with read and write permissions only.
+/// with read and write permissions only, using write-back caching.
 pub struct Config<'physical_allocator, A: VirtualAllocator> {
 	physical_location: Location<Frame>,
 	virtual_location: Location<Page>,
 	laziness: Laziness,
 	length: NonZeroUsize,
 	physical_allocator: &'physical_allocator dyn BackingAllocator,
+	/// The physical allocator, if it lives forever so can be registered with the page fault handler
+	static_physical_allocator: Option<&'static dyn BackingAllocator>,
 	virtual_allocator: A,
 	protection: Protection,
+	cache: CacheMode,
+	page_size: PageSize,
 }
 
 impl<'physical_allocator, A: VirtualAllocator> Config<'physical_allocator, A> {
@@ -346,14 +505,32 @@ impl<'physical_allocator, A: VirtualAllocator> Config<'physical_allocator, A> {
 			laziness: Laziness::Lazy,
 			length,
 			physical_allocator: highmem(),
+			static_physical_allocator: Some(highmem()),
 			virtual_allocator: Global,
-			protection: Protection::RWX,
+			protection: Protection::RW,
+			cache: CacheMode::WriteBack,
+			page_size: PageSize::Size4KiB,
 		}
 	}
 
+	/// Allocates physical memory from `allocator`
+	///
+	/// The page fault handler can't keep hold of an allocator that may be dropped, so mappings using it are always
+	/// prefaulted, even if they are configured to be [lazy](Laziness::Lazy), and can't be [shared](RawMapping::share).
+	/// Use [`static_physical_allocator`](Self::static_physical_allocator) for allocators that live forever.
 	pub fn physical_allocator<'a>(self, allocator: &'a dyn BackingAllocator) -> Config<'a, A> {
 		Config {
 			physical_allocator: allocator,
+			static_physical_allocator: None,
+			.. self
+		}
+	}
+
+	/// Allocates physical memory from `allocator`, which can be used for lazy and shared mappings
+	pub fn static_physical_allocator(self, allocator: &'static dyn BackingAllocator) -> Self {
+		Config {
+			physical_allocator: allocator,
+			static_physical_allocator: Some(allocator),
 			.. self
 		}
 	}
@@ -372,6 +549,13 @@ impl<'physical_allocator, A: VirtualAllocator> Config<'physical_allocator, A> {
 		}
 	}
 
+	pub fn cache(self, cache: CacheMode) -> Self {
+		Config {
+			cache,
+			.. self
+		}
+	}
+
 	pub fn physical_location(self, location: Location<Frame>) -> Self {
 		Config {
 			physical_location: location,
@@ -385,13 +569,81 @@ impl<'physical_allocator, A: VirtualAllocator> Config<'physical_allocator, A> {
 			.. self
 		}
 	}
+
+	pub fn laziness(self, laziness: Laziness) -> Self {
+		Config {
+			laziness,
+			.. self
+		}
+	}
+
+	/// Maps the memory using pages of `page_size` where possible
+	///
+	/// The physical and virtual memory are aligned to the page size, unless a specific
+	/// [`physical_location`](Self::physical_location) is requested, in which case the physical memory is only mapped
+	/// with larger pages if it happens to be aligned. Any memory at the end of the mapping that doesn't fill a whole page
+	/// is mapped with 4KiB pages.
+	///
+	/// Mappings with pages larger than 4KiB are always prefaulted.
+	pub fn page_size(self, page_size: PageSize) -> Self {
+		Config {
+			page_size,
+			.. self
+		}
+	}
+}
+
+/// The physical memory backing a [`RawMapping`]
+enum Backing<'phys_allocator> {
+	Prefault(OwnedFrames<'phys_allocator>),
+	/// The allocator is registered with the page fault handler, so has to live forever
+	Lazy {
+		len: NonZeroUsize,
+		allocator: &'static dyn BackingAllocator
+	},
+	/// Every page is mapped, and owns its frame, but the frames may be physically discontiguous
+	Scattered {
+		len: NonZeroUsize,
+		allocator: &'phys_allocator dyn BackingAllocator
+	},
+	/// Each mapped page owns a reference to its frame, which may be shared with other mappings until written to
+	CopyOnWrite {
+		len: NonZeroUsize,
+		allocator: &'static dyn BackingAllocator
+	}
+}
+
+impl Debug for Backing<'_> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Backing::Prefault(frames) => frames.fmt(f),
+			Backing::Lazy { len, .. } => f.debug_struct("Lazy")
+			                              .field("len", len)
+			                              .field("allocator", &"<physical allocator>")
+			                              .finish(),
+			Backing::Scattered { len, .. } => f.debug_struct("Scattered")
+			                                   .field("len", len)
+			                                   .field("allocator", &"<physical allocator>")
+			                                   .finish(),
+			Backing::CopyOnWrite { len, .. } => f.debug_struct("CopyOnWrite")
+			                                     .field("len", len)
+			                                     .field("allocator", &"<physical allocator>")
+			                                     .finish()
+		}
+	}
 }
 
 // This exists to allow writing functions that only access the owned memory, rather than causing any allocations or deallocations,
 // to not have to be generic at runtime over the allocator and mapping controller
 pub(super) struct RawMappingInner<'phys_allocator> {
-	physical: OwnedFrames<'phys_allocator>,
+	physical: Backing<'phys_allocator>,
+	/// The allocator behind `physical`, if it can be registered with the page fault handler
+	static_allocator: Option<&'static dyn BackingAllocator>,
 	virtual_valid_start: Page,
+	protection: Protection,
+	cache: CacheMode,
+	/// The largest pages that may be used to map the memory
+	page_size: PageSize,
 }
 
 impl RawMappingInner<'_> {
@@ -400,11 +652,19 @@ impl RawMappingInner<'_> {
 	}
 
 	pub(super) fn physical_len(&self) -> NonZeroUsize {
-		self.physical.len
+		match self.physical {
+			Backing::Prefault(ref frames) => frames.len,
+			Backing::Lazy { len, .. } | Backing::Scattered { len, .. } | Backing::CopyOnWrite { len, .. } => len
+		}
 	}
 
 	pub(super) fn physical_start(&self) -> Frame {
-		self.physical.base
+		match self.physical {
+			Backing::Prefault(ref frames) => frames.base,
+			Backing::Lazy { .. } => panic!("Lazily allocated mappings are not physically contiguous"),
+			Backing::Scattered { .. } => panic!("Scattered mappings are not physically contiguous"),
+			Backing::CopyOnWrite { .. } => panic!("Copy-on-write mappings are not physically contiguous")
+		}
 	}
 
 	pub(super) fn physical_end(&self) -> Frame {
@@ -435,36 +695,107 @@ impl<R: Mappable, A: VirtualAllocator> Debug for RawMapping<'_, R, A> {
 
 impl<'phys_alloc, R: Mappable, A: VirtualAllocator> RawMapping<'phys_alloc, R, A> {
 	pub fn new(config: Config<'phys_alloc, A>) -> Result<Self, AllocError> {
-		let Config { length, physical_allocator, virtual_allocator, physical_location, .. } = config;
+		let Config { length, physical_allocator, static_physical_allocator, virtual_allocator, physical_location, laziness, protection, cache, page_size, .. } = config;
 
 		let virtual_len = R::physical_length_to_virtual_length(length);
 		let physical_len = length;
 
-		let physical_mem = OwnedFrames::xnew(physical_len, physical_allocator, physical_location.into())?;
-		let virtual_mem = OwnedPages::new_with(virtual_len, virtual_allocator)?;
+		if let (Laziness::Lazy, Location::Any, PageSize::Size4KiB, Some(allocator)) = (laziness, &physical_location, page_size, static_physical_allocator) {
+			let virtual_mem = OwnedPages::new_with(virtual_len, virtual_allocator)?;
+			let (virtual_base, _, virtual_allocator) = virtual_mem.into_raw_parts();
+			let offset_base = virtual_base + R::physical_start_offset_from_virtual();
+
+			unsafe { crate::bridge::memory::__popcorn_memory_lazy_register(offset_base..(offset_base + physical_len.get()), allocator, protection, cache) }
+					.expect("Virtual memory uniquely owned by the allocation so should not already be registered");
+
+			return Ok(Self {
+				raw: PhantomData,
+				inner: RawMappingInner {
+					physical: Backing::Lazy { len: physical_len, allocator },
+					static_allocator: static_physical_allocator,
+					virtual_valid_start: offset_base,
+					protection,
+					cache,
+					page_size
+				},
+				virtual_allocator: ManuallyDrop::new(virtual_allocator)
+			});
+		}
+
+		if let (Laziness::Scattered, Location::Any, PageSize::Size4KiB) = (laziness, &physical_location, page_size) {
+			let virtual_mem = OwnedPages::new_with(virtual_len, virtual_allocator)?;
+			let (virtual_base, _, virtual_allocator) = virtual_mem.into_raw_parts();
+			let offset_base = virtual_base + R::physical_start_offset_from_virtual();
+
+			let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+			let mapped = map_scattered(&mut page_table, offset_base..(offset_base + physical_len.get()), physical_allocator, protection, cache);
+			drop(page_table);
+
+			if let Err(e) = mapped {
+				virtual_allocator.deallocate_contiguous(virtual_base, virtual_len.get());
+				return Err(e);
+			}
+
+			return Ok(Self {
+				raw: PhantomData,
+				inner: RawMappingInner {
+					physical: Backing::Scattered { len: physical_len, allocator: physical_allocator },
+					static_allocator: static_physical_allocator,
+					virtual_valid_start: offset_base,
+					protection,
+					cache,
+					page_size
+				},
+				virtual_allocator: ManuallyDrop::new(virtual_allocator)
+			});
+		}
+
+		let physical_mem = match (physical_location, page_size) {
+			(Location::Any, PageSize::Size4KiB) => OwnedFrames::new_with(physical_len, physical_allocator)?,
+			(Location::Any, _) => {
+				let alignment = NonZeroU32::new(page_size.frame_count().try_into().unwrap()).unwrap();
+
+				// Larger pages are only an optimisation, so fall back to unaligned memory rather than failing
+				OwnedFrames::xnew(physical_len, physical_allocator, Location::Aligned(alignment).into())
+						.or_else(|_| OwnedFrames::new_with(physical_len, physical_allocator))?
+			}
+			(location, _) => OwnedFrames::xnew(physical_len, physical_allocator, location.into())?
+		};
+		let virtual_mem = match page_size {
+			PageSize::Size4KiB => OwnedPages::new_with(virtual_len, virtual_allocator)?,
+			_ => allocate_aligned(virtual_len, virtual_allocator, page_size.frame_count(), R::physical_start_offset_from_virtual())?
+		};
 
 		let physical_base = physical_mem.base;
 		let (virtual_base, _, virtual_allocator) = virtual_mem.into_raw_parts();
 		let offset_base = virtual_base + R::physical_start_offset_from_virtual();
 
-		// TODO: huge pages
 		let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
-		for (frame, page) in (0..physical_len.get()).map(|i| (physical_base + i, offset_base + i)) {
-			unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame) }
-					.expect("Virtual memory uniquely owned by the allocation so should not be mapped in this address space");
-		}
+		map_sized(&mut page_table, offset_base, physical_base, physical_len.get(), page_size, protection, cache);
 
 		Ok(Self {
 			raw: PhantomData,
 			inner: RawMappingInner {
-				physical: physical_mem,
-				virtual_valid_start: offset_base
+				physical: Backing::Prefault(physical_mem),
+				static_allocator: static_physical_allocator,
+				virtual_valid_start: offset_base,
+				protection,
+				cache,
+				page_size
 			},
 			virtual_allocator: ManuallyDrop::new(virtual_allocator)
 		})
 	}
 
+	/// Splits the mapping into the physical and virtual memory that it owns, without unmapping it
+	///
+	/// # Panics
+	///
+	/// Panics if the mapping is [lazily](Laziness::Lazy) allocated or has been [shared](Self::share), since it does not
+	/// own a contiguous physical region
 	pub fn into_raw_parts(mut self) -> (OwnedFrames<'phys_alloc>, OwnedPages<A>) {
+		assert!(matches!(self.inner.physical, Backing::Prefault(_)), "Only prefaulted mappings can be split into raw parts");
+
 		let virtual_allocator = unsafe { ManuallyDrop::take(&mut self.virtual_allocator) };
 		let pages = unsafe {
 			OwnedPages::from_raw_parts(
@@ -475,7 +806,8 @@ impl<'phys_alloc, R: Mappable, A: VirtualAllocator> RawMapping<'phys_alloc, R, A
 		};
 
 		let this = ManuallyDrop::new(self);
-		(unsafe { ptr::read(&this.inner.physical) }, pages)
+		let Backing::Prefault(ref physical) = this.inner.physical else { unreachable!() };
+		(unsafe { ptr::read(physical) }, pages)
 	}
 
 	pub unsafe fn from_raw_parts(frames: OwnedFrames<'phys_alloc>, pages: OwnedPages<A>) -> Self {
@@ -486,8 +818,14 @@ impl<'phys_alloc, R: Mappable, A: VirtualAllocator> RawMapping<'phys_alloc, R, A
 		Self {
 			raw: PhantomData,
 			inner: RawMappingInner {
-				physical: frames,
-				virtual_valid_start: virtual_base + R::physical_start_offset_from_virtual()
+				physical: Backing::Prefault(frames),
+				// There's no way to know whether the frames' allocator lives forever
+				static_allocator: None,
+				virtual_valid_start: virtual_base + R::physical_start_offset_from_virtual(),
+				// Raw parts come from mappings created with the default protection and caching
+				protection: Protection::RW,
+				cache: CacheMode::WriteBack,
+				page_size: PageSize::Size4KiB
 			},
 			virtual_allocator: ManuallyDrop::new(virtual_allocator)
 		}
@@ -513,24 +851,374 @@ impl<'phys_alloc, R: Mappable, A: VirtualAllocator> RawMapping<'phys_alloc, R, A
 		self.inner.physical_len()
 	}
 
+	/// # Panics
+	///
+	/// Panics if the mapping is [lazily](Laziness::Lazy) allocated or [scattered](Laziness::Scattered)
 	pub fn physical_start(&self) -> Frame {
 		self.inner.physical_start()
 	}
 
+	/// # Panics
+	///
+	/// Panics if the mapping is [lazily](Laziness::Lazy) allocated or [scattered](Laziness::Scattered)
 	pub fn physical_end(&self) -> Frame {
 		self.inner.physical_end()
 	}
+
+	/// Creates a second mapping of the same physical memory, which is copied one page at a time on the first write to it
+	/// from either mapping
+	///
+	/// Both mappings are read only until written to, so writes to a page in one mapping are never visible in the other.
+	///
+	/// # Panics
+	///
+	/// Panics if the mapping is [lazily](Laziness::Lazy) allocated, or its physical allocator wasn't given with
+	/// [`Config::static_physical_allocator`]
+	pub fn share(&mut self) -> Result<Self, AllocError> where A: Clone {
+		let static_allocator = self.inner.static_allocator.expect("Only mappings with a 'static physical allocator can be shared");
+		let virtual_allocator = (*self.virtual_allocator).clone();
+		let (new_base, _, virtual_allocator) = OwnedPages::new_with(self.virtual_len(), virtual_allocator)?.into_raw_parts();
+		let new_valid_start = new_base + R::physical_start_offset_from_virtual();
+
+		let physical_len = self.physical_len();
+		let RawMappingInner { protection, cache, .. } = self.inner;
+		let read_only = protection.difference(Protection::WRITE);
+
+		// The page table is only locked after registering regions, as the page fault handler locks them in that order
+		match self.inner.physical {
+			Backing::Prefault(ref frames) => {
+				// From now on, each page table entry owns one reference to its frame
+				let (base, len, _) = frames.share().map_err(|_| AllocError)?.into_raw_parts();
+				let Backing::Prefault(frames) = mem::replace(&mut self.inner.physical, Backing::CopyOnWrite { len, allocator: static_allocator }) else { unreachable!() };
+				let _ = frames.into_raw_parts();
+
+				unsafe { crate::bridge::memory::__popcorn_memory_cow_register(self.virtual_valid_start()..(self.virtual_valid_start() + len.get()), static_allocator, protection, cache) }
+						.expect("Virtual memory uniquely owned by the allocation so should not already be registered");
+
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				for page in (0..len.get()).map(|i| self.virtual_valid_start() + i) {
+					unsafe { crate::bridge::paging::__popcorn_paging_ktable_protect_page(&mut page_table, page, read_only) }
+							.expect("Virtual memory uniquely owned by this mmap so should be mapped");
+				}
+
+				for (frame, page) in (0..len.get()).map(|i| (base + i, new_valid_start + i)) {
+					unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, page, frame, read_only, cache) }
+							.expect("Virtual memory uniquely owned by the allocation so should not be mapped in this address space");
+				}
+			}
+			Backing::Scattered { .. } | Backing::CopyOnWrite { .. } => {
+				if let Backing::Scattered { len, .. } = self.inner.physical {
+					// From now on, each page table entry owns one reference to its frame
+					self.inner.physical = Backing::CopyOnWrite { len, allocator: static_allocator };
+
+					unsafe { crate::bridge::memory::__popcorn_memory_cow_register(self.virtual_valid_start()..(self.virtual_valid_start() + len.get()), static_allocator, protection, cache) }
+							.expect("Virtual memory uniquely owned by the allocation so should not already be registered");
+				}
+
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				for (i, page) in (0..physical_len.get()).map(|i| (i, self.virtual_valid_start() + i)) {
+					let frame = unsafe { crate::bridge::paging::__popcorn_paging_ktable_translate_page(&page_table, page) }
+							.expect("Virtual memory uniquely owned by this mmap so should be mapped");
+					unsafe {
+						crate::bridge::memory::__popcorn_memory_physical_share(frame)
+								.expect("Frames of copy-on-write mappings must be reference counted");
+						crate::bridge::paging::__popcorn_paging_ktable_protect_page(&mut page_table, page, read_only)
+								.expect("Virtual memory uniquely owned by this mmap so should be mapped");
+						crate::bridge::paging::__popcorn_paging_ktable_map_page(&mut page_table, new_valid_start + i, frame, read_only, cache)
+								.expect("Virtual memory uniquely owned by the allocation so should not be mapped in this address space");
+					}
+				}
+			}
+			Backing::Lazy { .. } => panic!("Lazily allocated mappings cannot be shared")
+		}
+
+		unsafe { crate::bridge::memory::__popcorn_memory_cow_register(new_valid_start..(new_valid_start + physical_len.get()), static_allocator, protection, cache) }
+				.expect("Virtual memory uniquely owned by the allocation so should not already be registered");
+
+		Ok(Self {
+			raw: PhantomData,
+			inner: RawMappingInner {
+				physical: Backing::CopyOnWrite { len: physical_len, allocator: static_allocator },
+				static_allocator: Some(static_allocator),
+				virtual_valid_start: new_valid_start,
+				protection,
+				cache,
+				page_size: PageSize::Size4KiB
+			},
+			virtual_allocator: ManuallyDrop::new(virtual_allocator)
+		})
+	}
+
+	/// Changes the protection of every page in the mapping, keeping the existing caching behaviour
+	pub fn change_protection(&mut self, protection: Protection) {
+		self.inner.protection = protection;
+		let pages = (0..self.physical_len().get()).map(|i| self.virtual_valid_start() + i);
+
+		match self.inner.physical {
+			Backing::Prefault(_) | Backing::Scattered { .. } => {
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				for (page, size) in sized_pages(self.virtual_valid_start(), self.physical_len().get(), self.inner.page_size) {
+					unsafe { crate::bridge::paging::__popcorn_paging_ktable_protect_huge_page(&mut page_table, page, size, protection) }
+							.expect("Virtual memory uniquely owned by this mmap so should be mapped");
+				}
+			}
+			Backing::Lazy { .. } => {
+				unsafe { crate::bridge::memory::__popcorn_memory_lazy_protect(self.virtual_valid_start(), protection); }
+
+				// Pages that haven't been touched yet will be mapped with the new protection when faulted in
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				for page in pages {
+					let _ = unsafe { crate::bridge::paging::__popcorn_paging_ktable_protect_page(&mut page_table, page, protection) };
+				}
+			}
+			Backing::CopyOnWrite { .. } => {
+				// Shared pages have to stay read only, so the kernel decides the protection of each page
+				unsafe { crate::bridge::memory::__popcorn_memory_cow_protect(self.virtual_valid_start(), protection); }
+			}
+		}
+	}
+
+	/// Changes the number of frames in the mapping, without moving it in virtual memory
+	///
+	/// Shrinking unmaps the end of the mapping and gives its memory back to the physical allocator. Prefaulted mappings
+	/// are kept physically contiguous, so growing one may move it to different physical memory, ignoring its original
+	/// physical location.
+	///
+	/// # Errors
+	///
+	/// Returns [`AllocError`] if the memory couldn't be allocated, or if the virtual memory after the mapping is in use.
+	/// The mapping is unchanged on error.
+	///
+	/// # Panics
+	///
+	/// Panics if the mapping has been [shared](Self::share)
+	pub fn resize_in_place(&mut self, new_len: NonZeroUsize) -> Result<(), AllocError> {
+		self.resize_inner(new_len, false)
+	}
+
+	/// Changes the number of frames in the mapping, moving it to a new virtual range if it can't grow in place
+	///
+	/// See [`resize_in_place`](Self::resize_in_place) for details.
+	pub fn resize(&mut self, new_len: NonZeroUsize) -> Result<(), AllocError> {
+		self.resize_inner(new_len, true)
+	}
+
+	fn resize_inner(&mut self, new_len: NonZeroUsize, may_move: bool) -> Result<(), AllocError> {
+		assert!(!matches!(self.inner.physical, Backing::CopyOnWrite { .. }), "Copy-on-write mappings cannot be resized");
+
+		match new_len.cmp(&self.physical_len()) {
+			Ordering::Equal => Ok(()),
+			Ordering::Less => {
+				self.shrink(new_len);
+				Ok(())
+			},
+			Ordering::Greater => self.grow(new_len, may_move)
+		}
+	}
+
+	fn shrink(&mut self, new_len: NonZeroUsize) {
+		let old_len = self.physical_len();
+		let old_virtual_len = self.virtual_len();
+		let valid_start = self.virtual_valid_start();
+		let removed = (valid_start + new_len.get())..(valid_start + old_len.get());
+
+		match self.inner.physical {
+			Backing::Prefault(ref mut frames) => {
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				for (page, size) in sized_pages(removed.start, old_len.get() - new_len.get(), self.inner.page_size) {
+					unsafe { crate::bridge::paging::__popcorn_paging_ktable_unmap_huge_page(&mut page_table, page, size) }
+							.expect("Virtual memory uniquely owned by this mmap so shouldn't be unmapped");
+				}
+				drop(page_table);
+
+				frames.truncate(new_len);
+			}
+			Backing::Lazy { ref mut len, allocator } => {
+				// The page table is only locked after registering regions, as the page fault handler locks them in that order
+				unsafe {
+					crate::bridge::memory::__popcorn_memory_lazy_unregister(valid_start);
+					crate::bridge::memory::__popcorn_memory_lazy_register(valid_start..(valid_start + new_len.get()), allocator, self.inner.protection, self.inner.cache)
+							.expect("Region was just unregistered");
+				}
+
+				// Only pages that have been touched have any physical memory to free
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				unsafe { unmap_and_free(&mut page_table, removed, allocator); }
+
+				*len = new_len;
+			}
+			Backing::Scattered { ref mut len, allocator } => {
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				unsafe { unmap_and_free(&mut page_table, removed, allocator); }
+
+				*len = new_len;
+			}
+			Backing::CopyOnWrite { .. } => unreachable!()
+		}
+
+		let new_virtual_len = R::physical_length_to_virtual_length(new_len);
+		self.virtual_allocator.deallocate_contiguous(self.virtual_start() + new_virtual_len.get(), old_virtual_len.get() - new_virtual_len.get());
+	}
+
+	fn grow(&mut self, new_len: NonZeroUsize, may_move: bool) -> Result<(), AllocError> {
+		let old_len = self.physical_len();
+		let old_start = self.virtual_start();
+		let old_valid_start = self.virtual_valid_start();
+		let old_virtual_len = self.virtual_len();
+		let new_virtual_len = R::physical_length_to_virtual_length(new_len);
+		let extra_virtual_len = new_virtual_len.get() - old_virtual_len.get();
+
+		// Virtual memory is reserved first, as it is the easiest to give back if the physical memory can't be allocated
+		let new_start = match self.virtual_allocator.allocate_contiguous_at(self.virtual_end(), extra_virtual_len) {
+			Ok(_) => old_start,
+			Err(_) if may_move => self.virtual_allocator.allocate_contiguous(new_virtual_len.get())?,
+			Err(e) => return Err(e)
+		};
+		let moved = new_start != old_start;
+		let new_valid_start = new_start + R::physical_start_offset_from_virtual();
+		let RawMappingInner { protection, cache, page_size, .. } = self.inner;
+
+		match self.inner.physical {
+			Backing::Prefault(ref mut frames) => {
+				let extended = frames.allocator.allocate_at(new_len.get() - old_len.get(), SpecificLocation::At(frames.base + old_len.get())).is_ok();
+
+				// If the frames can't be extended, the contents are moved to a new contiguous region
+				let old_frames = if extended {
+					frames.len = new_len;
+					None
+				} else {
+					let new_frames = match OwnedFrames::new_with(new_len, frames.allocator) {
+						Ok(new_frames) => new_frames,
+						Err(e) => {
+							if moved {
+								self.virtual_allocator.deallocate_contiguous(new_start, new_virtual_len.get());
+							} else {
+								self.virtual_allocator.deallocate_contiguous(old_start + old_virtual_len.get(), extra_virtual_len);
+							}
+							return Err(e);
+						}
+					};
+
+					unsafe {
+						ptr::copy_nonoverlapping(frames.base.to_page().as_ptr(), new_frames.base.to_page().as_ptr(), old_len.get() * 4096);
+					}
+					Some(mem::replace(frames, new_frames))
+				};
+
+				let remap_all = moved || old_frames.is_some();
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				if remap_all {
+					for (page, size) in sized_pages(old_valid_start, old_len.get(), page_size) {
+						unsafe { crate::bridge::paging::__popcorn_paging_ktable_unmap_huge_page(&mut page_table, page, size) }
+								.expect("Virtual memory uniquely owned by this mmap so shouldn't be unmapped");
+					}
+				}
+
+				let first_new = if remap_all { 0 } else { old_len.get() };
+				map_sized(&mut page_table, new_valid_start + first_new, frames.base + first_new, new_len.get() - first_new, page_size, protection, cache);
+				drop(page_table);
+
+				drop(old_frames);
+			}
+			Backing::Lazy { ref mut len, allocator } => {
+				// The page table is only locked after registering regions, as the page fault handler locks them in that order
+				unsafe {
+					crate::bridge::memory::__popcorn_memory_lazy_unregister(old_valid_start);
+					crate::bridge::memory::__popcorn_memory_lazy_register(new_valid_start..(new_valid_start + new_len.get()), allocator, protection, cache)
+							.expect("Virtual memory uniquely owned by the allocation so should not already be registered");
+				}
+
+				// Pages that have already been faulted in keep their frames
+				if moved {
+					let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+					unsafe { move_pages(&mut page_table, old_valid_start, new_valid_start, old_len.get(), protection, cache); }
+				}
+
+				*len = new_len;
+			}
+			Backing::Scattered { ref mut len, allocator } => {
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+
+				// The new pages are mapped first, so that the mapping is unchanged if there isn't enough memory
+				let added = (new_valid_start + old_len.get())..(new_valid_start + new_len.get());
+				if let Err(e) = map_scattered(&mut page_table, added, allocator, protection, cache) {
+					drop(page_table);
+					if moved {
+						self.virtual_allocator.deallocate_contiguous(new_start, new_virtual_len.get());
+					} else {
+						self.virtual_allocator.deallocate_contiguous(old_start + old_virtual_len.get(), extra_virtual_len);
+					}
+					return Err(e);
+				}
+
+				if moved {
+					unsafe { move_pages(&mut page_table, old_valid_start, new_valid_start, old_len.get(), protection, cache); }
+				}
+
+				*len = new_len;
+			}
+			Backing::CopyOnWrite { .. } => unreachable!()
+		}
+
+		if moved {
+			self.virtual_allocator.deallocate_contiguous(old_start, old_virtual_len.get());
+		}
+		self.inner.virtual_valid_start = new_valid_start;
+
+		Ok(())
+	}
+}
+
+impl<A: VirtualAllocator> RawMapping<'_, RawStack, A> {
+	/// The page below the stack, which is never mapped so that overflowing the stack faults
+	pub fn guard_page(&self) -> Page {
+		self.virtual_start()
+	}
 }
 
 impl<R: Mappable, A: VirtualAllocator> Drop for RawMapping<'_, R, A> {
 	fn drop(&mut self) {
 		debug!("mmap dropped: {self:x?}");
 
-		let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
-		for page in (0..self.physical_len().get()).map(|i| self.virtual_valid_start() + i) {
-			debug!("unmapping page {page:x?}");
-			unsafe { crate::bridge::paging::__popcorn_paging_ktable_unmap_page(&mut page_table, page) }
-					.expect("Virtual memory uniquely owned by this mmap so shouldn't be unmapped");
+		match self.inner.physical {
+			Backing::Prefault(_) => {
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				for (page, size) in sized_pages(self.virtual_valid_start(), self.physical_len().get(), self.inner.page_size) {
+					debug!("unmapping {size:?} page {page:x?}");
+					unsafe { crate::bridge::paging::__popcorn_paging_ktable_unmap_huge_page(&mut page_table, page, size) }
+							.expect("Virtual memory uniquely owned by this mmap so shouldn't be unmapped");
+				}
+			}
+			Backing::Lazy { len, allocator } => {
+				unsafe { crate::bridge::memory::__popcorn_memory_lazy_unregister(self.virtual_valid_start()); }
+
+				// Only pages that have been touched have any physical memory to free
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				unsafe { unmap_and_free(&mut page_table, self.virtual_valid_start()..(self.virtual_valid_start() + len.get()), allocator); }
+			}
+			Backing::Scattered { len, allocator } => {
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				unsafe { unmap_and_free(&mut page_table, self.virtual_valid_start()..(self.virtual_valid_start() + len.get()), allocator); }
+			}
+			Backing::CopyOnWrite { len, allocator } => {
+				unsafe { crate::bridge::memory::__popcorn_memory_cow_unregister(self.virtual_valid_start()); }
+
+				let mut page_table = unsafe { crate::bridge::paging::__popcorn_paging_get_ktable() };
+				for page in (0..len.get()).map(|i| self.virtual_valid_start() + i) {
+					debug!("unmapping page {page:x?}");
+					let frame = unsafe { crate::bridge::paging::__popcorn_paging_ktable_translate_page(&page_table, page) }
+							.expect("Virtual memory uniquely owned by this mmap so should be mapped");
+					unsafe {
+						crate::bridge::paging::__popcorn_paging_ktable_unmap_page(&mut page_table, page)
+								.expect("Virtual memory uniquely owned by this mmap so shouldn't be unmapped");
+
+						// Other mappings may still be using the frame
+						if crate::bridge::memory::__popcorn_memory_physical_release(frame) {
+							allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap());
+						}
+					}
+				}
+			}
 		}
 
 		let virtual_allocator = unsafe { ManuallyDrop::take(&mut self.virtual_allocator) };
@@ -544,6 +1232,143 @@ impl<R: Mappable, A: VirtualAllocator> Drop for RawMapping<'_, R, A> {
 	}
 }
 
+/// Allocates a frame for every page in `pages` and maps it, a physically contiguous run at a time
+///
+/// Either every page is mapped, or none are and any frames that were allocated are freed again.
+fn map_scattered(page_table: &mut KTable, pages: Range<Page>, allocator: &dyn BackingAllocator, protection: Protection, cache: CacheMode) -> Result<(), AllocError> {
+	let len = pages.end - pages.start;
+	let mut mapped = 0;
+	let mut request = len;
+
+	while mapped < len {
+		let frames = match allocator.allocate(request.min(len - mapped)) {
+			Ok(frames) => frames,
+			// The frames may be too fragmented to fit in one `ScatterList`, so try fewer at once
+			Err(_) if request > 1 => {
+				request = request.div_ceil(2);
+				continue;
+			}
+			Err(e) => {
+				unsafe { unmap_and_free(page_table, pages.start..(pages.start + mapped), allocator); }
+				return Err(e);
+			}
+		};
+
+		for frame in frames {
+			unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_page(page_table, pages.start + mapped, frame, protection, cache) }
+					.expect("Virtual memory uniquely owned by the allocation so should not be mapped in this address space");
+			mapped += 1;
+		}
+	}
+
+	Ok(())
+}
+
+/// Unmaps every mapped page in `pages`, and gives its frame back to `allocator`
+///
+/// # Safety
+///
+/// The pages must own their frames, which must have been allocated from `allocator`
+unsafe fn unmap_and_free(page_table: &mut KTable, pages: Range<Page>, allocator: &dyn BackingAllocator) {
+	for page in pages {
+		let Some(frame) = (unsafe { crate::bridge::paging::__popcorn_paging_ktable_translate_page(page_table, page) }) else { continue };
+
+		debug!("unmapping page {page:x?}");
+		unsafe {
+			crate::bridge::paging::__popcorn_paging_ktable_unmap_page(page_table, page)
+					.expect("Page was just translated");
+			allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap());
+		}
+	}
+}
+
+/// Moves the frames mapped at the `len` pages from `from` to the same offsets from `to`, skipping unmapped pages
+///
+/// # Safety
+///
+/// The pages at `to` must be owned by the caller
+unsafe fn move_pages(page_table: &mut KTable, from: Page, to: Page, len: usize, protection: Protection, cache: CacheMode) {
+	for i in 0..len {
+		let Some(frame) = (unsafe { crate::bridge::paging::__popcorn_paging_ktable_translate_page(page_table, from + i) }) else { continue };
+
+		unsafe {
+			crate::bridge::paging::__popcorn_paging_ktable_unmap_page(page_table, from + i)
+					.expect("Page was just translated");
+			crate::bridge::paging::__popcorn_paging_ktable_map_page(page_table, to + i, frame, protection, cache)
+					.expect("Virtual memory uniquely owned by the allocation so should not be mapped in this address space");
+		}
+	}
+}
+
+/// Returns the largest page size, up to `largest`, that `page` and `frame` are both aligned to and that fits in `remaining`
+/// frames
+fn page_size_at(page: Page, frame: Option<Frame>, remaining: usize, largest: PageSize) -> PageSize {
+	let mut size = largest;
+	loop {
+		let count = size.frame_count();
+		let page_aligned = (page.start().addr / 4096) % count == 0;
+		let frame_aligned = frame.map_or(true, |frame| (frame.start().addr / 4096) % count == 0);
+		if page_aligned && frame_aligned && count <= remaining { return size; }
+
+		let Some(smaller) = size.smaller() else { return size };
+		size = smaller;
+	}
+}
+
+/// Splits the `len` pages from `start` into the largest pages, up to `largest`, that they can be mapped with
+///
+/// This doesn't check how the memory is actually mapped, so a chunk may have been mapped with smaller pages instead.
+fn sized_pages(start: Page, len: usize, largest: PageSize) -> impl Iterator<Item = (Page, PageSize)> {
+	let mut offset = 0;
+	core::iter::from_fn(move || {
+		if offset >= len { return None; }
+
+		let page = start + offset;
+		let size = page_size_at(page, None, len - offset, largest);
+		offset += size.frame_count();
+		Some((page, size))
+	})
+}
+
+/// Maps the `len` frames from `frame` to the pages from `page`, using pages up to `page_size` wherever both are aligned
+fn map_sized(page_table: &mut KTable, page: Page, frame: Frame, len: usize, page_size: PageSize, protection: Protection, cache: CacheMode) {
+	let mut largest = page_size;
+	let mut mapped = 0;
+
+	while mapped < len {
+		let (page, frame) = (page + mapped, frame + mapped);
+		let size = page_size_at(page, Some(frame), len - mapped, largest);
+
+		match unsafe { crate::bridge::paging::__popcorn_paging_ktable_map_huge_page(page_table, page, frame, size, protection, cache) } {
+			Ok(()) => mapped += size.frame_count(),
+			// The CPU will never support this size, so don't try it for the rest of the mapping
+			Err(MapPageError::UnsupportedSize) => largest = size.smaller().expect("4KiB pages are always supported"),
+			Err(_) => panic!("Virtual memory uniquely owned by the allocation so should not be mapped in this address space")
+		}
+	}
+}
+
+/// Allocates `len` pages such that the page `offset` pages in is aligned to `alignment` pages
+///
+/// Falls back to an unaligned allocation if an aligned one can't be made.
+fn allocate_aligned<A: VirtualAllocator>(len: NonZeroUsize, allocator: A, alignment: usize, offset: isize) -> Result<OwnedPages<A>, AllocError> {
+	// Virtual allocators can't align allocations themselves, so find a big enough gap and then allocate the aligned part of
+	// it, which can fail if something else takes the gap in between
+	let padded_len = len.get() + alignment - 1;
+	for _ in 0..4 {
+		let Ok(padded) = allocator.allocate_contiguous(padded_len) else { break };
+		allocator.deallocate_contiguous(padded, padded_len);
+
+		let misalignment = ((padded + offset).start().addr / 4096) % alignment;
+		let aligned = padded + (alignment - misalignment) % alignment;
+		if let Ok(base) = allocator.allocate_contiguous_at(aligned, len.get()) {
+			return Ok(unsafe { OwnedPages::from_raw_parts(base, len, allocator) });
+		}
+	}
+
+	OwnedPages::new_with(len, allocator)
+}
+
 #[doc(hidden)]
 pub enum RawMmap {}
 
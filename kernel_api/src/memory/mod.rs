@@ -9,6 +9,8 @@ use crate::sync::RwReadGuard;
 
 #[cfg(feature = "full")]
 pub mod allocator;
+#[cfg(all(not(feature = "use_std"), feature = "full"))]
+pub mod cache;
 #[cfg(feature = "full")]
 pub mod heap;
 mod type_ops;
@@ -16,6 +18,8 @@ mod type_ops;
 pub mod r#virtual;
 #[cfg(all(not(feature = "use_std"), feature = "full"))]
 pub mod mapping;
+#[cfg(all(not(feature = "use_std"), feature = "full"))]
+pub mod dma;
 #[cfg(feature = "full")]
 pub mod physical;
 
@@ -261,6 +265,15 @@ impl<const ALIGN: usize> VirtualAddress<ALIGN> {
 }
 
 impl Page {
+    /// Converts a [`Page`] within the physical page map region back into the [`Frame`] it maps
+    #[unstable(feature = "kernel_physical_page_offset", issue = "1")]
+    pub const fn to_frame(&self) -> Frame {
+        assert!(self.base.addr >= PAGE_MAP_OFFSET, "Page is not in the physical page map");
+        Frame {
+            base: PhysicalAddress::new(self.base.addr - PAGE_MAP_OFFSET)
+        }
+    }
+
     /// Converts a [`Page`] into a raw pointer pointing to the first address within the page
     #[stable(feature = "kernel_core_api", since = "0.1.0")]
     #[rustc_const_stable(feature = "kernel_core_api", since = "0.1.0")]
@@ -3,7 +3,8 @@
 use core::fmt::{Debug, Formatter};
 use core::mem::ManuallyDrop;
 use core::num::NonZeroUsize;
-use crate::memory::allocator::{BackingAllocator, Location, SpecificLocation};
+use core::ops::Range;
+use crate::memory::allocator::{BackingAllocator, Location, ScatterList, SpecificLocation};
 use crate::memory::{allocator, AllocError, Frame};
 use crate::sync::RwLock;
 
@@ -22,6 +23,19 @@ unsafe impl BackingAllocator for GlobalAllocator {
 				.allocate_contiguous(frame_count)
 	}
 
+	fn allocate(&self, frame_count: usize) -> Result<ScatterList, AllocError> {
+		self.rwlock.read()
+		    .expect("No global allocator set")
+		    .allocate(frame_count)
+	}
+
+	fn allocate_zeroed_nowait(&self) -> Result<Frame, AllocError> {
+		self.rwlock.try_read()
+		    .ok_or(AllocError)?
+		    .expect("No global allocator set")
+		    .allocate_zeroed_nowait()
+	}
+
 	unsafe fn deallocate_contiguous(&self, base: Frame, frame_count: NonZeroUsize) {
 		self.rwlock.read()
 		    .expect("No global allocator set")
@@ -33,6 +47,26 @@ unsafe impl BackingAllocator for GlobalAllocator {
 		    .expect("No global allocator set")
 		    .allocate_at(frame_count, location)
 	}
+
+	fn stats(&self) -> Option<allocator::Stats> {
+		self.rwlock.read()
+		    .expect("No global allocator set")
+		    .stats()
+	}
+
+	fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, allocator::Stats)) {
+		self.rwlock.read()
+		    .expect("No global allocator set")
+		    .region_stats(f)
+	}
+
+	unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+		unsafe {
+			self.rwlock.read()
+			    .expect("No global allocator set")
+			    .add_region(region)
+		}
+	}
 }
 
 #[unstable(feature = "kernel_internals", issue = "none")]
@@ -58,7 +92,7 @@ pub fn dmamem() -> &'static GlobalAllocator {
 pub struct OwnedFrames<'allocator> {
 	pub(super) base: Frame,
 	pub(super) len: NonZeroUsize,
-	allocator: &'allocator dyn BackingAllocator
+	pub(super) allocator: &'allocator dyn BackingAllocator
 }
 
 impl Debug for OwnedFrames<'_> {
@@ -112,6 +146,19 @@ impl<'a> OwnedFrames<'a> {
 		//(Self { base: self.base, len: lens.0 }, Self { base: second_base, len: lens.1 })
 	}
 
+	/// Gives up ownership of every frame after the first `len`, as if they had been dropped
+	pub(super) fn truncate(&mut self, len: NonZeroUsize) {
+		let Some(tail_len) = self.len.get().checked_sub(len.get()).and_then(NonZeroUsize::new) else { return; };
+
+		let tail = Self {
+			base: self.base + len.get(),
+			len: tail_len,
+			allocator: self.allocator
+		};
+		self.len = len;
+		drop(tail);
+	}
+
 	pub fn into_raw_parts(self) -> (Frame, NonZeroUsize, &'a dyn BackingAllocator) {
 		let this = ManuallyDrop::new(self);
 		(this.base, this.len, this.allocator)
@@ -122,19 +169,53 @@ impl<'a> OwnedFrames<'a> {
 			base, len, allocator
 		}
 	}
+
+	/// Creates another owner of the same frames
+	///
+	/// The frames are only deallocated once every owner has been dropped.
+	/// Fails if the frames are not in the reference counted range of physical memory.
+	pub fn share(&self) -> Result<Self, ()> {
+		for (i, frame) in (0..self.len.get()).map(|i| (i, self.base + i)) {
+			if unsafe { crate::bridge::memory::__popcorn_memory_physical_share(frame) }.is_err() {
+				// Undo the references already added, which can't be the last ones
+				for frame in (0..i).map(|i| self.base + i) {
+					unsafe { crate::bridge::memory::__popcorn_memory_physical_release(frame); }
+				}
+				return Err(());
+			}
+		}
+
+		Ok(Self { .. *self })
+	}
 }
 
 impl Clone for OwnedFrames<'_> {
 	fn clone(&self) -> Self {
-		todo!("Update RC nodes");
-		Self { .. *self }
+		self.share().expect("Frames are not reference counted")
 	}
 }
 
 impl Drop for OwnedFrames<'_> {
 	fn drop(&mut self) {
-		unsafe {
-			self.allocator.deallocate_contiguous(self.base, self.len);
+		// Only frames that have no other owners can be freed, so free each run of them
+		let mut run_start = None;
+		for i in 0..self.len.get() {
+			let frame = self.base + i;
+			let is_last = unsafe { crate::bridge::memory::__popcorn_memory_physical_release(frame) };
+
+			match (is_last, run_start) {
+				(true, None) => run_start = Some(frame),
+				(false, Some(start)) => {
+					unsafe { self.allocator.deallocate_contiguous(start, NonZeroUsize::new(frame - start).unwrap()); }
+					run_start = None;
+				}
+				_ => {}
+			}
+		}
+
+		if let Some(start) = run_start {
+			let end = self.base + self.len.get();
+			unsafe { self.allocator.deallocate_contiguous(start, NonZeroUsize::new(end - start).unwrap()); }
 		}
 	}
 }
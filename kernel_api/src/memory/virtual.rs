@@ -14,8 +14,30 @@ pub trait VirtualAllocator: Send + Sync {
 	fn allocate_contiguous(&self, len: usize) -> Result<Page, AllocError>;
 	fn allocate_contiguous_at(&self, at: Page, len: usize) -> Result<Page, AllocError>;
 	fn deallocate_contiguous(&self, base: Page, len: usize);
+
+	/// Returns how much of the allocator's address space is in use, or `None` if it doesn't keep track
+	fn stats(&self) -> Option<Stats> { None }
+}
+
+/// A snapshot of how much of a [`VirtualAllocator`]'s address space is in use
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Stats {
+	/// The number of pages the allocator manages
+	pub total_pages: usize,
+	/// The number of pages that aren't currently allocated
+	pub free_pages: usize,
+	/// The length of the longest run of free pages, which is the largest allocation that can succeed
+	pub largest_free_run: usize
+}
+
+impl Stats {
+	/// The number of pages that are currently allocated
+	pub fn used_pages(&self) -> usize {
+		self.total_pages - self.free_pages
+	}
 }
 
+#[derive(Copy, Clone)]
 pub struct Global;
 
 extern "Rust" {
@@ -42,6 +64,10 @@ impl VirtualAllocator for Global {
 	fn deallocate_contiguous(&self, base: Page, len: usize) {
 		unsafe { &GLOBAL_VIRTUAL_ALLOCATOR }.read().deallocate_contiguous(base, len)
 	}
+
+	fn stats(&self) -> Option<Stats> {
+		unsafe { &GLOBAL_VIRTUAL_ALLOCATOR }.read().stats()
+	}
 }
 
 pub struct OwnedPages<A: VirtualAllocator = Global> {
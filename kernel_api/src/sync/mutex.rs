@@ -68,6 +68,7 @@ unsafe impl lock_api::RawMutex for RawSpinlock {
     type GuardMarker = lock_api::GuardNoSend; // Interrupts are only disabled on the locking core so sending guard
 
     fn lock(&self) {
+        unsafe { crate::bridge::threading::__popcorn_preempt_disable(); }
         let irq_state = unsafe { crate::bridge::hal::__popcorn_disable_irq() };
 
         while let Err(_) = self.state.compare_exchange_weak(
@@ -83,6 +84,7 @@ unsafe impl lock_api::RawMutex for RawSpinlock {
     }
 
     fn try_lock(&self) -> bool {
+        unsafe { crate::bridge::threading::__popcorn_preempt_disable(); }
         let irq_state = unsafe { crate::bridge::hal::__popcorn_disable_irq() };
         let success = self.state.compare_exchange(
             State::Unlocked.into(),
@@ -91,8 +93,12 @@ unsafe impl lock_api::RawMutex for RawSpinlock {
             Ordering::Relaxed
         ).is_ok();
 
-        if !success { unsafe { crate::bridge::hal::__popcorn_set_irq(irq_state) } }
-        else { self.irq_state.store(irq_state, Ordering::Relaxed) }
+        if !success {
+            unsafe {
+                crate::bridge::hal::__popcorn_set_irq(irq_state);
+                crate::bridge::threading::__popcorn_preempt_enable();
+            }
+        } else { self.irq_state.store(irq_state, Ordering::Relaxed) }
 
         success
     }
@@ -104,7 +110,10 @@ unsafe impl lock_api::RawMutex for RawSpinlock {
 
         match old_state {
             State::Unlocked => unreachable!("Mutex was unlocked while unlocked"),
-            State::Locked => unsafe { crate::bridge::hal::__popcorn_set_irq(old_irq_state) },
+            State::Locked => unsafe {
+                crate::bridge::hal::__popcorn_set_irq(old_irq_state);
+                crate::bridge::threading::__popcorn_preempt_enable();
+            },
         }
     }
 }
@@ -22,6 +22,15 @@ pub type RwWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RwCount, T>;
 #[stable(feature = "kernel_core_api", since = "0.1.0")]
 pub struct RwCount(AtomicUsize);
 
+// A holder is never preempted, since a thread spinning on the lock could otherwise stop it from being released
+fn preempt_disable() {
+    unsafe { crate::bridge::threading::__popcorn_preempt_disable(); }
+}
+
+fn preempt_enable() {
+    unsafe { crate::bridge::threading::__popcorn_preempt_enable(); }
+}
+
 // FIXME: Deadlocks due to interrupts
 impl RwCount {
     const WRITE_BIT_MASK: usize = 1<<(mem::size_of::<usize>() * 8 - 1);
@@ -55,13 +64,17 @@ unsafe impl lock_api::RawRwLock for RwCount {
     }
 
     fn try_lock_shared(&self) -> bool {
+        preempt_disable();
         let mut old_value = self.0.load(Ordering::Relaxed);
 
         loop {
             let old_normal_count = old_value & Self::READ_COUNT_MASK;
 
             if old_normal_count == Self::READ_COUNT_MASK { panic!("Reader count overflowed") }
-            if (old_value & Self::WRITE_BIT_MASK) != 0 { return false; }
+            if (old_value & Self::WRITE_BIT_MASK) != 0 {
+                preempt_enable();
+                return false;
+            }
 
             let new_value = (old_normal_count + 1) | (old_value & Self::UPGRADEABLE_BIT_MASK);
 
@@ -82,7 +95,7 @@ unsafe impl lock_api::RawRwLock for RwCount {
             }
             let new_value = (old_normal_count - 1) | (old_value & Self::UPGRADEABLE_BIT_MASK);
             match self.0.compare_exchange_weak(old_value, new_value, Ordering::Release, Ordering::Relaxed) {
-                Ok(_) => return,
+                Ok(_) => return preempt_enable(),
                 Err(new_old_value) => old_value = new_old_value
             }
         }
@@ -95,8 +108,12 @@ unsafe impl lock_api::RawRwLock for RwCount {
     }
 
     fn try_lock_exclusive(&self) -> bool {
-        self.0.compare_exchange_weak(0, Self::WRITE_BIT_MASK, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
+        preempt_disable();
+        let locked = self.0.compare_exchange_weak(0, Self::WRITE_BIT_MASK, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        if !locked { preempt_enable(); }
+        locked
     }
 
     unsafe fn unlock_exclusive(&self) {
@@ -106,6 +123,7 @@ unsafe impl lock_api::RawRwLock for RwCount {
         } else {
             self.0.store(0, Ordering::Release);
         }
+        preempt_enable();
     }
 }
 
@@ -131,11 +149,14 @@ unsafe impl lock_api::RawRwLockUpgrade for RwCount {
     }
 
     fn try_lock_upgradable(&self) -> bool {
+        preempt_disable();
         let mut old_value = self.0.load(Ordering::Relaxed);
 
         loop {
-            if (old_value & Self::WRITE_BIT_MASK) != 0 { return false; }
-            if (old_value & Self::UPGRADEABLE_BIT_MASK) != 0 { return false; }
+            if (old_value & (Self::WRITE_BIT_MASK | Self::UPGRADEABLE_BIT_MASK)) != 0 {
+                preempt_enable();
+                return false;
+            }
 
             let new_value = old_value | Self::UPGRADEABLE_BIT_MASK;
 
@@ -154,7 +175,7 @@ unsafe impl lock_api::RawRwLockUpgrade for RwCount {
             }
             let new_value = old_value & !Self::UPGRADEABLE_BIT_MASK;
             match self.0.compare_exchange_weak(old_value, new_value, Ordering::Release, Ordering::Relaxed) {
-                Ok(_) => return,
+                Ok(_) => return preempt_enable(),
                 Err(new_old_value) => old_value = new_old_value
             }
         }
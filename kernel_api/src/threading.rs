@@ -0,0 +1,152 @@
+//! Kernel threads
+//!
+//! Threads are started with a [`Builder`], or with [`spawn`] for the default stack size and priority. They run until
+//! their closure returns, and the value it returns can be collected by [joining](JoinHandle::join) them.
+
+#![unstable(feature = "kernel_threads", issue = "none")]
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::num::NonZeroUsize;
+use crate::bridge::threading as bridge;
+use crate::memory::AllocError;
+use crate::sync::Mutex;
+
+const PAGE_SIZE: usize = 4096;
+
+/// The kernel stack size used when a [`Builder`] isn't given one
+pub const DEFAULT_STACK_SIZE: usize = 8 * PAGE_SIZE;
+
+/// Which threads are run first when several are ready
+///
+/// Priorities are strict, so a ready thread is only run once no thread with a higher priority is ready. Threads of the
+/// same priority are scheduled so that ones which block often run ahead of ones that use all of their time slice.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub enum Priority {
+	/// Background work that should only use otherwise idle time
+	Low,
+	/// The priority threads are given unless they ask for another
+	#[default]
+	Normal,
+	/// Work that has to happen promptly, such as servicing a device
+	High
+}
+
+/// Identifies a thread for as long as it exists
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ThreadId(usize);
+
+/// Configures a new thread before it is started
+#[derive(Debug, Clone)]
+#[must_use = "a `Builder` does nothing until it is spawned"]
+pub struct Builder {
+	name: Option<Cow<'static, str>>,
+	stack_size: usize,
+	priority: Priority
+}
+
+impl Builder {
+	/// Creates a builder for an unnamed thread with the [default stack size](DEFAULT_STACK_SIZE) and
+	/// [normal priority](Priority::Normal)
+	pub fn new() -> Self {
+		Self {
+			name: None,
+			stack_size: DEFAULT_STACK_SIZE,
+			priority: Priority::default()
+		}
+	}
+
+	/// Sets the name shown for the thread in debug output and panics
+	pub fn name(self, name: impl Into<Cow<'static, str>>) -> Self {
+		Self { name: Some(name.into()), ..self }
+	}
+
+	/// Sets the size of the thread's kernel stack in bytes, which is rounded up to a whole number of pages
+	pub fn stack_size(self, stack_size: usize) -> Self {
+		Self { stack_size, ..self }
+	}
+
+	/// Sets the [`Priority`] the thread is scheduled with
+	pub fn priority(self, priority: Priority) -> Self {
+		Self { priority, ..self }
+	}
+
+	/// Starts a thread running `f`
+	///
+	/// # Errors
+	///
+	/// Returns [`AllocError`] if the thread's stack or address space couldn't be allocated
+	pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, AllocError>
+			where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+		let packet = Arc::new(Mutex::new(None));
+		let their_packet = Arc::clone(&packet);
+		let main = Box::new(move || {
+			let result = f();
+			*their_packet.lock() = Some(result);
+		});
+
+		let name = self.name.unwrap_or(Cow::Borrowed("<unnamed>"));
+		let stack_pages = NonZeroUsize::new(self.stack_size.div_ceil(PAGE_SIZE)).unwrap_or(NonZeroUsize::MIN);
+		let tid = unsafe { bridge::__popcorn_thread_spawn(name, stack_pages, self.priority, main) }?;
+
+		Ok(JoinHandle { id: ThreadId(tid), packet })
+	}
+}
+
+impl Default for Builder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Starts a thread called `name` running `f`, with the default stack size and priority
+///
+/// # Panics
+///
+/// Panics if the thread couldn't be created. Use [`Builder::spawn`] to handle the error instead.
+pub fn spawn<F, T>(name: impl Into<Cow<'static, str>>, f: F) -> JoinHandle<T>
+		where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+	Builder::new().name(name).spawn(f).expect("Failed to spawn thread")
+}
+
+/// Lets another thread run, if any are ready
+pub fn yield_now() {
+	unsafe { bridge::__popcorn_thread_yield() }
+}
+
+/// Returns the ID of the current thread
+pub fn current() -> ThreadId {
+	ThreadId(unsafe { bridge::__popcorn_thread_current() })
+}
+
+/// Changes the [`Priority`] of `thread`, returning `false` if it has exited
+///
+/// The new priority is used the next time a thread is picked to run, so lowering the priority of the current thread
+/// doesn't switch away from it straight away.
+pub fn set_priority(thread: ThreadId, priority: Priority) -> bool {
+	unsafe { bridge::__popcorn_thread_set_priority(thread.0, priority) }
+}
+
+/// A thread that can be waited on until it exits
+///
+/// Dropping the handle detaches the thread, which carries on running and discards its result.
+#[derive(Debug)]
+#[must_use = "dropping a `JoinHandle` detaches the thread"]
+pub struct JoinHandle<T> {
+	id: ThreadId,
+	packet: Arc<Mutex<Option<T>>>
+}
+
+impl<T> JoinHandle<T> {
+	/// Returns the ID of the thread
+	pub fn id(&self) -> ThreadId {
+		self.id
+	}
+
+	/// Blocks the current thread until this thread has exited, and returns the value its closure returned
+	pub fn join(self) -> T {
+		unsafe { bridge::__popcorn_thread_join(self.id.0) }
+		self.packet.lock().take().expect("Thread exited without returning a value")
+	}
+}
@@ -0,0 +1,252 @@
+//! A heap wrapper that checks for memory corruption, enabled with the `debug` feature
+//!
+//! Every allocation is given a header and is surrounded by redzones, which are checked when it is freed. Freed
+//! allocations are poisoned and held in a quarantine for a while before being given back, so that double frees and
+//! writes after free can be caught.
+
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use core::ptr::NonNull;
+use kernel_api::memory::AllocError;
+use kernel_api::memory::heap::{Heap, Stats};
+use kernel_api::sync::Mutex;
+use log::debug;
+use crate::Allocation;
+
+/// The number of bytes checked on either side of an allocation
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfd;
+/// Written over new allocations, so that reads of uninitialised memory stand out
+const UNINIT_BYTE: u8 = 0xcd;
+/// Written over freed allocations
+const POISON_BYTE: u8 = 0xdd;
+
+const ALLOCATED_MAGIC: u64 = 0xa110_ca7e_d0b1_ec75;
+const FREED_MAGIC: u64 = 0xf7ee_d0b1_ec75_dead;
+
+/// The number of stack frames recorded for each allocation
+const CALL_SITE_DEPTH: usize = 12;
+/// The number of freed allocations held back from the inner heap
+const QUARANTINE_LEN: usize = 64;
+
+/// Stored directly before the front redzone of every allocation
+#[repr(C)]
+struct Header {
+    magic: u64,
+    layout: Layout,
+    prev: Option<NonNull<Header>>,
+    next: Option<NonNull<Header>>,
+    call_sites: [usize; CALL_SITE_DEPTH],
+    call_site_count: usize
+}
+
+/// The live allocations, so that they can be listed in leak reports
+struct AllocationList {
+    head: Option<NonNull<Header>>
+}
+
+/// Freed allocations that haven't been given back to the inner heap yet
+struct Quarantine {
+    entries: [Option<NonNull<Header>>; QUARANTINE_LEN],
+    next: usize
+}
+
+// SAFETY: headers are only accessed with the lock held, or by the owner of the allocation
+unsafe impl Send for AllocationList {}
+unsafe impl Send for Quarantine {}
+
+/// Where the parts of an allocation are, relative to the start of the memory from the inner heap
+#[derive(Debug, Copy, Clone)]
+struct Geometry {
+    /// The layout requested from the inner heap
+    outer: Layout,
+    /// The offset of the memory given to the caller
+    payload: usize
+}
+
+impl Geometry {
+    fn of(layout: Layout) -> Result<Self, AllocError> {
+        let align = layout.align().max(align_of::<Header>());
+        let payload = (size_of::<Header>() + REDZONE).next_multiple_of(align);
+        let size = payload.checked_add(layout.size())
+                .and_then(|size| size.checked_add(REDZONE))
+                .ok_or(AllocError)?;
+
+        Ok(Self {
+            outer: Layout::from_size_align(size, align).map_err(|_| AllocError)?,
+            payload
+        })
+    }
+}
+
+/// Wraps another heap, adding redzones and poisoning to find heap corruption
+///
+/// Detected corruption causes a panic, which reports the call site the allocation was made from.
+pub struct DebugHeap<H: Heap> {
+    inner: H,
+    live: Mutex<AllocationList>,
+    quarantine: Mutex<Quarantine>
+}
+
+impl<H: Heap> DebugHeap<H> {
+    fn header(payload: NonNull<u8>) -> NonNull<Header> {
+        unsafe { NonNull::new_unchecked(payload.as_ptr().sub(REDZONE + size_of::<Header>())).cast() }
+    }
+
+    fn payload(header: NonNull<Header>) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(header.as_ptr().cast::<u8>().add(size_of::<Header>() + REDZONE)) }
+    }
+
+    /// Returns the first byte of `len` bytes at `ptr` that isn't `expected`
+    unsafe fn find_mismatch(ptr: *const u8, len: usize, expected: u8) -> Option<usize> {
+        (0..len).find(|&i| unsafe { ptr.add(i).read() } != expected)
+    }
+
+    fn report(header: &Header, problem: core::fmt::Arguments<'_>) -> ! {
+        log::error!("Heap corruption: {problem}");
+        log::error!("Allocation of {:?} was made from:", header.layout);
+        for ip in &header.call_sites[..header.call_site_count] {
+            log::error!("    {ip:#x}");
+        }
+        panic!("Heap corruption: {problem}");
+    }
+
+    /// Checks that the redzones around an allocation are intact
+    unsafe fn check_redzones(header: &Header, payload: NonNull<u8>) {
+        let front = unsafe { payload.as_ptr().sub(REDZONE) };
+        if let Some(i) = unsafe { Self::find_mismatch(front, REDZONE, REDZONE_BYTE) } {
+            Self::report(header, format_args!("write {} bytes before the start of {payload:p}", REDZONE - i));
+        }
+
+        let back = unsafe { payload.as_ptr().add(header.layout.size()) };
+        if let Some(i) = unsafe { Self::find_mismatch(back, REDZONE, REDZONE_BYTE) } {
+            Self::report(header, format_args!("write {i} bytes past the end of {payload:p}"));
+        }
+    }
+
+    /// Gives a quarantined allocation back to the inner heap, checking it wasn't written to after being freed
+    unsafe fn release(&self, header: NonNull<Header>) {
+        let header_ref = unsafe { header.as_ref() };
+        let payload = Self::payload(header);
+        let geometry = Geometry::of(header_ref.layout).expect("Layout was allocated so must fit");
+
+        if let Some(i) = unsafe { Self::find_mismatch(payload.as_ptr(), header_ref.layout.size(), POISON_BYTE) } {
+            Self::report(header_ref, format_args!("write to offset {i} of {payload:p} after it was freed"));
+        }
+        unsafe { Self::check_redzones(header_ref, payload); }
+
+        let base = unsafe { NonNull::new_unchecked(payload.as_ptr().sub(geometry.payload)) };
+        unsafe { self.inner.deallocate(base, geometry.outer); }
+    }
+
+    /// Calls `f` with every live allocation
+    ///
+    /// The list of allocations is locked while `f` is running, so `f` must not allocate from the heap.
+    pub fn for_each_allocation(&self, f: &mut dyn FnMut(Allocation<'_>)) {
+        let live = self.live.lock();
+        let mut next = live.head;
+        while let Some(header) = next {
+            let header_ref = unsafe { header.as_ref() };
+            f(Allocation {
+                ptr: Self::payload(header),
+                layout: header_ref.layout,
+                call_sites: &header_ref.call_sites[..header_ref.call_site_count]
+            });
+            next = header_ref.next;
+        }
+    }
+}
+
+impl<H: Heap> Heap for DebugHeap<H> {
+    fn new() -> Self where Self: Sized {
+        debug!("Creating debug heap with {REDZONE} byte redzones and a quarantine of {QUARANTINE_LEN}");
+        Self {
+            inner: H::new(),
+            live: Mutex::new(AllocationList { head: None }),
+            quarantine: Mutex::new(Quarantine { entries: [None; QUARANTINE_LEN], next: 0 })
+        }
+    }
+
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let geometry = Geometry::of(layout)?;
+        let base = self.inner.allocate(geometry.outer)?;
+
+        let payload = unsafe { NonNull::new_unchecked(base.as_ptr().add(geometry.payload)) };
+        let header = Self::header(payload);
+
+        let mut call_sites = [0; CALL_SITE_DEPTH];
+        let call_site_count = unsafe { kernel_api::bridge::panic::__popcorn_backtrace_capture(&mut call_sites) };
+
+        unsafe {
+            header.write(Header {
+                magic: ALLOCATED_MAGIC,
+                layout,
+                prev: None,
+                next: None,
+                call_sites,
+                call_site_count
+            });
+            ptr::write_bytes(payload.as_ptr().sub(REDZONE), REDZONE_BYTE, REDZONE);
+            ptr::write_bytes(payload.as_ptr(), UNINIT_BYTE, layout.size());
+            ptr::write_bytes(payload.as_ptr().add(layout.size()), REDZONE_BYTE, REDZONE);
+        }
+
+        let mut live = self.live.lock();
+        unsafe {
+            (*header.as_ptr()).next = live.head;
+            if let Some(head) = live.head { (*head.as_ptr()).prev = Some(header); }
+        }
+        live.head = Some(header);
+
+        Ok(payload)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let header = Self::header(ptr);
+        let header_ref = unsafe { header.as_ref() };
+
+        match header_ref.magic {
+            ALLOCATED_MAGIC => {},
+            FREED_MAGIC => Self::report(header_ref, format_args!("double free of {ptr:p}")),
+            magic => panic!("Heap corruption: {ptr:p} was not allocated from the heap, or its header was overwritten (magic {magic:#x})")
+        }
+        if header_ref.layout != layout {
+            Self::report(header_ref, format_args!("{ptr:p} freed with {layout:?}"));
+        }
+        unsafe { Self::check_redzones(header_ref, ptr); }
+
+        {
+            let mut live = self.live.lock();
+            let Header { prev, next, .. } = *header_ref;
+            unsafe {
+                match prev {
+                    Some(prev) => (*prev.as_ptr()).next = next,
+                    None => live.head = next
+                }
+                if let Some(next) = next { (*next.as_ptr()).prev = prev; }
+            }
+        }
+
+        unsafe {
+            (*header.as_ptr()).magic = FREED_MAGIC;
+            ptr::write_bytes(ptr.as_ptr(), POISON_BYTE, layout.size());
+        }
+
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let idx = quarantine.next;
+            quarantine.next = (idx + 1) % QUARANTINE_LEN;
+            quarantine.entries[idx].replace(header)
+        };
+
+        // Released without the quarantine locked, as the inner heap may reenter this one to map more memory
+        if let Some(evicted) = evicted {
+            unsafe { self.release(evicted); }
+        }
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        self.inner.stats()
+    }
+}
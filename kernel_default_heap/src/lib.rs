@@ -1,77 +1,369 @@
 #![no_std]
 
 #![feature(kernel_heap)]
-#![feature(kernel_address_alignment_runtime)]
 #![feature(kernel_sync_once)]
 #![feature(kernel_mmap)]
+#![feature(kernel_virtual_memory)]
+#![feature(kernel_internals)]
 #![feature(int_roundings)]
 
 use core::alloc::Layout;
-use core::fmt::Debug;
+use core::cmp::min;
+use core::fmt::{Debug, Formatter};
+use core::num::NonZeroUsize;
+use core::ptr;
 use core::ptr::NonNull;
-use kernel_api::memory::heap::Heap;
-use kernel_api::memory::{VirtualAddress, AllocError};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel_api::memory::heap::{Heap, Stats};
+use kernel_api::memory::{AllocError, Page, VirtualAddress};
+use kernel_api::memory::mapping::{Config, Laziness, Mapping};
+use kernel_api::memory::physical::{highmem, OwnedFrames};
+use kernel_api::memory::r#virtual::{Global, OwnedPages};
 use kernel_api::sync::{LazyLock, Mutex};
-use log::debug;
-use kernel_api::memory::mapping::OldMapping;
+use log::{debug, trace};
 
-//const _: () = {
-    static KERNEL_HEAP: LazyLock<SyncHeap> = LazyLock::new(SyncHeap::new);
+#[cfg(feature = "debug")]
+mod debug;
 
-    #[no_mangle]
-    pub extern "Rust" fn __popcorn_kernel_heap_allocate(layout: Layout) -> Result<NonNull<u8>, AllocError> {
-        <SyncHeap as Heap>::allocate(&KERNEL_HEAP, layout)
+#[cfg(not(feature = "debug"))]
+type KernelHeap = SizeClassHeap;
+#[cfg(feature = "debug")]
+type KernelHeap = debug::DebugHeap<SizeClassHeap>;
+
+static KERNEL_HEAP: LazyLock<KernelHeap> = LazyLock::new(KernelHeap::new);
+
+#[no_mangle]
+pub extern "Rust" fn __popcorn_kernel_heap_allocate(layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    <KernelHeap as Heap>::allocate(&KERNEL_HEAP, layout)
+}
+
+#[no_mangle]
+pub unsafe extern "Rust" fn __popcorn_kernel_heap_deallocate(ptr: NonNull<u8>, layout: Layout)  {
+    <KernelHeap as Heap>::deallocate(&KERNEL_HEAP, ptr, layout)
+}
+
+#[no_mangle]
+pub unsafe extern "Rust" fn __popcorn_kernel_heap_reallocate(ptr: NonNull<u8>, old_layout: Layout, new_size: usize) -> Result<NonNull<u8>, AllocError> {
+    <KernelHeap as Heap>::reallocate(&KERNEL_HEAP, ptr, old_layout, new_size)
+}
+
+#[no_mangle]
+pub extern "Rust" fn __popcorn_kernel_heap_stats() -> Option<Stats> {
+    <KernelHeap as Heap>::stats(&KERNEL_HEAP)
+}
+
+/// A live heap allocation, as listed by [`__popcorn_kernel_heap_allocations`]
+#[derive(Debug, Copy, Clone)]
+pub struct Allocation<'a> {
+    pub ptr: NonNull<u8>,
+    pub layout: Layout,
+    /// The instruction pointers of the stack frames the allocation was made from, innermost first
+    pub call_sites: &'a [usize]
+}
+
+/// Calls `f` with every live heap allocation, returning `false` if the heap doesn't keep track of them
+///
+/// Allocations are only tracked with the `debug` feature. The heap is locked while `f` is running, so `f` must not
+/// allocate.
+#[no_mangle]
+pub extern "Rust" fn __popcorn_kernel_heap_allocations(f: &mut dyn FnMut(Allocation<'_>)) -> bool {
+    #[cfg(feature = "debug")]
+    {
+        KERNEL_HEAP.for_each_allocation(f);
+        true
     }
 
-    #[no_mangle]
-    pub unsafe extern "Rust" fn __popcorn_kernel_heap_deallocate(ptr: NonNull<u8>, layout: Layout)  {
-        <SyncHeap as Heap>::deallocate(&KERNEL_HEAP, ptr, layout)
+    #[cfg(not(feature = "debug"))]
+    {
+        let _ = f;
+        false
     }
-//};
+}
 
-#[derive(Debug)]
-struct SyncHeap(Mutex<BadHeap>);
+const PAGE_SIZE: usize = 4096;
 
-#[derive(Debug)]
-struct BadHeap {
-    watermark: VirtualAddress,
-    mapping: OldMapping
+/// The object sizes served from slabs, anything larger is given its own pages
+const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// Where an allocation of a given layout is served from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Class {
+    /// An index into [`SIZE_CLASSES`]
+    Small(usize),
+    /// The number of pages needed
+    Large(NonZeroUsize)
 }
 
-impl Heap for SyncHeap {
-    fn new() -> Self where Self: Sized {
-        let mapping = OldMapping::new(0).unwrap();
+impl Class {
+    fn of(layout: Layout) -> Result<Self, AllocError> {
+        // Objects are aligned to their size class, so a class large enough for the alignment always satisfies it
+        let size = layout.size().max(layout.align());
+        if let Some(idx) = SIZE_CLASSES.iter().position(|&class| class >= size) {
+            return Ok(Self::Small(idx));
+        }
+
+        // Pages can't be given any more alignment than the virtual allocator provides
+        if layout.align() > PAGE_SIZE { return Err(AllocError); }
+        Ok(Self::Large(NonZeroUsize::new(layout.size().div_ceil(PAGE_SIZE)).unwrap()))
+    }
+}
+
+/// An unused object in a slab, which links to the next unused object
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>
+}
+
+/// The header at the start of every slab page
+///
+/// Objects start at the first multiple of the object size after the header.
+struct Slab {
+    free: Option<NonNull<FreeObject>>,
+    used: usize,
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>
+}
+
+impl Slab {
+    /// # Safety
+    ///
+    /// `page` must point to an unused, writable page
+    unsafe fn init(page: NonNull<u8>, object_size: usize) -> NonNull<Slab> {
+        let first_object = core::mem::size_of::<Slab>().next_multiple_of(object_size);
+
+        let mut free = None;
+        for offset in (first_object..PAGE_SIZE).step_by(object_size).rev() {
+            let object = unsafe { NonNull::new_unchecked(page.as_ptr().add(offset)).cast::<FreeObject>() };
+            unsafe { object.write(FreeObject { next: free }); }
+            free = Some(object);
+        }
+
+        let slab = page.cast::<Slab>();
+        unsafe { slab.write(Slab { free, used: 0, prev: None, next: None }); }
+        slab
+    }
+
+    fn containing(ptr: NonNull<u8>) -> NonNull<Slab> {
+        let addr = ptr.as_ptr() as usize;
+        unsafe { NonNull::new_unchecked(ptr.as_ptr().sub(addr % PAGE_SIZE)).cast() }
+    }
+}
+
+/// The slabs of one size class that still have unused objects
+///
+/// Full slabs aren't tracked, as they are found from the objects in them when they are freed.
+struct SizeClass {
+    object_size: usize,
+    partial: Option<NonNull<Slab>>,
+    /// The number of slabs, including full ones
+    slabs: usize,
+    /// The number of objects allocated across all slabs
+    in_use: usize
+}
+
+// SAFETY: slabs are only accessed with the lock of their size class held
+unsafe impl Send for SizeClass {}
+
+impl SizeClass {
+    unsafe fn push(&mut self, mut slab: NonNull<Slab>) {
+        unsafe {
+            slab.as_mut().prev = None;
+            slab.as_mut().next = self.partial;
+            if let Some(mut head) = self.partial { head.as_mut().prev = Some(slab); }
+        }
+        self.partial = Some(slab);
+    }
+
+    unsafe fn remove(&mut self, slab: NonNull<Slab>) {
+        let Slab { prev, next, .. } = *unsafe { slab.as_ref() };
+        unsafe {
+            match prev {
+                Some(mut prev) => prev.as_mut().next = next,
+                None => self.partial = next
+            }
+            if let Some(mut next) = next { next.as_mut().prev = prev; }
+        }
+    }
 
-        Self(Mutex::new(BadHeap {
-            watermark: mapping.end().start().align_down(),
-            mapping
-        }))
+    fn allocate(&mut self) -> Option<NonNull<u8>> {
+        let mut slab = self.partial?;
+        let slab_ref = unsafe { slab.as_mut() };
+
+        let object = slab_ref.free.expect("Partial slabs have a free object");
+        slab_ref.free = unsafe { object.as_ref().next };
+        slab_ref.used += 1;
+        self.in_use += 1;
+
+        if slab_ref.free.is_none() {
+            unsafe { self.remove(slab); }
+        }
+
+        Some(object.cast())
+    }
+
+    /// Returns the slab if it is now unused and can be given back
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>) -> Option<NonNull<Slab>> {
+        let mut slab = Slab::containing(ptr);
+
+        let (was_full, used) = {
+            let slab_ref = unsafe { slab.as_mut() };
+            let was_full = slab_ref.free.is_none();
+            let object = ptr.cast::<FreeObject>();
+            unsafe { object.write(FreeObject { next: slab_ref.free }); }
+            slab_ref.free = Some(object);
+            slab_ref.used -= 1;
+            (was_full, slab_ref.used)
+        };
+        self.in_use -= 1;
+
+        if was_full {
+            unsafe { self.push(slab); }
+        }
+
+        // Keep the last slab around, so that a single allocation and free doesn't map and unmap a page every time
+        let slab_ref = unsafe { slab.as_ref() };
+        let is_only_slab = slab_ref.prev.is_none() && slab_ref.next.is_none();
+        if used == 0 && !is_only_slab {
+            unsafe { self.remove(slab); }
+            self.slabs -= 1;
+            Some(slab)
+        } else {
+            None
+        }
+    }
+}
+
+/// A heap with slabs for small objects, and separately mapped pages for larger objects
+///
+/// Each size class has its own lock, and no lock is held while mapping more memory, so the virtual and physical
+/// allocators are free to use the heap themselves.
+struct SizeClassHeap {
+    classes: [Mutex<SizeClass>; SIZE_CLASSES.len()],
+    /// The number of pages mapped for allocations too large for any size class
+    large_pages: AtomicUsize
+}
+
+impl Debug for SizeClassHeap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SizeClassHeap")
+                .field("size_classes", &SIZE_CLASSES)
+                .finish_non_exhaustive()
+    }
+}
+
+/// Maps `count` pages of memory that are owned by the heap until passed to [`unmap_pages`]
+fn map_pages(count: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+    // Heap memory may be touched while the page tables are locked, so it can't be faulted in lazily
+    let mapping = Mapping::new(Config::<Global>::new(count).laziness(Laziness::Prefault))?;
+    let (frames, pages) = mapping.into_raw_parts();
+    let _ = frames.into_raw_parts();
+    let (base, _, _) = pages.into_raw_parts();
+
+    trace!("Heap mapped {count} pages at {base:x?}");
+    Ok(NonNull::new(base.start().as_ptr()).expect("Mapping was at null"))
+}
+
+/// # Safety
+///
+/// `ptr` and `count` must be from a call to [`map_pages`]
+unsafe fn unmap_pages(ptr: NonNull<u8>, count: NonZeroUsize) {
+    let page = Page::new(VirtualAddress::new(ptr.as_ptr() as usize));
+    let frame = unsafe {
+        let page_table = kernel_api::bridge::paging::__popcorn_paging_get_ktable();
+        kernel_api::bridge::paging::__popcorn_paging_ktable_translate_page(&page_table, page)
+    }.expect("Heap memory is always mapped");
+
+    trace!("Heap unmapping {count} pages at {page:x?}");
+    let mapping = unsafe {
+        Mapping::from_raw_parts(
+            OwnedFrames::from_raw_parts(frame, count, highmem()),
+            OwnedPages::from_raw_parts(page, count, Global)
+        )
+    };
+    drop(mapping);
+}
+
+impl Heap for SizeClassHeap {
+    fn new() -> Self where Self: Sized {
+        debug!("Creating kernel heap with size classes {SIZE_CLASSES:?}");
+        Self {
+            classes: SIZE_CLASSES.map(|object_size| Mutex::new(SizeClass { object_size, partial: None, slabs: 0, in_use: 0 })),
+            large_pages: AtomicUsize::new(0)
+        }
     }
 
     fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
-        debug!("allocate {layout:?}");
-
-        let mut guard = self.0.lock();
-        let start = guard.watermark.align_up_runtime(layout.align());
-        let end = start + layout.size();
-
-        let max_addr = guard.mapping.end().start();
-        if end > max_addr {
-            debug!("Increment heap end");
-            let increment = isize::try_from(end - max_addr).map_err(|_| AllocError)?;
-            let increment = increment.div_ceil(4096);
-            let new_len = guard.mapping.len() + increment.unsigned_abs();
-            debug!("Trying to remap");
-            guard.mapping.resize_in_place(new_len)?;
+        trace!("allocate {layout:?}");
+
+        match Class::of(layout)? {
+            Class::Small(idx) => loop {
+                let mut class = self.classes[idx].lock();
+                if let Some(object) = class.allocate() { return Ok(object); }
+                let object_size = class.object_size;
+                drop(class);
+
+                // Another thread may also add a slab in the meantime, in which case both are used
+                let page = map_pages(NonZeroUsize::new(1).unwrap())?;
+                let mut class = self.classes[idx].lock();
+                unsafe {
+                    let slab = Slab::init(page, object_size);
+                    class.push(slab);
+                }
+                class.slabs += 1;
+            },
+            Class::Large(count) => {
+                let pages = map_pages(count)?;
+                self.large_pages.fetch_add(count.get(), Ordering::Relaxed);
+                Ok(pages)
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        trace!("deallocate {ptr:p} {layout:?}");
+
+        match Class::of(layout).expect("Layout was allocated so must have a class") {
+            Class::Small(idx) => {
+                let unused = unsafe { self.classes[idx].lock().deallocate(ptr) };
+                if let Some(slab) = unused {
+                    unsafe { unmap_pages(slab.cast(), NonZeroUsize::new(1).unwrap()); }
+                }
+            },
+            Class::Large(count) => {
+                unsafe { unmap_pages(ptr, count); }
+                self.large_pages.fetch_sub(count.get(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    unsafe fn reallocate(&self, ptr: NonNull<u8>, old_layout: Layout, new_size: usize) -> Result<NonNull<u8>, AllocError> {
+        let new_layout = Layout::from_size_align(new_size, old_layout.align()).map_err(|_| AllocError)?;
+
+        // The allocation will be freed using the new layout, so it can only stay put if that gives the same class
+        if Class::of(old_layout)? == Class::of(new_layout)? {
+            return Ok(ptr);
         }
 
-        guard.watermark = end;
-        Ok(NonNull::new(start.as_ptr()).unwrap())
+        let new = self.allocate(new_layout)?;
+        unsafe {
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr(), min(old_layout.size(), new_size));
+            self.deallocate(ptr, old_layout);
+        }
+        Ok(new)
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, _: Layout) {
-        if let Some(guard) = self.0.try_lock() {
-            debug_assert!(guard.watermark.as_ptr() >= ptr.as_ptr(), "Out of range pointer was freed");
+    fn stats(&self) -> Option<Stats> {
+        let large_pages = self.large_pages.load(Ordering::Relaxed);
+        let mut stats = Stats {
+            mapped_pages: large_pages,
+            allocated_bytes: large_pages * PAGE_SIZE
+        };
+
+        for class in &self.classes {
+            let class = class.lock();
+            stats.mapped_pages += class.slabs;
+            stats.allocated_bytes += class.in_use * class.object_size;
         }
+
+        Some(stats)
     }
 }
@@ -1,175 +1,465 @@
 #![cfg_attr(not(test), no_std)]
-#![feature(generic_const_exprs)]
-#![feature(slice_ptr_get)]
-#![feature(pointer_byte_offsets)]
-#![feature(strict_provenance)]
-#![feature(new_uninit)]
-#![feature(pointer_is_aligned)]
+#![feature(kernel_virtual_memory)]
+#![feature(kernel_mmap)]
+#![feature(kernel_internals)]
+#![feature(kernel_physical_page_offset)]
+#![feature(kernel_object_cache)]
 
 extern crate alloc;
 
-pub mod linked_list;
-
-use core::marker::PhantomData;
+use core::alloc::Layout;
 use core::mem;
-use core::mem::MaybeUninit;
+use core::num::NonZeroUsize;
 use core::ptr::NonNull;
-use more_asserts::assert_ge;
-use crate::linked_list::LinkedList;
+use kernel_api::memory::{AllocError, Page, VirtualAddress};
+use kernel_api::memory::cache::{Hooks, Placement, Stats};
+use kernel_api::memory::mapping::{Config, Laziness, Mapping};
+use kernel_api::memory::physical::OwnedFrames;
+use kernel_api::memory::r#virtual::{Global, OwnedPages};
+
+const PAGE_SIZE: usize = 4096;
 
-const SLAB_SIZE: usize = 4096;
+/// Slabs are made large enough to hold at least this many objects, so that large objects don't waste most of a slab
+const MIN_OBJECTS_PER_SLAB: usize = 8;
 
-pub trait Cacheable: Default {
-    fn recycle(&mut self) {}
+/// Provides the memory that slabs are carved out of
+///
+/// # Safety
+///
+/// Slabs must be page aligned, writable, and not used by anything else until they are given back
+pub unsafe trait SlabSource {
+    /// Allocates a slab of `pages` contiguous pages
+    fn allocate_slab(&self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError>;
+
+    /// # Safety
+    ///
+    /// `slab` and `pages` must be from a call to [`allocate_slab`](Self::allocate_slab) on the same source
+    unsafe fn deallocate_slab(&self, slab: NonNull<u8>, pages: NonZeroUsize);
 }
 
-pub struct SlabAllocator<'vmm, T: Cacheable> {
-    virtual_allocator: &'vmm dyn VirtualMemoryAllocator,
-    slabs: linked_list::LinkedList<SlabMetadata>
-    _phantom: PhantomData<T>
+unsafe impl SlabSource for Placement {
+    fn allocate_slab(&self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+        let base = match *self {
+            Placement::Mapped { physical_allocator, virtual_allocator } => {
+                // Objects may be touched while the page tables are locked, so slabs can't be faulted in lazily
+                let config = Config::<Global>::new(pages)
+                        .physical_allocator(physical_allocator)
+                        .virtual_allocator(virtual_allocator)
+                        .laziness(Laziness::Prefault);
+                let (frames, pages) = Mapping::new(config)?.into_raw_parts();
+                let _ = frames.into_raw_parts();
+                let (base, _, _) = pages.into_raw_parts();
+                base
+            },
+            Placement::PhysicalMap(allocator) => allocator.allocate_contiguous(pages.get())?.to_page()
+        };
+
+        Ok(NonNull::new(base.as_ptr()).expect("Slab was at null"))
+    }
+
+    unsafe fn deallocate_slab(&self, slab: NonNull<u8>, pages: NonZeroUsize) {
+        let page = Page::new(VirtualAddress::new(slab.as_ptr() as usize));
+
+        match *self {
+            Placement::Mapped { physical_allocator, virtual_allocator } => {
+                let frame = unsafe {
+                    let page_table = kernel_api::bridge::paging::__popcorn_paging_get_ktable();
+                    kernel_api::bridge::paging::__popcorn_paging_ktable_translate_page(&page_table, page)
+                }.expect("Slabs are always mapped");
+
+                let mapping = unsafe {
+                    Mapping::from_raw_parts(
+                        OwnedFrames::from_raw_parts(frame, pages, physical_allocator),
+                        OwnedPages::from_raw_parts(page, pages, virtual_allocator)
+                    )
+                };
+                drop(mapping);
+            },
+            Placement::PhysicalMap(allocator) => unsafe {
+                allocator.deallocate_contiguous(page.to_frame(), pages)
+            }
+        }
+    }
 }
 
-pub trait VirtualMemoryAllocator {}
+/// The header at the start of every slab
+///
+/// It is followed by a stack of the indices of the free objects in the slab, and then the objects themselves.
+/// Free objects are tracked outside of the objects, so that they are kept in their constructed state.
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    /// The number of indices on the free stack
+    free: usize
+}
 
-impl<'vmm, T: Cacheable> SlabAllocator<'vmm, T> {
-    const ALLOC_SIZE: usize = mem::size_of::<T>().next_power_of_two();
+impl Slab {
+    fn free_stack(slab: NonNull<Slab>) -> NonNull<u16> {
+        unsafe { NonNull::new_unchecked(slab.as_ptr().add(1)).cast() }
+    }
+}
+
+/// How objects are laid out within each slab of a [`SlabAllocator`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Geometry {
+    pages: NonZeroUsize,
+    objects: usize,
+    stride: usize,
+    first_object: usize
+}
 
-    pub fn new(vmm: &dyn VirtualMemoryAllocator) -> Self {
-        todo!()
+impl Geometry {
+    fn first_object(objects: usize, align: usize) -> usize {
+        (mem::size_of::<Slab>() + objects * mem::size_of::<u16>()).next_multiple_of(align)
     }
 
-    pub fn alloc(&self) -> Result<NonNull<T>, AllocError> {
+    fn of(layout: Layout) -> Self {
+        let layout = layout.pad_to_align();
+        let stride = layout.size().max(1);
 
+        for pages in 1.. {
+            let slab_size = pages * PAGE_SIZE;
+            let mut objects = ((slab_size - mem::size_of::<Slab>()) / (stride + mem::size_of::<u16>())).min(u16::MAX as usize);
+            while objects > 0 && Self::first_object(objects, layout.align()) + objects * stride > slab_size {
+                objects -= 1;
+            }
+
+            if objects >= MIN_OBJECTS_PER_SLAB {
+                return Self {
+                    pages: NonZeroUsize::new(pages).unwrap(),
+                    objects,
+                    stride,
+                    first_object: Self::first_object(objects, layout.align())
+                };
+            }
+        }
+        unreachable!()
     }
 
-    /// # Safety
-    /// Pointer must have been allocated by this allocator
-    /// Size must be the original allocation size
-    pub unsafe fn dealloc(&self, ptr: NonNull<T>) {
-        todo!()
+    fn object(&self, slab: NonNull<Slab>, idx: usize) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(slab.as_ptr().cast::<u8>().add(self.first_object + idx * self.stride)) }
+    }
+
+    fn contains(&self, slab: NonNull<Slab>, ptr: NonNull<u8>) -> bool {
+        let start = slab.as_ptr() as usize;
+        (start..(start + self.pages.get() * PAGE_SIZE)).contains(&(ptr.as_ptr() as usize))
     }
 }
 
-type EmptyBlock = linked_list::Node<()>;
+/// An intrusive list of slabs, linked through their headers
+#[derive(Debug, Default)]
+struct SlabList {
+    head: Option<NonNull<Slab>>
+}
+
+impl SlabList {
+    unsafe fn push(&mut self, mut slab: NonNull<Slab>) {
+        unsafe {
+            slab.as_mut().prev = None;
+            slab.as_mut().next = self.head;
+            if let Some(mut head) = self.head { head.as_mut().prev = Some(slab); }
+        }
+        self.head = Some(slab);
+    }
+
+    unsafe fn remove(&mut self, slab: NonNull<Slab>) {
+        let Slab { prev, next, .. } = *unsafe { slab.as_ref() };
+        unsafe {
+            match prev {
+                Some(mut prev) => prev.as_mut().next = next,
+                None => self.head = next
+            }
+            if let Some(mut next) = next { next.as_mut().prev = prev; }
+        }
+    }
+
+    fn pop(&mut self) -> Option<NonNull<Slab>> {
+        let slab = self.head?;
+        unsafe { self.remove(slab); }
+        Some(slab)
+    }
 
-#[derive(Debug)]
-struct SlabMetadata {
-    block_free_list: linked_list::LinkedList<()>,
-    slab: NonNull<[u8]>
+    fn iter(&self) -> impl Iterator<Item = NonNull<Slab>> + '_ {
+        core::iter::successors(self.head, |slab| unsafe { slab.as_ref().next })
+    }
 }
 
-impl SlabMetadata {
+/// A cache of equally sized objects, carved out of slabs from a [`SlabSource`]
+///
+/// If a constructor is given, every object is constructed when its slab is created, and objects must be given back in
+/// their constructed state. The destructor is run on every object when its slab is given back to the source.
+/// At most one empty slab is kept around, so that a single allocation and free doesn't create a new slab every time.
+pub struct SlabAllocator<S: SlabSource> {
+    source: S,
+    geometry: Geometry,
+    object_size: usize,
+    hooks: Hooks,
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    slabs: usize,
+    in_use: usize
+}
 
+// SAFETY: slabs are only accessed through the allocator that owns them
+unsafe impl<S: SlabSource + Send> Send for SlabAllocator<S> {}
 
-    unsafe fn new(allocation: NonNull<[u8]>, block_size_log2: usize) -> &'slab mut linked_list::Node<SlabMetadata> {
-        use linked_list::Node;
+impl<S: SlabSource> SlabAllocator<S> {
+    pub fn new(source: S, layout: Layout, hooks: Hooks) -> Self {
+        Self {
+            source,
+            geometry: Geometry::of(layout),
+            object_size: layout.size(),
+            hooks,
+            partial: SlabList::default(),
+            full: SlabList::default(),
+            empty: SlabList::default(),
+            slabs: 0,
+            in_use: 0
+        }
+    }
 
-        let block_size = 1 << block_size_log2;
-        assert_ge!(block_size, mem::size_of::<EmptyBlock>());
-        assert!(allocation.as_mut_ptr().is_aligned_to(block_size));
+    pub fn allocate(&mut self) -> Result<NonNull<u8>, AllocError> {
+        let mut slab = match self.partial.pop().or_else(|| self.empty.pop()) {
+            Some(slab) => slab,
+            None => self.new_slab()?
+        };
 
-        let block_count = allocation.len() / block_size;
-        if block_count < 8 {
-            // Large blocks - store metadata outside
-            let mut previous_block = None;
-            for block_backing in allocation.chunks_exact_mut(block_size) {
-                // SAFETY: Safe to assume random memory is uninitialised
-                // We assert that the block array starts on an aligned pointer therefore since `size == align`,
-                // each subsequent block is also aligned
-                // Pointer was generated from a `&mut [u8]` so must point to valid memory
-                let block = unsafe { &mut *(block_backing.as_mut_ptr() as *mut MaybeUninit<EmptyBlock>) };
-                let block = block.write(Node::new((), previous_block));
-                previous_block = Some(NonNull::from(block));
+        let idx = unsafe {
+            let slab_ref = slab.as_mut();
+            slab_ref.free -= 1;
+            Slab::free_stack(slab).as_ptr().add(slab_ref.free).read()
+        };
+        self.in_use += 1;
+
+        if unsafe { slab.as_ref().free } == 0 {
+            unsafe { self.full.push(slab); }
+        } else {
+            unsafe { self.partial.push(slab); }
+        }
+
+        Ok(self.geometry.object(slab, idx.into()))
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator, and be in its constructed state if there is a constructor
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let mut slab = self.containing(ptr);
+        let was_full = unsafe { slab.as_ref().free } == 0;
+
+        let idx = (ptr.as_ptr() as usize - slab.as_ptr() as usize - self.geometry.first_object) / self.geometry.stride;
+        unsafe {
+            let slab_ref = slab.as_mut();
+            Slab::free_stack(slab).as_ptr().add(slab_ref.free).write(idx as u16);
+            slab_ref.free += 1;
+        }
+        self.in_use -= 1;
+
+        unsafe {
+            if was_full { self.full.remove(slab); } else { self.partial.remove(slab); }
+
+            if slab.as_ref().free < self.geometry.objects {
+                self.partial.push(slab);
+            } else if self.empty.head.is_none() {
+                self.empty.push(slab);
+            } else {
+                self.release(slab);
             }
+        }
+    }
 
-            let metadata: &mut Node<SlabMetadata> = todo!();
-            metadata.data = SlabMetadata {
-                block_free_list: LinkedList::from(previous_block),
-                slab: NonNull::from(allocation),
-                _phantom: PhantomData
-            };
+    /// Gives every empty slab back to the source, returning the number of slabs released
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        while let Some(slab) = self.empty.pop() {
+            unsafe { self.release(slab); }
+            released += 1;
+        }
+        released
+    }
 
-            metadata
-        } else {
-            // Small blocks - store metadata inside
-            const METADATA_SIZE: usize = mem::size_of::<Node<SlabMetadata>>();
-            const METADATA_ALIGN: usize = mem::align_of::<Node<SlabMetadata>>();
-
-            // SAFETY: Resulting pointer is one past the end of the slice which is legal
-            let allocation_end_ptr = unsafe { allocation.as_mut_ptr().byte_add(allocation.len()) };
-            let metadata_start_pointer = unsafe {
-                // SAFETY: Pointer gets manually aligned and we assert that its within the original allocation
-                let unaligned = allocation_end_ptr.byte_sub(METADATA_SIZE);
-                let aligned = (unaligned.addr()) & !(METADATA_ALIGN);
-                assert_ge!(aligned, allocation.as_mut_ptr().addr());
-                aligned
-            };
-            let difference = allocation_end_ptr.addr() - metadata_start_pointer;
-            let (slab_data, metadata_storage) = allocation.split_at_mut(allocation.len() - difference);
-
-            let mut previous_block = None;
-            for block_backing in slab_data.chunks_exact_mut(block_size) {
-                // SAFETY: Safe to assume random memory is uninitialised
-                // We assert that the block array starts on an aligned pointer therefore since `size == align`,
-                // each subsequent block is also aligned
-                // Pointer was generated from a `&mut [u8]` so must point to valid memory
-                let block = unsafe { &mut *(block_backing.as_mut_ptr() as *mut MaybeUninit<EmptyBlock>) };
-                let block = block.write(Node::new((), previous_block));
-                previous_block = Some(NonNull::from(block));
+    pub fn stats(&self) -> Stats {
+        Stats {
+            object_size: self.object_size,
+            in_use: self.in_use,
+            free: self.slabs * self.geometry.objects - self.in_use,
+            slabs: self.slabs
+        }
+    }
+
+    fn new_slab(&mut self) -> Result<NonNull<Slab>, AllocError> {
+        let slab = self.source.allocate_slab(self.geometry.pages)?.cast::<Slab>();
+
+        unsafe {
+            slab.write(Slab { prev: None, next: None, free: self.geometry.objects });
+            let free_stack = Slab::free_stack(slab).as_ptr();
+            for idx in 0..self.geometry.objects {
+                free_stack.add(idx).write(idx as u16);
+                if let Some(constructor) = self.hooks.constructor {
+                    constructor(self.geometry.object(slab, idx));
+                }
             }
+        }
 
-            let metadata = SlabMetadata {
-                block_free_list: LinkedList::from(previous_block),
-                slab: NonNull::from(slab_data),
-                _phantom: PhantomData
-            };
+        self.slabs += 1;
+        Ok(slab)
+    }
 
-            // SAFETY: We aligned this pointer at generation from a valid allocation
-            // and safe to assume it is uninitialized
-            let metadata_storage = unsafe {
-                &mut *(metadata_storage.as_mut_ptr() as *mut MaybeUninit<Node<SlabMetadata>>)
-            };
-            metadata_storage.write(Node::new(metadata, None))
+    /// # Safety
+    ///
+    /// `slab` must be unused and not in any list
+    unsafe fn release(&mut self, slab: NonNull<Slab>) {
+        if let Some(destructor) = self.hooks.destructor {
+            for idx in 0..self.geometry.objects {
+                unsafe { destructor(self.geometry.object(slab, idx)); }
+            }
         }
+
+        unsafe { self.source.deallocate_slab(slab.cast(), self.geometry.pages); }
+        self.slabs -= 1;
     }
-}
 
-/*
-0x000         0x400         0x800         0xc00         0x1000
-   ______________________________________________________________________
-  | EMPTY BLOCK | EMPTY BLOCK | EMPTY BLOCK | EMPTY BLOCK | METADATA     |
-  | next: 0x400 | next: 0x800 | next: 0xc00 | next: None  | first: 0x000 |
-  |_____________|_____________|_____________|_____________|______________|
- */
+    fn containing(&self, ptr: NonNull<u8>) -> NonNull<Slab> {
+        // Single page slabs are page aligned, otherwise the slab has to be searched for
+        if self.geometry.pages.get() == 1 {
+            let addr = ptr.as_ptr() as usize;
+            return unsafe { NonNull::new_unchecked(ptr.as_ptr().sub(addr % PAGE_SIZE)).cast() };
+        }
 
-pub struct AllocError;
+        self.partial.iter()
+                .chain(self.full.iter())
+                .find(|&slab| self.geometry.contains(slab, ptr))
+                .expect("Object was not allocated by this allocator")
+    }
+}
+
+impl<S: SlabSource> Drop for SlabAllocator<S> {
+    fn drop(&mut self) {
+        assert_eq!(self.in_use, 0, "Slab allocator dropped with objects still allocated");
+        self.shrink();
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use alloc::boxed::Box;
-    use core::ops::DerefMut;
-    use crate::SlabMetadata;
+    use std::alloc::{alloc, dealloc};
+    use std::cell::Cell;
+    use std::vec::Vec;
+    use super::*;
+
+    #[derive(Default)]
+    struct TestSource {
+        live: Cell<usize>
+    }
 
-    #[repr(align(4096), C)]
-    struct AlignedPage([u8; 4096]);
+    fn slab_layout(pages: NonZeroUsize) -> Layout {
+        Layout::from_size_align(pages.get() * PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    unsafe impl SlabSource for &TestSource {
+        fn allocate_slab(&self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+            self.live.set(self.live.get() + 1);
+            NonNull::new(unsafe { alloc(slab_layout(pages)) }).ok_or(AllocError)
+        }
+
+        unsafe fn deallocate_slab(&self, slab: NonNull<u8>, pages: NonZeroUsize) {
+            self.live.set(self.live.get() - 1);
+            unsafe { dealloc(slab.as_ptr(), slab_layout(pages)); }
+        }
+    }
 
     #[test]
-    fn foo() {
-        let mut backing = unsafe {
-            let b = Box::<AlignedPage>::new_zeroed();
-            b.assume_init()
-        };
-        let baz = SlabMetadata::new(&mut backing.deref_mut().0, 8);
-        println!("{baz:?}");
+    fn small_objects_fit_in_one_page() {
+        let geometry = Geometry::of(Layout::new::<u64>());
+        assert_eq!(geometry.pages.get(), 1);
+        assert!(geometry.first_object + geometry.objects * geometry.stride <= PAGE_SIZE);
+        assert_eq!(geometry.first_object % 8, 0);
     }
 
     #[test]
-    fn just_enough_space() {
-        let mut backing = unsafe {
-            let b = Box::<AlignedPage>::new_zeroed();
-            b.assume_init()
-        };
-        let baz = SlabMetadata::new(&mut backing.deref_mut().0[0..64], 3);
-        println!("{baz:?}");
+    fn large_objects_span_pages() {
+        let geometry = Geometry::of(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap());
+        assert_eq!(geometry.objects, MIN_OBJECTS_PER_SLAB);
+        assert_eq!(geometry.first_object, PAGE_SIZE);
+        assert_eq!(geometry.pages.get(), MIN_OBJECTS_PER_SLAB + 1);
+    }
+
+    #[test]
+    fn objects_are_distinct_and_aligned() {
+        let source = TestSource::default();
+        let mut slabs = SlabAllocator::new(&source, Layout::new::<[u64; 3]>(), Hooks::default());
+
+        let mut objects = (0..500).map(|_| slabs.allocate().unwrap()).collect::<Vec<_>>();
+        objects.sort();
+        objects.dedup();
+        assert_eq!(objects.len(), 500);
+        assert!(objects.iter().all(|ptr| ptr.as_ptr() as usize % 8 == 0));
+
+        for object in objects { unsafe { slabs.deallocate(object); } }
+        assert_eq!(slabs.stats().in_use, 0);
+    }
+
+    #[test]
+    fn stats_track_slabs() {
+        let source = TestSource::default();
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let mut slabs = SlabAllocator::new(&source, layout, Hooks::default());
+
+        let objects = (0..=MIN_OBJECTS_PER_SLAB).map(|_| slabs.allocate().unwrap()).collect::<Vec<_>>();
+        assert_eq!(slabs.stats(), Stats {
+            object_size: PAGE_SIZE,
+            in_use: MIN_OBJECTS_PER_SLAB + 1,
+            free: MIN_OBJECTS_PER_SLAB - 1,
+            slabs: 2
+        });
+
+        for object in objects { unsafe { slabs.deallocate(object); } }
+        assert_eq!(slabs.stats().slabs, 1, "One empty slab should be kept");
+        assert_eq!(slabs.shrink(), 1);
+        assert_eq!(source.live.get(), 0);
+    }
+
+    #[test]
+    fn objects_stay_constructed() {
+        static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+        static DESTRUCTED: AtomicUsize = AtomicUsize::new(0);
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        unsafe fn construct(ptr: NonNull<u8>) {
+            unsafe { ptr.cast::<u32>().write(0xcafe); }
+            CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+        }
+
+        unsafe fn destruct(ptr: NonNull<u8>) {
+            assert_eq!(unsafe { ptr.cast::<u32>().read() }, 0xcafe);
+            DESTRUCTED.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let source = TestSource::default();
+        let mut slabs = SlabAllocator::new(&source, Layout::new::<u32>(), Hooks {
+            constructor: Some(construct),
+            destructor: Some(destruct)
+        });
+
+        let object = slabs.allocate().unwrap();
+        let per_slab = CONSTRUCTED.load(Ordering::Relaxed);
+        assert_eq!(unsafe { object.cast::<u32>().read() }, 0xcafe);
+        unsafe { slabs.deallocate(object); }
+
+        let object = slabs.allocate().unwrap();
+        assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), per_slab, "Reused objects aren't constructed again");
+        unsafe { slabs.deallocate(object); }
+
+        drop(slabs);
+        assert_eq!(DESTRUCTED.load(Ordering::Relaxed), per_slab);
+    }
+
+    #[test]
+    #[should_panic]
+    fn drop_with_allocated_objects() {
+        let source = TestSource::default();
+        let mut slabs = SlabAllocator::new(&source, Layout::new::<u8>(), Hooks::default());
+        let _ = slabs.allocate().unwrap();
     }
 }
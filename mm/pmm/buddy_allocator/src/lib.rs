@@ -0,0 +1,727 @@
+#![cfg_attr(not(test), no_std)]
+
+#![feature(kernel_allocation_new)]
+#![feature(kernel_frame_zero)]
+#![feature(kernel_physical_allocator_location)]
+#![feature(kernel_physical_page_offset)]
+#![feature(kernel_memory_stats)]
+#![feature(kernel_physical_allocator_reclaim)]
+#![feature(kernel_physical_allocator_non_contiguous)]
+#![feature(kernel_allocation_zeroing)]
+
+//! A binary buddy allocator for physical memory
+//!
+//! Free memory is tracked as power-of-two sized blocks of frames, from a single frame up to `2^MAX_ORDER` frames.
+//! Each order has a doubly linked free list, stored intrusively in the free frames themselves, and a bitmap marking
+//! which blocks are currently on that free list so that buddies can be found without walking the list.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use kernel_api::memory::{Frame, AllocError};
+use kernel_api::memory::allocator::{AllocationMeta, BackingAllocator, Config, ScatterList, SizedBackingAllocator, SpecificLocation, Stats};
+use kernel_api::sync::Mutex;
+use log::{debug, warn};
+
+macro_rules! alloc_err {
+    ($reason:literal) => {
+        debug!(concat!("BuddyAllocator: ", $reason));
+        return Err(AllocError.into());
+    };
+
+    ($reason:literal, $($arg:tt)+) => {
+        debug!(concat!("BuddyAllocator: ", $reason), $($arg)+);
+        return Err(AllocError.into());
+    };
+}
+
+/// The largest block is `2^MAX_ORDER` frames (1 GiB)
+const MAX_ORDER: usize = 18;
+
+/// Number of extra pending regions to reserve space for, since [`push`](BackingAllocator::push) can split a region in
+/// two and the heap may not be able to grow at that point
+const PENDING_HEADROOM: usize = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
+struct FreeNode {
+    prev: Option<usize>,
+    next: Option<usize>
+}
+
+/// Returns the smallest order whose blocks can hold `frame_count` frames
+fn order_for(frame_count: usize) -> usize {
+    frame_count.next_power_of_two().trailing_zeros() as usize
+}
+
+struct BuddyAllocator {
+    /// Absolute index of the first frame covered, aligned to the largest block size
+    base: usize,
+    /// Number of frames covered, starting from `base`
+    frame_count: usize,
+    heads: [Option<usize>; MAX_ORDER + 1],
+    free_bitmaps: [Box<[u64]>; MAX_ORDER + 1],
+    /// Free regions that have not yet been placed into the free lists
+    ///
+    /// The free lists are built lazily on first use, since frames handed out by the bootstrap allocator (including the
+    /// memory backing this allocator) are only known once they have been [`push`](BackingAllocator::push)ed.
+    pending: Vec<Range<usize>>,
+    populated: bool,
+    /// The number of frames in the regions given to the allocator, which excludes any holes in its range
+    usable_frames: usize,
+    #[cfg(test)]
+    nodes: std::collections::HashMap<usize, FreeNode>
+}
+
+impl BuddyAllocator {
+    fn new(allocation_range: Range<Frame>, regions: &mut dyn Iterator<Item = Range<Frame>>) -> Self {
+        let first = allocation_range.start - Frame::zero();
+        let last = allocation_range.end - Frame::zero();
+
+        let base = first & !((1 << MAX_ORDER) - 1);
+        let frame_count = last.saturating_sub(base);
+
+        let free_bitmaps = core::array::from_fn(|order| {
+            let bits = (frame_count >> order) + 1;
+            vec![0u64; bits.div_ceil(64)].into_boxed_slice()
+        });
+
+        let mut pending = Vec::new();
+        for region in regions {
+            let start = max(region.start - Frame::zero(), first);
+            let end = min(region.end - Frame::zero(), last);
+            if start < end { pending.push((start - base)..(end - base)); }
+        }
+        pending.reserve(PENDING_HEADROOM);
+        let usable_frames = pending.iter().map(|region| region.len()).sum();
+
+        Self {
+            base,
+            frame_count,
+            heads: [None; MAX_ORDER + 1],
+            free_bitmaps,
+            pending,
+            populated: false,
+            usable_frames,
+            #[cfg(test)]
+            nodes: Default::default()
+        }
+    }
+
+    fn to_frame(&self, index: usize) -> Frame {
+        Frame::zero() + (self.base + index)
+    }
+
+    fn to_index(&self, frame: Frame) -> Option<usize> {
+        (frame - Frame::zero()).checked_sub(self.base)
+                .filter(|&index| index < self.frame_count)
+    }
+
+    #[cfg(not(test))]
+    fn read_node(&self, index: usize) -> FreeNode {
+        let ptr = self.to_frame(index).to_page().as_ptr().cast::<FreeNode>();
+        // SAFETY: only frames that are on a free list are read, and these are owned by the allocator
+        unsafe { ptr.read() }
+    }
+
+    #[cfg(not(test))]
+    fn write_node(&mut self, index: usize, node: FreeNode) {
+        let ptr = self.to_frame(index).to_page().as_ptr().cast::<FreeNode>();
+        // SAFETY: only frames that are being put on a free list are written, and these are owned by the allocator
+        unsafe { ptr.write(node); }
+    }
+
+    #[cfg(test)]
+    fn read_node(&self, index: usize) -> FreeNode {
+        self.nodes[&index]
+    }
+
+    #[cfg(test)]
+    fn write_node(&mut self, index: usize, node: FreeNode) {
+        self.nodes.insert(index, node);
+    }
+
+    fn is_free(&self, order: usize, index: usize) -> bool {
+        let bit = index >> order;
+        self.free_bitmaps[order].get(bit / 64)
+                .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+    }
+
+    fn set_free(&mut self, order: usize, index: usize, free: bool) {
+        let bit = index >> order;
+        let word = &mut self.free_bitmaps[order][bit / 64];
+        if free { *word |= 1 << (bit % 64); }
+        else { *word &= !(1 << (bit % 64)); }
+    }
+
+    fn push_free(&mut self, order: usize, index: usize) {
+        debug_assert_eq!(index & ((1 << order) - 1), 0, "Block is not aligned to its order");
+
+        let old_head = self.heads[order];
+        if let Some(old_head) = old_head {
+            let node = self.read_node(old_head);
+            self.write_node(old_head, FreeNode { prev: Some(index), ..node });
+        }
+        self.write_node(index, FreeNode { prev: None, next: old_head });
+        self.heads[order] = Some(index);
+        self.set_free(order, index, true);
+    }
+
+    fn remove_free(&mut self, order: usize, index: usize) {
+        debug_assert!(self.is_free(order, index));
+
+        let FreeNode { prev, next } = self.read_node(index);
+        match prev {
+            Some(prev) => {
+                let node = self.read_node(prev);
+                self.write_node(prev, FreeNode { next, ..node });
+            }
+            None => self.heads[order] = next,
+        }
+        if let Some(next) = next {
+            let node = self.read_node(next);
+            self.write_node(next, FreeNode { prev, ..node });
+        }
+        self.set_free(order, index, false);
+    }
+
+    /// Frees a single aligned block, merging it with its buddies where possible
+    fn free_block(&mut self, mut order: usize, mut index: usize) {
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if !self.is_free(order, buddy) { break; }
+
+            self.remove_free(order, buddy);
+            index &= !(1 << order);
+            order += 1;
+        }
+
+        self.push_free(order, index);
+    }
+
+    /// Frees an arbitrary range of frames by splitting it into the largest aligned blocks possible
+    fn free_range(&mut self, Range { mut start, end }: Range<usize>) {
+        while start < end {
+            let mut order = min(start.trailing_zeros() as usize, MAX_ORDER);
+            while start + (1 << order) > end { order -= 1; }
+
+            self.free_block(order, start);
+            start += 1 << order;
+        }
+    }
+
+    /// Frees every frame in a range that isn't already free, returning how many frames were freed
+    fn free_unless_free(&mut self, Range { start, end }: Range<usize>) -> usize {
+        let mut freed = 0;
+        let mut i = start;
+        while i < end {
+            if let Some((order, block)) = self.containing_free_block(i) {
+                i = block + (1 << order);
+                continue;
+            }
+
+            let run_start = i;
+            while i < end && self.containing_free_block(i).is_none() { i += 1; }
+            self.free_range(run_start..i);
+            freed += i - run_start;
+        }
+
+        freed
+    }
+
+    /// Builds the free lists from any regions not yet added
+    fn populate(&mut self) {
+        if self.populated { return; }
+
+        for i in 0..self.pending.len() {
+            let region = self.pending[i].clone();
+            self.free_range(region);
+        }
+        self.pending.clear();
+        self.populated = true;
+    }
+
+    /// Removes a range of frames from the pending regions
+    ///
+    /// Returns `false` if there was not enough space to keep track of the remaining regions
+    fn reserve_pending(&mut self, Range { start, end }: Range<usize>) -> bool {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let region = self.pending[i].clone();
+            if region.end <= start || end <= region.start {
+                i += 1;
+                continue;
+            }
+
+            let before = region.start..start;
+            let after = end..region.end;
+            match (before.is_empty(), after.is_empty()) {
+                (true, true) => { self.pending.swap_remove(i); continue; },
+                (false, true) => self.pending[i] = before,
+                (true, false) => self.pending[i] = after,
+                (false, false) => {
+                    if self.pending.len() == self.pending.capacity() { return false; }
+                    self.pending[i] = before;
+                    self.pending.push(after);
+                }
+            }
+            i += 1;
+        }
+
+        true
+    }
+
+    fn stats(&self) -> Stats {
+        let mut free_frames = self.pending.iter().map(|region| region.len()).sum();
+        let mut largest_free_run = self.pending.iter().map(|region| region.len()).max().unwrap_or(0);
+
+        // Neighbouring free blocks that aren't buddies are never merged, so the largest run is only a lower bound
+        for (order, bitmap) in self.free_bitmaps.iter().enumerate() {
+            let blocks: usize = bitmap.iter().map(|word| word.count_ones() as usize).sum();
+            free_frames += blocks << order;
+            if blocks > 0 { largest_free_run = max(largest_free_run, 1 << order); }
+        }
+
+        Stats {
+            total_frames: self.usable_frames,
+            free_frames,
+            largest_free_run
+        }
+    }
+
+    /// Finds the free block containing `index`, returning its order and first frame
+    fn containing_free_block(&self, index: usize) -> Option<(usize, usize)> {
+        (0..=MAX_ORDER).map(|order| (order, index & !((1 << order) - 1)))
+                .find(|&(order, block)| self.is_free(order, block))
+    }
+
+    /// Marks a range of frames as allocated, if every frame within it is free
+    fn claim_range(&mut self, Range { start, end }: Range<usize>) -> Result<(), AllocError> {
+        if end > self.frame_count { alloc_err!("Requested range {:#x?} out of range", start..end); }
+
+        let mut i = start;
+        while i < end {
+            let Some((order, block)) = self.containing_free_block(i) else {
+                alloc_err!("Requested memory at {:#x?} already allocated", self.to_frame(i));
+            };
+            i = block + (1 << order);
+        }
+
+        let mut i = start;
+        while i < end {
+            let (order, block) = self.containing_free_block(i).expect("Range was checked to be free");
+            let block_end = block + (1 << order);
+
+            self.remove_free(order, block);
+            self.free_range(block..i);
+            self.free_range(min(end, block_end)..block_end);
+            i = block_end;
+        }
+
+        Ok(())
+    }
+
+    /// Allocates a block of the given order, splitting larger blocks as required
+    fn allocate_block(&mut self, order: usize) -> Result<usize, AllocError> {
+        let Some(mut found_order) = (order..=MAX_ORDER).find(|&order| self.heads[order].is_some()) else {
+            alloc_err!("No free blocks of order {} or above", order);
+        };
+        let index = self.heads[found_order].expect("Order was checked to have free blocks");
+        self.remove_free(found_order, index);
+
+        while found_order > order {
+            found_order -= 1;
+            self.push_free(found_order, index + (1 << found_order));
+        }
+
+        Ok(index)
+    }
+
+    fn allocate_aligned(&mut self, frame_count: usize, alignment_order: usize) -> Result<Frame, AllocError> {
+        let order = max(order_for(frame_count), alignment_order);
+        if order > MAX_ORDER { alloc_err!("Allocation of {} frames is larger than the maximum block size", frame_count); }
+
+        let index = self.allocate_block(order)?;
+        self.free_range((index + frame_count)..(index + (1 << order)));
+        Ok(self.to_frame(index))
+    }
+
+    /// Allocates `frame_count` frames as blocks of decreasing order, for when no single block is large enough
+    #[cold]
+    fn allocate_scattered(&mut self, frame_count: usize) -> Result<ScatterList, AllocError> {
+        let mut frames = ScatterList::new();
+        let mut remaining = frame_count;
+        let mut order = MAX_ORDER;
+
+        while remaining > 0 {
+            order = min(order, (usize::BITS - 1 - remaining.leading_zeros()) as usize);
+
+            let index = match self.allocate_block(order) {
+                Ok(index) => index,
+                Err(_) if order > 0 => { order -= 1; continue; },
+                Err(_) => {
+                    self.free_scattered(&frames);
+                    alloc_err!("No free memory");
+                }
+            };
+
+            let base = self.to_frame(index);
+            if frames.push(base..(base + (1 << order))).is_err() {
+                self.free_block(order, index);
+                self.free_scattered(&frames);
+                alloc_err!("Free memory too fragmented for {} frames", frame_count);
+            }
+            remaining -= 1 << order;
+        }
+
+        Ok(frames)
+    }
+
+    fn free_scattered(&mut self, frames: &ScatterList) {
+        for run in frames.runs() {
+            let start = self.to_index(run.start).expect("Cannot have allocated an out of range frame");
+            self.free_range(start..(start + (run.end - run.start)));
+        }
+    }
+
+    fn allocate_below(&mut self, frame_count: usize, alignment_order: usize, limit: Frame) -> Result<Frame, AllocError> {
+        let order = max(order_for(frame_count), alignment_order);
+        if order > MAX_ORDER { alloc_err!("Allocation of {} frames is larger than the maximum block size", frame_count); }
+
+        let limit = (limit - Frame::zero()).saturating_sub(self.base);
+
+        for found_order in order..=MAX_ORDER {
+            let mut next = self.heads[found_order];
+            while let Some(index) = next {
+                if index + frame_count <= limit {
+                    self.remove_free(found_order, index);
+                    self.free_range((index + frame_count)..(index + (1 << found_order)));
+                    return Ok(self.to_frame(index));
+                }
+                next = self.read_node(index).next;
+            }
+        }
+
+        alloc_err!("No free memory below {:#x?}", self.to_frame(limit));
+    }
+}
+
+pub struct Wrapped(Mutex<BuddyAllocator>);
+
+unsafe impl BackingAllocator for Wrapped {
+    fn allocate(&self, frame_count: usize) -> Result<ScatterList, AllocError> {
+        if frame_count == 0 { return Ok(ScatterList::new()); }
+
+        let mut guard = self.0.lock();
+        guard.populate();
+
+        match guard.allocate_aligned(frame_count, 0) {
+            Ok(base) => Ok(ScatterList::from(base..(base + frame_count))),
+            Err(_) => guard.allocate_scattered(frame_count)
+        }
+    }
+
+    fn allocate_contiguous(&self, frame_count: usize) -> Result<Frame, AllocError> {
+        if frame_count == 0 { return Ok(Frame::zero()); }
+
+        let mut guard = self.0.lock();
+        guard.populate();
+        guard.allocate_aligned(frame_count, 0)
+    }
+
+    fn allocate_zeroed_nowait(&self) -> Result<Frame, AllocError> {
+        let mut guard = self.0.try_lock().ok_or(AllocError)?;
+        guard.populate();
+        let frame = guard.allocate_aligned(1, 0)?;
+        drop(guard);
+
+        unsafe { core::ptr::write_bytes(frame.to_page().as_ptr(), 0, 4096); }
+        Ok(frame)
+    }
+
+    unsafe fn deallocate_contiguous(&self, base: Frame, frame_count: NonZeroUsize) {
+        let mut guard = self.0.lock();
+        guard.populate();
+
+        let start = guard.to_index(base)
+                .expect("Attempted to free frame that wasn't allocated by this allocator");
+        let end = start + frame_count.get();
+        assert!(end <= guard.frame_count, "Attempted to free frame that wasn't allocated by this allocator");
+
+        guard.free_range(start..end);
+    }
+
+    fn push(&mut self, allocation: AllocationMeta) {
+        let allocator = self.0.get_mut();
+
+        let Range { start, end } = allocation.region;
+        let start = (start - Frame::zero()).saturating_sub(allocator.base);
+        let end = min((end - Frame::zero()).saturating_sub(allocator.base), allocator.frame_count);
+        if start >= end { return; }
+
+        if !allocator.populated {
+            if allocator.reserve_pending(start..end) { return; }
+
+            warn!("BuddyAllocator: ran out of space for pending regions, populating free lists early");
+            allocator.populate();
+        }
+
+        allocator.claim_range(start..end)
+                .expect("Pushed allocation overlaps memory that was already allocated");
+    }
+
+    fn allocate_at(&self, frame_count: usize, location: SpecificLocation) -> Result<Frame, AllocError> {
+        if frame_count == 0 { return Ok(Frame::zero()); }
+
+        let mut guard = self.0.lock();
+        guard.populate();
+
+        match location {
+            SpecificLocation::Aligned(alignment) => {
+                if !alignment.is_power_of_two() { alloc_err!("Alignment {} is not a power of two", alignment); }
+                guard.allocate_aligned(frame_count, alignment.trailing_zeros() as usize)
+            }
+            SpecificLocation::At(addr) => {
+                let Some(start) = guard.to_index(addr) else {
+                    alloc_err!("Requested memory at {:x?} out of range", addr);
+                };
+                guard.claim_range(start..(start + frame_count))?;
+                Ok(addr)
+            }
+            SpecificLocation::Below { location, with_alignment } => {
+                if !with_alignment.is_power_of_two() { alloc_err!("Alignment {} is not a power of two", with_alignment); }
+                guard.allocate_below(frame_count, with_alignment.trailing_zeros() as usize, location)
+            }
+        }
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.0.lock().stats())
+    }
+
+    fn region_stats(&self, f: &mut dyn FnMut(Range<Frame>, Stats)) {
+        let guard = self.0.lock();
+        let range = guard.to_frame(0)..guard.to_frame(guard.frame_count);
+        let stats = guard.stats();
+        drop(guard);
+
+        f(range, stats);
+    }
+
+    unsafe fn add_region(&self, region: Range<Frame>) -> Result<(), ()> {
+        let mut guard = self.0.lock();
+        guard.populate();
+
+        let Some(start) = guard.to_index(region.start) else { return Err(()); };
+        let end = start + (region.end - region.start);
+        if end > guard.frame_count { return Err(()); }
+
+        // Frames that are already free were counted when they were given to the allocator
+        let freed = guard.free_unless_free(start..end);
+        guard.usable_frames += freed;
+        Ok(())
+    }
+}
+
+unsafe impl SizedBackingAllocator for Wrapped {
+    fn new(config: Config) -> &'static mut dyn BackingAllocator where Self: Sized {
+        let allocator = BuddyAllocator::new(config.allocation_range, config.regions);
+        Box::leak(Box::new(Wrapped(Mutex::new(allocator))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::{NonZeroU32, NonZeroUsize};
+    use kernel_api::memory::{Frame, PhysicalAddress};
+    use kernel_api::memory::allocator::{AllocationMeta, BackingAllocator, SpecificLocation};
+    use kernel_api::sync::Mutex;
+    use super::{BuddyAllocator, MAX_ORDER, Wrapped};
+
+    fn frame(index: usize) -> Frame {
+        Frame::new(PhysicalAddress::new(index * 4096))
+    }
+
+    fn allocator(regions: &[(usize, usize)]) -> Wrapped {
+        let start = regions.iter().map(|&(start, _)| start).min().unwrap();
+        let end = regions.iter().map(|&(_, end)| end).max().unwrap();
+        let mut regions = regions.iter().map(|&(start, end)| frame(start)..frame(end));
+        Wrapped(Mutex::new(BuddyAllocator::new(frame(start)..frame(end), &mut regions)))
+    }
+
+    #[test]
+    fn allocations_are_disjoint() {
+        let allocator = allocator(&[(0, 64)]);
+
+        let a = allocator.allocate_contiguous(3).unwrap();
+        let b = allocator.allocate_contiguous(5).unwrap();
+        let c = allocator.allocate_one().unwrap();
+
+        let ranges = [a..(a + 3), b..(b + 5), c..(c + 1)];
+        for (i, x) in ranges.iter().enumerate() {
+            for y in &ranges[(i + 1)..] {
+                assert!(x.end <= y.start || y.end <= x.start, "{x:?} overlaps {y:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let allocator = allocator(&[(0, 8)]);
+
+        assert!(allocator.allocate_contiguous(8).is_ok());
+        assert!(allocator.allocate_one().is_err());
+    }
+
+    #[test]
+    fn coalesces_on_free() {
+        let allocator = allocator(&[(0, 16)]);
+
+        let frames: Vec<_> = (0..16).map(|_| allocator.allocate_one().unwrap()).collect();
+        for frame in frames {
+            unsafe { allocator.deallocate_contiguous(frame, NonZeroUsize::new(1).unwrap()); }
+        }
+
+        assert_eq!(allocator.allocate_contiguous(16), Ok(frame(0)));
+    }
+
+    #[test]
+    fn stats_track_allocations() {
+        let allocator = allocator(&[(0, 16), (32, 40)]);
+        assert_eq!(allocator.stats().map(|stats| stats.free_frames), Some(24));
+
+        allocator.allocate_contiguous(4).unwrap();
+        let stats = allocator.stats().unwrap();
+        assert_eq!((stats.total_frames, stats.free_frames, stats.used_frames()), (24, 20, 4));
+        assert!(stats.largest_free_run >= 8);
+    }
+
+    #[test]
+    fn added_region_can_be_allocated() {
+        let allocator = allocator(&[(0, 8), (24, 32)]);
+        assert!(allocator.allocate_contiguous(16).is_err());
+
+        unsafe { allocator.add_region(frame(8)..frame(24)).unwrap(); }
+        assert_eq!(allocator.stats().map(|stats| stats.total_frames), Some(32));
+        assert_eq!(allocator.allocate_contiguous(32), Ok(frame(0)));
+    }
+
+    #[test]
+    fn add_region_rejects_out_of_range() {
+        let allocator = allocator(&[(0, 8)]);
+
+        assert!(unsafe { allocator.add_region(frame(4)..frame(12)) }.is_err());
+        assert_eq!(allocator.stats().map(|stats| stats.total_frames), Some(8));
+    }
+
+    #[test]
+    fn add_region_skips_free_frames() {
+        let allocator = allocator(&[(0, 8), (12, 16)]);
+
+        unsafe { allocator.add_region(frame(4)..frame(16)).unwrap(); }
+        let stats = allocator.stats().unwrap();
+        assert_eq!((stats.total_frames, stats.free_frames), (16, 16));
+
+        let mut allocated: Vec<_> = (0..16).map(|_| allocator.allocate_one().unwrap() - frame(0)).collect();
+        allocated.sort();
+        assert_eq!(allocated, (0..16).collect::<Vec<_>>());
+        assert!(allocator.allocate_one().is_err());
+    }
+
+    #[test]
+    fn scattered_allocation() {
+        let allocator = allocator(&[(0, 4), (8, 10), (16, 19)]);
+
+        let frames = allocator.allocate(9).unwrap();
+        let mut allocated: Vec<_> = frames.frames().map(|allocated| allocated - frame(0)).collect();
+        allocated.sort();
+        assert_eq!(allocated, (0..4).chain(8..10).chain(16..19).collect::<Vec<_>>());
+        assert!(allocator.allocate_one().is_err());
+    }
+
+    #[test]
+    fn failed_scattered_allocation_frees_everything() {
+        let allocator = allocator(&[(0, 4), (8, 10)]);
+
+        assert!(allocator.allocate(7).is_err());
+        assert_eq!(allocator.stats().map(|stats| stats.free_frames), Some(6));
+    }
+
+    #[test]
+    fn tail_of_block_is_reused() {
+        let allocator = allocator(&[(0, 8)]);
+
+        assert_eq!(allocator.allocate_contiguous(5), Ok(frame(0)));
+        assert_eq!(allocator.allocate_contiguous(2), Ok(frame(6)));
+        assert_eq!(allocator.allocate_one(), Ok(frame(5)));
+    }
+
+    #[test]
+    fn aligned_allocation() {
+        let allocator = allocator(&[(1, 64)]);
+
+        let allocated = allocator.allocate_at(2, SpecificLocation::Aligned(NonZeroU32::new(16).unwrap())).unwrap();
+        assert_eq!((allocated - frame(0)) % 16, 0);
+        assert!(allocator.allocate_at(1, SpecificLocation::Aligned(NonZeroU32::new(3).unwrap())).is_err());
+    }
+
+    #[test]
+    fn allocate_at() {
+        let allocator = allocator(&[(0, 64)]);
+
+        assert_eq!(allocator.allocate_at(4, SpecificLocation::At(frame(13))), Ok(frame(13)));
+        assert!(allocator.allocate_at(1, SpecificLocation::At(frame(15))).is_err());
+        assert_eq!(allocator.allocate_at(13, SpecificLocation::At(frame(0))), Ok(frame(0)));
+        assert_eq!(allocator.allocate_contiguous(32), Ok(frame(32)));
+    }
+
+    #[test]
+    fn allocate_below() {
+        let allocator = allocator(&[(0, 4), (32, 64)]);
+
+        let location = SpecificLocation::Below { location: frame(8), with_alignment: NonZeroU32::new(1).unwrap() };
+        assert_eq!(allocator.allocate_at(4, location), Ok(frame(0)));
+
+        let location = SpecificLocation::Below { location: frame(8), with_alignment: NonZeroU32::new(1).unwrap() };
+        assert!(allocator.allocate_at(1, location).is_err());
+    }
+
+    #[test]
+    fn pushed_allocations_are_reserved() {
+        let mut allocator = allocator(&[(0, 32)]);
+
+        allocator.push(AllocationMeta::new(frame(20)..frame(32)));
+        allocator.push(AllocationMeta::new(frame(2)..frame(4)));
+
+        let mut allocated = Vec::new();
+        while let Ok(allocation) = allocator.allocate_one() {
+            allocated.push(allocation - frame(0));
+        }
+        allocated.sort();
+        assert_eq!(allocated, [0, 1].into_iter().chain(4..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic]
+    fn pushing_allocated_memory_panics() {
+        let mut allocator = allocator(&[(0, 32)]);
+
+        allocator.allocate_contiguous(32).unwrap();
+        allocator.push(AllocationMeta::new(frame(4)..frame(8)));
+    }
+
+    #[test]
+    fn unaligned_base() {
+        let start = (1 << MAX_ORDER) + 3;
+        let allocator = allocator(&[(start, start + 5)]);
+
+        let mut allocated: Vec<_> = (0..5).map(|_| allocator.allocate_one().unwrap() - frame(0)).collect();
+        allocated.sort();
+        assert_eq!(allocated, (start..(start + 5)).collect::<Vec<_>>());
+    }
+}
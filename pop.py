@@ -18,6 +18,7 @@ parser.add_argument("-j", "--jobs", action="store", type=int)
 parser.add_argument("--release", action="store_true")
 parser.add_argument("--accel", choices=["none", "kvm", "hvf"], default="none")
 parser.add_argument("--symbol-map", action="store_true")
+parser.add_argument("--kernel-features", action="store", help="comma separated list of extra features to enable for the kernel")
 
 args, subcommand_parse = parser.parse_known_args()
 
@@ -111,6 +112,8 @@ def run_qemu(iso: str, *qemu_args: [str], capture_output: bool = False) -> tuple
 def build(kernel_file: str | None = None, kernel_cargo_flags = None, kernel_build_env: dict[str, str] | None = None):
     if kernel_cargo_flags is None:
         kernel_cargo_flags = []
+    if args.kernel_features:
+        kernel_cargo_flags = [*kernel_cargo_flags, "--features", args.kernel_features]
     if kernel_build_env is None:
         kernel_build_env = {}
     try:
@@ -6,13 +6,13 @@ use alloc::collections::BTreeMap;
 use core::cmp::Ordering;
 use core::ops::Range;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RangedBTreeMap<K, V> {
     inner: BTreeMap<KeyType<K>, V>
 }
 
 impl<K, V> RangedBTreeMap<K, V> {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             inner: BTreeMap::new()
         }
@@ -29,6 +29,19 @@ impl<K, V> RangedBTreeMap<K, V> where K: Ord {
         self.inner.get(&KeyType::Point(point))
     }
 
+    pub fn get_entry_at_point_mut(&mut self, point: K) -> Option<&mut V> {
+        self.inner.get_mut(&KeyType::Point(point))
+    }
+
+    /// Returns the range containing `point` along with its value
+    pub fn get_range_at_point(&self, point: K) -> Option<(&Range<K>, &V)> {
+        self.inner.get_key_value(&KeyType::Point(point))
+                .map(|(k, v)| match k {
+                    KeyType::Range(r) => (r, v),
+                    _ => unreachable!()
+                })
+    }
+
     pub fn used_regions(&self) -> impl Iterator<Item = &Range<K>> + '_ {
         self.inner.keys()
                 .map(|key| match key {
@@ -37,6 +50,15 @@ impl<K, V> RangedBTreeMap<K, V> where K: Ord {
                 })
     }
 
+    /// Iterates over every range along with its value, in order
+    pub fn iter(&self) -> impl Iterator<Item = (&Range<K>, &V)> + '_ {
+        self.inner.iter()
+                .map(|(k, v)| match k {
+                    KeyType::Range(r) => (r, v),
+                    _ => unreachable!()
+                })
+    }
+
     pub fn first_key(&self) -> Option<&K> {
         self.inner.first_key_value()
             .map(|(k, _)| match k {
@@ -58,7 +80,7 @@ impl<K, V> RangedBTreeMap<K, V> where K: Ord {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum KeyType<T> {
     Range(Range<T>),
     Point(T)
@@ -118,6 +140,22 @@ mod tests {
         b.insert(3u8..u8::MAX, "bar").unwrap_err();
     }
 
+    #[test]
+    fn retrieve_whole_range() {
+        let mut b = RangedBTreeMap::new();
+        b.insert(6u8..34, "foo").unwrap();
+        assert_eq!(b.get_range_at_point(20), Some((&(6..34), &"foo")));
+        assert_eq!(b.get_range_at_point(34), None);
+    }
+
+    #[test]
+    fn iterates_in_order() {
+        let mut b = RangedBTreeMap::new();
+        b.insert(10u8..20, "bar").unwrap();
+        b.insert(0u8..5, "foo").unwrap();
+        assert!(b.iter().eq([(&(0..5), &"foo"), (&(10..20), &"bar")]));
+    }
+
     #[test]
     fn cannot_retrieve_outside_range() {
         let mut b = RangedBTreeMap::new();
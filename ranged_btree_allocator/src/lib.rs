@@ -1,88 +1,266 @@
 #![cfg_attr(not(test), no_std)]
 
 #![feature(kernel_virtual_memory)]
+#![feature(kernel_memory_addr_access)]
 
 use core::cmp::{max, min};
-use core::ops::Range;
-use kernel_api::memory::{AllocError, Page};
+use core::fmt::{Debug, Formatter};
+use core::ops::{Range, RangeInclusive};
+use kernel_api::memory::{AllocError, Page, VirtualAddress};
 use ranged_btree::RangedBTreeMap;
-use kernel_api::memory::r#virtual::VirtualAllocator;
+use kernel_api::memory::r#virtual::{Stats, VirtualAllocator};
 use kernel_api::sync::Mutex;
 
+const PAGE_SIZE: usize = 4096;
+
+/// How an allocation is placed when the caller doesn't ask for a specific address
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Placement {
+    /// The start of the lowest gap that is large enough
+    FirstFit,
+    /// The start of the smallest gap that is large enough, which keeps large gaps free for large allocations
+    BestFit,
+    /// A random position within any gap that is large enough, so that mappings aren't at predictable addresses
+    Randomised { seed: u64 }
+}
+
+/// A SplitMix64 generator, which is plenty for picking addresses but isn't cryptographically secure
 #[derive(Debug)]
-struct Meta {
-    len: usize
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
 }
 
 #[derive(Debug)]
+struct Inner {
+    /// Allocated regions, as ranges of page numbers
+    ///
+    /// Page numbers are used rather than [`Page`]s so that the last page of the address space can have an end.
+    map: RangedBTreeMap<usize, ()>,
+    placement: Placement,
+    rng: Rng
+}
+
 pub struct RangedBtreeAllocator {
-    range: Range<Page>,
-    map: Mutex<RangedBTreeMap<Page, Meta>>
+    /// The managed range of page numbers
+    range: Range<usize>,
+    inner: Mutex<Inner>
+}
+
+fn number(page: Page) -> usize {
+    page.start().addr / PAGE_SIZE
+}
+
+fn page(number: usize) -> Page {
+    Page::new(VirtualAddress::new(number * PAGE_SIZE))
+}
+
+/// The first position in `gap` that an allocation of `len` pages aligned to `align` pages fits at, and how many
+/// positions it fits at in total
+fn fits(gap: &Range<usize>, len: usize, align: usize) -> Option<(usize, usize)> {
+    let first = gap.start.checked_next_multiple_of(align)?;
+    let last = gap.end.checked_sub(len)?;
+    if first > last { return None; }
+    Some((first, (last - first) / align + 1))
 }
 
 impl RangedBtreeAllocator {
     pub fn new(range: Range<Page>) -> Self {
+        Self::from_numbers(number(range.start)..number(range.end))
+    }
+
+    /// Creates an allocator that includes `range.end`, so that the last page of the address space can be managed
+    pub fn new_inclusive(range: RangeInclusive<Page>) -> Self {
+        Self::from_numbers(number(*range.start())..(number(*range.end()) + 1))
+    }
+
+    fn from_numbers(range: Range<usize>) -> Self {
         Self {
             range,
-            map: Mutex::new(RangedBTreeMap::new())
+            inner: Mutex::new(Inner {
+                map: RangedBTreeMap::new(),
+                placement: Placement::BestFit,
+                rng: Rng(0)
+            })
+        }
+    }
+
+    /// Sets how allocations without a specific address are placed, which is [`Placement::BestFit`] by default
+    pub fn with_placement(mut self, placement: Placement) -> Self {
+        let inner = self.inner.get_mut();
+        inner.placement = placement;
+        if let Placement::Randomised { seed } = placement {
+            inner.rng = Rng(seed);
         }
+        self
     }
 
     pub fn add_allocations(&mut self, allocations: impl IntoIterator<Item = Range<Page>>) {
-        let guard = self.map.get_mut();
+        let inner = self.inner.get_mut();
 
         for allocation in allocations {
-            let isect = max(allocation.start, self.range.start)..min(allocation.end, self.range.end);
+            let isect = max(number(allocation.start), self.range.start)..min(number(allocation.end), self.range.end);
             if !isect.is_empty() {
-                let _ = guard.insert(allocation, Meta { len: isect.end - isect.start });
+                let _ = inner.map.insert(isect, ());
             }
         }
     }
-}
 
-impl VirtualAllocator for RangedBtreeAllocator {
-    fn allocate_contiguous(&self, len: usize) -> Result<Page, AllocError> {
-        let mut guard = self.map.lock();
-
-        let Some(first) = guard.first_key() else {
-            guard.insert(self.range.start..(self.range.start + len), Meta { len })
-                    .expect("BTree should be empty");
-            return Ok(self.range.start);
-        };
-
-        if (*first - self.range.start) >= len {
-            guard.insert(self.range.start..(self.range.start + len), Meta { len })
-                 .expect("Just checked this region is free");
-            return Ok(self.range.start);
+    /// The unallocated ranges of page numbers, in ascending order
+    fn gaps<'a>(&'a self, map: &'a RangedBTreeMap<usize, ()>) -> impl Iterator<Item = Range<usize>> + 'a {
+        let mut gap_start = self.range.start;
+        map.used_regions()
+                .map(Some)
+                .chain([None])
+                .filter_map(move |region| {
+                    let gap = match region {
+                        Some(region) => {
+                            let gap = gap_start..region.start.clamp(self.range.start, self.range.end);
+                            gap_start = max(gap_start, region.end.clamp(self.range.start, self.range.end));
+                            gap
+                        },
+                        None => gap_start..self.range.end
+                    };
+                    (!gap.is_empty()).then_some(gap)
+                })
+    }
+
+    /// Calls `f` with the first page and length of every unallocated region, in ascending order
+    pub fn for_each_gap(&self, mut f: impl FnMut(Page, usize)) {
+        let inner = self.inner.lock();
+        for gap in self.gaps(&inner.map) {
+            f(page(gap.start), gap.end - gap.start);
         }
+    }
+
+    /// Allocates `len` pages starting at a multiple of `align` pages
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two
+    pub fn allocate_contiguous_aligned(&self, len: usize, align: usize) -> Result<Page, AllocError> {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        if len == 0 { return Err(AllocError); }
+
+        let mut guard = self.inner.lock();
+        let inner = &mut *guard;
+
+        let candidates = self.gaps(&inner.map)
+                .filter_map(|gap| fits(&gap, len, align).map(|fit| (gap, fit)));
+
+        let start = match inner.placement {
+            Placement::FirstFit => candidates.map(|(_, (first, _))| first).next(),
+            Placement::BestFit => candidates.min_by_key(|(gap, _)| gap.end - gap.start)
+                    .map(|(_, (first, _))| first),
+            Placement::Randomised { .. } => {
+                let positions: usize = candidates.map(|(_, (_, count))| count).sum();
+                if positions == 0 { return Err(AllocError); }
+
+                // Every position in every gap is equally likely
+                let mut chosen = (inner.rng.next() % positions as u64) as usize;
+                self.gaps(&inner.map)
+                        .filter_map(|gap| fits(&gap, len, align))
+                        .find_map(|(first, count)| {
+                            if chosen < count { Some(first + chosen * align) }
+                            else { chosen -= count; None }
+                        })
+            }
+        }.ok_or(AllocError)?;
+
+        inner.map.insert(start..(start + len), ())
+                .expect("Just checked this region is free");
+        Ok(page(start))
+    }
+}
 
-        let last_used = *guard.last_key().expect("Already checked for at least one entry");
-        if (self.range.end - last_used) >= len {
-            guard.insert(last_used..(last_used + len), Meta { len })
-                 .expect("Just checked this region is free");
-            return Ok(last_used);
+impl Debug for RangedBtreeAllocator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        struct Regions<'a>(&'a RangedBTreeMap<usize, ()>);
+
+        impl Debug for Regions<'_> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+                f.debug_list()
+                        .entries(self.0.used_regions().map(|region| page(region.start)..=page(region.end - 1)))
+                        .finish()
+            }
         }
 
-        todo!()
+        let inner = self.inner.lock();
+        f.debug_struct("RangedBtreeAllocator")
+                .field("range", &(page(self.range.start)..=page(self.range.end - 1)))
+                .field("placement", &inner.placement)
+                .field("allocations", &Regions(&inner.map))
+                .finish()
+    }
+}
+
+impl VirtualAllocator for RangedBtreeAllocator {
+    fn allocate_contiguous(&self, len: usize) -> Result<Page, AllocError> {
+        self.allocate_contiguous_aligned(len, 1)
     }
 
     fn allocate_contiguous_at(&self, at: Page, len: usize) -> Result<Page, AllocError> {
-        let mut guard = self.map.lock();
-        match guard.insert(at..(at + len), Meta { len }) {
+        let start = number(at);
+        let end = start.checked_add(len).ok_or(AllocError)?;
+        if len == 0 || start < self.range.start || end > self.range.end { return Err(AllocError); }
+
+        // Any overlap with a neighbouring allocation, including reserved regions, makes the insert fail
+        let mut guard = self.inner.lock();
+        match guard.map.insert(start..end, ()) {
             Ok(_) => Ok(at),
             Err(_) => Err(AllocError)
         }
     }
 
+    /// Deallocates `len` pages from `base`
+    ///
+    /// This may cover just part of an allocation, or several adjacent allocations, such as a mapping that was extended
+    /// in place with [`allocate_contiguous_at`](Self::allocate_contiguous_at).
     fn deallocate_contiguous(&self, base: Page, len: usize) {
-        // assumes that deallocations cover an entire allocation
+        let start = number(base);
+        let end = start + len;
+
+        let mut guard = self.inner.lock();
+        let mut cursor = start;
+        while cursor < end {
+            let Some((region, _)) = guard.map.get_range_at_point(cursor) else {
+                unreachable!("Attempted to deallocate memory that wasn't allocated by this allocator")
+            };
+            let region = region.clone();
+
+            guard.map.remove(cursor);
+            if region.start < cursor {
+                guard.map.insert(region.start..cursor, ()).expect("Region was just removed");
+            }
+            if region.end > end {
+                guard.map.insert(end..region.end, ()).expect("Region was just removed");
+            }
+            cursor = region.end;
+        }
+    }
 
-        let mut guard = self.map.lock();
-        if let Some(meta) = guard.remove(base) {
-            debug_assert_eq!(meta.len, len);
-        } else {
-            unreachable!("Attempted to deallocate memory that wasn't allocated by this allocator")
+    fn stats(&self) -> Option<Stats> {
+        let guard = self.inner.lock();
+
+        let mut free_pages = 0;
+        let mut largest_free_run = 0;
+        for gap in self.gaps(&guard.map) {
+            free_pages += gap.end - gap.start;
+            largest_free_run = max(largest_free_run, gap.end - gap.start);
         }
+
+        Some(Stats {
+            total_pages: self.range.end - self.range.start,
+            free_pages,
+            largest_free_run
+        })
     }
 }
 
@@ -126,6 +304,21 @@ mod tests {
         assert_eq!(allocation, START + 10);
     }
 
+    #[test]
+    fn stats_count_gaps() {
+        let allocator = RangedBtreeAllocator::new(START..END);
+        let total = END - START;
+
+        allocator.allocate_contiguous(5).expect("Allocation should not fail");
+        allocator.allocate_contiguous_at(START + 10, 5).expect("Region is free");
+
+        let stats = allocator.stats().unwrap();
+        assert_eq!(stats.total_pages, total);
+        assert_eq!(stats.free_pages, total - 10);
+        assert_eq!(stats.used_pages(), 10);
+        assert_eq!(stats.largest_free_run, total - 15);
+    }
+
     #[test]
     fn allocate_and_deallocate() {
         let allocator = RangedBtreeAllocator::new(START..END);
@@ -142,6 +335,99 @@ mod tests {
         assert_eq!(allocation, START);
     }
 
+    #[test]
+    fn best_fit_uses_smallest_gap() {
+        let allocator = RangedBtreeAllocator::new(START..END);
+
+        allocator.allocate_contiguous_at(START + 10, 1).expect("Region is free");
+        allocator.allocate_contiguous_at(START + 14, 1).expect("Region is free");
+
+        let allocation = allocator.allocate_contiguous(3).expect("Allocation should not fail");
+        assert_eq!(allocation, START + 11);
+    }
+
+    #[test]
+    fn first_fit_uses_lowest_gap() {
+        let allocator = RangedBtreeAllocator::new(START..END).with_placement(Placement::FirstFit);
+
+        allocator.allocate_contiguous_at(START + 10, 1).expect("Region is free");
+        allocator.allocate_contiguous_at(START + 14, 1).expect("Region is free");
+
+        let allocation = allocator.allocate_contiguous(3).expect("Allocation should not fail");
+        assert_eq!(allocation, START);
+    }
+
+    #[test]
+    fn aligned_allocation() {
+        let allocator = RangedBtreeAllocator::new(START..END);
+
+        allocator.allocate_contiguous(1).expect("Allocation should not fail");
+        let allocation = allocator.allocate_contiguous_aligned(4, 16).expect("Allocation should not fail");
+        assert_eq!(allocation.start().addr % (16 * PAGE_SIZE), 0);
+        assert!(allocation > START);
+    }
+
+    #[test]
+    fn allocate_at_honours_neighbours() {
+        let allocator = RangedBtreeAllocator::new(START..END);
+
+        allocator.allocate_contiguous_at(START + 10, 5).expect("Region is free");
+        allocator.allocate_contiguous_at(START + 5, 6).expect_err("Overlaps the start of the neighbour");
+        allocator.allocate_contiguous_at(START + 14, 2).expect_err("Overlaps the end of the neighbour");
+        allocator.allocate_contiguous_at(START + 5, 5).expect("Adjacent to the neighbour");
+        allocator.allocate_contiguous_at(START + 15, 5).expect("Adjacent to the neighbour");
+        allocator.allocate_contiguous_at(END - 1, 2).expect_err("Runs past the end of the range");
+    }
+
+    #[test]
+    fn partial_deallocation() {
+        let allocator = RangedBtreeAllocator::new(START..END);
+
+        let allocation = allocator.allocate_contiguous(10).expect("Allocation should not fail");
+        allocator.deallocate_contiguous(allocation + 3, 4);
+
+        allocator.allocate_contiguous_at(allocation + 3, 4).expect("Middle of the allocation was freed");
+        allocator.allocate_contiguous_at(allocation + 2, 1).expect_err("Start of the allocation is still allocated");
+        allocator.allocate_contiguous_at(allocation + 7, 1).expect_err("End of the allocation is still allocated");
+    }
+
+    #[test]
+    fn deallocate_across_adjacent_allocations() {
+        let allocator = RangedBtreeAllocator::new(START..END);
+
+        let allocation = allocator.allocate_contiguous(5).expect("Allocation should not fail");
+        allocator.allocate_contiguous_at(allocation + 5, 5).expect("Region is free");
+        allocator.deallocate_contiguous(allocation, 10);
+
+        assert_eq!(allocator.stats().unwrap().free_pages, END - START);
+    }
+
+    #[test]
+    fn randomised_allocations_are_in_range() {
+        let allocator = RangedBtreeAllocator::new(START..END).with_placement(Placement::Randomised { seed: 0x1234 });
+
+        let mut allocations = [START; 16];
+        for allocation in &mut allocations {
+            *allocation = allocator.allocate_contiguous_aligned(3, 2).expect("Allocation should not fail");
+            assert!(*allocation >= START && *allocation + 3 <= END);
+            assert_eq!(allocation.start().addr % (2 * PAGE_SIZE), 0);
+        }
+
+        // The chance of every allocation landing next to the last is vanishingly small
+        assert!(allocations.windows(2).any(|pair| pair[1] != pair[0] + 4));
+    }
+
+    #[test]
+    fn last_page_can_be_managed() {
+        let last = Page::new(VirtualAddress::new(0xffff_ffff_ffff_f000));
+        let allocator = RangedBtreeAllocator::new_inclusive((last - 1)..=last);
+
+        allocator.allocate_contiguous_at(last, 1).expect("Last page is managed");
+        assert_eq!(allocator.allocate_contiguous(1), Ok(last - 1));
+        allocator.deallocate_contiguous(last, 1);
+        assert_eq!(allocator.stats().unwrap().free_pages, 1);
+    }
+
     #[test]
     #[should_panic]
     fn allocator_length_sanity() {
@@ -149,7 +435,7 @@ mod tests {
 
         let allocation = allocator.allocate_contiguous(5).expect("Allocation should not fail");
         assert_eq!(allocation, START);
-        allocator.deallocate_contiguous(START, 3);
+        allocator.deallocate_contiguous(START, 7);
     }
 
     #[test]